@@ -0,0 +1,321 @@
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::auditlog::{self, AuditAction};
+use crate::entitymanagement::{Error, Memory, Success, MEMORY_MANAGER};
+
+// Admins of the canister before roles were stored on-chain; made SuperAdmin when an existing canister is upgraded to
+// the role registry
+const BOOTSTRAP_SUPER_ADMINS: [&str; 7] = [
+    "u6mjj-6nldg-axc2d-yhwxu-324vw-aq4s2-n4l35-boxrh-4rnbn-qyz4m-pae",
+    "grvsb-a7n2k-5ddft-lyfah-kl62t-ir2ih-4zvsc-ti5sf-qxboa-5f4zk-oae",
+    "ipd2t-z274n-iv4hx-ravmg-7yq3w-ownym-5zwnb-cfuu4-ayo4s-k7tp5-jae",
+    "3ut3n-6rt35-45boi-6vidq-fur7n-5jlvg-wggto-dqwcr-gwtuk-rst7y-zae",
+    "7eeri-bkt2d-qcqc3-7w5cv-etdbs-mulun-napzl-alxdp-7dj4j-qbbfz-cqe",
+    "jfsrf-v3jew-2j7c5-z2v5l-cpib7-7h66c-y2axw-ybf4m-qmljz-coz7i-6ae",
+    "gpjen-s3wpj-cuhjh-w3qhk-d662f-zuqhs-cp4bq-oz2hw-aqbu4-nzg6y-kqe",
+];
+
+// Principals are at most 29 bytes
+type PrincipalKey = Blob<29>;
+
+pub fn principal_key(principal: &Principal) -> PrincipalKey {
+    PrincipalKey::try_from(principal.as_slice()).expect("principals are at most 29 bytes")
+}
+
+/**
+* Role Enum
+* Privileges that can be granted to a principal. SuperAdmins hold every other role implicitly.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    SuperAdmin,
+    KYCOfficer,  // Verifies farmers, investors and agribusinesses
+    LoanOfficer, // Runs the loan lifecycle: cancellations, sweeps and round refunds
+    Treasurer,   // Moves canister funds and records fees
+    Support,     // Moderates farm images and reports
+}
+
+/**
+* RoleGrant Struct
+* A role held by a principal and who granted it.
+* @param Defined In-Line
+* @return RoleGrant instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RoleGrant {
+    pub role: Role,
+    pub granted_by: Principal,
+    pub granted_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+struct RoleGrants(Vec<RoleGrant>);
+
+impl Storable for RoleGrants {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RoleGrants {
+    const MAX_SIZE: u32 = 1_024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/**
+* PrincipalRoles Struct
+* Every role held by one principal.
+* @param Defined In-Line
+* @return PrincipalRoles instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PrincipalRoles {
+    pub principal: Principal,
+    pub grants: Vec<RoleGrant>,
+}
+
+thread_local! {
+    // Roles granted to each principal
+    static ROLE_GRANTS: RefCell<StableBTreeMap<PrincipalKey, RoleGrants, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32)))
+        ));
+}
+
+fn grants_of(principal: &Principal) -> Vec<RoleGrant> {
+    ROLE_GRANTS
+        .with(|grants| grants.borrow().get(&principal_key(principal)))
+        .map(|grants| grants.0)
+        .unwrap_or_default()
+}
+
+/**
+* Function: has_role
+* Description: Checks whether a principal holds a role. SuperAdmins and the canister's controllers hold every role.
+* @param principal: &Principal - The principal to check
+* @param role: Role - The role required
+* @return bool - Whether the principal holds the role
+*/
+pub fn has_role(principal: &Principal, role: Role) -> bool {
+    // Controllers can always recover access, even if every SuperAdmin has been revoked
+    if ic_cdk::api::is_controller(principal) {
+        return true;
+    }
+    grants_of(principal)
+        .iter()
+        .any(|grant| grant.role == role || grant.role == Role::SuperAdmin)
+}
+
+pub fn caller_has_role(role: Role) -> bool {
+    has_role(&ic_cdk::caller(), role)
+}
+
+fn require_role(role: Role) -> Result<(), String> {
+    if caller_has_role(role) {
+        Ok(())
+    } else {
+        Err(format!("Caller does not have the {:?} role", role))
+    }
+}
+
+// Guards for privileged endpoints, used as `#[update(guard = "...")]`
+pub fn caller_is_super_admin() -> Result<(), String> {
+    require_role(Role::SuperAdmin)
+}
+
+pub fn caller_is_kyc_officer() -> Result<(), String> {
+    require_role(Role::KYCOfficer)
+}
+
+pub fn caller_is_loan_officer() -> Result<(), String> {
+    require_role(Role::LoanOfficer)
+}
+
+pub fn caller_is_treasurer() -> Result<(), String> {
+    require_role(Role::Treasurer)
+}
+
+pub fn caller_is_support() -> Result<(), String> {
+    require_role(Role::Support)
+}
+
+// Adds a role to a principal's grants, returning false if they already held it
+pub fn insert_grant(principal: &Principal, role: Role, granted_by: Principal) -> bool {
+    let key = principal_key(principal);
+    ROLE_GRANTS.with(|grants| {
+        let mut grants = grants.borrow_mut();
+        let mut held = grants.get(&key).unwrap_or_default();
+        if held.0.iter().any(|grant| grant.role == role) {
+            return false;
+        }
+        held.0.push(RoleGrant {
+            role,
+            granted_by,
+            granted_at: ic_cdk::api::time(),
+        });
+        grants.insert(key, held);
+        true
+    })
+}
+
+fn super_admin_count() -> usize {
+    ROLE_GRANTS.with(|grants| {
+        grants
+            .borrow()
+            .iter()
+            .filter(|(_, held)| held.0.iter().any(|grant| grant.role == Role::SuperAdmin))
+            .count()
+    })
+}
+
+/**
+* Function: bootstrap_roles
+* Description: Grants SuperAdmin to the admins given at install, when no roles have been granted yet. With no admins
+* given nobody is granted a role; the canister's controllers pass every role check and can grant roles themselves.
+* @param admins: Vec<Principal> - The principals to make SuperAdmin
+* @return None
+*/
+pub fn bootstrap_roles(admins: Vec<Principal>) {
+    if ROLE_GRANTS.with(|grants| !grants.borrow().is_empty()) {
+        return;
+    }
+
+    let granted_by = ic_cdk::id();
+    for principal in admins {
+        insert_grant(&principal, Role::SuperAdmin, granted_by);
+    }
+}
+
+/**
+* Function: grant_legacy_admins
+* Description: Makes the admins hard-coded before the role registry existed SuperAdmin, when no roles have been
+* granted yet. Only for canisters upgraded to the role registry, which those admins were running.
+* @param None
+* @return None
+*/
+pub fn grant_legacy_admins() {
+    bootstrap_roles(
+        BOOTSTRAP_SUPER_ADMINS
+            .iter()
+            .map(|text| Principal::from_text(text).expect("bootstrap principals are valid"))
+            .collect(),
+    );
+}
+
+/**
+* Function: grant_role
+* Description: Grants a role to a principal. SuperAdmin only.
+* @param principal: Principal - The principal to grant the role to
+* @param role: Role - The role to grant
+* @return Result<Success, Error> - Success message if the role was granted, or an error otherwise
+*/
+#[update(guard = "caller_is_super_admin")]
+pub fn grant_role(principal: Principal, role: Role) -> Result<Success, Error> {
+    if principal == Principal::anonymous() {
+        return Err(Error::Error {
+            msg: "Roles cannot be granted to the anonymous principal".to_string(),
+        });
+    }
+    if !insert_grant(&principal, role, ic_cdk::caller()) {
+        return Err(Error::Error {
+            msg: format!("{} already has the {:?} role", principal, role),
+        });
+    }
+
+    auditlog::record(
+        AuditAction::RoleGranted,
+        format!("principal:{}", principal),
+        None,
+        Some(format!("{:?}", role)),
+    );
+    Ok(Success::RolesUpdated {
+        msg: format!("{:?} granted to {}", role, principal),
+    })
+}
+
+/**
+* Function: revoke_role
+* Description: Revokes a role from a principal. The last SuperAdmin cannot be revoked. SuperAdmin only.
+* @param principal: Principal - The principal to revoke the role from
+* @param role: Role - The role to revoke
+* @return Result<Success, Error> - Success message if the role was revoked, or an error otherwise
+*/
+#[update(guard = "caller_is_super_admin")]
+pub fn revoke_role(principal: Principal, role: Role) -> Result<Success, Error> {
+    let mut held = grants_of(&principal);
+    if !held.iter().any(|grant| grant.role == role) {
+        return Err(Error::Error {
+            msg: format!("{} does not have the {:?} role", principal, role),
+        });
+    }
+    if role == Role::SuperAdmin && super_admin_count() <= 1 {
+        return Err(Error::Error {
+            msg: "The last SuperAdmin cannot be revoked".to_string(),
+        });
+    }
+
+    held.retain(|grant| grant.role != role);
+    let key = principal_key(&principal);
+    ROLE_GRANTS.with(|grants| {
+        let mut grants = grants.borrow_mut();
+        if held.is_empty() {
+            grants.remove(&key);
+        } else {
+            grants.insert(key, RoleGrants(held));
+        }
+    });
+
+    auditlog::record(
+        AuditAction::RoleRevoked,
+        format!("principal:{}", principal),
+        Some(format!("{:?}", role)),
+        None,
+    );
+    Ok(Success::RolesUpdated {
+        msg: format!("{:?} revoked from {}", role, principal),
+    })
+}
+
+/**
+* Function: get_my_roles
+* Description: Returns the roles held by the caller.
+* @param None
+* @return Vec<Role> - The caller's roles
+*/
+#[query]
+pub fn get_my_roles() -> Vec<Role> {
+    grants_of(&ic_cdk::caller())
+        .into_iter()
+        .map(|grant| grant.role)
+        .collect()
+}
+
+/**
+* Function: get_role_grants
+* Description: Returns every principal holding a role. SuperAdmin only.
+* @param None
+* @return Vec<PrincipalRoles> - The principals and their roles
+*/
+#[query(guard = "caller_is_super_admin")]
+pub fn get_role_grants() -> Vec<PrincipalRoles> {
+    ROLE_GRANTS.with(|grants| {
+        grants
+            .borrow()
+            .iter()
+            .map(|(key, held)| PrincipalRoles {
+                principal: Principal::from_slice(key.as_slice()),
+                grants: held.0,
+            })
+            .collect()
+    })
+}
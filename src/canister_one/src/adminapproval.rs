@@ -1,6 +1,8 @@
 use crate::farmsagribizmanagement::FARM_IMAGES;
 use crate::entitymanagement; 
-use candid::{CandidType, Principal};
+use crate::auditlog::{self, AuditAction};
+use crate::accesscontrol::{has_role, caller_is_kyc_officer, caller_is_support, Role};
+use candid::CandidType;
 use ic_cdk::{update, caller, query};
 use serde::{Deserialize, Serialize};
 
@@ -16,56 +18,27 @@ pub enum Error {
     FarmNotFound { msg: String },
 }
 
-// Helper function to check if caller is an admin or registered entity
-fn is_authorized(entity_type: &str) -> bool {
-    let caller_id = caller();
-    
-    // First check if caller is an admin
-    if is_allowed_principal() {
-        return true;
-    }
-
-    // Then check if caller is a registered entity of the specified type
-    match entity_type {
-        "farmer" => {
-            let farmers = entitymanagement::return_farmers();
-            farmers.iter().any(|f| f.principal_id == caller_id && f.verified)
-        },
-        "investor" => {
-            let investors = entitymanagement::return_investors();
-            investors.iter().any(|i| i.principal_id == caller_id && i.verified)
-        },
-        "supply_agribusiness" => {
-            let businesses = entitymanagement::return_supply_agribusiness();
-            businesses.iter().any(|b| b.principal_id == caller_id && b.verified)
-        },
-        "farms_agribusiness" => {
-            let businesses = entitymanagement::return_farms_agribusiness();
-            businesses.iter().any(|b| b.principal_id == caller_id && b.verified)
-        },
-        _ => false
-    }
-}
-
-// Admin callers
+// Whether the caller is a SuperAdmin, kept for frontends that check admin access
 #[query]
 pub fn is_allowed_principal() -> bool {
-    let allowed_principals = vec![
-        Principal::from_text("u6mjj-6nldg-axc2d-yhwxu-324vw-aq4s2-n4l35-boxrh-4rnbn-qyz4m-pae").unwrap(),
-        Principal::from_text("grvsb-a7n2k-5ddft-lyfah-kl62t-ir2ih-4zvsc-ti5sf-qxboa-5f4zk-oae").unwrap(), 
-        Principal::from_text("ipd2t-z274n-iv4hx-ravmg-7yq3w-ownym-5zwnb-cfuu4-ayo4s-k7tp5-jae").unwrap(), 
-        Principal::from_text("3ut3n-6rt35-45boi-6vidq-fur7n-5jlvg-wggto-dqwcr-gwtuk-rst7y-zae").unwrap(),
-        Principal::from_text("7eeri-bkt2d-qcqc3-7w5cv-etdbs-mulun-napzl-alxdp-7dj4j-qbbfz-cqe").unwrap(), 
-        Principal::from_text("jfsrf-v3jew-2j7c5-z2v5l-cpib7-7h66c-y2axw-ybf4m-qmljz-coz7i-6ae").unwrap(), 
-        Principal::from_text("gpjen-s3wpj-cuhjh-w3qhk-d662f-zuqhs-cp4bq-oz2hw-aqbu4-nzg6y-kqe").unwrap()
-    ];
-
-    let caller_principal = caller();
+    has_role(&caller(), Role::SuperAdmin)
+}
 
-    allowed_principals.contains(&caller_principal)
+// Records a change to an entity's verification status in the audit log
+fn audit_verification(target: String, was_verified: bool, verified: bool, kyc_job_id: Option<&str>) {
+    let after = match kyc_job_id {
+        Some(job_id) => format!("verified: {}, KYC job: {}", verified, job_id),
+        None => format!("verified: {}", verified),
+    };
+    auditlog::record(
+        AuditAction::EntityVerified,
+        target,
+        Some(format!("verified: {}", was_verified)),
+        Some(after),
+    );
 }
 
-#[update]
+#[update(guard = "caller_is_kyc_officer")]
 pub fn verify_farmer(id: u64, verified: bool, kyc_job_id: String) -> Result<(), Error> {
     // Validate job_id is not empty
     if kyc_job_id.trim().is_empty() {
@@ -74,21 +47,17 @@ pub fn verify_farmer(id: u64, verified: bool, kyc_job_id: String) -> Result<(),
         });
     }
 
-    if !is_authorized("farmer") {
-        return Err(Error::PermissionDenied { 
-            msg: String::from("Caller must be an admin or a verified farmer") 
-        });
-    }
-
-    let mut farmers = entitymanagement::return_farmers();
-    if let Some(farmer) = farmers.iter_mut().find(|f| f.id == id) {
+    let farmer = entitymanagement::FARMER_STORAGE.with(|service| service.borrow().get(&id));
+    if let Some(mut farmer) = farmer {
+        let was_verified = farmer.verified;
         farmer.verified = verified;
         // Add the KYC job ID to the farmer struct
         // Note: You'll need to add a kyc_job_id field to your Farmer struct
-        farmer.kyc_job_id = Some(kyc_job_id);
+        farmer.kyc_job_id = Some(kyc_job_id.clone());
         
         entitymanagement::FARMER_STORAGE
-            .with(|service| service.borrow_mut().insert(id, farmer.clone()));
+            .with(|service| service.borrow_mut().insert(id, farmer));
+        audit_verification(format!("farmer:{}", id), was_verified, verified, Some(&kyc_job_id));
         Ok(())
     } else {
         Err(Error::FarmerNotFound {
@@ -97,7 +66,7 @@ pub fn verify_farmer(id: u64, verified: bool, kyc_job_id: String) -> Result<(),
     }
 }
 
-#[update]
+#[update(guard = "caller_is_kyc_officer")]
 pub fn verify_investor(id: u64, verified: bool, kyc_job_id: String) -> Result<(), Error> {
     if kyc_job_id.trim().is_empty() {
         return Err(Error::InvalidJobId { 
@@ -105,20 +74,16 @@ pub fn verify_investor(id: u64, verified: bool, kyc_job_id: String) -> Result<()
         });
     }
 
-    if !is_authorized("investor") {
-        return Err(Error::PermissionDenied { 
-            msg: String::from("Caller must be an admin or a verified investor") 
-        });
-    }
-
-    let mut investors = entitymanagement::return_investors();
-    if let Some(investor) = investors.iter_mut().find(|i| i.id == id) {
+    let investor = entitymanagement::INVESTOR_STORAGE.with(|service| service.borrow().get(&id));
+    if let Some(mut investor) = investor {
+        let was_verified = investor.verified;
         investor.verified = verified;
         // Add the KYC job ID to the investor struct
-        investor.kyc_job_id = Some(kyc_job_id);
+        investor.kyc_job_id = Some(kyc_job_id.clone());
         
         entitymanagement::INVESTOR_STORAGE
-            .with(|service| service.borrow_mut().insert(id, investor.clone()));
+            .with(|service| service.borrow_mut().insert(id, investor));
+        audit_verification(format!("investor:{}", id), was_verified, verified, Some(&kyc_job_id));
         Ok(())
     } else {
         Err(Error::InvestorNotFound {
@@ -128,7 +93,7 @@ pub fn verify_investor(id: u64, verified: bool, kyc_job_id: String) -> Result<()
 }
 
 // Debug this as it replicates a similar supply agri business when someone verifies it
-#[update]
+#[update(guard = "caller_is_kyc_officer")]
 pub fn verify_supply_agribusiness(id: u64, verified: bool, kyc_job_id: String) -> Result<(), Error> {
     if kyc_job_id.trim().is_empty() {
         return Err(Error::InvalidJobId { 
@@ -136,20 +101,16 @@ pub fn verify_supply_agribusiness(id: u64, verified: bool, kyc_job_id: String) -
         });
     }
 
-    if !is_authorized("supply_agribusiness") {
-        return Err(Error::PermissionDenied { 
-            msg: String::from("Caller must be an admin or a verified supply agribusiness") 
-        });
-    }
-
-    let mut supply_agribusinesses = entitymanagement::return_supply_agribusiness();
-    if let Some(agribiz) = supply_agribusinesses.iter_mut().find(|s| s.id == id) {
+    let agribiz = entitymanagement::SUPPLY_AGRIBUSINESS_STORAGE.with(|service| service.borrow().get(&id));
+    if let Some(mut agribiz) = agribiz {
+        let was_verified = agribiz.verified;
         agribiz.verified = verified;
         // Add the KYC job ID to the agribusiness struct
-        agribiz.kyc_job_id = Some(kyc_job_id);
+        agribiz.kyc_job_id = Some(kyc_job_id.clone());
         
         entitymanagement::SUPPLY_AGRIBUSINESS_STORAGE
-            .with(|service| service.borrow_mut().insert(id, agribiz.clone()));
+            .with(|service| service.borrow_mut().insert(id, agribiz));
+        audit_verification(format!("supply_agribusiness:{}", id), was_verified, verified, Some(&kyc_job_id));
         Ok(())
     } else {
         Err(Error::SupplyAgriBizNotFound {
@@ -160,7 +121,7 @@ pub fn verify_supply_agribusiness(id: u64, verified: bool, kyc_job_id: String) -
 
 // Debug this as it replicates a similar supply agri business when someone verifies it
 
-#[update]
+#[update(guard = "caller_is_kyc_officer")]
 pub fn verify_farms_agribusiness(id: u64, verified: bool, kyc_job_id: String) -> Result<(), Error> {
     if kyc_job_id.trim().is_empty() {
         return Err(Error::InvalidJobId { 
@@ -168,20 +129,16 @@ pub fn verify_farms_agribusiness(id: u64, verified: bool, kyc_job_id: String) ->
         });
     }
 
-    if !is_authorized("farms_agribusiness") {
-        return Err(Error::PermissionDenied { 
-            msg: String::from("Caller must be an admin or a verified farms agribusiness") 
-        });
-    }
-
-    let mut farms_agribusinesses = entitymanagement::return_farms_agribusiness();
-    if let Some(agribiz) = farms_agribusinesses.iter_mut().find(|s| s.id == id) {
+    let agribiz = entitymanagement::FARMS_AGRIBUSINESS_STORAGE.with(|service| service.borrow().get(&id));
+    if let Some(mut agribiz) = agribiz {
+        let was_verified = agribiz.verified;
         agribiz.verified = verified;
         // Add the KYC job ID to the agribusiness struct
-        agribiz.kyc_job_id = Some(kyc_job_id);
+        agribiz.kyc_job_id = Some(kyc_job_id.clone());
         
         entitymanagement::FARMS_AGRIBUSINESS_STORAGE
-            .with(|service| service.borrow_mut().insert(id, agribiz.clone()));
+            .with(|service| service.borrow_mut().insert(id, agribiz));
+        audit_verification(format!("farms_agribusiness:{}", id), was_verified, verified, Some(&kyc_job_id));
         Ok(())
     } else {
         Err(Error::FarmsAgriBizNotFound {
@@ -190,22 +147,17 @@ pub fn verify_farms_agribusiness(id: u64, verified: bool, kyc_job_id: String) ->
     }
 }
 
-#[update]
+#[update(guard = "caller_is_kyc_officer")]
 fn manual_verify_entity(entity_type: String, id: u64, verified: bool) -> Result<(), Error> {
-    // Ensure only admins can manually verify entities
-    if !is_allowed_principal() {
-        return Err(Error::PermissionDenied { 
-            msg: String::from("Caller must be an admin to manually verify entities")
-        });
-    }
-
     match entity_type.as_str() {
         "farmer" => {
-            let mut farmers = entitymanagement::return_farmers();
-            if let Some(farmer) = farmers.iter_mut().find(|f| f.id == id) {
+            let farmer = entitymanagement::FARMER_STORAGE.with(|service| service.borrow().get(&id));
+            if let Some(mut farmer) = farmer {
+                let was_verified = farmer.verified;
                 farmer.verified = verified;
                 entitymanagement::FARMER_STORAGE
-                    .with(|service| service.borrow_mut().insert(id, farmer.clone()));
+                    .with(|service| service.borrow_mut().insert(id, farmer));
+                audit_verification(format!("farmer:{}", id), was_verified, verified, None);
                 Ok(())
             } else {
                 Err(Error::FarmerNotFound {
@@ -214,11 +166,13 @@ fn manual_verify_entity(entity_type: String, id: u64, verified: bool) -> Result<
             }
         },
         "investor" => {
-            let mut investors = entitymanagement::return_investors();
-            if let Some(investor) = investors.iter_mut().find(|i| i.id == id) {
+            let investor = entitymanagement::INVESTOR_STORAGE.with(|service| service.borrow().get(&id));
+            if let Some(mut investor) = investor {
+                let was_verified = investor.verified;
                 investor.verified = verified;
                 entitymanagement::INVESTOR_STORAGE
-                    .with(|service| service.borrow_mut().insert(id, investor.clone()));
+                    .with(|service| service.borrow_mut().insert(id, investor));
+                audit_verification(format!("investor:{}", id), was_verified, verified, None);
                 Ok(())
             } else {
                 Err(Error::InvestorNotFound {
@@ -227,11 +181,13 @@ fn manual_verify_entity(entity_type: String, id: u64, verified: bool) -> Result<
             }
         },
         "supply_agribusiness" => {
-            let mut supply_agribusinesses = entitymanagement::return_supply_agribusiness();
-            if let Some(agribiz) = supply_agribusinesses.iter_mut().find(|s| s.id == id) {
+            let agribiz = entitymanagement::SUPPLY_AGRIBUSINESS_STORAGE.with(|service| service.borrow().get(&id));
+            if let Some(mut agribiz) = agribiz {
+                let was_verified = agribiz.verified;
                 agribiz.verified = verified;
                 entitymanagement::SUPPLY_AGRIBUSINESS_STORAGE
-                    .with(|service| service.borrow_mut().insert(id, agribiz.clone()));
+                    .with(|service| service.borrow_mut().insert(id, agribiz));
+                audit_verification(format!("supply_agribusiness:{}", id), was_verified, verified, None);
                 Ok(())
             } else {
                 Err(Error::SupplyAgriBizNotFound {
@@ -240,11 +196,13 @@ fn manual_verify_entity(entity_type: String, id: u64, verified: bool) -> Result<
             }
         },
         "farms_agribusiness" => {
-            let mut farms_agribusinesses = entitymanagement::return_farms_agribusiness();
-            if let Some(agribiz) = farms_agribusinesses.iter_mut().find(|s| s.id == id) {
+            let agribiz = entitymanagement::FARMS_AGRIBUSINESS_STORAGE.with(|service| service.borrow().get(&id));
+            if let Some(mut agribiz) = agribiz {
+                let was_verified = agribiz.verified;
                 agribiz.verified = verified;
                 entitymanagement::FARMS_AGRIBUSINESS_STORAGE
-                    .with(|service| service.borrow_mut().insert(id, agribiz.clone()));
+                    .with(|service| service.borrow_mut().insert(id, agribiz));
+                audit_verification(format!("farms_agribusiness:{}", id), was_verified, verified, None);
                 Ok(())
             } else {
                 Err(Error::FarmsAgriBizNotFound {
@@ -259,14 +217,8 @@ fn manual_verify_entity(entity_type: String, id: u64, verified: bool) -> Result<
 }
 
 // Functionality for removing farm images and farm reports
-#[update]
+#[update(guard = "caller_is_support")]
 pub fn admin_remove_farm_image(farm_id: u64, image_index: usize) -> Result<(), Error> {
-    // if !is_allowed_principal() {
-    //     return Err(Error::PermissionDenied { 
-    //         msg: String::from("Only admins can remove farm images") 
-    //     });
-    // }
-
     // Get existing images first
     let mut images = FARM_IMAGES.with(|images_storage| {
         images_storage
@@ -320,21 +272,36 @@ pub fn admin_remove_farm_image(farm_id: u64, image_index: usize) -> Result<(), E
         });
     }
 
+    auditlog::record(
+        AuditAction::ContentRemoved,
+        format!("farm:{}", farm_id),
+        Some(format!("{} images", images.0.len() + 1)),
+        Some(format!("{} images, image {} removed", images.0.len(), image_index)),
+    );
     Ok(())
 }
 
-#[update]
+#[update(guard = "caller_is_support")]
 pub fn admin_remove_farm_report(farm_id: u64) -> Result<(), Error> {
     // Delete the specific file from FILE_STORAGE
-    entitymanagement::FILE_STORAGE.with(|storage| {
+    let removed = entitymanagement::FILE_STORAGE.with(|storage| {
         let mut storage = storage.borrow_mut();
-        storage
+        let key = storage
             .iter()
-            .filter(|(k, _)| k.0.starts_with(&format!("{}_{}", "farm", farm_id)))
-            .next()
-            .map(|(k, _)| storage.remove(&k))
-            .is_some()
+            .map(|(k, _)| k)
+            .find(|k| k.0.starts_with(&format!("{}_{}", "farm", farm_id)));
+        key.inspect(|k| {
+            storage.remove(k);
+        })
     });
 
+    if let Some(key) = removed {
+        auditlog::record(
+            AuditAction::ContentRemoved,
+            format!("farm:{}", farm_id),
+            Some(format!("report {}", key.0)),
+            None,
+        );
+    }
     Ok(())
 }
\ No newline at end of file
@@ -1,11 +1,21 @@
-use std::{cell::RefCell, collections::HashMap};
+use std::cell::RefCell;
 use candid::Principal;
 use ic_cdk::{
-    query, update
+    caller, query, update
 };
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::StableBTreeMap;
+
+use crate::accesscontrol::{has_role, principal_key, Role};
+use crate::entitymanagement::{Memory, MEMORY_MANAGER};
 
 thread_local! {
-    static APPROVED_SPENDERS: RefCell<HashMap<Principal, Vec<Principal>>> = RefCell::new(HashMap::new());
+    // (owner, spender) => ()
+    static APPROVED_SPENDERS: RefCell<StableBTreeMap<(Blob<29>, Blob<29>), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39)))
+        ));
 }
 
 // Function to check if spender is already approved
@@ -13,19 +23,21 @@ thread_local! {
 fn is_spender_approved(owner: Principal, spender: Principal) -> bool {
     APPROVED_SPENDERS.with(|approved| {
         approved.borrow()
-            .get(&owner)
-            .map_or(false, |spenders| spenders.contains(&spender))
+            .contains_key(&(principal_key(&owner), principal_key(&spender)))
     })
 }
 
-// Function to store approved spender
+// Function to store approved spender. Owners record their own approvals; treasurers may record them for anyone.
 #[update]
 fn store_approved_spender(owner: Principal, spender: Principal) -> Result<(), String> {
+    let caller = caller();
+    if caller != owner && !has_role(&caller, Role::Treasurer) {
+        return Err("Only the owner or a treasurer can record an approved spender".to_string());
+    }
+
     APPROVED_SPENDERS.with(|approved| {
-        let mut approved = approved.borrow_mut();
-        approved.entry(owner)
-            .or_insert_with(Vec::new)
-            .push(spender);
+        approved.borrow_mut()
+            .insert((principal_key(&owner), principal_key(&spender)), ());
         Ok(())
     })
 }
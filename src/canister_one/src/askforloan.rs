@@ -1,12 +1,21 @@
+use crate::accesscontrol::caller_is_loan_officer;
+use crate::auditlog::{self, AuditAction};
 use crate::entitymanagement::{self};
+use crate::fundingrounds;
+use crate::loandisbursements;
+use crate::loanmanagement::{self, LoanStatus};
+use crate::loanpricing;
+use crate::tokens;
 use ic_cdk::{query, update};
-use std::time::Duration;
 
 /**
  * Handles the request for a loan by a farmer.
+ * The loan is priced from the farm's credit score and tenor, and the quoted terms are fixed on the loan.
  *
  * @param farm_id The ID of the farm requesting the loan.
  * @param loan_amount The amount of loan requested.
+ * @param token_collateral Collateral pledged against the loan.
+ * @param tenor The number of monthly installments; defaults to 6 months.
  * @return Result<entitymanagement::Success, entitymanagement::Error>
  *  Returns a success message if the loan is applied successfully, otherwise returns an error.
  */
@@ -15,89 +24,36 @@ pub fn ask_for_loan(
     farm_id: u64,
     loan_amount: u64,
     token_collateral: entitymanagement::TokenCollateral,
+    tenor: Option<u32>,
 ) -> Result<entitymanagement::Success, entitymanagement::Error> {
-    // Retrieve the list of farmers
-    let mut farmers = entitymanagement::return_farmers();
+    // Price the loan, checking the farm's credit score and max loan amount
+    let tenor = tenor.unwrap_or(loanmanagement::DEFAULT_INSTALLMENT_COUNT);
+    let quote = loanpricing::price_loan(farm_id, loan_amount, tenor)?;
 
-    // Find the farmer with the specified farm_id
-    if let Some(farm) = farmers.iter_mut().find(|f| f.id == farm_id) {
-        // Check if the farm has a credit score and max loan amount
-        if let Some(_credit_score) = farm.credit_score {
-            if let Some(max_loan_amount) = farm.max_loan_amount {
-                // Ensure the loan amount is not greater than the max loan amount
-                if loan_amount > max_loan_amount {
-                    return Err(entitymanagement::Error::Error {
-                        msg: format!("Loan ask should not be greater than maximum loan amount!"),
-                    });
-                // Ensure the farm is not already processing another loan
-                } else if farm.loaned {
-                    return Err(entitymanagement::Error::Error {
-                        msg: format!("You cannot ask for a loan while processing another loan!"),
-                    });
-                }
-            } else {
-                return Err(entitymanagement::Error::Error {
-                    msg: format!("Maximum loan amount is not available!"),
-                });
-            }
-        } else {
-            return Err(entitymanagement::Error::Error {
-                msg: format!("Credit score is not available!"),
-            });
-        }
-
-        // Set the current loan ask amount
-        farm.current_loan_ask = Some(loan_amount);
-        // Set the funding_round_start_time field to the current time
-        farm.funding_round_start_time = Some(ic_cdk::api::time());
-        // Set the time_for_funding_round_to_expire field to a duration of 1 month
-        farm.time_for_funding_round_to_expire = Some(Duration::from_secs(30 * 24 * 60 * 60)); // For Production
-        // farm.time_for_funding_round_to_expire = Some(Duration::from_secs(3 * 60)); //(use this to test)
+    // Create the loan and open its funding round
+    let loan = loanmanagement::create_loan(&quote, token_collateral)?;
 
-        // Set the token collateral
-        farm.token_collateral = Some(token_collateral);
-
-        // Reset the loan_maturity
-        farm.loan_maturity = None;
-
-        // Clone the updated farm and store it in FARMER_STORAGE
-        let farm_clone = farm.clone();
-        entitymanagement::FARMER_STORAGE
-            .with(|service| service.borrow_mut().insert(farm_id, farm_clone));
-
-        Ok(entitymanagement::Success::AppliedForLoanSuccesfully {
-            msg: format!(
-                "Loan applied successfully for farm_id: {}. Funding round will close in 1 month.",
-                farm_id
-            ),
-        })
-    } else {
-        Err(entitymanagement::Error::Error {
-            msg: format!("An error occured!"),
-        })
-    }
+    Ok(entitymanagement::Success::AppliedForLoanSuccesfully {
+        msg: format!(
+            "Loan {} applied successfully for farm_id: {} at {} bps APR. Funding round will close in 1 month.",
+            loan.id, farm_id, quote.apr_bps
+        ),
+    })
 }
 
 /**
- * Checks if the loan's funding round has expired and sets the loan maturity duration if it has.
+ * Checks if the loan's funding round has expired.
  *
  * @param farm_id The ID of the farm to check for loan expiry.
- * @return Result<(), entitymanagement::Error>
- *  Returns Ok(()) if the funding round has expired and loan maturity is set, otherwise returns an error.
+ * @return Result<String, entitymanagement::Error>
+ *  Returns whether the funding round of the farm's active loan has expired, otherwise returns an error.
  */
 #[update]
 pub fn check_funding_round_expiry(farm_id: u64) -> Result<String, entitymanagement::Error> {
-    let farmers = entitymanagement::return_farmers();
-    if let Some(farm) = farmers.iter().find(|f| f.id == farm_id) {
-        if let (Some(start_time), Some(duration)) = (
-            farm.funding_round_start_time,
-            farm.time_for_funding_round_to_expire,
-        ) {
-            // Get the current time and calculate the expiry time
-            let current_time = ic_cdk::api::time();
-            let expiry_time = start_time + duration.as_nanos() as u64;
+    if let Some(loan) = loanmanagement::get_active_loan_for_farm(farm_id) {
+        if let (LoanStatus::Funding, Some(expiry_time)) = (loan.status, loan.funding_round_expiry) {
             // Check if the current time is greater than or equal to the expiry time
-            if current_time >= expiry_time {
+            if ic_cdk::api::time() >= expiry_time {
                 return Ok(format!("Funding round for Farm ID {} has expired", farm_id));
             } else {
                 return Ok(format!(
@@ -112,26 +68,60 @@ pub fn check_funding_round_expiry(farm_id: u64) -> Result<String, entitymanageme
     })
 }
 
-#[update]
-pub fn initiate_loan(farm_id: u64) -> Result<(), entitymanagement::Error> {
-    entitymanagement::FARMER_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
-        if let Some(mut farm) = storage.get(&farm_id) {
-            let current_time = ic_cdk::api::time();
-            farm.funding_round_start_time = None;
-            farm.time_for_funding_round_to_expire = None;
-            farm.loan_start_time = Some(current_time);
-            // farm.loan_maturity = Some(Duration::from_secs(6 * 30 * 24 * 60 * 60));
-            farm.loan_maturity = Some(Duration::from_secs(3 * 60)); // 3 minutes (for testing)
-            farm.loaned = true;
-            storage.insert(farm_id, farm);
-            Ok(())
-        } else {
-            Err(entitymanagement::Error::Error {
-                msg: format!("Farm not found!"),
-            })
+/**
+ * Closes the funding round of the farm's active loan and sends the raised funds to the farm,
+ * less the origination fee. Once every transfer has gone through the loan is disbursed and
+ * its installment schedule generated from the current time; if one fails the loan stays Funded
+ * and calling this again retries it. The round must be fully subscribed. LoanOfficer only.
+ *
+ * @param farm_id The ID of the farm whose loan should be initiated.
+ * @return Result<(), entitymanagement::Error>
+ *  Returns Ok(()) if the loan was disbursed, otherwise returns an error.
+ */
+#[update(guard = "caller_is_loan_officer")]
+pub async fn initiate_loan(farm_id: u64) -> Result<(), entitymanagement::Error> {
+    let mut loan = loanmanagement::get_active_loan_for_farm(farm_id).ok_or_else(|| {
+        entitymanagement::Error::LoanNotFound {
+            msg: format!("No active loan for farm_id: {}", farm_id),
         }
-    })
+    })?;
+
+    // An undersubscribed round is refunded when it expires, not disbursed
+    let progress = fundingrounds::progress_for(&loan);
+    if !progress.fully_subscribed {
+        return Err(entitymanagement::Error::Error {
+            msg: format!(
+                "Funding round for loan {} is not fully subscribed: {} of {} committed",
+                loan.id, progress.committed, progress.target
+            ),
+        });
+    }
+
+    let before = format!("{:?}", loan.status);
+
+    // Close the funding round if it is still open
+    if loan.status == LoanStatus::Funding {
+        loan.transition(LoanStatus::Funded)?;
+        loanmanagement::save_loan(&loan);
+    }
+
+    let loan = loandisbursements::disburse_funded_loan(loan.id).await?;
+
+    auditlog::record(
+        AuditAction::LoanDisbursed,
+        format!("loan:{}", loan.id),
+        Some(before),
+        Some(format!(
+            "{:?}: {} of {} {} to farm {} after the origination fee, maturing at {:?}",
+            loan.status,
+            tokens::format_units(loan.disbursed_amount() as u128, loanmanagement::LOAN_TOKEN.decimals()),
+            tokens::format_units(loan.principal_amount as u128, loanmanagement::LOAN_TOKEN.decimals()),
+            loanmanagement::LOAN_TOKEN.symbol(),
+            loan.farm_id,
+            loan.maturity
+        )),
+    );
+    Ok(())
 }
 
 /**
@@ -143,19 +133,11 @@ pub fn initiate_loan(farm_id: u64) -> Result<(), entitymanagement::Error> {
  */
 #[query]
 pub fn get_remaining_funding_time(farm_id: u64) -> Result<u64, entitymanagement::Error> {
-    // Retrieve the list of farmers
-    let farmers = entitymanagement::return_farmers();
-
-    // Find the farmer with the specified farm_id
-    if let Some(farm) = farmers.iter().find(|f| f.id == farm_id) {
-        // Check if the funding round start time and duration are available
-        if let (Some(start_time), Some(duration)) = (
-            farm.funding_round_start_time,
-            farm.time_for_funding_round_to_expire,
-        ) {
-            // Get the current time and calculate the expiry time
+    // Find the active loan for the specified farm_id
+    if let Some(loan) = loanmanagement::get_active_loan_for_farm(farm_id) {
+        // Check if the funding round is still open
+        if let (LoanStatus::Funding, Some(expiry_time)) = (loan.status, loan.funding_round_expiry) {
             let current_time = ic_cdk::api::time();
-            let expiry_time = start_time + duration.as_nanos() as u64;
             // Check if the current time is less than the expiry time
             if current_time < expiry_time {
                 // Return the remaining funding time in seconds
@@ -170,15 +152,9 @@ pub fn get_remaining_funding_time(farm_id: u64) -> Result<u64, entitymanagement:
 
 #[query]
 pub fn get_remaining_loan_maturity_time(farm_id: u64) -> Result<u64, entitymanagement::Error> {
-    let farmers = entitymanagement::return_farmers();
-    if let Some(farm) = farmers.iter().find(|f| f.id == farm_id) {
-        if let (Some(loan_start_time), Some(loan_duration)) =
-            (farm.loan_start_time, farm.loan_maturity)
-        {
-            let current_time = ic_cdk::api::time();
-            let elapsed = current_time.saturating_sub(loan_start_time);
-            let total_duration = loan_duration.as_nanos() as u64;
-            let remaining = total_duration.saturating_sub(elapsed);
+    if let Some(loan) = loanmanagement::get_active_loan_for_farm(farm_id) {
+        if let Some(maturity) = loan.maturity {
+            let remaining = maturity.saturating_sub(ic_cdk::api::time());
             return Ok(remaining / 1_000_000_000); // Convert nanoseconds to seconds
         }
     }
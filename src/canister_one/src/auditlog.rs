@@ -0,0 +1,317 @@
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::query;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::accesscontrol::caller_is_super_admin;
+use crate::entitymanagement::{Error, Memory, MEMORY_MANAGER};
+
+// Longest target or before/after summary kept, so entries stay within their bounded size
+const MAX_SUMMARY_LEN: usize = 512;
+const MAX_PAGE_SIZE: u32 = 100;
+// Previous hash of the first entry
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/**
+* AuditAction Enum
+* Privileged or financial actions recorded in the audit log.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditAction {
+    EntityVerified,
+    ContentRemoved,
+    FeeRecorded,
+    InvestmentRecorded,
+    TokensSent,
+    TokensWithdrawn,
+    TransferProposed,
+    TransferApproved,
+    TransferRejected,
+    RoleGranted,
+    RoleRevoked,
+    ConfigChanged,
+    LoanCancelled,
+    LoanDisbursed,
+    DepositCredited,
+}
+
+/**
+* AuditEntry Struct
+* One recorded action. Each entry's hash covers its fields and the previous entry's hash, so rewriting or removing an
+* entry breaks every hash after it.
+* @param Defined In-Line
+* @return AuditEntry instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AuditEntry {
+    pub entry_id: u64,
+    pub timestamp: u64,
+    pub caller: Principal,
+    pub action: AuditAction,
+    pub target: String, // What was acted on, e.g. "farmer:12" or "refund:4"
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub previous_hash: String,
+    pub hash: String,
+}
+
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for AuditEntry {
+    const MAX_SIZE: u32 = 4_096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl AuditEntry {
+    // Hash of every field except the hash itself, chained to the previous entry
+    fn compute_hash(&self) -> String {
+        let fields = Encode!(
+            &self.entry_id,
+            &self.timestamp,
+            &self.caller,
+            &self.action,
+            &self.target,
+            &self.before,
+            &self.after
+        )
+        .expect("audit entry fields encode");
+
+        let mut hasher = Sha256::new();
+        hasher.update(self.previous_hash.as_bytes());
+        hasher.update(&fields);
+        hex::encode(hasher.finalize())
+    }
+}
+
+/**
+* AuditFilter Struct
+* Narrows an audit log query. Fields left empty match every entry.
+* @param Defined In-Line
+* @return AuditFilter instance with the specified fields.
+*/
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct AuditFilter {
+    pub caller: Option<Principal>,
+    pub action: Option<AuditAction>,
+    pub target: Option<String>, // Matches targets starting with this, e.g. "farmer:" for every farmer
+    pub from: Option<u64>,      // Earliest timestamp, inclusive
+    pub to: Option<u64>,        // Latest timestamp, inclusive
+}
+
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.caller.is_none_or(|caller| entry.caller == caller)
+            && self.action.is_none_or(|action| entry.action == action)
+            && self.target.as_ref().is_none_or(|target| entry.target.starts_with(target.as_str()))
+            && self.from.is_none_or(|from| entry.timestamp >= from)
+            && self.to.is_none_or(|to| entry.timestamp <= to)
+    }
+}
+
+/**
+* AuditHead Struct
+* The newest audit entry's hash, certified by the subnet, so anyone can check a copy of the log against it.
+* @param Defined In-Line
+* @return AuditHead instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AuditHead {
+    pub entry_id: u64, // 0 while the log is empty
+    pub hash: String,
+    pub certificate: Option<Vec<u8>>, // Certificate over the hash as the canister's certified data
+}
+
+/**
+* AuditLogPage Struct
+* A page of audit entries, newest first.
+* @param Defined In-Line
+* @return AuditLogPage instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AuditLogPage {
+    pub entries: Vec<AuditEntry>,
+    pub next_before: Option<u64>, // Pass as `before` to fetch the next page; None when there are no more entries
+}
+
+thread_local! {
+    // Audit entries keyed by entry id; only ever appended to
+    static AUDIT_LOG: RefCell<StableBTreeMap<u64, AuditEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34)))
+        ));
+}
+
+fn truncate(mut summary: String) -> String {
+    if summary.len() > MAX_SUMMARY_LEN {
+        let mut end = MAX_SUMMARY_LEN;
+        while !summary.is_char_boundary(end) {
+            end -= 1;
+        }
+        summary.truncate(end);
+    }
+    summary
+}
+
+/**
+* Function: record
+* Description: Appends an action taken by the current caller to the audit log.
+* @param action: AuditAction - What was done
+* @param target: String - What it was done to
+* @param before: Option<String> - Summary of the target before the action, if it had a prior state
+* @param after: Option<String> - Summary of the target after the action
+* @return None
+*/
+pub fn record(action: AuditAction, target: String, before: Option<String>, after: Option<String>) {
+    AUDIT_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        let (entry_id, previous_hash) = match log.last_key_value() {
+            Some((id, last)) => (id + 1, last.hash),
+            None => (1, GENESIS_HASH.to_string()),
+        };
+
+        let mut entry = AuditEntry {
+            entry_id,
+            timestamp: ic_cdk::api::time(),
+            caller: ic_cdk::caller(),
+            action,
+            target: truncate(target),
+            before: before.map(truncate),
+            after: after.map(truncate),
+            previous_hash,
+            hash: String::new(),
+        };
+        entry.hash = entry.compute_hash();
+        set_certified_head(&entry.hash);
+        log.insert(entry_id, entry);
+    });
+}
+
+fn head() -> (u64, String) {
+    AUDIT_LOG.with(|log| {
+        log.borrow()
+            .last_key_value()
+            .map(|(id, entry)| (id, entry.hash))
+            .unwrap_or((0, GENESIS_HASH.to_string()))
+    })
+}
+
+fn set_certified_head(hash: &str) {
+    let hash = hex::decode(hash).expect("audit hashes are hex");
+    ic_cdk::api::set_certified_data(&hash);
+}
+
+/**
+* Function: certify_head
+* Description: Certifies the newest audit entry's hash. Certified data does not survive an upgrade, so this is called
+* from both `init` and `post_upgrade`; `record` keeps it up to date afterwards.
+* @param None
+* @return None
+*/
+pub fn certify_head() {
+    set_certified_head(&head().1);
+}
+
+/**
+* Function: get_audit_head
+* Description: Returns the newest audit entry's hash with the certificate the subnet signed it with.
+* @param None
+* @return AuditHead - The head of the audit log's hash chain
+*/
+#[query]
+pub fn get_audit_head() -> AuditHead {
+    let (entry_id, hash) = head();
+    AuditHead {
+        entry_id,
+        hash,
+        certificate: ic_cdk::api::data_certificate(),
+    }
+}
+
+/**
+* Function: get_audit_log
+* Description: Returns audit entries matching a filter, newest first. SuperAdmin only.
+* @param filter: AuditFilter - Which entries to return
+* @param before: Option<u64> - Only return entries older than this entry id, for paging
+* @param limit: u32 - The most entries to return, capped at 100
+* @return AuditLogPage - The matching entries and where the next page starts
+*/
+#[query(guard = "caller_is_super_admin")]
+pub fn get_audit_log(filter: AuditFilter, before: Option<u64>, limit: u32) -> AuditLogPage {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let newest = log.last_key_value().map(|(id, _)| id).unwrap_or(0);
+
+        // Entry ids are contiguous, so walk them backwards instead of collecting the whole log
+        let mut id = before.map_or(newest + 1, |before| before.min(newest + 1));
+        let mut entries = Vec::new();
+        let mut next_before = None;
+        while id > 1 {
+            id -= 1;
+            let Some(entry) = log.get(&id) else { continue };
+            if !filter.matches(&entry) {
+                continue;
+            }
+            if entries.len() == limit {
+                next_before = entries.last().map(|last: &AuditEntry| last.entry_id);
+                break;
+            }
+            entries.push(entry);
+        }
+        AuditLogPage { entries, next_before }
+    })
+}
+
+/**
+* Function: verify_audit_log
+* Description: Recomputes the hash chain over a range of entries and reports the first entry that does not match.
+* SuperAdmin only.
+* @param from: u64 - The first entry id to check
+* @param limit: u32 - The number of entries to check
+* @return Result<u64, Error> - The number of entries checked, or an error naming the first broken entry
+*/
+#[query(guard = "caller_is_super_admin")]
+pub fn verify_audit_log(from: u64, limit: u32) -> Result<u64, Error> {
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let mut previous_hash = match from {
+            0 | 1 => GENESIS_HASH.to_string(),
+            _ => log
+                .get(&(from - 1))
+                .map(|entry| entry.hash)
+                .ok_or_else(|| Error::Error {
+                    msg: format!("Audit entry {} is missing", from - 1),
+                })?,
+        };
+
+        let start = from.max(1);
+        let mut checked = 0;
+        for (expected_id, (id, entry)) in (start..).zip(log.range(start..).take(limit as usize)) {
+            if id != expected_id {
+                return Err(Error::Error {
+                    msg: format!("Audit entry {} is missing", expected_id),
+                });
+            }
+            if entry.previous_hash != previous_hash || entry.compute_hash() != entry.hash {
+                return Err(Error::Error {
+                    msg: format!("Audit entry {} does not match its hash chain", id),
+                });
+            }
+            previous_hash = entry.hash;
+            checked += 1;
+        }
+        Ok(checked)
+    })
+}
@@ -0,0 +1,224 @@
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::query;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableCell, Storable};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::auditlog::{self, AuditAction};
+use crate::ck_eth_payments;
+use crate::ckusdc_payments;
+use crate::entitymanagement::{Memory, MEMORY_MANAGER};
+use crate::ifarm_tokens;
+use crate::investorpayouts;
+use crate::tokens;
+
+const MAX_FEE_BPS: u64 = 10_000;
+
+/**
+* CanisterSettings Struct
+* Canisters and fees that differ between local, staging and mainnet deployments. Set through the init and upgrade
+* arguments; the defaults are the Sepolia test ledgers.
+* @param Defined In-Line
+* @return CanisterSettings instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CanisterSettings {
+    pub cketh_ledger: Principal,
+    pub ckusdc_ledger: Principal,
+    pub ifarm_ledger: Principal,
+    pub cketh_minter: Principal,  // Minter for ckETH and the ckERC20 tokens
+    pub fee_collector: Principal, // Receives the fee on iFarm transfers
+    pub investment_fee_bps: u64,
+    pub repayment_fee_bps: u64,
+    pub ifarm_transfer_fee_bps: u64,
+}
+
+impl Default for CanisterSettings {
+    fn default() -> Self {
+        let principal = |text: &str| Principal::from_text(text).expect("default canister ids are valid principals");
+        Self {
+            cketh_ledger: principal(ck_eth_payments::LEDGER),
+            ckusdc_ledger: principal(ckusdc_payments::USDC_LEDGER),
+            ifarm_ledger: principal(ifarm_tokens::IFARM_TOKEN),
+            cketh_minter: principal(ck_eth_payments::MINTER),
+            fee_collector: principal(ifarm_tokens::FEE_COLLECTOR_PRINCIPAL),
+            investment_fee_bps: tokens::INVESTMENT_FEE_BPS,
+            repayment_fee_bps: investorpayouts::REPAYMENT_FEE_BPS,
+            ifarm_transfer_fee_bps: ifarm_tokens::FEE_BPS,
+        }
+    }
+}
+
+impl Storable for CanisterSettings {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl CanisterSettings {
+    fn validate(&self) -> Result<(), String> {
+        for (name, bps) in [
+            ("investment_fee_bps", self.investment_fee_bps),
+            ("repayment_fee_bps", self.repayment_fee_bps),
+            ("ifarm_transfer_fee_bps", self.ifarm_transfer_fee_bps),
+        ] {
+            if bps > MAX_FEE_BPS {
+                return Err(format!("{} must be at most {}, got {}", name, MAX_FEE_BPS, bps));
+            }
+        }
+        if self.fee_collector == Principal::anonymous() {
+            return Err("The fee collector cannot be the anonymous principal".to_string());
+        }
+        Ok(())
+    }
+
+    // Overwrites the settings given in the upgrade arguments, leaving the rest unchanged
+    fn apply(&mut self, args: UpgradeArgs) {
+        if let Some(ledger) = args.cketh_ledger {
+            self.cketh_ledger = ledger;
+        }
+        if let Some(ledger) = args.ckusdc_ledger {
+            self.ckusdc_ledger = ledger;
+        }
+        if let Some(ledger) = args.ifarm_ledger {
+            self.ifarm_ledger = ledger;
+        }
+        if let Some(minter) = args.cketh_minter {
+            self.cketh_minter = minter;
+        }
+        if let Some(fee_collector) = args.fee_collector {
+            self.fee_collector = fee_collector;
+        }
+        if let Some(bps) = args.investment_fee_bps {
+            self.investment_fee_bps = bps;
+        }
+        if let Some(bps) = args.repayment_fee_bps {
+            self.repayment_fee_bps = bps;
+        }
+        if let Some(bps) = args.ifarm_transfer_fee_bps {
+            self.ifarm_transfer_fee_bps = bps;
+        }
+    }
+}
+
+/**
+* InitArgs Struct
+* Arguments accepted when the canister is installed. Settings left out keep their defaults; with no admins, only the
+* canister's controllers can act until they grant roles.
+* @param Defined In-Line
+* @return InitArgs instance with the specified fields.
+*/
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct InitArgs {
+    pub admins: Vec<Principal>, // Granted SuperAdmin
+    pub cketh_ledger: Option<Principal>,
+    pub ckusdc_ledger: Option<Principal>,
+    pub ifarm_ledger: Option<Principal>,
+    pub cketh_minter: Option<Principal>,
+    pub fee_collector: Option<Principal>,
+    pub investment_fee_bps: Option<u64>,
+    pub repayment_fee_bps: Option<u64>,
+    pub ifarm_transfer_fee_bps: Option<u64>,
+}
+
+/**
+* UpgradeArgs Struct
+* Arguments accepted when the canister is upgraded. Only the settings given are changed.
+* @param Defined In-Line
+* @return UpgradeArgs instance with the specified fields.
+*/
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct UpgradeArgs {
+    pub cketh_ledger: Option<Principal>,
+    pub ckusdc_ledger: Option<Principal>,
+    pub ifarm_ledger: Option<Principal>,
+    pub cketh_minter: Option<Principal>,
+    pub fee_collector: Option<Principal>,
+    pub investment_fee_bps: Option<u64>,
+    pub repayment_fee_bps: Option<u64>,
+    pub ifarm_transfer_fee_bps: Option<u64>,
+}
+
+impl InitArgs {
+    // Splits the admins from the settings, which are applied the same way as on upgrade
+    pub fn into_parts(self) -> (Vec<Principal>, UpgradeArgs) {
+        let settings = UpgradeArgs {
+            cketh_ledger: self.cketh_ledger,
+            ckusdc_ledger: self.ckusdc_ledger,
+            ifarm_ledger: self.ifarm_ledger,
+            cketh_minter: self.cketh_minter,
+            fee_collector: self.fee_collector,
+            investment_fee_bps: self.investment_fee_bps,
+            repayment_fee_bps: self.repayment_fee_bps,
+            ifarm_transfer_fee_bps: self.ifarm_transfer_fee_bps,
+        };
+        (self.admins, settings)
+    }
+}
+
+/**
+* CanisterArgs Enum
+* Argument the canister is installed or upgraded with. One type serves both so the service signature in the candid
+* file accepts either.
+*/
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum CanisterArgs {
+    Init(InitArgs),
+    Upgrade(UpgradeArgs),
+}
+
+thread_local! {
+    static CANISTER_SETTINGS: RefCell<StableCell<CanisterSettings, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33))),
+            CanisterSettings::default(),
+        )
+        .expect("Failed to initialise canister settings")
+    );
+}
+
+pub fn settings() -> CanisterSettings {
+    CANISTER_SETTINGS.with(|settings| settings.borrow().get().clone())
+}
+
+/**
+* Function: apply_settings
+* Description: Applies init or upgrade arguments to the stored settings. Traps on invalid settings so a bad install
+* or upgrade is rolled back instead of running with them.
+* @param args: UpgradeArgs - The settings to change
+* @return None
+*/
+pub fn apply_settings(args: UpgradeArgs) {
+    let mut updated = settings();
+    updated.apply(args);
+
+    if let Err(e) = updated.validate() {
+        ic_cdk::trap(&format!("Invalid canister settings: {}", e));
+    }
+
+    let after = format!("{:?}", updated);
+    let previous = CANISTER_SETTINGS
+        .with(|cell| cell.borrow_mut().set(updated))
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to save canister settings: {:?}", e)));
+
+    let before = format!("{:?}", previous);
+    if before != after {
+        auditlog::record(AuditAction::ConfigChanged, "canister_settings".to_string(), Some(before), Some(after));
+    }
+}
+
+/**
+* Function: get_canister_settings
+* Description: Returns the ledgers, minter, fee collector and fees the canister is running with.
+* @param None
+* @return CanisterSettings - The current settings
+*/
+#[query]
+pub fn get_canister_settings() -> CanisterSettings {
+    settings()
+}
@@ -1,4 +1,4 @@
-use candid::{CandidType, Deserialize, Nat};
+use candid::{CandidType, Deserialize, Nat, Principal};
 
 #[derive(CandidType, Deserialize)]
 pub struct WithdrawalArg {
@@ -19,4 +19,88 @@ pub enum WithdrawalError {
     TemporarilyUnavailable(String),
 }
 
-pub type WithdrawalResult = Result<RetrieveEthRequest, WithdrawalError>;
\ No newline at end of file
+pub type WithdrawalResult = Result<RetrieveEthRequest, WithdrawalError>;
+
+#[derive(CandidType, Deserialize)]
+pub struct WithdrawErc20Arg {
+    pub amount: Nat,
+    pub ckerc20_ledger_id: Principal,
+    pub recipient: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RetrieveErc20Request {
+    pub cketh_block_index: Nat,
+    pub ckerc20_block_index: Nat,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct Erc20Token {
+    pub ckerc20_token_symbol: String,
+    pub erc20_contract_address: String,
+    pub ledger_canister_id: Principal,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum LedgerError {
+    InsufficientFunds { balance: Nat, failed_transfer_amount: Nat, token_symbol: String, ledger_id: Principal },
+    AmountTooLow { minimum_burn_amount: Nat, failed_burn_amount: Nat, token_symbol: String, ledger_id: Principal },
+    InsufficientAllowance { allowance: Nat, failed_burn_amount: Nat, token_symbol: String, ledger_id: Principal },
+    TemporarilyUnavailable(String),
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum WithdrawErc20Error {
+    TokenNotSupported { supported_tokens: Vec<Erc20Token> },
+    RecipientAddressBlocked { address: String },
+    CkEthLedgerError { error: LedgerError },
+    CkErc20LedgerError { cketh_block_index: Nat, error: LedgerError },
+    TemporarilyUnavailable(String),
+}
+
+pub type WithdrawErc20Result = Result<RetrieveErc20Request, WithdrawErc20Error>;
+
+#[derive(CandidType, Deserialize)]
+pub struct Eip1559TransactionPriceArg {
+    pub ckerc20_ledger_id: Principal,
+}
+
+// Only the field the canister needs; the minter returns more
+#[derive(CandidType, Deserialize, Debug)]
+pub struct Eip1559TransactionPrice {
+    pub max_transaction_fee: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EthTransaction {
+    pub transaction_hash: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TxFinalizedSuccess {
+    pub transaction_hash: String,
+    pub effective_transaction_fee: Option<Nat>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TxReimbursed {
+    pub transaction_hash: String,
+    pub reimbursed_amount: Nat,
+    pub reimbursed_in_block: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TxFinalizedStatus {
+    Success(TxFinalizedSuccess),
+    Reimbursed(TxReimbursed),
+    PendingReimbursement(EthTransaction),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum RetrieveEthStatus {
+    NotFound,
+    Pending,
+    TxCreated,
+    TxSent(EthTransaction),
+    TxFinalized(TxFinalizedStatus),
+}
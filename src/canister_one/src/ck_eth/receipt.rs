@@ -27,4 +27,15 @@ pub struct TransactionReceiptData {
 pub struct VerifiedTransactionDetails {
     pub amount: String,
     pub from: String,
-}
\ No newline at end of file
+}
+
+// Outcome of verifying a deposit: credited to the funding round, added to the investor's deposit balance because the
+// round could not take it, still waiting for enough block confirmations, or confirmed but not yet minted into the
+// canister's account
+#[derive(Serialize, CandidType, Deserialize)]
+pub enum DepositVerification {
+    Credited(VerifiedTransactionDetails),
+    HeldAsBalance { details: VerifiedTransactionDetails, reason: String },
+    Pending { confirmations: u64, required: u64 },
+    Minting,
+}
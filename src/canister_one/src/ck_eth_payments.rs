@@ -1,19 +1,30 @@
-use std::str::FromStr;
 use candid::Principal;
 use b3_utils::{vec_to_hex_string_with_0x, Subaccount, caller_is_controller};
-use b3_utils::api::{InterCall, CallCycles};
-use evm_rpc_canister_types::{EvmRpcCanister, GetTransactionReceiptResult};
+use evm_rpc_canister_types::{EvmRpcCanister, GetTransactionReceiptResult, LogEntry};
 use candid::Nat;
-use b3_utils::ledger::{ICRCAccount, ICRC1, ICRC1TransferArgs, ICRC1TransferResult};
+use b3_utils::ledger::{ICRCAccount, ICRC1, ICRC2, ICRC2TransferFromArgs, ICRC2TransferFromResult};
 use crate::ck_eth::receipt;
-use crate::ck_eth::minter;
-use crate::payments;
-use crate::transaction_fees;
-use crate::common::{eth_get_transaction_receipt, hex_string_with_0x_to_f64};
+use crate::deposits;
+use crate::depositaccounts;
+use crate::evmrpc;
+use crate::withdrawals;
+use crate::treasury::{self, TreasuryTransfer};
+use crate::accesscontrol::caller_is_treasurer;
+use crate::entitymanagement::{self, Error};
+use crate::tokens::{self, Token};
+use crate::common::{decode_address_topic, decode_deposit_data, eth_get_confirmations, eth_get_transaction_receipt};
+use num_traits::ToPrimitive;
 
-const MINTER_ADDRESS: &str = "0xb44b5e756a894775fc32eddf3314bb1b1944dc34";
-const LEDGER: &str = "apia6-jaaaa-aaaar-qabma-cai";
-const MINTER: &str = "jzenf-aiaaa-aaaar-qaa7q-cai";
+// Sepolia helper contract, the default until an admin configures another chain
+pub const MINTER_ADDRESS: &str = "0xb44b5e756a894775fc32eddf3314bb1b1944dc34";
+// Sepolia ckETH ledger and minter, used unless the init or upgrade arguments name others
+pub const LEDGER: &str = "apia6-jaaaa-aaaar-qabma-cai";
+pub const MINTER: &str = "jzenf-aiaaa-aaaar-qaa7q-cai";
+// keccak256("ReceivedEthOrErc20(address,address,uint256,bytes32,bytes32)"), emitted by the helper contract that
+// deposits to a subaccount
+const RECEIVED_ETH_OR_ERC20_EVENT_TOPIC: &str = "0x918adbebdb8f3b36fc337ab76df10b147b2def5c9dd62cb3456d9aeca40e0b07";
+// ETH deposits name the zero address as their token
+pub const ETH_TOKEN_ADDRESS: &str = "0x0000000000000000000000000000000000000000";
 
 pub const EVM_RPC_CANISTER_ID: Principal =
     Principal::from_slice(b"\x00\x00\x00\x00\x02\x30\x00\xCC\x01\x01");
@@ -44,9 +55,16 @@ impl From<GetTransactionReceiptResult> for receipt::ReceiptWrapper {
     }
 }
 
+// Encodes a principal as a bytes32 for the helper contracts. Deposits verified by transaction hash pass the
+// canister's principal and, as the subaccount, the encoding of the investor's own principal.
 #[ic_cdk::query]
 fn deposit_principal(principal: String) -> String {
     let principal = Principal::from_text(principal).unwrap();
+    principal_deposit_topic(principal)
+}
+
+// Encodes a principal the way the minter's deposit logs carry it, as a 0x-prefixed bytes32
+pub fn principal_deposit_topic(principal: Principal) -> String {
     let subaccount = Subaccount::from_principal(principal);
     let bytes32 = subaccount.to_bytes32().unwrap();
     vec_to_hex_string_with_0x(bytes32)
@@ -59,8 +77,58 @@ async fn get_receipt(hash: String) -> String {
     serde_json::to_string(&wrapper).unwrap()
 }
 
-#[ic_cdk::update]
-async fn verify_cketh_transaction(hash: String, farm_id: u64, investor_id: u64) -> Result<receipt::VerifiedTransactionDetails, String> {
+// A helper contract deposit event that passed validation
+pub struct HelperDeposit {
+    pub amount: u128,
+}
+
+impl HelperDeposit {
+    // The amount as the 0x-prefixed uint256 the helper logged
+    pub fn amount_hex(&self) -> String {
+        format!("0x{:064x}", self.amount)
+    }
+}
+
+/**
+* Function: find_helper_deposit
+* Description: Finds the single deposit event the helper contract emitted in a receipt. The deposit must be minted to
+* this canister, into the depositor's verification subaccount, so the funds it credits are the canister's to invest.
+* @param logs: &[LogEntry] - The receipt's logs
+* @param helper: &str - The helper contract the event must come from
+* @param token_address: &str - The ERC-20 contract deposited, or the zero address for ETH
+* @param depositor: Principal - The principal whose verification subaccount the deposit must be minted to
+* @return Result<HelperDeposit, String> - The deposited amount, or an error if no single matching event is found
+*/
+pub fn find_helper_deposit(logs: &[LogEntry], helper: &str, token_address: &str, depositor: Principal) -> Result<HelperDeposit, String> {
+    // topics: [event signature, erc20 contract, owner, principal], data: amount, subaccount
+    let deposit_logs: Vec<_> = logs.iter()
+        .filter(|log| !log.removed && log.address.to_lowercase() == helper)
+        .filter(|log| log.topics.len() == 4 && log.topics[0].to_lowercase() == RECEIVED_ETH_OR_ERC20_EVENT_TOPIC)
+        .collect();
+    let deposit_log = match deposit_logs.as_slice() {
+        [log] => *log,
+        [] => return Err("No deposit event found in logs".to_string()),
+        _ => return Err("More than one deposit event found in logs".to_string()),
+    };
+
+    if decode_address_topic(&deposit_log.topics[1])? != token_address {
+        return Err("Deposited token does not match".to_string());
+    }
+    if deposit_log.topics[3].to_lowercase() != canister_deposit_principal() {
+        return Err("Deposit was not minted to this canister".to_string());
+    }
+
+    let (amount, subaccount) = decode_deposit_data(&deposit_log.data)?;
+    if subaccount != *depositaccounts::verification_subaccount(depositor).as_ref() {
+        return Err("Deposit was not made to the caller's subaccount".to_string());
+    }
+
+    Ok(HelperDeposit { amount })
+}
+
+// Checking a ckETH deposit receipt made to the given principal's verification subaccount, returning the deposit,
+// sender and block
+async fn check_cketh_receipt(hash: String, depositor: Principal) -> Result<(HelperDeposit, String, u64), String> {
     let receipt = match eth_get_transaction_receipt(&EVM_RPC, hash.clone()).await {
         Ok(receipt) => receipt,
         Err(e) => return Err(format!("Failed to get receipt: {}", e)),
@@ -77,72 +145,128 @@ async fn verify_cketh_transaction(hash: String, farm_id: u64, investor_id: u64)
         return Err("Transaction failed".to_string());
     }
 
-    if receipt_data.to != MINTER_ADDRESS {
+    let helper = evmrpc::config().cketh_helper;
+    if receipt_data.to.to_lowercase() != helper {
         return Err("Minter address does not match".to_string());
     }
 
-    let log_principal = receipt_data.logs.iter()
-        .find(|log| log.topics.get(2).map(|topic| topic.as_str()) == Some(&canister_deposit_principal()))
-        .ok_or_else(|| "Principal does not match or missing in logs".to_string())?;
-
-    let amount = log_principal.data.clone();
+    let deposit = find_helper_deposit(&receipt_data.logs, &helper, ETH_TOKEN_ADDRESS, depositor)?;
     let from_address = receipt_data.from.clone();
+    let block_number = receipt_data.blockNumber.0.to_u64()
+        .ok_or_else(|| format!("Block number {} is too large", receipt_data.blockNumber))?;
 
-    let amount_f64 = hex_string_with_0x_to_f64(amount.clone());
+    Ok((deposit, from_address, block_number))
+}
+
+#[ic_cdk::update]
+async fn verify_cketh_transaction(hash: String, farm_id: u64) -> Result<receipt::DepositVerification, Error> {
+    // Deposits are credited to the caller, and only if they were made to the caller's verification subaccount
+    let investor_id = entitymanagement::caller_investor_id()?;
+    credit_cketh_deposit(hash, farm_id, investor_id, ic_cdk::caller()).await
+}
+
+// Credits a deposit once it has enough block confirmations, otherwise queues it to be checked again
+pub async fn credit_cketh_deposit(hash: String, farm_id: u64, investor_id: u64, depositor: Principal) -> Result<receipt::DepositVerification, Error> {
+    // Held until the deposit is credited, so the same hash cannot be credited twice
+    let claim = deposits::claim_deposit(&hash)?;
 
-    let deduction = amount_f64 * 0.005;
-    let _ = transaction_fees::store_transaction_fee(hash.clone(), deduction);
-    let new_amount = amount_f64 - deduction;
+    let (deposit, from_address, block_number) = check_cketh_receipt(hash.clone(), depositor)
+        .await
+        .map_err(|msg| Error::Error { msg })?;
 
-    let _ = payments::store_investments(
+    let required = evmrpc::config().min_confirmations;
+    let confirmations = eth_get_confirmations(&EVM_RPC, block_number)
+        .await
+        .map_err(|msg| Error::Error { msg })?;
+    if confirmations < required {
+        deposits::queue_pending_deposit(&hash, farm_id, investor_id, depositor, Token::CkETH, confirmations)?;
+        return Ok(receipt::DepositVerification::Pending { confirmations, required });
+    }
+
+    // Move the deposit into the canister's main account, where refunds and payouts are paid from
+    let amount_base_units = match depositaccounts::collect_verified_deposit(depositor, Token::CkETH, deposit.amount)
+        .await
+        .map_err(|msg| Error::Error { msg })?
+    {
+        Some((moved, _)) => moved,
+        None => {
+            deposits::queue_pending_deposit(&hash, farm_id, investor_id, depositor, Token::CkETH, confirmations)?;
+            return Ok(receipt::DepositVerification::Minting);
+        }
+    };
+
+    // The deposit is in the canister now, so it is credited to the investor whether or not the round can take it
+    let invested = deposits::invest_collected_deposit(&hash, farm_id, investor_id, Token::CkETH, amount_base_units).await;
+
+    deposits::clear_pending_deposit(&hash);
+    claim.complete(deposits::ProcessedDeposit {
+        transaction_hash: hash,
         farm_id,
-        new_amount,
         investor_id,
-        hash.clone(),
-        "ckETH".to_string()
-    );
+        token: Token::CkETH,
+        amount: deposit.amount,
+        credited: *invested.as_ref().unwrap_or(&0),
+        processed_at: ic_cdk::api::time(),
+    });
 
-    Ok(receipt::VerifiedTransactionDetails {
-        amount,
+    let details = receipt::VerifiedTransactionDetails {
+        amount: deposit.amount_hex(),
         from: from_address,
+    };
+    Ok(match invested {
+        Ok(_) => receipt::DepositVerification::Credited(details),
+        Err(reason) => receipt::DepositVerification::HeldAsBalance { details, reason },
     })
 }
 
 #[ic_cdk::query]
 pub fn canister_deposit_principal() -> String {
-    let subaccount = Subaccount::from(ic_cdk::id());
-    let bytes32 = subaccount.to_bytes32().unwrap();
-    vec_to_hex_string_with_0x(bytes32)
+    principal_deposit_topic(ic_cdk::id())
 }
 
 #[ic_cdk::update]
 async fn cketh_balance() -> Nat {
     let account = ICRCAccount::new(ic_cdk::id(), None);
-    ICRC1::from(LEDGER).balance_of(account).await.unwrap()
+    ICRC1::from(Token::CkETH.ledger()).balance_of(account).await.unwrap()
 }
 
-#[ic_cdk::update]
-async fn cketh_transfer(to: String, amount: Nat) -> ICRC1TransferResult {
-    let to = ICRCAccount::from_str(&to).unwrap();
-    let transfer_args = ICRC1TransferArgs {
-        to,
+/// Send ckETH from the treasury, subject to the treasury roles, limits and allow-list
+#[ic_cdk::update(guard = "caller_is_treasurer")]
+async fn cketh_transfer(to: String, amount: Nat) -> Result<TreasuryTransfer, Error> {
+    treasury::treasury_transfer(Token::CkETH, to, amount).await
+}
+
+/// Pull ckETH from an account that has approved this canister as a spender (ICRC-2)
+pub async fn cketh_transfer_from(from: Principal, to: Principal, amount: Nat) -> Result<ICRC2TransferFromResult, String> {
+    let transfer_from_args = ICRC2TransferFromArgs {
+        from: ICRCAccount::new(from, None),
+        to: ICRCAccount::new(to, None),
         amount,
-        from_subaccount: None,
+        spender_subaccount: None,
         fee: None,
         memo: None,
         created_at_time: None,
     };
-    ICRC1::from(LEDGER).transfer(transfer_args).await.unwrap()
+
+    ICRC2::from(Token::CkETH.ledger())
+        .transfer_from(transfer_from_args)
+        .await
+        .map_err(|e| format!("Failed to call ckETH transfer_from: {}", e))
 }
 
 #[ic_cdk::update(guard = "caller_is_controller")]
-async fn cketh_withdraw(amount: Nat, recipient: String) -> minter::WithdrawalResult {
-    let withdraw = minter::WithdrawalArg {
-        amount,
-        recipient,
-    };
-    InterCall::from(MINTER)
-        .call("withdraw_eth", withdraw, CallCycles::NoPay)
+async fn cketh_withdraw(amount: Nat, recipient: String) -> Result<withdrawals::Withdrawal, Error> {
+    let amount = tokens::nat_to_u128(&amount).map_err(|msg| Error::Error { msg })?;
+    withdrawals::request_withdrawal(Token::CkETH, amount, recipient)
         .await
-        .unwrap()
-}
\ No newline at end of file
+        .map_err(|msg| Error::TransferFailed { msg })
+}
+
+// Fetching the Ethereum address a deposit transaction was sent from
+pub async fn deposit_source_address(hash: String) -> Result<String, String> {
+    match eth_get_transaction_receipt(&EVM_RPC, hash).await? {
+        GetTransactionReceiptResult::Ok(Some(receipt)) => Ok(receipt.from),
+        GetTransactionReceiptResult::Ok(None) => Err("Receipt is None".to_string()),
+        GetTransactionReceiptResult::Err(e) => Err(format!("Error on Get transaction receipt result: {:?}", e)),
+    }
+}
@@ -1,21 +1,28 @@
-use std::str::FromStr;
-use b3_utils::api::{InterCall, CallCycles};
 use evm_rpc_canister_types::GetTransactionReceiptResult;
 use candid::Nat;
-use b3_utils::ledger::{ICRCAccount, ICRC1, ICRC1TransferArgs, ICRC1TransferResult};
+use b3_utils::ledger::{ICRCAccount, ICRC1, ICRC2, ICRC2TransferFromArgs, ICRC2TransferFromResult};
 use b3_utils::caller_is_controller;
-use crate::ck_eth::minter;
+use candid::Principal;
 use crate::receipt;
-use crate::payments;
-use crate::transaction_fees;
-use crate::common::{eth_get_transaction_receipt, hex_string_with_0x_to_f64};
-use crate::ck_eth_payments::EVM_RPC;
-use crate::entitymanagement::{check_entity_type, EntityType };
+use crate::deposits;
+use crate::depositaccounts;
+use crate::evmrpc;
+use crate::withdrawals;
+use crate::treasury::{self, TreasuryTransfer};
+use crate::accesscontrol::caller_is_treasurer;
+use crate::tokens::{self, Token};
+use crate::common::{eth_get_confirmations, eth_get_transaction_receipt};
+use num_traits::ToPrimitive;
+use crate::ck_eth_payments::{find_helper_deposit, HelperDeposit, EVM_RPC};
+use crate::entitymanagement::{self, Error};
 // use crate::ifarm_tokens;
 
-const USDC_HELPER: &str = "0x70e02abf44e62da8206130cd7ca5279a8f6d6241";
-const USDC_LEDGER: &str = "yfumr-cyaaa-aaaar-qaela-cai";
-const USDC_MINTER: &str = "jzenf-aiaaa-aaaar-qaa7q-cai"; 
+// Helper contract on Sepolia; the address in use comes from the EVM RPC config
+pub const USDC_HELPER: &str = "0x70e02abf44e62da8206130cd7ca5279a8f6d6241";
+// USDC token contract on Sepolia
+pub const USDC_CONTRACT: &str = "0x1c7d4b196cb0c7b01d743fbc6116a902379c7238";
+// Default ckUSDC ledger
+pub const USDC_LEDGER: &str = "yfumr-cyaaa-aaaar-qaela-cai";
 
 #[ic_cdk::update]
 async fn get_usdc_receipt(hash: String) -> String {
@@ -24,8 +31,9 @@ async fn get_usdc_receipt(hash: String) -> String {
     serde_json::to_string(&wrapper).unwrap()
 }
 
-#[ic_cdk::update]
-async fn verify_usdc_transaction(hash: String, farm_id: u64, investor_id: u64) -> Result<receipt::VerifiedTransactionDetails, String> {
+// Checking a ckUSDC deposit receipt made to the given principal's verification subaccount, returning the deposit,
+// sender and block. The deposit must be a single event emitted by the helper contract for the USDC contract.
+async fn check_usdc_receipt(hash: String, depositor: Principal) -> Result<(HelperDeposit, String, u64), String> {
     let receipt = match eth_get_transaction_receipt(&EVM_RPC, hash.clone()).await {
         Ok(receipt) => receipt,
         Err(e) => return Err(format!("Failed to get receipt: {}", e)),
@@ -42,98 +50,112 @@ async fn verify_usdc_transaction(hash: String, farm_id: u64, investor_id: u64) -
         return Err("Transaction failed".to_string());
     }
 
-    if receipt_data.to != USDC_HELPER {
+    let config = evmrpc::config();
+    if receipt_data.to.to_lowercase() != config.ckusdc_helper {
         return Err("Minter address does not match".to_string());
     }
 
-    let log_principal = receipt_data.logs.iter()
-        .find(|log| log.topics.get(3).map(|topic| topic.as_str()) == Some(&crate::ck_eth_payments::canister_deposit_principal()))
-        .ok_or_else(|| "Principal does not match or missing in logs".to_string())?;
+    let deposit = find_helper_deposit(&receipt_data.logs, &config.ckusdc_helper, &config.usdc_contract, depositor)?;
+    let block_number = receipt_data.blockNumber.0.to_u64()
+        .ok_or_else(|| format!("Block number {} is too large", receipt_data.blockNumber))?;
+
+    Ok((deposit, receipt_data.from.clone(), block_number))
+}
 
-    let amount = log_principal.data.clone();
-    let from_address = receipt_data.from.clone();
+#[ic_cdk::update]
+async fn verify_usdc_transaction(hash: String, farm_id: u64) -> Result<receipt::DepositVerification, Error> {
+    // Deposits are credited to the caller, and only if they were made to the caller's verification subaccount
+    let investor_id = entitymanagement::caller_investor_id()?;
+    credit_usdc_deposit(hash, farm_id, investor_id, ic_cdk::caller()).await
+}
 
-    let amount_f64 = hex_string_with_0x_to_f64(amount.clone());
+// Credits a deposit once it has enough block confirmations, otherwise queues it to be checked again
+pub async fn credit_usdc_deposit(hash: String, farm_id: u64, investor_id: u64, depositor: Principal) -> Result<receipt::DepositVerification, Error> {
+    // Held until the deposit is credited, so the same hash cannot be credited twice
+    let claim = deposits::claim_deposit(&hash)?;
 
-    let deduction = amount_f64 * 0.005;
-    let _ = transaction_fees::store_transaction_fee(hash.clone(), deduction);
-    let new_amount = amount_f64 - deduction;
+    let (deposit, from_address, block_number) = check_usdc_receipt(hash.clone(), depositor)
+        .await
+        .map_err(|msg| Error::Error { msg })?;
 
-    let _ = payments::store_investments(
+    let required = evmrpc::config().min_confirmations;
+    let confirmations = eth_get_confirmations(&EVM_RPC, block_number)
+        .await
+        .map_err(|msg| Error::Error { msg })?;
+    if confirmations < required {
+        deposits::queue_pending_deposit(&hash, farm_id, investor_id, depositor, Token::CkUSDC, confirmations)?;
+        return Ok(receipt::DepositVerification::Pending { confirmations, required });
+    }
+
+    // Move the deposit into the canister's main account, where refunds and payouts are paid from
+    let amount_base_units = match depositaccounts::collect_verified_deposit(depositor, Token::CkUSDC, deposit.amount)
+        .await
+        .map_err(|msg| Error::Error { msg })?
+    {
+        Some((moved, _)) => moved,
+        None => {
+            deposits::queue_pending_deposit(&hash, farm_id, investor_id, depositor, Token::CkUSDC, confirmations)?;
+            return Ok(receipt::DepositVerification::Minting);
+        }
+    };
+
+    // The deposit is in the canister now, so it is credited to the investor whether or not the round can take it
+    let invested = deposits::invest_collected_deposit(&hash, farm_id, investor_id, Token::CkUSDC, amount_base_units).await;
+
+    deposits::clear_pending_deposit(&hash);
+    claim.complete(deposits::ProcessedDeposit {
+        transaction_hash: hash,
         farm_id,
-        new_amount,
         investor_id,
-        hash.clone(),
-        "ckUSDC".to_string()
-    );
-
-    Ok(receipt::VerifiedTransactionDetails {
-        amount,
+        token: Token::CkUSDC,
+        amount: deposit.amount,
+        credited: *invested.as_ref().unwrap_or(&0),
+        processed_at: ic_cdk::api::time(),
+    });
+
+    let details = receipt::VerifiedTransactionDetails {
+        amount: deposit.amount_hex(),
         from: from_address,
+    };
+    Ok(match invested {
+        Ok(_) => receipt::DepositVerification::Credited(details),
+        Err(reason) => receipt::DepositVerification::HeldAsBalance { details, reason },
     })
 }
 
-/// Store transaction fee separately
-async fn store_transaction_fee(hash: String, fee: f64) -> Result<(), String> {
-    transaction_fees::store_transaction_fee(hash, fee).map_err(|e| format!("Failed to store transaction fee: {}", e))
-}
-
-/// Store investment details separately
-#[ic_cdk::update]
-async fn store_investment(farm_id: u64, amount: f64, investor_id: u64, hash: String) -> Result<(), String> {
-    match check_entity_type() {
-        EntityType::Investor => {
-            // Calculate and store transaction fee
-            let deduction = amount * 0.005;
-            store_transaction_fee(hash.clone(), deduction).await?;
-            let new_amount = amount - deduction;
-
-            // Store the USDC investment
-            payments::store_investments(farm_id, new_amount, investor_id, hash, "ckUSDC".to_string())?;
-
-           // Get farmer's principal from farm_id
-           // let farmer = display_specific_farm(farm_id)
-           //    .map_err(|e| format!("Failed to get farmer details: {}", e))?;
-            
-            // Transfer iFarm tokens from farmer to investor
-            // let ifarm_amount = Nat::from((new_amount as u64) * 1_000_000);
-            // let investor = ic_cdk::caller();
-            // let _ = ifarm_tokens::ifarm_transfer_from(farmer.principal_id, investor, ifarm_amount).await
-            //     .map_err(|e| format!("Failed to transfer iFarm tokens: {}", e))?;
-
-            Ok(())
-        },
-        _ => Err("Only investors can store investments".to_string())
-    }
-}
-
 #[ic_cdk::update]async fn ckusdc_balance() -> Nat {
     let account = ICRCAccount::new(ic_cdk::id(), None);
-    ICRC1::from(USDC_LEDGER).balance_of(account).await.unwrap()
+    ICRC1::from(Token::CkUSDC.ledger()).balance_of(account).await.unwrap()
 }
 
-#[ic_cdk::update]
-async fn ckusdc_transfer(to: String, amount: Nat) -> ICRC1TransferResult {
-    let to = ICRCAccount::from_str(&to).unwrap();
-    let transfer_args = ICRC1TransferArgs {
-        to,
+/// Send ckUSDC from the treasury, subject to the treasury roles, limits and allow-list
+#[ic_cdk::update(guard = "caller_is_treasurer")]
+async fn ckusdc_transfer(to: String, amount: Nat) -> Result<TreasuryTransfer, Error> {
+    treasury::treasury_transfer(Token::CkUSDC, to, amount).await
+}
+
+/// Pull ckUSDC from an account that has approved this canister as a spender (ICRC-2)
+pub async fn ckusdc_transfer_from(from: Principal, to: Principal, amount: Nat) -> Result<ICRC2TransferFromResult, String> {
+    let transfer_from_args = ICRC2TransferFromArgs {
+        from: ICRCAccount::new(from, None),
+        to: ICRCAccount::new(to, None),
         amount,
-        from_subaccount: None,
+        spender_subaccount: None,
         fee: None,
         memo: None,
         created_at_time: None,
     };
-    ICRC1::from(USDC_LEDGER).transfer(transfer_args).await.unwrap()
+
+    ICRC2::from(Token::CkUSDC.ledger())
+        .transfer_from(transfer_from_args)
+        .await
+        .map_err(|e| format!("Failed to call ckUSDC transfer_from: {}", e))
 }
 
 #[ic_cdk::update(guard = "caller_is_controller")]
-async fn ckusdc_withdraw(amount: Nat, recipient: String) -> minter::WithdrawalResult {
-    let withdraw = minter::WithdrawalArg {
-        amount,
-        recipient,
-    };
-    InterCall::from(USDC_MINTER)
-        .call("withdraw_eth", withdraw, CallCycles::NoPay)
+async fn ckusdc_withdraw(amount: Nat, recipient: String) -> Result<withdrawals::Withdrawal, Error> {
+    let amount = tokens::nat_to_u128(&amount).map_err(|msg| Error::Error { msg })?;
+    withdrawals::request_withdrawal(Token::CkUSDC, amount, recipient)
         .await
-        .unwrap()
-}
\ No newline at end of file
+        .map_err(|msg| Error::TransferFailed { msg })
+}
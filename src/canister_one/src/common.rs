@@ -1,43 +1,106 @@
 use evm_rpc_canister_types::{
-    EthSepoliaService, GetTransactionReceiptResult, MultiGetTransactionReceiptResult, RpcServices,
-    EvmRpcCanister,
+    GetBlockByNumberResult, GetTransactionReceiptResult, MultiGetBlockByNumberResult,
+    MultiGetTransactionReceiptResult, EvmRpcCanister,
 };
-use candid::Nat;
 use hex;
 use num_traits::cast::ToPrimitive;
 use num_bigint::BigUint;      
+use crate::evmrpc;
 
 pub async fn eth_get_transaction_receipt(
     evm_rpc: &EvmRpcCanister,
     hash: String,
 ) -> Result<GetTransactionReceiptResult, String> {
+    let config = evmrpc::config();
     let result: Result<(MultiGetTransactionReceiptResult,), String> = evm_rpc
         .eth_get_transaction_receipt(
-            RpcServices::EthSepolia(Some(vec![
-                EthSepoliaService::PublicNode,
-                EthSepoliaService::BlockPi,
-                EthSepoliaService::Ankr,
-            ])),
+            config.rpc_services(),
             None,
             hash,
-            10_000_000_000,
+            config.cycles,
         )
         .await
         .map_err(|e| format!("Failed to call eth_getTransactionReceipt: {:?}", e));
 
     match result {
         Ok((MultiGetTransactionReceiptResult::Consistent(receipt),)) => Ok(receipt),
-        Ok((MultiGetTransactionReceiptResult::Inconsistent(error),)) => {
-            Err(format!("EVM_RPC returned inconsistent results: {:?}", error))
+        Ok((MultiGetTransactionReceiptResult::Inconsistent(results),)) => {
+            evmrpc::resolve_consensus(results, config.consensus_threshold)
+                .map_err(|e| format!("EVM_RPC returned inconsistent results: {}", e))
         }
         Err(e) => Err(format!("Error calling EVM_RPC: {}", e)),
     }
 }
 
-pub fn hex_string_with_0x_to_f64(hex_string: String) -> f64 {
-    let hex_string = hex_string.trim_start_matches("0x");
-    let bytes = hex::decode(hex_string).expect("Failed to decode hex string");
-    let big_uint = BigUint::from_bytes_be(&bytes);
-    let nat = Nat::from(big_uint);
-    nat.0.to_f64().unwrap_or(f64::MAX)
-}
\ No newline at end of file
+// Number of the configured confirmation block. When providers disagree, the highest block that at least the
+// consensus threshold of them have reached is used.
+pub async fn eth_get_confirmation_block_number(evm_rpc: &EvmRpcCanister) -> Result<u64, String> {
+    let config = evmrpc::config();
+    let result = evm_rpc
+        .eth_get_block_by_number(config.rpc_services(), None, config.confirmation_tag(), config.cycles)
+        .await
+        .map_err(|e| format!("Failed to call eth_getBlockByNumber: {:?}", e))?;
+
+    let block_number = |block: GetBlockByNumberResult| match block {
+        GetBlockByNumberResult::Ok(block) => block
+            .number
+            .0
+            .to_u64()
+            .ok_or_else(|| format!("Block number {} is too large", block.number)),
+        GetBlockByNumberResult::Err(e) => Err(format!("Error on Get block by number result: {:?}", e)),
+    };
+
+    match result {
+        (MultiGetBlockByNumberResult::Consistent(block),) => block_number(block),
+        (MultiGetBlockByNumberResult::Inconsistent(results),) => {
+            let mut numbers: Vec<u64> = results
+                .into_iter()
+                .filter_map(|(_, block)| block_number(block).ok())
+                .collect();
+            numbers.sort_unstable_by(|a, b| b.cmp(a));
+            numbers
+                .get(config.consensus_threshold as usize - 1)
+                .copied()
+                .ok_or_else(|| "Too few providers returned a block".to_string())
+        }
+    }
+}
+
+// Confirmations a transaction mined in `block_number` has, counting its own block
+pub async fn eth_get_confirmations(evm_rpc: &EvmRpcCanister, block_number: u64) -> Result<u64, String> {
+    let confirmation_block = eth_get_confirmation_block_number(evm_rpc).await?;
+    Ok((confirmation_block + 1).saturating_sub(block_number))
+}
+
+// Decodes an ABI-encoded uint256, such as an event's `data`, failing unless it is exactly 32 bytes and fits in a u128
+pub fn decode_uint256(data: &str) -> Result<u128, String> {
+    let bytes = hex::decode(data.trim_start_matches("0x")).map_err(|e| format!("Failed to decode uint256: {}", e))?;
+    if bytes.len() != 32 {
+        return Err(format!("Expected a 32-byte uint256, got {} bytes", bytes.len()));
+    }
+    BigUint::from_bytes_be(&bytes)
+        .to_u128()
+        .ok_or_else(|| format!("Amount {} is too large", data))
+}
+
+// Decodes the data of a deposit made through the helper contract that takes a subaccount: the amount as a uint256,
+// then the subaccount as a bytes32
+pub fn decode_deposit_data(data: &str) -> Result<(u128, [u8; 32]), String> {
+    let bytes = hex::decode(data.trim_start_matches("0x")).map_err(|e| format!("Failed to decode deposit data: {}", e))?;
+    if bytes.len() != 64 {
+        return Err(format!("Expected an amount and a subaccount, got {} bytes", bytes.len()));
+    }
+    let amount = decode_uint256(&hex::encode(&bytes[..32]))?;
+    let mut subaccount = [0u8; 32];
+    subaccount.copy_from_slice(&bytes[32..]);
+    Ok((amount, subaccount))
+}
+
+// Decodes an indexed address topic into a lowercase 0x-prefixed address
+pub fn decode_address_topic(topic: &str) -> Result<String, String> {
+    let bytes = hex::decode(topic.trim_start_matches("0x")).map_err(|e| format!("Failed to decode topic: {}", e))?;
+    if bytes.len() != 32 || bytes[..12].iter().any(|byte| *byte != 0) {
+        return Err(format!("Topic {} is not an address", topic));
+    }
+    Ok(format!("0x{}", hex::encode(&bytes[12..])))
+}
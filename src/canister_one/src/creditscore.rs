@@ -116,13 +116,12 @@ async fn fetch_credit_score(mpesa_statement: Vec<u8>, passcode: String) -> Resul
 
 #[update]
 async fn add_credit_score(farm_id: u64, credit_score: u64, max_loan_amount: u64) -> Result<entitymanagement::Success, entitymanagement::Error> {
-    let farmers = entitymanagement::return_farmers();
-    let farms_agribusinesses = entitymanagement::return_farms_agribusiness(); 
     let caller = ic_cdk::caller();
 
     // Check if the caller is either a registered farmer or a farms agribusiness
-    let is_registered_farmer = farmers.iter().any(|farmer| farmer.principal_id == caller);
-    let is_registered_agribusiness = farms_agribusinesses.iter().any(|agribiz| agribiz.principal_id == caller);
+    let entity_type = entitymanagement::registered_entity(&caller).map(|entity| entity.entity_type);
+    let is_registered_farmer = entity_type == Some(entitymanagement::EntityType::Farmer);
+    let is_registered_agribusiness = entity_type == Some(entitymanagement::EntityType::FarmsAgriBusiness);
 
     if !is_registered_farmer && !is_registered_agribusiness {
         return Err(entitymanagement::Error::Error { 
@@ -130,13 +129,12 @@ async fn add_credit_score(farm_id: u64, credit_score: u64, max_loan_amount: u64)
         });
     }
 
-    let mut farmers = entitymanagement::return_farmers(); 
-
     // Transfer ifarm token to the caller
     let amount = Nat::from(max_loan_amount); 
     let _ = ifarm_tokens::ifarm_transfer(caller, amount).await; 
 
-    if let Some(farm) = farmers.iter_mut().find(|f| f.id == farm_id) {
+    let farm = entitymanagement::FARMER_STORAGE.with(|service| service.borrow().get(&farm_id));
+    if let Some(mut farm) = farm {
 
         farm.credit_score = Some(credit_score); 
         farm.ifarm_tokens = Some(max_loan_amount);
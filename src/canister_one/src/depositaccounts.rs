@@ -0,0 +1,852 @@
+use b3_utils::ledger::{ICRCAccount, ICRC1, ICRC1TransferArgs};
+use b3_utils::{vec_to_hex_string_with_0x, Environment, Subaccount};
+use candid::{CandidType, Decode, Encode, Nat, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::accesscontrol::caller_is_treasurer;
+use crate::auditlog::{self, AuditAction};
+use crate::ck_eth_payments;
+use crate::entitymanagement::{self, Error, Memory, Success, MEMORY_MANAGER};
+use crate::fundingrounds;
+use crate::ledgertransfers::{self, PreparedTransfer, TransferAttempt};
+use crate::loanmanagement::{self, LoanStatus};
+use crate::tokens::{self, nat_to_u128, Token};
+use crate::transaction_fees;
+
+// How often deposit subaccounts are checked for new funds
+pub const DEPOSIT_SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60); // Every 10 minutes
+// Tokens investors can deposit through the ckETH and ckUSDC helper contracts
+const DEPOSIT_TOKENS: [Token; 2] = [Token::CkETH, Token::CkUSDC];
+// Accounts unused for this long are left out of the timed sweep until their address is requested again
+const DEPOSIT_ACCOUNT_ACTIVE_WINDOW: u64 = 7 * 24 * 60 * 60 * 1_000_000_000; // 7 days in nanoseconds
+// How long a balance withdrawal can stay Pending before a retry assumes its send was interrupted
+const STALE_PENDING_WITHDRAWAL: u64 = 30 * 60 * 1_000_000_000; // 30 minutes in nanoseconds
+
+/**
+* DepositAccount Struct
+* A subaccount of the canister dedicated to one investor's deposits, optionally for a single funding round.
+* Funds found in it are credited to that investor without needing a transaction hash.
+* @param Defined In-Line
+* @return DepositAccount instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DepositAccount {
+    pub account_id: u64, // Nonce the subaccount is derived from
+    pub investor_id: u64,
+    pub loan_id: Option<u64>, // Set for accounts dedicated to a loan's funding round
+    pub farm_id: Option<u64>,
+    pub created_at: u64,
+    pub last_swept_at: Option<u64>,
+    pub last_active_at: Option<u64>, // Last time its address was requested or funds were found in it
+}
+
+impl Storable for DepositAccount {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DepositAccount {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl DepositAccount {
+    pub fn subaccount(&self) -> Subaccount {
+        Subaccount::new(Environment::Production, self.account_id)
+    }
+
+    // Whether the timed sweep checks the account
+    fn is_active(&self, now: u64) -> bool {
+        let last_active_at = self.last_active_at.unwrap_or(self.created_at);
+        now.saturating_sub(last_active_at) < DEPOSIT_ACCOUNT_ACTIVE_WINDOW
+    }
+}
+
+// Key of an account in the investor index; loan ids start at 1, so 0 stands for an account without a loan
+fn account_key(investor_id: u64, loan_id: Option<u64>) -> (u64, u64) {
+    (investor_id, loan_id.unwrap_or(0))
+}
+
+/**
+* DepositAddress Struct
+* Everything an investor needs to send funds to their deposit account, either on the IC or through the
+* Ethereum helper contracts.
+* @param Defined In-Line
+* @return DepositAddress instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DepositAddress {
+    pub account: DepositAccount,
+    pub icrc_account: String, // ICRC-1 text encoding of the canister's subaccount
+    pub principal: String,    // The canister's principal as bytes32, for the helper contracts
+    pub subaccount: String,   // The subaccount as bytes32, for the helper contracts
+}
+
+/**
+* DepositBalance Struct
+* Swept deposits held for an investor that were not invested into a funding round.
+* @param Defined In-Line
+* @return DepositBalance instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DepositBalance {
+    pub token: Token,
+    pub amount: u128, // In the token's base units
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+struct InvestorBalances {
+    balances: Vec<DepositBalance>,
+    spent: u64, // Number of investments and withdrawals made from the balances, used to reference them
+}
+
+impl Storable for InvestorBalances {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for InvestorBalances {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/**
+* SweepCredit Enum
+* Where the funds moved out of a deposit account were credited.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum SweepCredit {
+    FundingRound { loan_id: u64, accepted: u128 },
+    Balance,
+}
+
+/**
+* DepositSweep Struct
+* Records funds moved from a deposit account into the canister's main account.
+* @param Defined In-Line
+* @return DepositSweep instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DepositSweep {
+    pub sweep_id: u64,
+    pub account_id: u64,
+    pub investor_id: u64,
+    pub token: Token,
+    pub amount: u128, // Amount moved, after the ledger fee
+    pub fee: u128,    // Investment fee taken from the amount
+    pub block_index: Nat,
+    pub credit: SweepCredit,
+    pub swept_at: u64,
+}
+
+impl Storable for DepositSweep {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DepositSweep {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/**
+* BalanceWithdrawalStatus Enum
+* Tracks whether a withdrawal from a deposit balance has reached the investor.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum BalanceWithdrawalStatus {
+    Pending,
+    Sent { block_index: Nat },
+    Failed { error: String },
+}
+
+/**
+* BalanceWithdrawal Struct
+* Funds taken out of an investor's deposit balance and sent to their principal over ICRC-1. The amount stays taken
+* out of the balance while the withdrawal is retried.
+* @param Defined In-Line
+* @return BalanceWithdrawal instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct BalanceWithdrawal {
+    pub withdrawal_id: u64,
+    pub investor_id: u64,
+    pub to: Principal,
+    pub token: Token,
+    pub amount: u128, // In the token's base units
+    pub status: BalanceWithdrawalStatus,
+    pub transfer_attempt: Option<TransferAttempt>,
+    pub requested_at: u64,
+    pub last_attempt_at: u64,
+}
+
+impl Storable for BalanceWithdrawal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for BalanceWithdrawal {
+    const MAX_SIZE: u32 = 1_024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Deposit accounts keyed by the nonce their subaccount is derived from
+    pub static DEPOSIT_ACCOUNTS: RefCell<StableBTreeMap<u64, DepositAccount, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+        ));
+
+    // Uninvested deposit balances keyed by investor id
+    static DEPOSIT_BALANCES: RefCell<StableBTreeMap<u64, InvestorBalances, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+        ));
+
+    // Sweeps keyed by sweep id
+    pub static DEPOSIT_SWEEPS: RefCell<StableBTreeMap<u64, DepositSweep, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+        ));
+
+    // Index of deposit accounts per investor: (investor_id, loan_id or 0) => account_id
+    static DEPOSIT_ACCOUNT_INDEX: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(55)))
+        ));
+
+    // Withdrawals from deposit balances keyed by withdrawal id
+    static BALANCE_WITHDRAWALS: RefCell<StableBTreeMap<u64, BalanceWithdrawal, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(56)))
+        ));
+
+    // Set while a sweep is running, so overlapping sweeps do not move the same funds
+    static SWEEP_RUNNING: RefCell<bool> = const { RefCell::new(false) };
+}
+
+// Held for the duration of a sweep; dropping it, including when the sweep traps, lets the next sweep run
+struct SweepGuard;
+
+impl SweepGuard {
+    fn acquire() -> Option<SweepGuard> {
+        let already_running = SWEEP_RUNNING.with(|running| running.replace(true));
+        (!already_running).then_some(SweepGuard)
+    }
+}
+
+impl Drop for SweepGuard {
+    fn drop(&mut self) {
+        SWEEP_RUNNING.with(|running| running.replace(false));
+    }
+}
+
+fn to_deposit_address(account: DepositAccount) -> DepositAddress {
+    let subaccount = account.subaccount();
+    DepositAddress {
+        icrc_account: ICRCAccount::new(ic_cdk::id(), Some(subaccount.clone())).to_string(),
+        principal: ck_eth_payments::canister_deposit_principal(),
+        subaccount: vec_to_hex_string_with_0x(subaccount.as_slice()),
+        account,
+    }
+}
+
+/**
+* Function: get_deposit_address
+* Description: Returns the caller's deposit account, creating it on first use. Passing a loan dedicates the
+* account to that loan's funding round, otherwise deposits are held as the investor's balance. Requesting the
+* address marks the account active, so the timed sweep checks it for the next DEPOSIT_ACCOUNT_ACTIVE_WINDOW.
+* @param loan_id: Option<u64> - The loan whose funding round the deposits are for
+* @return Result<DepositAddress, Error> - Where to send deposits, or an error otherwise
+*/
+#[update]
+pub fn get_deposit_address(loan_id: Option<u64>) -> Result<DepositAddress, Error> {
+    let investor_id = entitymanagement::caller_investor_id()?;
+
+    let farm_id = match loan_id {
+        Some(loan_id) => {
+            let loan = loanmanagement::find_loan(loan_id)?;
+            if loan.status != LoanStatus::Funding {
+                return Err(Error::InvalidLoanTransition {
+                    msg: format!("Loan {} is not raising funds ({:?})", loan_id, loan.status),
+                });
+            }
+            Some(loan.farm_id)
+        }
+        None => None,
+    };
+
+    let now = ic_cdk::api::time();
+    let existing = DEPOSIT_ACCOUNT_INDEX
+        .with(|index| index.borrow().get(&account_key(investor_id, loan_id)))
+        .and_then(|account_id| DEPOSIT_ACCOUNTS.with(|accounts| accounts.borrow().get(&account_id)));
+    if let Some(mut account) = existing {
+        account.last_active_at = Some(now);
+        DEPOSIT_ACCOUNTS.with(|accounts| accounts.borrow_mut().insert(account.account_id, account.clone()));
+        return Ok(to_deposit_address(account));
+    }
+
+    let account = DEPOSIT_ACCOUNTS.with(|accounts| {
+        let mut accounts = accounts.borrow_mut();
+        let account_id = accounts
+            .last_key_value()
+            .map(|(id, _)| id + 1)
+            .unwrap_or(1);
+        let account = DepositAccount {
+            account_id,
+            investor_id,
+            loan_id,
+            farm_id,
+            created_at: now,
+            last_swept_at: None,
+            last_active_at: Some(now),
+        };
+        accounts.insert(account_id, account.clone());
+        account
+    });
+    DEPOSIT_ACCOUNT_INDEX.with(|index| {
+        index
+            .borrow_mut()
+            .insert(account_key(investor_id, loan_id), account.account_id)
+    });
+
+    Ok(to_deposit_address(account))
+}
+
+/**
+* Function: index_deposit_accounts
+* Description: Indexes every deposit account by investor and loan, for accounts created before the index existed.
+* @param None
+* @return None
+*/
+pub fn index_deposit_accounts() {
+    DEPOSIT_ACCOUNTS.with(|accounts| {
+        DEPOSIT_ACCOUNT_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            for (account_id, account) in accounts.borrow().iter() {
+                // Keep the first account an investor opened for a loan, which is the one the address lookup returned
+                let key = account_key(account.investor_id, account.loan_id);
+                if !index.contains_key(&key) {
+                    index.insert(key, account_id);
+                }
+            }
+        })
+    });
+}
+
+pub fn credit_balance(investor_id: u64, token: Token, amount: u128) {
+    let (before, after) = DEPOSIT_BALANCES.with(|balances| {
+        let mut balances = balances.borrow_mut();
+        let mut investor = balances.get(&investor_id).unwrap_or_default();
+        let balance = match investor.balances.iter_mut().position(|balance| balance.token == token) {
+            Some(index) => &mut investor.balances[index],
+            None => {
+                investor.balances.push(DepositBalance { token, amount: 0 });
+                investor.balances.last_mut().expect("balance was just added")
+            }
+        };
+        let before = balance.amount;
+        balance.amount += amount;
+        let after = balance.amount;
+        balances.insert(investor_id, investor);
+        (before, after)
+    });
+
+    let describe = |amount: u128| format!("{} {}", tokens::format_units(amount, token.decimals()), token.symbol());
+    auditlog::record(
+        AuditAction::DepositCredited,
+        format!("deposit_balance:{}", investor_id),
+        Some(describe(before)),
+        Some(describe(after)),
+    );
+}
+
+// Moves everything above the ledger fee out of a deposit account, returning the amount moved and its block
+async fn collect_deposit(account: &DepositAccount, token: Token) -> Result<Option<(u128, Nat)>, String> {
+    let ledger = ICRC1::from(token.ledger());
+    let subaccount = account.subaccount();
+
+    let balance = ledger
+        .balance_of(ICRCAccount::new(ic_cdk::id(), Some(subaccount.clone())))
+        .await
+        .map_err(|e| format!("Failed to read {} balance: {}", token.symbol(), e))?;
+    let ledger_fee = ledger
+        .fee()
+        .await
+        .map_err(|e| format!("Failed to read {} fee: {}", token.symbol(), e))?;
+
+    let balance = nat_to_u128(&balance)?;
+    let ledger_fee = nat_to_u128(&ledger_fee)?;
+    if balance <= ledger_fee {
+        return Ok(None);
+    }
+
+    let amount = balance - ledger_fee;
+    let transfer_args = ICRC1TransferArgs {
+        to: ICRCAccount::new(ic_cdk::id(), None),
+        amount: Nat::from(amount),
+        from_subaccount: Some(subaccount),
+        fee: Some(Nat::from(ledger_fee)),
+        memo: None,
+        created_at_time: None,
+    };
+
+    match ledger.transfer(transfer_args).await {
+        Ok(Ok(block_index)) => Ok(Some((amount, block_index))),
+        Ok(Err(e)) => Err(format!("Ledger rejected the sweep: {:?}", e)),
+        Err(e) => Err(format!("Failed to call ledger: {}", e)),
+    }
+}
+
+// Subaccount an investor's deposits verified by transaction hash are minted to. It is derived from their principal,
+// so it never collides with a deposit account, and the sweeper leaves it alone.
+pub fn verification_subaccount(depositor: Principal) -> Subaccount {
+    Subaccount::from_principal(depositor)
+}
+
+/**
+* Function: collect_verified_deposit
+* Description: Moves a deposit verified from its Ethereum receipt out of the depositor's verification subaccount
+* into the canister's main account.
+* @param depositor: Principal - The principal the deposit was minted for
+* @param token: Token - The token deposited
+* @param amount: u128 - The amount the receipt shows was deposited
+* @return Result<Option<(u128, Nat)>, String> - The amount moved after the ledger fee and its block, None if the
+* minter has not minted the deposit yet, or an error otherwise
+*/
+pub async fn collect_verified_deposit(depositor: Principal, token: Token, amount: u128) -> Result<Option<(u128, Nat)>, String> {
+    let ledger = ICRC1::from(token.ledger());
+    let subaccount = verification_subaccount(depositor);
+
+    let balance = ledger
+        .balance_of(ICRCAccount::new(ic_cdk::id(), Some(subaccount.clone())))
+        .await
+        .map_err(|e| format!("Failed to read {} balance: {}", token.symbol(), e))?;
+    if nat_to_u128(&balance)? < amount {
+        return Ok(None);
+    }
+
+    let ledger_fee = ledger
+        .fee()
+        .await
+        .map_err(|e| format!("Failed to read {} fee: {}", token.symbol(), e))?;
+    let ledger_fee = nat_to_u128(&ledger_fee)?;
+    if amount <= ledger_fee {
+        return Err(format!("Deposit of {} is below the {} ledger fee", amount, token.symbol()));
+    }
+
+    let moved = amount - ledger_fee;
+    let transfer_args = ICRC1TransferArgs {
+        to: ICRCAccount::new(ic_cdk::id(), None),
+        amount: Nat::from(moved),
+        from_subaccount: Some(subaccount),
+        fee: Some(Nat::from(ledger_fee)),
+        memo: None,
+        created_at_time: None,
+    };
+
+    match ledger.transfer(transfer_args).await {
+        Ok(Ok(block_index)) => Ok(Some((moved, block_index))),
+        Ok(Err(e)) => Err(format!("Ledger rejected collecting the deposit: {:?}", e)),
+        Err(e) => Err(format!("Failed to call ledger: {}", e)),
+    }
+}
+
+/**
+* Function: sweep_deposit_account
+* Description: Credits the funds found in a deposit account. Deposits into a round's account are invested
+* into that round while it is open; everything else is added to the investor's balance.
+* @param account: DepositAccount - The account to sweep
+* @param token: Token - The token to sweep
+* @return Result<Option<DepositSweep>, String> - The sweep, None if the account held nothing, or an error
+*/
+async fn sweep_deposit_account(account: &DepositAccount, token: Token) -> Result<Option<DepositSweep>, String> {
+    let (amount, block_index) = match collect_deposit(account, token).await? {
+        Some(collected) => collected,
+        None => return Ok(None),
+    };
+
+    // Sweeps are referenced by their ledger block, which is unique per token
+    let reference = format!("{}:{}", token.symbol(), block_index);
+    let fee = tokens::investment_fee(amount);
+    let net_amount = amount - fee;
+    let _ = transaction_fees::store_transaction_fee(reference.clone(), fee, token);
+
+    let round_open = match account.loan_id {
+        Some(loan_id) => loanmanagement::find_loan(loan_id).is_ok_and(|loan| loan.status == LoanStatus::Funding),
+        None => false,
+    };
+
+    let mut credit = SweepCredit::Balance;
+    if let (true, Some(loan_id), Some(farm_id)) = (round_open, account.loan_id, account.farm_id) {
+        match fundingrounds::commit_investment(farm_id, account.investor_id, net_amount, reference, token).await {
+            Ok(accepted) => credit = SweepCredit::FundingRound { loan_id, accepted },
+            Err(e) => ic_cdk::println!("Deposit sweeper: {}, holding as balance", e),
+        }
+    }
+    if credit == SweepCredit::Balance {
+        credit_balance(account.investor_id, token, net_amount);
+    }
+
+    let sweep = DEPOSIT_SWEEPS.with(|sweeps| {
+        let mut sweeps = sweeps.borrow_mut();
+        let sweep_id = sweeps
+            .last_key_value()
+            .map(|(id, _)| id + 1)
+            .unwrap_or(1);
+        let sweep = DepositSweep {
+            sweep_id,
+            account_id: account.account_id,
+            investor_id: account.investor_id,
+            token,
+            amount,
+            fee,
+            block_index,
+            credit,
+            swept_at: ic_cdk::api::time(),
+        };
+        sweeps.insert(sweep_id, sweep.clone());
+        sweep
+    });
+
+    auditlog::record(
+        AuditAction::DepositCredited,
+        format!("sweep:{}", sweep.sweep_id),
+        None,
+        Some(format!(
+            "{} {} from deposit account {} of investor {}: {:?}",
+            tokens::format_units(amount, token.decimals()),
+            token.symbol(),
+            account.account_id,
+            account.investor_id,
+            sweep.credit
+        )),
+    );
+    Ok(Some(sweep))
+}
+
+/**
+* Function: sweep_deposits
+* Description: Sweeps every active deposit account for every deposit token. Accounts whose address has not been
+* requested and that have not received funds within DEPOSIT_ACCOUNT_ACTIVE_WINDOW are skipped, so each sweep only
+* calls the ledgers for accounts that may hold new deposits.
+* @param None
+* @return Vec<DepositSweep> - The sweeps made
+*/
+pub async fn sweep_deposits() -> Vec<DepositSweep> {
+    let _guard = match SweepGuard::acquire() {
+        Some(guard) => guard,
+        None => return Vec::new(),
+    };
+
+    let now = ic_cdk::api::time();
+    let accounts: Vec<DepositAccount> = DEPOSIT_ACCOUNTS.with(|accounts| {
+        accounts
+            .borrow()
+            .iter()
+            .map(|(_, account)| account)
+            .filter(|account| account.is_active(now))
+            .collect()
+    });
+
+    let mut swept = Vec::new();
+    for mut account in accounts {
+        for token in DEPOSIT_TOKENS {
+            match sweep_deposit_account(&account, token).await {
+                Ok(Some(sweep)) => {
+                    account.last_active_at = Some(sweep.swept_at);
+                    swept.push(sweep);
+                }
+                Ok(None) => {}
+                Err(e) => ic_cdk::println!("Deposit sweeper: account {}: {}", account.account_id, e),
+            }
+        }
+
+        account.last_swept_at = Some(ic_cdk::api::time());
+        DEPOSIT_ACCOUNTS.with(|accounts| accounts.borrow_mut().insert(account.account_id, account));
+    }
+
+    swept
+}
+
+/**
+* Function: start_deposit_sweeper
+* Description: Arms the periodic deposit sweeper. Called from both `init` and `post_upgrade`.
+* @param None
+* @return None
+*/
+pub fn start_deposit_sweeper() {
+    ic_cdk_timers::set_timer_interval(DEPOSIT_SWEEP_INTERVAL, || {
+        ic_cdk::spawn(async {
+            sweep_deposits().await;
+        })
+    });
+}
+
+/**
+* Function: run_deposit_sweeper
+* Description: Runs the deposit sweeper immediately instead of waiting for the timer. Treasurer only.
+* @param None
+* @return Result<Vec<DepositSweep>, Error> - The sweeps made, or an error otherwise
+*/
+#[update(guard = "caller_is_treasurer")]
+pub async fn run_deposit_sweeper() -> Result<Vec<DepositSweep>, Error> {
+    Ok(sweep_deposits().await)
+}
+
+// Takes an amount out of an investor's balance, returning the number of the spend
+fn debit_balance(investor_id: u64, token: Token, amount: u128) -> Result<u64, Error> {
+    DEPOSIT_BALANCES.with(|balances| {
+        let mut balances = balances.borrow_mut();
+        let mut investor = balances.get(&investor_id).unwrap_or_default();
+        let balance = investor
+            .balances
+            .iter_mut()
+            .find(|balance| balance.token == token && balance.amount >= amount)
+            .ok_or_else(|| Error::Error {
+                msg: format!("Insufficient {} deposit balance", token.symbol()),
+            })?;
+        balance.amount -= amount;
+        investor.spent += 1;
+        let spend = investor.spent;
+        balances.insert(investor_id, investor);
+        Ok(spend)
+    })
+}
+
+/**
+* Function: invest_deposit_balance
+* Description: Invests part of the caller's deposit balance into a farm's funding round.
+* @param farm_id: u64 - The ID of the farm
+* @param token: Token - The token to invest
+* @param amount: u128 - The amount to invest, in the token's base units
+* @return Result<Success, Error> - Success message with the amount accepted, or an error otherwise
+*/
+#[update]
+pub async fn invest_deposit_balance(farm_id: u64, token: Token, amount: u128) -> Result<Success, Error> {
+    let investor_id = entitymanagement::caller_investor_id()?;
+
+    // Take the amount out of the balance before committing it, so it cannot be spent twice meanwhile
+    let spend = debit_balance(investor_id, token, amount)?;
+    let reference = format!("balance:{}:{}", investor_id, spend);
+
+    match fundingrounds::commit_investment(farm_id, investor_id, amount, reference, token).await {
+        Ok(accepted) => Ok(Success::InvestmentRecorded {
+            msg: format!(
+                "Invested {} {} into farm {}",
+                tokens::format_units(accepted, token.decimals()),
+                token.symbol(),
+                farm_id
+            ),
+        }),
+        Err(msg) => {
+            credit_balance(investor_id, token, amount);
+            Err(Error::Error { msg })
+        }
+    }
+}
+
+/**
+* Function: get_deposit_balances
+* Description: Returns the caller's uninvested deposit balances.
+* @param None
+* @return Result<Vec<DepositBalance>, Error> - The balances, or an error if the caller is not an investor
+*/
+#[query]
+pub fn get_deposit_balances() -> Result<Vec<DepositBalance>, Error> {
+    let investor_id = entitymanagement::caller_investor_id()?;
+    Ok(DEPOSIT_BALANCES.with(|balances| {
+        balances
+            .borrow()
+            .get(&investor_id)
+            .map(|investor| investor.balances)
+            .unwrap_or_default()
+    }))
+}
+
+/**
+* Function: get_deposit_sweeps
+* Description: Returns every sweep credited to an investor.
+* @param investor_id: u64 - The ID of the investor
+* @return Vec<DepositSweep> - The investor's sweeps
+*/
+#[query]
+pub fn get_deposit_sweeps(investor_id: u64) -> Vec<DepositSweep> {
+    DEPOSIT_SWEEPS.with(|sweeps| {
+        sweeps
+            .borrow()
+            .iter()
+            .map(|(_, sweep)| sweep)
+            .filter(|sweep| sweep.investor_id == investor_id)
+            .collect()
+    })
+}
+
+fn save_balance_withdrawal(withdrawal: &BalanceWithdrawal) {
+    BALANCE_WITHDRAWALS.with(|withdrawals| {
+        withdrawals
+            .borrow_mut()
+            .insert(withdrawal.withdrawal_id, withdrawal.clone())
+    });
+}
+
+// Sends a balance withdrawal to the investor's principal over ICRC-1. The withdrawal's id is the memo; resends within
+// the ledger's deduplication window reuse the previous attempt, and later ones first look it up on the ledger.
+async fn send_balance_withdrawal(mut withdrawal: BalanceWithdrawal) -> BalanceWithdrawal {
+    withdrawal.last_attempt_at = ic_cdk::api::time();
+    withdrawal.status = BalanceWithdrawalStatus::Pending;
+    save_balance_withdrawal(&withdrawal);
+
+    let memo = withdrawal.withdrawal_id.to_be_bytes().to_vec();
+    let token = withdrawal.token;
+    withdrawal.status = match ledgertransfers::prepare_transfer(token, withdrawal.transfer_attempt.as_ref(), &memo).await {
+        Ok(PreparedTransfer::AlreadySent { block_index }) => BalanceWithdrawalStatus::Sent { block_index },
+        Ok(PreparedTransfer::Send(attempt)) => {
+            withdrawal.transfer_attempt = Some(attempt.clone());
+            save_balance_withdrawal(&withdrawal);
+
+            let to = ICRCAccount::new(withdrawal.to, None);
+            match ledgertransfers::send_transfer(token, &attempt, to, withdrawal.amount, memo).await {
+                Ok(block_index) => BalanceWithdrawalStatus::Sent { block_index },
+                Err(error) => BalanceWithdrawalStatus::Failed { error },
+            }
+        }
+        Err(error) => BalanceWithdrawalStatus::Failed { error },
+    };
+    save_balance_withdrawal(&withdrawal);
+
+    auditlog::record(
+        AuditAction::TokensSent,
+        format!("balance_withdrawal:{}", withdrawal.withdrawal_id),
+        None,
+        Some(format!(
+            "{} {} to investor {}: {:?}",
+            tokens::format_units(withdrawal.amount, token.decimals()),
+            token.symbol(),
+            withdrawal.investor_id,
+            withdrawal.status
+        )),
+    );
+    withdrawal
+}
+
+/**
+* Function: withdraw_deposit_balance
+* Description: Sends part of the caller's deposit balance to their principal over ICRC-1. The amount is taken out
+* of the balance before it is sent; a withdrawal that fails keeps it and can be retried.
+* @param token: Token - The token to withdraw
+* @param amount: u128 - The amount to withdraw, in the token's base units
+* @return Result<BalanceWithdrawal, Error> - The withdrawal with its status, or an error otherwise
+*/
+#[update]
+pub async fn withdraw_deposit_balance(token: Token, amount: u128) -> Result<BalanceWithdrawal, Error> {
+    let investor_id = entitymanagement::caller_investor_id()?;
+    if amount == 0 {
+        return Err(Error::FieldEmpty {
+            msg: "Withdrawal amount must be greater than zero".to_string(),
+        });
+    }
+
+    debit_balance(investor_id, token, amount)?;
+
+    let now = ic_cdk::api::time();
+    let withdrawal = BALANCE_WITHDRAWALS.with(|withdrawals| {
+        let mut withdrawals = withdrawals.borrow_mut();
+        let withdrawal_id = withdrawals
+            .last_key_value()
+            .map(|(id, _)| id + 1)
+            .unwrap_or(1);
+        let withdrawal = BalanceWithdrawal {
+            withdrawal_id,
+            investor_id,
+            to: ic_cdk::caller(),
+            token,
+            amount,
+            status: BalanceWithdrawalStatus::Pending,
+            transfer_attempt: None,
+            requested_at: now,
+            last_attempt_at: now,
+        };
+        withdrawals.insert(withdrawal_id, withdrawal.clone());
+        withdrawal
+    });
+
+    Ok(send_balance_withdrawal(withdrawal).await)
+}
+
+/**
+* Function: retry_balance_withdrawal
+* Description: Re-sends one of the caller's failed balance withdrawals, or one left pending for longer than
+* STALE_PENDING_WITHDRAWAL.
+* @param withdrawal_id: u64 - The ID of the withdrawal
+* @return Result<BalanceWithdrawal, Error> - The withdrawal with its new status, or an error otherwise
+*/
+#[update]
+pub async fn retry_balance_withdrawal(withdrawal_id: u64) -> Result<BalanceWithdrawal, Error> {
+    let investor_id = entitymanagement::caller_investor_id()?;
+    let withdrawal = BALANCE_WITHDRAWALS
+        .with(|withdrawals| withdrawals.borrow().get(&withdrawal_id))
+        .filter(|withdrawal| withdrawal.investor_id == investor_id)
+        .ok_or_else(|| Error::Error {
+            msg: format!("Withdrawal {} not found", withdrawal_id),
+        })?;
+
+    let retryable = match withdrawal.status {
+        BalanceWithdrawalStatus::Failed { .. } => true,
+        BalanceWithdrawalStatus::Pending => {
+            ic_cdk::api::time().saturating_sub(withdrawal.last_attempt_at) >= STALE_PENDING_WITHDRAWAL
+        }
+        BalanceWithdrawalStatus::Sent { .. } => false,
+    };
+    if !retryable {
+        return Err(Error::Error {
+            msg: format!("Withdrawal {} has not failed", withdrawal_id),
+        });
+    }
+
+    Ok(send_balance_withdrawal(withdrawal).await)
+}
+
+/**
+* Function: get_balance_withdrawals
+* Description: Returns the caller's withdrawals from their deposit balances.
+* @param None
+* @return Result<Vec<BalanceWithdrawal>, Error> - The withdrawals, or an error if the caller is not an investor
+*/
+#[query]
+pub fn get_balance_withdrawals() -> Result<Vec<BalanceWithdrawal>, Error> {
+    let investor_id = entitymanagement::caller_investor_id()?;
+    Ok(BALANCE_WITHDRAWALS.with(|withdrawals| {
+        withdrawals
+            .borrow()
+            .iter()
+            .map(|(_, withdrawal)| withdrawal)
+            .filter(|withdrawal| withdrawal.investor_id == investor_id)
+            .collect()
+    }))
+}
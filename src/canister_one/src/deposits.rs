@@ -0,0 +1,405 @@
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::query;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::auditlog::{self, AuditAction};
+use crate::ck_eth::receipt::DepositVerification;
+use crate::ck_eth_payments;
+use crate::ckusdc_payments;
+use crate::depositaccounts;
+use crate::entitymanagement::{Error, Memory, MEMORY_MANAGER};
+use crate::fundingrounds;
+use crate::payments;
+use crate::tokens::{self, Token};
+use crate::transaction_fees;
+
+// How often deposits waiting for confirmations are checked again
+pub const PENDING_DEPOSIT_INTERVAL: Duration = Duration::from_secs(5 * 60); // Every 5 minutes
+// How long a deposit is re-checked before it is left for the investor to verify again
+const PENDING_DEPOSIT_TTL: u64 = 24 * 60 * 60 * 1_000_000_000; // 1 day in nanoseconds
+
+// Ethereum transaction hashes are 32 bytes
+type TxHash = Blob<32>;
+
+/**
+* ProcessedDeposit Struct
+* Records the investment an Ethereum deposit was credited to.
+* @param Defined In-Line
+* @return ProcessedDeposit instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct ProcessedDeposit {
+    pub transaction_hash: String,
+    pub farm_id: u64,
+    pub investor_id: u64,
+    pub token: Token,
+    pub amount: u128,   // Amount deposited, in the token's base units
+    pub credited: u128, // Amount recorded against the funding round after fees and any excess refund, 0 if the
+                        // round could not take the deposit and it was added to the investor's deposit balance
+    pub processed_at: u64,
+}
+
+impl Storable for ProcessedDeposit {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ProcessedDeposit {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/**
+* PendingDeposit Struct
+* A deposit that was found on chain but does not yet have enough block confirmations to be credited.
+* @param Defined In-Line
+* @return PendingDeposit instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct PendingDeposit {
+    pub transaction_hash: String,
+    pub farm_id: u64,
+    pub investor_id: u64,
+    pub depositor: Principal, // Principal whose verification subaccount the deposit must be minted to
+    pub token: Token,
+    pub confirmations: u64,
+    pub first_seen_at: u64,
+    pub last_checked_at: u64,
+    pub last_error: Option<String>,
+}
+
+impl Storable for PendingDeposit {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PendingDeposit {
+    const MAX_SIZE: u32 = 1_024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Deposits already credited, keyed by transaction hash
+    pub static PROCESSED_DEPOSITS: RefCell<StableBTreeMap<TxHash, ProcessedDeposit, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+        ));
+
+    // Deposits waiting for block confirmations, keyed by transaction hash
+    pub static PENDING_DEPOSITS: RefCell<StableBTreeMap<TxHash, PendingDeposit, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+        ));
+
+    // Deposits being verified right now, so the same hash cannot be credited by two concurrent calls
+    static DEPOSITS_IN_FLIGHT: RefCell<BTreeSet<TxHash>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+// Normalises a 0x-prefixed transaction hash into its 32 bytes
+fn parse_tx_hash(hash: &str) -> Result<TxHash, Error> {
+    let invalid = || Error::Error {
+        msg: format!("Invalid transaction hash: {}", hash),
+    };
+
+    let bytes = hex::decode(hash.trim().trim_start_matches("0x")).map_err(|_| invalid())?;
+    if bytes.len() != 32 {
+        return Err(invalid());
+    }
+    TxHash::try_from(bytes.as_slice()).map_err(|_| invalid())
+}
+
+/**
+* DepositClaim Struct
+* Exclusive claim on a deposit's transaction hash while it is verified. Dropping the claim without completing it
+* releases the hash so the deposit can be verified again.
+*/
+pub struct DepositClaim {
+    key: TxHash,
+}
+
+impl DepositClaim {
+    // Records the deposit as credited; the hash can never be claimed again
+    pub fn complete(self, deposit: ProcessedDeposit) {
+        PROCESSED_DEPOSITS.with(|deposits| deposits.borrow_mut().insert(self.key, deposit));
+    }
+}
+
+impl Drop for DepositClaim {
+    fn drop(&mut self) {
+        DEPOSITS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&self.key));
+    }
+}
+
+/**
+* Function: claim_deposit
+* Description: Claims a deposit's transaction hash before it is verified and credited.
+* @param hash: &str - The transaction hash of the deposit
+* @return Result<DepositClaim, Error> - The claim, or AlreadyProcessed if the deposit was credited or is being verified
+*/
+pub fn claim_deposit(hash: &str) -> Result<DepositClaim, Error> {
+    let key = parse_tx_hash(hash)?;
+
+    if let Some(deposit) = PROCESSED_DEPOSITS.with(|deposits| deposits.borrow().get(&key)) {
+        return Err(Error::AlreadyProcessed {
+            msg: format!(
+                "Transaction {} was already credited to farm {} for investor {}",
+                hash, deposit.farm_id, deposit.investor_id
+            ),
+        });
+    }
+
+    let claimed = DEPOSITS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(key));
+    if !claimed {
+        return Err(Error::AlreadyProcessed {
+            msg: format!("Transaction {} is already being verified", hash),
+        });
+    }
+
+    Ok(DepositClaim { key })
+}
+
+/**
+* Function: register_recorded_investments
+* Description: Marks the deposits behind investments recorded before this registry existed as processed.
+* @param None
+* @return None
+*/
+pub fn register_recorded_investments() {
+    let investments: Vec<payments::Investment> =
+        payments::INVESTMENTS.with(|investments| investments.borrow().iter().map(|(_, investment)| investment).collect());
+
+    for investment in investments {
+        let key = match parse_tx_hash(&investment.transaction_hash) {
+            Ok(key) => key,
+            Err(_) => continue,
+        };
+
+        PROCESSED_DEPOSITS.with(|deposits| {
+            let mut deposits = deposits.borrow_mut();
+            if !deposits.contains_key(&key) {
+                deposits.insert(
+                    key,
+                    ProcessedDeposit {
+                        transaction_hash: investment.transaction_hash,
+                        farm_id: investment.farm_id,
+                        investor_id: investment.investor_id,
+                        token: investment.token,
+                        amount: investment.amount,
+                        credited: investment.amount,
+                        processed_at: ic_cdk::api::time(),
+                    },
+                );
+            }
+        });
+    }
+}
+
+/**
+* Function: queue_pending_deposit
+* Description: Records a deposit that needs more block confirmations, so the timer credits it once it has them.
+* @param hash: &str - The transaction hash of the deposit
+* @param farm_id: u64 - The farm the deposit is for
+* @param investor_id: u64 - The investor the deposit is credited to
+* @param depositor: Principal - The principal whose verification subaccount the deposit must be minted to
+* @param token: Token - The token deposited
+* @param confirmations: u64 - The confirmations the deposit has so far
+* @return Result<(), Error> - Ok if the deposit was queued, or an error if the hash is malformed
+*/
+pub fn queue_pending_deposit(
+    hash: &str,
+    farm_id: u64,
+    investor_id: u64,
+    depositor: Principal,
+    token: Token,
+    confirmations: u64,
+) -> Result<(), Error> {
+    let key = parse_tx_hash(hash)?;
+    let now = ic_cdk::api::time();
+
+    PENDING_DEPOSITS.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        let first_seen_at = pending.get(&key).map(|deposit| deposit.first_seen_at).unwrap_or(now);
+        pending.insert(
+            key,
+            PendingDeposit {
+                transaction_hash: hash.to_string(),
+                farm_id,
+                investor_id,
+                depositor,
+                token,
+                confirmations,
+                first_seen_at,
+                last_checked_at: now,
+                last_error: None,
+            },
+        );
+    });
+    Ok(())
+}
+
+// Drops a deposit from the pending queue once it has been credited
+pub fn clear_pending_deposit(hash: &str) {
+    if let Ok(key) = parse_tx_hash(hash) {
+        PENDING_DEPOSITS.with(|pending| pending.borrow_mut().remove(&key));
+    }
+}
+
+/**
+* Function: invest_collected_deposit
+* Description: Invests a verified deposit, already moved into the canister's main account, into the farm's funding
+* round. The platform fee is only taken once the round accepts the investment. If the round cannot take it, for
+* example because it is full or has expired, the whole deposit is added to the investor's deposit balance instead.
+* @param hash: &str - The transaction hash of the deposit
+* @param farm_id: u64 - The farm the deposit is for
+* @param investor_id: u64 - The investor the deposit is credited to
+* @param token: Token - The token deposited
+* @param amount: u128 - The amount moved into the main account, in the token's base units
+* @return Result<u128, String> - The amount recorded against the round, or why the deposit was held as balance
+*/
+pub async fn invest_collected_deposit(hash: &str, farm_id: u64, investor_id: u64, token: Token, amount: u128) -> Result<u128, String> {
+    let fee = tokens::investment_fee(amount);
+    let invested = match fundingrounds::commit_investment(farm_id, investor_id, amount - fee, hash.to_string(), token).await {
+        Ok(credited) => {
+            if let Err(e) = transaction_fees::store_transaction_fee(hash.to_string(), fee, token) {
+                ic_cdk::println!("Deposit {}: failed to record the fee: {}", hash, e);
+            }
+            Ok(credited)
+        }
+        Err(reason) => {
+            depositaccounts::credit_balance(investor_id, token, amount);
+            Err(reason)
+        }
+    };
+
+    auditlog::record(
+        AuditAction::DepositCredited,
+        format!("deposit:{}", hash),
+        None,
+        Some(format!(
+            "{} {} from investor {} for farm {}: {}",
+            tokens::format_units(amount, token.decimals()),
+            token.symbol(),
+            investor_id,
+            farm_id,
+            match &invested {
+                Ok(credited) => format!("{} invested", tokens::format_units(*credited, token.decimals())),
+                Err(reason) => format!("held as deposit balance ({})", reason),
+            }
+        )),
+    );
+    invested
+}
+
+/**
+* Function: recheck_pending_deposits
+* Description: Verifies every pending deposit again, crediting those that now have enough confirmations. Deposits
+* pending for longer than a day are no longer checked; investors can verify them again themselves.
+* @param None
+* @return None
+*/
+pub async fn recheck_pending_deposits() {
+    let now = ic_cdk::api::time();
+    let due: Vec<PendingDeposit> = PENDING_DEPOSITS.with(|pending| {
+        pending
+            .borrow()
+            .iter()
+            .map(|(_, deposit)| deposit)
+            .filter(|deposit| now.saturating_sub(deposit.first_seen_at) < PENDING_DEPOSIT_TTL)
+            .collect()
+    });
+
+    for deposit in due {
+        let hash = deposit.transaction_hash.clone();
+        let result = match deposit.token {
+            Token::CkETH => {
+                ck_eth_payments::credit_cketh_deposit(hash.clone(), deposit.farm_id, deposit.investor_id, deposit.depositor).await
+            }
+            Token::CkUSDC => {
+                ckusdc_payments::credit_usdc_deposit(hash.clone(), deposit.farm_id, deposit.investor_id, deposit.depositor).await
+            }
+            Token::IFarm => Err(Error::Error {
+                msg: "iFarm deposits are not verified on chain".to_string(),
+            }),
+        };
+
+        match result {
+            Ok(DepositVerification::Credited(_))
+            | Ok(DepositVerification::HeldAsBalance { .. })
+            | Ok(DepositVerification::Pending { .. })
+            | Ok(DepositVerification::Minting) => {}
+            // Credited by another call since it was queued
+            Err(Error::AlreadyProcessed { .. }) => clear_pending_deposit(&hash),
+            Err(e) => {
+                ic_cdk::println!("Pending deposit {}: {}", hash, e);
+                if let Ok(key) = parse_tx_hash(&hash) {
+                    PENDING_DEPOSITS.with(|pending| {
+                        let mut pending = pending.borrow_mut();
+                        if let Some(mut deposit) = pending.get(&key) {
+                            deposit.last_checked_at = ic_cdk::api::time();
+                            deposit.last_error = Some(e.to_string());
+                            pending.insert(key, deposit);
+                        }
+                    });
+                }
+            }
+        }
+    }
+}
+
+/**
+* Function: start_pending_deposit_checks
+* Description: Arms the timer that re-checks deposits waiting for confirmations. Called from both `init` and
+* `post_upgrade`.
+* @param None
+* @return None
+*/
+pub fn start_pending_deposit_checks() {
+    ic_cdk_timers::set_timer_interval(PENDING_DEPOSIT_INTERVAL, || ic_cdk::spawn(recheck_pending_deposits()));
+}
+
+/**
+* Function: get_pending_deposits
+* Description: Returns an investor's deposits that are waiting for block confirmations.
+* @param investor_id: u64 - The ID of the investor
+* @return Vec<PendingDeposit> - The investor's pending deposits
+*/
+#[query]
+pub fn get_pending_deposits(investor_id: u64) -> Vec<PendingDeposit> {
+    PENDING_DEPOSITS.with(|pending| {
+        pending
+            .borrow()
+            .iter()
+            .map(|(_, deposit)| deposit)
+            .filter(|deposit| deposit.investor_id == investor_id)
+            .collect()
+    })
+}
+
+/**
+* Function: get_processed_deposit
+* Description: Looks up the investment a deposit's transaction hash was credited to.
+* @param hash: String - The transaction hash of the deposit
+* @return Result<Option<ProcessedDeposit>, Error> - The credited deposit, None if it has not been credited,
+* or an error if the hash is malformed
+*/
+#[query]
+pub fn get_processed_deposit(hash: String) -> Result<Option<ProcessedDeposit>, Error> {
+    let key = parse_tx_hash(&hash)?;
+    Ok(PROCESSED_DEPOSITS.with(|deposits| deposits.borrow().get(&key)))
+}
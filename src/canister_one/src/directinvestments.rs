@@ -0,0 +1,102 @@
+use candid::Nat;
+use ic_cdk::update;
+
+use crate::auditlog::{self, AuditAction};
+use crate::ck_eth_payments;
+use crate::ckusdc_payments;
+use crate::depositaccounts;
+use crate::entitymanagement::{self, Error, Success};
+use crate::fundingrounds;
+use crate::tokens::{self, Token};
+use crate::transaction_fees;
+
+/**
+* Function: collect_investment
+* Description: Pulls an investment from the investor's account into the canister using ICRC-2 transfer_from.
+* @param token: Token - The ledger to pull from
+* @param amount: u128 - The amount to pull, in the token's base units
+* @return Result<Nat, Error> - The ledger block index of the transfer, or an error
+*/
+async fn collect_investment(token: Token, amount: u128) -> Result<Nat, Error> {
+    let investor = ic_cdk::caller();
+    let canister = ic_cdk::id();
+    let result = match token {
+        Token::CkUSDC => ckusdc_payments::ckusdc_transfer_from(investor, canister, Nat::from(amount)).await,
+        Token::CkETH => ck_eth_payments::cketh_transfer_from(investor, canister, Nat::from(amount)).await,
+        Token::IFarm => {
+            return Err(Error::Error {
+                msg: "iFarm tokens cannot be invested".to_string(),
+            })
+        }
+    };
+
+    match result {
+        Ok(Ok(block_index)) => Ok(block_index),
+        Ok(Err(e)) => Err(Error::TransferFailed {
+            msg: format!("Ledger rejected the investment: {:?}", e),
+        }),
+        Err(e) => Err(Error::TransferFailed { msg: e }),
+    }
+}
+
+/**
+* Function: invest
+* Description: Invests in a farm's funding round straight from the caller's account on the IC. The caller must first
+* approve this canister on the token's ledger. The platform fee is deducted and the rest is recorded against the
+* round; if the round closed while the funds were being pulled, all of them are kept as the caller's deposit balance.
+* @param farm_id: u64 - The ID of the farm
+* @param token: Token - The token to invest
+* @param amount: u128 - The amount to invest, in the token's base units
+* @return Result<Success, Error> - Success message with the amount accepted, or an error otherwise
+*/
+#[update]
+pub async fn invest(farm_id: u64, token: Token, amount: u128) -> Result<Success, Error> {
+    let investor_id = entitymanagement::caller_investor_id()?;
+
+    if amount == 0 {
+        return Err(Error::FieldEmpty {
+            msg: "Investment amount must be greater than zero".to_string(),
+        });
+    }
+
+    // Check the round can take the investment before any funds are moved
+    fundingrounds::open_round(farm_id, token).map_err(|msg| Error::Error { msg })?;
+
+    let block_index = collect_investment(token, amount).await?;
+
+    // Referenced by the ledger block, which is unique per token
+    let reference = format!("{}:{}", token.symbol(), block_index);
+    auditlog::record(
+        AuditAction::DepositCredited,
+        format!("deposit:{}", reference),
+        None,
+        Some(format!(
+            "{} {} pulled from investor {} for farm {}",
+            tokens::format_units(amount, token.decimals()),
+            token.symbol(),
+            investor_id,
+            farm_id
+        )),
+    );
+    // The fee is only taken from an investment the round accepts
+    let fee = tokens::investment_fee(amount);
+    match fundingrounds::commit_investment(farm_id, investor_id, amount - fee, reference.clone(), token).await {
+        Ok(accepted) => {
+            transaction_fees::store_transaction_fee(reference, fee, token).map_err(|msg| Error::Error { msg })?;
+            Ok(Success::InvestmentRecorded {
+                msg: format!(
+                    "Invested {} {} into farm {}",
+                    tokens::format_units(accepted, token.decimals()),
+                    token.symbol(),
+                    farm_id
+                ),
+            })
+        }
+        Err(msg) => {
+            depositaccounts::credit_balance(investor_id, token, amount);
+            Err(Error::Error {
+                msg: format!("{}; the funds were added to your deposit balance", msg),
+            })
+        }
+    }
+}
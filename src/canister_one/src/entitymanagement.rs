@@ -5,9 +5,12 @@ use serde::{Deserialize, Serialize}; //serializing and deserializing Rust data s
                                      // use std::cell::Ref;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory}; //stable memory management
 use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, StableBTreeMap }; //defining and working with stable data structures
+use crate::unboundedmap::UnboundedMap;
+use crate::idallocator::{self, IdNamespace};
+use crate::accesscontrol::principal_key;
+use ic_stable_structures::storable::Blob;
 use std::{borrow::Cow, cell::RefCell}; //interior mutability with runtime borrow checking
                                        // use std::collections::BTreeMap;
-use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
 use std::fmt;
 
@@ -22,9 +25,6 @@ use std::fmt;
 pub type Memory = VirtualMemory<DefaultMemoryImpl>;
 // type IdCell = Cell<u64, Memory>;
 
-// A global atomic counter to ensure uniqueness
-static COUNTER: AtomicU64 = AtomicU64::new(0);
-
 /**
 * Farmer Struct
 * Represents a Farmer and their associated details.
@@ -166,7 +166,7 @@ impl TryFrom<String> for BoundedString {
  * Default Implementation for Entity Type [Constructor]
  * Provides a default implementation for the EntityType struct.
 **/
-#[derive(CandidType, Deserialize, Serialize)]
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
 pub enum EntityType {
     Farmer,
     Investor,
@@ -175,6 +175,33 @@ pub enum EntityType {
     NotRegistered,
 }
 
+/**
+* PrincipalEntity Struct
+* The entity a principal registered as, so the caller's role is found without scanning every entity map.
+* @param Defined In-Line
+* @return PrincipalEntity instance with the specified fields.
+*/
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug)]
+pub struct PrincipalEntity {
+    pub entity_type: EntityType,
+    pub id: u64,
+}
+
+impl Storable for PrincipalEntity {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PrincipalEntity {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
 /**
  * Default Implementation for Entity Details [Constructor]
  * For returning details of the specific user.
@@ -584,56 +611,6 @@ impl Storable for Order {
     }
 }
 
-/**
-* Implementation of BoundedStorable for Farmer
-* Specifies the storage constraints for the Farmer struct when used in a stable data structure.
-* Provides information on the maximum size and whether the size is fixed.
-*/
-impl BoundedStorable for Farmer {
-    const MAX_SIZE: u32 = 1024; //maximum size (in bytes) that a Farmer instance can occupy.
-    const IS_FIXED_SIZE: bool = false; // State of whether the size of a Farmer Struct is fixed.
-}
-
-/**
-* Implementation of BoundedStorable for Investor
-* Specifies the storage constraints for the Investor struct when used in a stable data structure.
-* Provides information on the maximum size and whether the size is fixed.
-*/
-impl BoundedStorable for Investor {
-    const MAX_SIZE: u32 = 1024; // State of whether the size of an Investor Struct is fixed.
-    const IS_FIXED_SIZE: bool = false; //maximum size (in bytes) that an Investor instance can occupy.
-}
-
-/**
-* Implementation of BoundedStorable for SupplyAgriBusiness
-* Specifies the storage constraints for the SupplyAgriBusiness struct when used in a stable data structure.
-* Provides information on the maximum size and whether the size is fixed.
-*/
-impl BoundedStorable for SupplyAgriBusiness {
-    const MAX_SIZE: u32 = 1024; // State of whether the size of a FaSupplyAgriBusinessrmer Struct is fixed.
-    const IS_FIXED_SIZE: bool = false; //maximum size (in bytes) that a SupplyAgriBusiness instance can occupy.
-}
-
-/**
-* Implementation of BoundedStorable for FarmsAgriBusiness
-* Specifies the storage constraints for the FarmsAgriBusiness struct when used in a stable data structure.
-* Provides information on the maximum size and whether the size is fixed.
-*/
-impl BoundedStorable for FarmsAgriBusiness {
-    const MAX_SIZE: u32 = 1024; // State of whether the size of a FarmsAgriBusiness Struct is fixed.
-    const IS_FIXED_SIZE: bool = false; //maximum size (in bytes) that a FarmsAgriBusiness instance can occupy.
-}
-
-/**
-* Implementation of BoundedStorable for Order
-* Specifies the storage constraints for the Order struct when used in a stable data structure.
-* Provides information on the maximum size and whether the size is fixed.
-*/
-impl BoundedStorable for Order {
-    const MAX_SIZE: u32 = 1024; // State of whether the size of an Order Struct is fixed.
-    const IS_FIXED_SIZE: bool = false; //maximum size (in bytes) that an Order instance can occupy.
-}
-
 impl BoundedStorable for BoundedString {
     const MAX_SIZE: u32 = 1024; 
     const IS_FIXED_SIZE: bool = false;
@@ -660,87 +637,81 @@ thread_local! {
 
     /**
     * FARMER_STORAGE
-    * Stores Farmer instances in a `StableBTreeMap` using memory managed by the MEMORY_MANAGER.
+    * Stores Farmer instances in an `UnboundedMap` using memory managed by the MEMORY_MANAGER.
     * Uses `RefCell` to allow mutable access.
     * @param None
-    * @return A thread-local `RefCell` containing the `StableBTreeMap` for the Farmer instances.
+    * @return A thread-local `RefCell` containing the `UnboundedMap` for the Farmer instances.
     */
-    pub static FARMER_STORAGE: RefCell<StableBTreeMap<u64, Farmer, Memory>> =
-    RefCell::new(StableBTreeMap::init(
-        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+    pub static FARMER_STORAGE: RefCell<UnboundedMap<Farmer>> =
+    RefCell::new(UnboundedMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41)))
     ));
 
     /**
     * INVESTOR_STORAGE
-    * Stores Investor instances in a `StableBTreeMap` using memory managed by the MEMORY_MANAGER.
+    * Stores Investor instances in an `UnboundedMap` using memory managed by the MEMORY_MANAGER.
     * Uses `RefCell` to allow mutable access.
     * @param None
-    * @return A thread-local `RefCell` containing the `StableBTreeMap` for the Investor instances.
+    * @return A thread-local `RefCell` containing the `UnboundedMap` for the Investor instances.
     */
-    pub static INVESTOR_STORAGE: RefCell<StableBTreeMap<u64, Investor, Memory>> =
-    RefCell::new(StableBTreeMap::init(
-        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+    pub static INVESTOR_STORAGE: RefCell<UnboundedMap<Investor>> =
+    RefCell::new(UnboundedMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42)))
     ));
 
      /**
     * SUPPLY_AGRIBUSINESS_STORAGE
-    * Stores SupplyAgriBusiness instances in a `StableBTreeMap` using memory managed by the MEMORY_MANAGER.
+    * Stores SupplyAgriBusiness instances in an `UnboundedMap` using memory managed by the MEMORY_MANAGER.
     * Uses `RefCell` to allow mutable access.
     * @param None
-    * @return A thread-local `RefCell` containing the `StableBTreeMap` for the SupplyAgriBusiness instances.
+    * @return A thread-local `RefCell` containing the `UnboundedMap` for the SupplyAgriBusiness instances.
     */
-    pub static SUPPLY_AGRIBUSINESS_STORAGE: RefCell<StableBTreeMap<u64, SupplyAgriBusiness, Memory>> =
-    RefCell::new(StableBTreeMap::init(
-        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+    pub static SUPPLY_AGRIBUSINESS_STORAGE: RefCell<UnboundedMap<SupplyAgriBusiness>> =
+    RefCell::new(UnboundedMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43)))
     ));
 
     /**
     * FARMS_AGRIBUSINESS_STORAGE
-    * Stores FarmsAgriBusiness instances in a `StableBTreeMap` using memory managed by the MEMORY_MANAGER.
+    * Stores FarmsAgriBusiness instances in an `UnboundedMap` using memory managed by the MEMORY_MANAGER.
     * Uses `RefCell` to allow mutable access.
     *@param None
-    *@return A thread-local `RefCell` containing the `StableBTreeMap` for the FarmsAgriBusiness instances.
+    *@return A thread-local `RefCell` containing the `UnboundedMap` for the FarmsAgriBusiness instances.
     */
-    pub static FARMS_AGRIBUSINESS_STORAGE: RefCell<StableBTreeMap<u64, FarmsAgriBusiness, Memory>> =
-    RefCell::new(StableBTreeMap::init(
-        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+    pub static FARMS_AGRIBUSINESS_STORAGE: RefCell<UnboundedMap<FarmsAgriBusiness>> =
+    RefCell::new(UnboundedMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44)))
     ));
 
     /**
     * FARMS_FOR_AGRIBUSINESS_STORAGE
-    * Stores Farmer instances related to an agribusiness in a `StableBTreeMap` using memory managed by  the MEMORY_MANAGER.
+    * Stores Farmer instances related to an agribusiness in an `UnboundedMap` using memory managed by  the MEMORY_MANAGER.
     * Uses `RefCell` to allow mutable access.
     * @param None
-    * @return A thread-local `RefCell` containing the `StableBTreeMap` for Farmer instances related to agribusiness.
+    * @return A thread-local `RefCell` containing the `UnboundedMap` for Farmer instances related to agribusiness.
     */
-    pub static FARMS_FOR_AGRIBUSINESS_STORAGE: RefCell<StableBTreeMap<u64, Farmer, Memory>> =
-    RefCell::new(StableBTreeMap::init(
-        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+    pub static FARMS_FOR_AGRIBUSINESS_STORAGE: RefCell<UnboundedMap<Farmer>> =
+    RefCell::new(UnboundedMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45)))
     ));
 
     /**
     * ORDER_STORAGE
-    * Stores Order instances in a `StableBTreeMap` using memory managed by the MEMORY_MANAGER.
+    * Stores Order instances in an `UnboundedMap` using memory managed by the MEMORY_MANAGER.
     * Uses `RefCell` to allow mutable access.
     *@param None
-    *@return A thread-local `RefCell` containing the `StableBTreeMap` for the Order instances.
+    *@return A thread-local `RefCell` containing the `UnboundedMap` for the Order instances.
     */
-    pub static ORDER_STORAGE: RefCell<StableBTreeMap<u64, Order, Memory>> =
-    RefCell::new(StableBTreeMap::init(
-        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+    pub static ORDER_STORAGE: RefCell<UnboundedMap<Order>> =
+    RefCell::new(UnboundedMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46)))
     ));
 
-    //Stores the current Farmer ID.
-    pub static FARMER_ID: RefCell<u64> = RefCell::new(0);
-
-    //Stores the current Investor ID.
-    static INVESTOR_ID: RefCell<u64> = RefCell::new(1);
-
-    //Stores the current Supply Agribusiness ID.
-    static SUPPLY_AGRIBUSINESS_ID: RefCell<u64> = RefCell::new(2);
-
-    //Stores the current Farms Agribusiness ID.
-    static FARMS_AGRIBUSINESS_ID: RefCell<u64> = RefCell::new(3);
+    // Entity each registered principal belongs to
+    static PRINCIPAL_INDEX: RefCell<StableBTreeMap<Blob<29>, PrincipalEntity, Memory>> =
+    RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53)))
+    ));
 
    // Storing files
    pub static FILE_STORAGE: RefCell<StableBTreeMap<BoundedString, BoundedBytes, Memory>> =
@@ -777,6 +748,13 @@ pub enum Success {
     ReportDeletedSuccessfully { msg: String }, 
     FileUploaded { msg: String },
     FarmCreatedSuccessfully { msg: String },
+    LoanCancelled { msg: String },
+    LoanSweepCompleted { msg: String },
+    PricingConfigUpdated { msg: String },
+    InvestmentRecorded { msg: String },
+    EvmRpcConfigUpdated { msg: String },
+    TreasuryUpdated { msg: String },
+    RolesUpdated { msg: String },
 }
 
 // Error Messages
@@ -797,7 +775,13 @@ pub enum Error {
     ErrorOccured { msg: String },
     Error { msg: String },
     FileNotFound { msg: String },
-    UploadFailed { msg: String }
+    UploadFailed { msg: String },
+    LoanNotFound { msg: String },
+    InvalidLoanTransition { msg: String },
+    TransferFailed { msg: String },
+    RefundNotFound { msg: String },
+    AlreadyProcessed { msg: String },
+    IdAlreadyInUse { msg: String },
 }
 
 impl fmt::Display for Error {
@@ -819,6 +803,12 @@ impl fmt::Display for Error {
             Error::Error { msg } => write!(f, "{}", msg),
             Error::FileNotFound { msg } => write!(f, "{}", msg),
             Error::UploadFailed { msg } => write!(f, "{}", msg),
+            Error::LoanNotFound { msg } => write!(f, "{}", msg),
+            Error::InvalidLoanTransition { msg } => write!(f, "{}", msg),
+            Error::TransferFailed { msg } => write!(f, "{}", msg),
+            Error::RefundNotFound { msg } => write!(f, "{}", msg),
+            Error::AlreadyProcessed { msg } => write!(f, "{}", msg),
+            Error::IdAlreadyInUse { msg } => write!(f, "{}", msg),
         }
     }
 }
@@ -868,7 +858,7 @@ pub fn register_farm(new_farmer: NewFarmer) -> Result<Success, Error> {
     _is_principal_id_registered(new_farmer_principal_id)?;
 
     // Generate a unique farmer ID
-    let id = _generate_unique_id();
+    let id = idallocator::next_id(IdNamespace::Farmer);
 
     // Create a new farmer instance
     let farmer = Farmer {
@@ -903,7 +893,10 @@ pub fn register_farm(new_farmer: NewFarmer) -> Result<Success, Error> {
     };
 
 
-    FARMER_STORAGE.with(|farmers| farmers.borrow_mut().insert(id, farmer));
+    if !FARMER_STORAGE.with(|farmers| farmers.borrow_mut().insert_new(id, farmer)) {
+        return Err(id_in_use("Farmer", id));
+    }
+    index_principal(new_farmer_principal_id, EntityType::Farmer, id);
 
     Ok(Success::FarmCreatedSuccesfully {
         msg: format!("Farm has been created successfully"),
@@ -977,26 +970,11 @@ pub fn delete_tag(farmer_id: u64, tag: String) -> Result<(), String> {
     })
 }
 
-/**
-* Function: _increament_id
-* Description: Increments the provided ID and returns the new value.
-* @param id: &RefCell<u64> - Reference to the ID to be incremented.
-* @return u64 - New incremented ID value
-*/
-pub fn _increament_id(id: &RefCell<u64>) -> u64 {
-    // is this more secure, I'd suggest randomization.
-    let mut id_borrowed = id.borrow_mut();
-    let new_id = *id_borrowed + 1;
-    *id_borrowed = new_id;
-    new_id
-}
-
-// Function: _generate_unique_id
-// Description: Generates a new unique ID using a counter.
-// @return u64 - New unique ID value
-pub fn _generate_unique_id() -> u64 {
-    // Increment the counter atomically
-    COUNTER.fetch_add(1, Ordering::SeqCst)
+// Error for a freshly allocated id that a stored record already has
+pub fn id_in_use(kind: &str, id: u64) -> Error {
+    Error::IdAlreadyInUse {
+        msg: format!("{} ID {} is already in use", kind, id),
+    }
 }
 
 /**
@@ -1006,49 +984,14 @@ pub fn _generate_unique_id() -> u64 {
 * @return Result<(), Error> - Returns Ok(()) if ID is not registered, otherwise returns an Error with a message
 */
 pub fn _is_principal_id_registered(new_principal_id: Principal) -> Result<(), Error> {
-    let mut is_principal_id_registered = false;
-
-    // Check if the principal ID is already registered in farmers
-    FARMER_STORAGE.with(|farmers| {
-        for (_, farmer) in farmers.borrow().iter() {
-            if farmer.principal_id == new_principal_id {
-                is_principal_id_registered = true;
-                break;
-            }
-        }
-    });    
-
-    // Check if the principal ID is already registered in investors
-    INVESTOR_STORAGE.with(|investors| {
-        for (_, investor)  in investors.borrow().iter() {
-            if investor.principal_id == new_principal_id {
-                is_principal_id_registered = true;
-                break;
-            }
-        }
-    });
-
-    // Check if the principal ID is already registered in supply agribusiness
-    FARMS_AGRIBUSINESS_STORAGE.with(|agribusiness| {
-        for (_, agribiz)  in agribusiness.borrow().iter() {
-            if agribiz.principal_id == new_principal_id {
-                is_principal_id_registered = true;
-                break;
-            }
-        }
-    });
-
-    // Check if the principal ID is already registered in farms agribusiness
-    SUPPLY_AGRIBUSINESS_STORAGE.with(|agribusiness| {
-        for (_, agribiz)  in agribusiness.borrow().iter() {
-            if agribiz.principal_id == new_principal_id {
-                is_principal_id_registered = true;
-                break;
-            }
-        }
-    });
+    // Farms added by agribusinesses carry the anonymous principal, so it can never register itself
+    if new_principal_id == Principal::anonymous() {
+        return Err(Error::NotAuthorized {
+            msg: "Log in before registering".to_string(),
+        });
+    }
 
-    if is_principal_id_registered {
+    if registered_entity(&new_principal_id).is_some() {
         return Err(Error::PrincipalIdAlreadyRegistered {
             msg: format!(
                 "The principal id {} has already been registered!",
@@ -1060,6 +1003,49 @@ pub fn _is_principal_id_registered(new_principal_id: Principal) -> Result<(), Er
     Ok(())
 }
 
+/**
+* Function: registered_entity
+* Description: Looks up the entity a principal registered as.
+* @param principal_id: &Principal - The principal to look up
+* @return Option<PrincipalEntity> - The entity type and ID, or None if the principal is not registered
+*/
+pub fn registered_entity(principal_id: &Principal) -> Option<PrincipalEntity> {
+    PRINCIPAL_INDEX.with(|index| index.borrow().get(&principal_key(principal_id)))
+}
+
+/**
+* Function: index_principal
+* Description: Records the entity a principal registered as. The anonymous principal is never indexed.
+* @param principal_id: Principal - The principal that registered
+* @param entity_type: EntityType - What they registered as
+* @param id: u64 - The ID of the entity
+* @return None
+*/
+pub fn index_principal(principal_id: Principal, entity_type: EntityType, id: u64) {
+    if principal_id == Principal::anonymous() {
+        return;
+    }
+    PRINCIPAL_INDEX.with(|index| {
+        index
+            .borrow_mut()
+            .insert(principal_key(&principal_id), PrincipalEntity { entity_type, id })
+    });
+}
+
+// Drops a principal from the index when the entity it points at is deleted
+pub fn unindex_principal(principal_id: &Principal, entity_type: EntityType, id: u64) {
+    let key = principal_key(principal_id);
+    PRINCIPAL_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if index
+            .get(&key)
+            .is_some_and(|entity| entity.entity_type == entity_type && entity.id == id)
+        {
+            index.remove(&key);
+        }
+    });
+}
+
 /**
 * Function: register_investor
 * Description: Registers a new investor with the specified details.
@@ -1082,7 +1068,7 @@ pub fn register_investor(new_investor: NewInvestor) -> Result<Success, Error> {
     }
 
     // Increamenting the ID
-    let id = INVESTOR_ID.with(|id| _increament_id(id));
+    let id = idallocator::next_id(IdNamespace::Investor);
 
     let investor = Investor {
         id,
@@ -1096,7 +1082,10 @@ pub fn register_investor(new_investor: NewInvestor) -> Result<Success, Error> {
 
     let investor_clone2 = investor.clone();
 
-    INVESTOR_STORAGE.with(|investors| investors.borrow_mut().insert(id, investor_clone2));
+    if !INVESTOR_STORAGE.with(|investors| investors.borrow_mut().insert_new(id, investor_clone2)) {
+        return Err(id_in_use("Investor", id));
+    }
+    index_principal(new_investor_principal_id, EntityType::Investor, id);
 
 
     Ok(Success::InvestorRegisteredSuccesfully {
@@ -1129,10 +1118,10 @@ pub fn register_supply_agribusiness(
     }
 
     // Increamenting the supply agribusiness ID
-    let id = SUPPLY_AGRIBUSINESS_ID.with(|id| _increament_id(id));
+    let id = idallocator::next_id(IdNamespace::SupplyAgriBusiness);
 
     let supply_agri_business = SupplyAgriBusiness {
-        id: 0,
+        id,
         agribusiness_name: new_supply_agribusiness.agribusiness_name,
         items_to_be_supplied: new_supply_agribusiness.items_to_be_supplied,
         orders: Vec::new(),
@@ -1145,11 +1134,15 @@ pub fn register_supply_agribusiness(
 
     let supply_agri_business_clone2 = supply_agri_business.clone();
 
-    SUPPLY_AGRIBUSINESS_STORAGE.with(|supplyagribusiness| {
+    let inserted = SUPPLY_AGRIBUSINESS_STORAGE.with(|supplyagribusiness| {
         supplyagribusiness
             .borrow_mut()
-            .insert(id, supply_agri_business_clone2)
+            .insert_new(id, supply_agri_business_clone2)
     });
+    if !inserted {
+        return Err(id_in_use("Supply agribusiness", id));
+    }
+    index_principal(new_supply_agribusiness_principal_id, EntityType::SupplyAgriBusiness, id);
 
     Ok(Success::SupplyAgriBizRegisteredSuccesfully {
         msg: format!("Supply Agri Business has been registered succesfully"),
@@ -1181,7 +1174,7 @@ pub fn register_farms_agribusiness(
     }
 
     // Increamenting the ID
-    let id = FARMS_AGRIBUSINESS_ID.with(|id| _increament_id(id));
+    let id = idallocator::next_id(IdNamespace::FarmsAgriBusiness);
 
     let farms_agri_business = FarmsAgriBusiness {
         id,
@@ -1196,11 +1189,15 @@ pub fn register_farms_agribusiness(
 
     let farms_agri_business_clone2 = farms_agri_business.clone();
 
-    FARMS_AGRIBUSINESS_STORAGE.with(|supplyagribusiness| {
+    let inserted = FARMS_AGRIBUSINESS_STORAGE.with(|supplyagribusiness| {
         supplyagribusiness
             .borrow_mut()
-            .insert(id, farms_agri_business_clone2)
+            .insert_new(id, farms_agri_business_clone2)
     });
+    if !inserted {
+        return Err(id_in_use("Farms agribusiness", id));
+    }
+    index_principal(new_farms_agribusiness_principal_id, EntityType::FarmsAgriBusiness, id);
 
     Ok(Success::SupplyAgriBizRegisteredSuccesfully {
         msg: format!("Supply Agri Business has been registered succesfully"),
@@ -1388,175 +1385,70 @@ pub fn update_farms_agribusiness_name(
 pub fn log_in() -> Result<Success, Error> {
     let principal_id = ic_cdk::caller();
 
-    let result = FARMER_STORAGE.with(|farmers| {
-        for (_, farmer) in farmers.borrow().iter() {
-            if farmer.principal_id == principal_id {
-                return Ok(Success::FarmerLogInSuccesfull {
-                    msg: format!("You've logged in as a farmer successfully"),
-                });
-            }
-        }
-        Err(Error::YouAreNotRegistered {
-            msg: format!("You are not registered!"),
-        })
-    });
-
-    if let Ok(res) = result {
-        return Ok(res);
-    }
-
-    let result = INVESTOR_STORAGE.with(|investors| {
-        for investor in investors.borrow().iter().map(|(_, v)| v) {
-            if investor.principal_id == principal_id {
-                return Ok(Success::InvestorLogInSuccesfull {
-                    msg: format!("You've logged in as an Investor succesfully"),
-                });
-            }
-        }
-        Err(Error::YouAreNotRegistered {
-            msg: format!("You are not registered!"),
-        })
-    });
-
-    if let Ok(res) = result {
-        return Ok(res);
-    }
-
-    let result = SUPPLY_AGRIBUSINESS_STORAGE.with(|agribusiness| {
-        for agribiz in agribusiness.borrow().iter().map(|(_, v)| v) {
-            if agribiz.principal_id == principal_id {
-                return Ok(Success::SupplyAgriBizRegisteredSuccesfully {
-                    msg: format!("You've logged in as an Investor succesfully"),
-                });
-            }
-        }
-        Err(Error::YouAreNotRegistered {
+    match registered_entity(&principal_id).map(|entity| entity.entity_type) {
+        Some(EntityType::Farmer) => Ok(Success::FarmerLogInSuccesfull {
+            msg: format!("You've logged in as a farmer successfully"),
+        }),
+        Some(EntityType::Investor) => Ok(Success::InvestorLogInSuccesfull {
+            msg: format!("You've logged in as an Investor succesfully"),
+        }),
+        Some(EntityType::SupplyAgriBusiness) => Ok(Success::SupplyAgriBizRegisteredSuccesfully {
+            msg: format!("You've logged in as an Investor succesfully"),
+        }),
+        Some(EntityType::FarmsAgriBusiness) => Ok(Success::FarmsAgriBizRegisteredSuccesfully {
+            msg: format!("You've logged in as an Investor succesfully"),
+        }),
+        _ => Err(Error::YouAreNotRegistered {
             msg: format!("You are not registered!"),
-        })
-    });
-
-    if let Ok(res) = result {
-        return Ok(res);
+        }),
     }
-
-    let result = FARMS_AGRIBUSINESS_STORAGE.with(|agribusiness| {
-        for agribiz in agribusiness.borrow().iter().map(|(_, v)| v) {
-            if agribiz.principal_id == principal_id {
-                return Ok(Success::FarmsAgriBizRegisteredSuccesfully {
-                    msg: format!("You've logged in as an Investor succesfully"),
-                });
-            }
-        }
-        Err(Error::YouAreNotRegistered {
-            msg: format!("You are not registered!"),
-        })
-    });
-
-    result
 }
 
 #[query]
 pub fn check_entity_type() -> EntityType {
-    let principal_id = ic_cdk::caller();
-
-    // Check if the principal ID is registered as a farmer
-    if FARMER_STORAGE.with(|farmers| {
-        farmers
-            .borrow()
-            .iter()
-            .any(|(_, farmer)| farmer.principal_id == principal_id)
-    }) {
-        return EntityType::Farmer;
-    }    
-
-    // Check if the principal ID is registered as an investor
-    if INVESTOR_STORAGE.with(|investors| {
-        investors
-            .borrow()
-            .iter()
-            .map(|(_, v)| v)
-            .any(|investor| investor.principal_id == principal_id)
-    }) {
-        return EntityType::Investor;
-    }    
-
-    // Check if the principal ID is registered as a supply agribusiness
-    if SUPPLY_AGRIBUSINESS_STORAGE.with(|agribusiness| {
-        agribusiness
-            .borrow()
-            .iter()
-            .map(|(_, v)| v)
-            .any(|agribiz| agribiz.principal_id == principal_id)
-    }) {
-        return EntityType::SupplyAgriBusiness;
-    }
-
-    // Check if the principal ID is registered as a farms agribusiness
-    if FARMS_AGRIBUSINESS_STORAGE.with(|agribusiness| {
-        agribusiness
-            .borrow()
-            .iter()
-            .map(|(_, v)| v)
-            .any(|agribiz| agribiz.principal_id == principal_id)
-    }) {
-        return EntityType::FarmsAgriBusiness;
-    }
+    registered_entity(&ic_cdk::caller())
+        .map(|entity| entity.entity_type)
+        .unwrap_or(EntityType::NotRegistered)
+}
 
-    // If not registered in any category
-    EntityType::NotRegistered
+/**
+* Function: caller_investor_id
+* Description: Resolves the investor registered under the caller's principal ID.
+* @param None
+* @return Result<u64, Error> - The caller's investor ID, or an error if the caller is not a registered investor
+*/
+pub fn caller_investor_id() -> Result<u64, Error> {
+    registered_entity(&ic_cdk::caller())
+        .filter(|entity| entity.entity_type == EntityType::Investor)
+        .map(|entity| entity.id)
+        .ok_or_else(|| Error::YouAreNotRegistered {
+            msg: "Only registered investors can make investments".to_string(),
+        })
 }
 
 #[query]
 pub fn get_entity_details() -> EntityDetails {
-    let principal_id = ic_cdk::caller();
-
-    // Check if the principal ID is registered as a farmer
-    if let Some(farmer) =  FARMER_STORAGE.with(|farmers| {
-        farmers
-            .borrow()
-            .iter()
-            .find(|(_, farmer)| farmer.principal_id == principal_id)
-            .map(|(_, farmer)| farmer.clone()) 
-    }) {
-        return EntityDetails::Farmer(farmer);
-    }
-    
-
-    // Check if the principal ID is registered as an investor
-    if let Some(investor) = INVESTOR_STORAGE.with(|investors| {
-        investors
-            .borrow()
-            .iter()
-            .find(|(_, investor)| investor.principal_id == principal_id)
-            .map(|(_, investor)| investor.clone()) 
-    }) {
-        return EntityDetails::Investor(investor);
-    }
-
-    // Check if the principal ID is registered as a supply agribusiness
-    if let Some(agribusiness) = SUPPLY_AGRIBUSINESS_STORAGE.with(|agribusiness| {
-        agribusiness
-            .borrow()
-            .iter()
-            .find(|(_, investor)| investor.principal_id == principal_id)
-            .map(|(_, investor)| investor.clone()) 
-    }) {
-        return EntityDetails::SupplyAgriBusiness(agribusiness);
-    }
+    let Some(entity) = registered_entity(&ic_cdk::caller()) else {
+        return EntityDetails::NotRegistered;
+    };
 
-    // Check if the principal ID is registered as a farms agribusiness
-    if let Some(agribusiness) = FARMS_AGRIBUSINESS_STORAGE.with(|agribusiness| {
-        agribusiness
-            .borrow()
-            .iter()
-            .find(|(_, agribiz)| agribiz.principal_id == principal_id)
-            .map(|(_, agribiz)| agribiz.clone()) 
-    }) {
-        return EntityDetails::FarmsAgriBusiness(agribusiness);
-    }
+    let details = match entity.entity_type {
+        EntityType::Farmer => FARMER_STORAGE
+            .with(|farmers| farmers.borrow().get(&entity.id))
+            .map(EntityDetails::Farmer),
+        EntityType::Investor => INVESTOR_STORAGE
+            .with(|investors| investors.borrow().get(&entity.id))
+            .map(EntityDetails::Investor),
+        EntityType::SupplyAgriBusiness => SUPPLY_AGRIBUSINESS_STORAGE
+            .with(|agribusiness| agribusiness.borrow().get(&entity.id))
+            .map(EntityDetails::SupplyAgriBusiness),
+        EntityType::FarmsAgriBusiness => FARMS_AGRIBUSINESS_STORAGE
+            .with(|agribusiness| agribusiness.borrow().get(&entity.id))
+            .map(EntityDetails::FarmsAgriBusiness),
+        EntityType::NotRegistered => None,
+    };
 
-    // If not registered in any category
-    EntityDetails::NotRegistered
+    details.unwrap_or(EntityDetails::NotRegistered)
 }
 
 /**
@@ -1587,17 +1479,12 @@ pub fn display_specific_farm(farm_id: u64) -> Result<Farmer, Error> {
 */
 #[query]
 pub fn display_specific_investor(principal_id: Principal) -> Result<Investor, Error> {
-    INVESTOR_STORAGE.with(|investors| {
-        let investors = investors.borrow();
-        for (_, investor) in investors.iter() {
-            if investor.principal_id == principal_id {
-                return Ok(investor.clone());
-            }
-        }
-        Err(Error::YouAreNotRegistered {
+    registered_entity(&principal_id)
+        .filter(|entity| entity.entity_type == EntityType::Investor)
+        .and_then(|entity| INVESTOR_STORAGE.with(|investors| investors.borrow().get(&entity.id)))
+        .ok_or_else(|| Error::YouAreNotRegistered {
             msg: format!("No investor found with Principal ID: {}", principal_id),
         })
-    })
 }
 
 /**
@@ -1610,20 +1497,17 @@ pub fn display_specific_investor(principal_id: Principal) -> Result<Investor, Er
 pub fn display_specific_farm_agribusiness(
     principal_id: Principal,
 ) -> Result<FarmsAgriBusiness, Error> {
-    FARMS_AGRIBUSINESS_STORAGE.with(|agribusiness_storage| {
-        let agribusinesses = agribusiness_storage.borrow();
-        for (_, agribusiness) in agribusinesses.iter() {
-            if agribusiness.principal_id == principal_id {
-                return Ok(agribusiness.clone());
-            }
-        }
-        Err(Error::MismatchId {
+    registered_entity(&principal_id)
+        .filter(|entity| entity.entity_type == EntityType::FarmsAgriBusiness)
+        .and_then(|entity| {
+            FARMS_AGRIBUSINESS_STORAGE.with(|agribusiness| agribusiness.borrow().get(&entity.id))
+        })
+        .ok_or_else(|| Error::MismatchId {
             msg: format!(
                 "No farms agribusiness found with principal ID: {}",
                 principal_id
             ),
         })
-    })
 }
 
 #[update]
@@ -1651,22 +1535,23 @@ pub fn update_email(new_email: String) -> Result<Success, Error> {
         });
     }
 
-    let caller = ic_cdk::caller();
-    let entity_type = check_entity_type();
+    let Some(PrincipalEntity { entity_type, id: entity_id }) = registered_entity(&ic_cdk::caller()) else {
+        return Err(Error::NotAuthorized {
+            msg: "Caller is not a recognized entity type.".to_string(),
+        });
+    };
 
     match entity_type {
         EntityType::Farmer => {
             FARMER_STORAGE.with(|storage| {
                 let mut farmers = storage.borrow_mut();
-                for (_, farmer) in farmers.iter() {
-                    if farmer.principal_id == caller {
-                        let mut farmer = farmer.clone();
-                        farmer.email = Some(new_email.clone());
-                        farmers.insert(farmer.id, farmer);
-                        return Ok(Success::FileUploaded {
-                            msg: "Email updated successfully!".to_string(),
-                        });
-                    }
+                let found = farmers.get(&entity_id);
+                if let Some(mut farmer) = found {
+                    farmer.email = Some(new_email.clone());
+                    farmers.insert(farmer.id, farmer);
+                    return Ok(Success::FileUploaded {
+                        msg: "Email updated successfully!".to_string(),
+                    });
                 }
                 Err(Error::NotAuthorized {
                     msg: "Caller is not a registered farmer.".to_string(),
@@ -1676,15 +1561,13 @@ pub fn update_email(new_email: String) -> Result<Success, Error> {
         EntityType::Investor => {
             INVESTOR_STORAGE.with(|storage| {
                 let mut investors = storage.borrow_mut();
-                for (_, investor) in investors.iter() {
-                    if investor.principal_id == caller {
-                        let mut investor = investor.clone();
-                        investor.email = Some(new_email.clone());
-                        investors.insert(investor.id, investor);
-                        return Ok(Success::FileUploaded {
-                            msg: "Email updated successfully!".to_string(),
-                        });
-                    }
+                let found = investors.get(&entity_id);
+                if let Some(mut investor) = found {
+                    investor.email = Some(new_email.clone());
+                    investors.insert(investor.id, investor);
+                    return Ok(Success::FileUploaded {
+                        msg: "Email updated successfully!".to_string(),
+                    });
                 }
                 Err(Error::NotAuthorized {
                     msg: "Caller is not a registered investor.".to_string(),
@@ -1694,15 +1577,13 @@ pub fn update_email(new_email: String) -> Result<Success, Error> {
         EntityType::SupplyAgriBusiness => {
             SUPPLY_AGRIBUSINESS_STORAGE.with(|storage| {
                 let mut agribusinesses = storage.borrow_mut();
-                for (_, agribusiness) in agribusinesses.iter() {
-                    if agribusiness.principal_id == caller {
-                        let mut agribusiness = agribusiness.clone();
-                        agribusiness.email = Some(new_email.clone());
-                        agribusinesses.insert(agribusiness.id, agribusiness);
-                        return Ok(Success::FileUploaded {
-                            msg: "Email updated successfully!".to_string(),
-                        });
-                    }
+                let found = agribusinesses.get(&entity_id);
+                if let Some(mut agribusiness) = found {
+                    agribusiness.email = Some(new_email.clone());
+                    agribusinesses.insert(agribusiness.id, agribusiness);
+                    return Ok(Success::FileUploaded {
+                        msg: "Email updated successfully!".to_string(),
+                    });
                 }
                 Err(Error::NotAuthorized {
                     msg: "Caller is not a registered supply agribusiness.".to_string(),
@@ -1712,15 +1593,13 @@ pub fn update_email(new_email: String) -> Result<Success, Error> {
         EntityType::FarmsAgriBusiness => {
             FARMS_AGRIBUSINESS_STORAGE.with(|storage| {
                 let mut agribusinesses = storage.borrow_mut();
-                for (_, agribusiness) in agribusinesses.iter() {
-                    if agribusiness.principal_id == caller {
-                        let mut agribusiness = agribusiness.clone();
-                        agribusiness.email = Some(new_email.clone());
-                        agribusinesses.insert(agribusiness.id, agribusiness);
-                        return Ok(Success::FileUploaded {
-                            msg: "Email updated successfully!".to_string(),
-                        });
-                    }
+                let found = agribusinesses.get(&entity_id);
+                if let Some(mut agribusiness) = found {
+                    agribusiness.email = Some(new_email.clone());
+                    agribusinesses.insert(agribusiness.id, agribusiness);
+                    return Ok(Success::FileUploaded {
+                        msg: "Email updated successfully!".to_string(),
+                    });
                 }
                 Err(Error::NotAuthorized {
                     msg: "Caller is not a registered farms agribusiness.".to_string(),
@@ -0,0 +1,256 @@
+use candid::{CandidType, Decode, Encode};
+use evm_rpc_canister_types::{BlockTag, EthMainnetService, EthSepoliaService, RpcService, RpcServices};
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableCell, Storable};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::accesscontrol::caller_is_super_admin;
+use crate::auditlog::{self, AuditAction};
+use crate::ck_eth_payments;
+use crate::ckusdc_payments;
+use crate::entitymanagement::{Error, Memory, Success, MEMORY_MANAGER};
+
+/**
+* EvmChain Enum
+* Ethereum network deposits are verified on.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvmChain {
+    EthMainnet,
+    EthSepolia,
+}
+
+/**
+* EvmProvider Enum
+* JSON-RPC providers the EVM RPC canister can query. Cloudflare only serves mainnet.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvmProvider {
+    Alchemy,
+    Ankr,
+    BlockPi,
+    Cloudflare,
+    PublicNode,
+}
+
+/**
+* ConfirmationBlock Enum
+* Block confirmations are counted up to: the chain head, or the latest block the network considers safe or final.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfirmationBlock {
+    Latest,
+    Safe,
+    Finalized,
+}
+
+/**
+* EvmRpcConfig Struct
+* How Ethereum data is fetched through the EVM RPC canister.
+* @param Defined In-Line
+* @return EvmRpcConfig instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EvmRpcConfig {
+    pub chain: EvmChain,
+    pub providers: Vec<EvmProvider>,
+    pub consensus_threshold: u8, // Number of providers that must return the same result
+    pub cycles: u128,            // Cycles attached to each EVM RPC call
+    pub cketh_helper: String,    // Contract ckETH deposits are sent to on this chain
+    pub ckusdc_helper: String,   // Contract ckUSDC deposits are sent to on this chain
+    pub usdc_contract: String,   // USDC token contract on this chain
+    pub confirmation_block: ConfirmationBlock,
+    pub min_confirmations: u64, // Blocks up to and including the deposit's, counted to `confirmation_block`
+}
+
+impl Default for EvmRpcConfig {
+    fn default() -> Self {
+        Self {
+            chain: EvmChain::EthSepolia,
+            providers: vec![EvmProvider::PublicNode, EvmProvider::BlockPi, EvmProvider::Ankr],
+            consensus_threshold: 3,
+            cycles: 10_000_000_000,
+            cketh_helper: ck_eth_payments::MINTER_ADDRESS.to_string(),
+            ckusdc_helper: ckusdc_payments::USDC_HELPER.to_string(),
+            usdc_contract: ckusdc_payments::USDC_CONTRACT.to_string(),
+            confirmation_block: ConfirmationBlock::Finalized,
+            min_confirmations: 1,
+        }
+    }
+}
+
+impl Storable for EvmRpcConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl EvmRpcConfig {
+    fn validate(&self) -> Result<(), String> {
+        if self.providers.is_empty() {
+            return Err("At least one provider is required".to_string());
+        }
+        for (i, provider) in self.providers.iter().enumerate() {
+            if self.providers[..i].contains(provider) {
+                return Err(format!("Provider {:?} is listed more than once", provider));
+            }
+        }
+        if self.chain == EvmChain::EthSepolia && self.providers.contains(&EvmProvider::Cloudflare) {
+            return Err("Cloudflare does not serve Sepolia".to_string());
+        }
+        if self.consensus_threshold == 0 || self.consensus_threshold as usize > self.providers.len() {
+            return Err(format!(
+                "Consensus threshold must be between 1 and {}",
+                self.providers.len()
+            ));
+        }
+        if self.min_confirmations == 0 {
+            return Err("At least one confirmation is required".to_string());
+        }
+        if self.cycles == 0 {
+            return Err("Cycle budget must be greater than zero".to_string());
+        }
+        for address in [&self.cketh_helper, &self.ckusdc_helper, &self.usdc_contract] {
+            let valid = address.len() == 42
+                && address.starts_with("0x")
+                && address[2..].chars().all(|c| c.is_ascii_hexdigit());
+            if !valid {
+                return Err(format!("Invalid contract address: {}", address));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn confirmation_tag(&self) -> BlockTag {
+        match self.confirmation_block {
+            ConfirmationBlock::Latest => BlockTag::Latest,
+            ConfirmationBlock::Safe => BlockTag::Safe,
+            ConfirmationBlock::Finalized => BlockTag::Finalized,
+        }
+    }
+
+    // Providers in the form the EVM RPC canister expects for the configured chain
+    pub fn rpc_services(&self) -> RpcServices {
+        match self.chain {
+            EvmChain::EthMainnet => RpcServices::EthMainnet(Some(
+                self.providers
+                    .iter()
+                    .map(|provider| match provider {
+                        EvmProvider::Alchemy => EthMainnetService::Alchemy,
+                        EvmProvider::Ankr => EthMainnetService::Ankr,
+                        EvmProvider::BlockPi => EthMainnetService::BlockPi,
+                        EvmProvider::Cloudflare => EthMainnetService::Cloudflare,
+                        EvmProvider::PublicNode => EthMainnetService::PublicNode,
+                    })
+                    .collect(),
+            )),
+            EvmChain::EthSepolia => RpcServices::EthSepolia(Some(
+                self.providers
+                    .iter()
+                    .filter_map(|provider| match provider {
+                        EvmProvider::Alchemy => Some(EthSepoliaService::Alchemy),
+                        EvmProvider::Ankr => Some(EthSepoliaService::Ankr),
+                        EvmProvider::BlockPi => Some(EthSepoliaService::BlockPi),
+                        EvmProvider::Cloudflare => None,
+                        EvmProvider::PublicNode => Some(EthSepoliaService::PublicNode),
+                    })
+                    .collect(),
+            )),
+        }
+    }
+}
+
+thread_local! {
+    pub static EVM_RPC_CONFIG: RefCell<StableCell<EvmRpcConfig, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))),
+            EvmRpcConfig::default(),
+        )
+        .expect("Failed to initialise EVM RPC config")
+    );
+}
+
+pub fn config() -> EvmRpcConfig {
+    EVM_RPC_CONFIG.with(|config| config.borrow().get().clone())
+}
+
+/**
+* Function: resolve_consensus
+* Description: Settles the per-provider results of an inconsistent EVM RPC call. The result returned by the most
+* providers is accepted if at least `consensus_threshold` of them agree on it.
+* @param results: Vec<(RpcService, T)> - The result each provider returned
+* @param threshold: u8 - The number of providers that must agree
+* @return Result<T, String> - The agreed result, or an error if too few providers agree
+*/
+pub fn resolve_consensus<T: CandidType>(results: Vec<(RpcService, T)>, threshold: u8) -> Result<T, String> {
+    let total = results.len();
+    let mut groups: Vec<(Vec<u8>, T, usize)> = Vec::new();
+
+    for (_, result) in results {
+        let encoded = Encode!(&result).map_err(|e| format!("Failed to encode RPC result: {}", e))?;
+        match groups.iter_mut().find(|(key, _, _)| *key == encoded) {
+            Some((_, _, count)) => *count += 1,
+            None => groups.push((encoded, result, 1)),
+        }
+    }
+
+    match groups.into_iter().max_by_key(|(_, _, count)| *count) {
+        Some((_, result, count)) if count >= threshold as usize => Ok(result),
+        Some((_, _, count)) => Err(format!(
+            "Only {} of {} providers agreed, {} required",
+            count, total, threshold
+        )),
+        None => Err("No provider returned a result".to_string()),
+    }
+}
+
+/**
+* Function: get_evm_rpc_config
+* Description: Returns the chain, providers, consensus threshold and cycle budget used for EVM RPC calls.
+* @param None
+* @return EvmRpcConfig - The current EVM RPC config
+*/
+#[query]
+pub fn get_evm_rpc_config() -> EvmRpcConfig {
+    config()
+}
+
+/**
+* Function: set_evm_rpc_config
+* Description: Replaces the EVM RPC config, e.g. to move deposit verification from Sepolia to mainnet. SuperAdmin only.
+* @param config: EvmRpcConfig - The new EVM RPC config
+* @return Result<Success, Error> - Success message if the config was updated, or an error otherwise
+*/
+#[update(guard = "caller_is_super_admin")]
+pub fn set_evm_rpc_config(config: EvmRpcConfig) -> Result<Success, Error> {
+    let config = EvmRpcConfig {
+        cketh_helper: config.cketh_helper.to_lowercase(),
+        ckusdc_helper: config.ckusdc_helper.to_lowercase(),
+        usdc_contract: config.usdc_contract.to_lowercase(),
+        ..config
+    };
+    config.validate().map_err(|msg| Error::Error { msg })?;
+
+    let after = format!("{:?}", config);
+    let previous = EVM_RPC_CONFIG
+        .with(|cell| cell.borrow_mut().set(config))
+        .map_err(|e| Error::Error {
+            msg: format!("Failed to save EVM RPC config: {:?}", e),
+        })?;
+    auditlog::record(
+        AuditAction::ConfigChanged,
+        "evm_rpc_config".to_string(),
+        Some(format!("{:?}", previous)),
+        Some(after),
+    );
+
+    Ok(Success::EvmRpcConfigUpdated {
+        msg: "EVM RPC config updated".to_string(),
+    })
+}
@@ -1,4 +1,6 @@
 use crate::entitymanagement::{self, check_entity_type, BoundedBytes, BoundedString, EntityType, MEMORY_MANAGER, Memory};
+use crate::unboundedmap::UnboundedMap;
+use crate::idallocator::{self, IdNamespace};
 use candid::{CandidType, Principal, Encode, Decode};
 use ic_cdk::{query, update};
 use ic_stable_structures::Storable;
@@ -35,6 +37,7 @@ pub enum Error {
     FieldEmpty { msg: String },
     FarmNameTaken { msg: String },
     FarmNotFound { msg: String },
+    IdAlreadyInUse { msg: String },
 }
 
 #[derive(CandidType, Serialize, Deserialize)]
@@ -57,11 +60,6 @@ impl Storable for FileInfo {
     }
 }
 
-impl BoundedStorable for FileInfo {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
-}
-
 impl Storable for ImagesBoundedBytes {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
         Cow::Owned(Encode!(&self.0).unwrap())
@@ -79,16 +77,14 @@ impl BoundedStorable for ImagesBoundedBytes {
 }
 
 thread_local! {
-    static NEXT_FILE_ID: RefCell<u64> = RefCell::new(1);
-    
     pub static AGRIBIZ_FILE_STORAGE: RefCell<StableBTreeMap<BoundedString, BoundedBytes, Memory>> =
         RefCell::new(StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
         ));
 
-    pub static FILE_INFO_STORAGE: RefCell<StableBTreeMap<u64, FileInfo, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+    pub static FILE_INFO_STORAGE: RefCell<UnboundedMap<FileInfo>> =
+        RefCell::new(UnboundedMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47)))
         ));
 
     pub static FARM_IMAGES: RefCell<StableBTreeMap<u64, ImagesBoundedBytes, Memory>> =
@@ -105,27 +101,27 @@ fn upload_agribusiness_spreadsheet(
     agribusiness_name: String,
 ) {
     // Generate a new unique file ID
-    let file_id = NEXT_FILE_ID.with(|id| {
-        let current_id = *id.borrow();
-        *id.borrow_mut() = current_id + 1;
-        current_id
-    });
+    let file_id = idallocator::next_id(IdNamespace::AgribizFile);
 
     AGRIBIZ_FILE_STORAGE.with(|storage| {
         let mut storage = storage.borrow_mut();
         storage.insert(entitymanagement::BoundedString(filename.clone()), entitymanagement::BoundedBytes(file_data));
     });
 
-    FILE_INFO_STORAGE.with(|info_storage| {
+    let inserted = FILE_INFO_STORAGE.with(|info_storage| {
         let mut info_storage = info_storage.borrow_mut();
-        info_storage.insert(file_id, FileInfo {
+        info_storage.insert_new(file_id, FileInfo {
             file_id,
             filename: filename.clone(),
             agribusiness_name,
             principal_id: ic_cdk::caller(),
             farms_uploaded: false,
-        });
+        })
     });
+    // Nothing to return the error through, so roll the upload back instead of overwriting another file's info
+    if !inserted {
+        ic_cdk::trap(&format!("File ID {} is already in use", file_id));
+    }
 
     // let entity_type = check_entity_type();
 
@@ -206,9 +202,8 @@ pub fn check_file_status(file_id: u64) -> Result<bool, Error> {
             let file_status = FILE_INFO_STORAGE.with(|info_storage| {
                 let storage = info_storage.borrow();
                 storage
-                    .iter()
-                    .find(|(id, _)| *id == file_id)
-                    .map(|(_, file_info)| file_info.farms_uploaded)
+                    .get(&file_id)
+                    .map(|file_info| file_info.farms_uploaded)
             });
 
             match file_status {
@@ -233,10 +228,10 @@ pub fn mark_file_complete(file_id: u64) -> Result<Success, Error> {
         EntityType::FarmsAgriBusiness => FILE_INFO_STORAGE.with(|info_storage| {
             let mut info_storage = info_storage.borrow_mut();
             if let Some(file_info) = info_storage
-                .iter()
-                .find(|(id, info)| *id == file_id && info.principal_id == caller)
+                .get(&file_id)
+                .filter(|info| info.principal_id == caller)
             {
-                let mut updated_info = file_info.1.clone();
+                let mut updated_info = file_info;
                 updated_info.farms_uploaded = true;
                 info_storage.insert(file_id, updated_info);
                 Ok(Success::FileUploaded {
@@ -280,11 +275,7 @@ pub fn register_single_farm(new_farmer: NewFarmer, file_id: u64) -> Result<Succe
                     }
 
                     // Generate a unique farmer ID
-                    let id = entitymanagement::FARMER_STORAGE.with(|farmers| {
-                        let farmers = farmers.borrow_mut();
-                        let new_id = farmers.len() as u64 + 1;
-                        new_id
-                    });
+                    let id = idallocator::next_id(IdNamespace::Farmer);
 
                     // Create a new farmer instance
                     let farmer = entitymanagement::Farmer {
@@ -318,8 +309,13 @@ pub fn register_single_farm(new_farmer: NewFarmer, file_id: u64) -> Result<Succe
                         email: None
                     };
 
-                    entitymanagement::FARMER_STORAGE
-                        .with(|farmers| farmers.borrow_mut().insert(id, farmer.clone()));
+                    let inserted = entitymanagement::FARMER_STORAGE
+                        .with(|farmers| farmers.borrow_mut().insert_new(id, farmer.clone()));
+                    if !inserted {
+                        return Err(Error::IdAlreadyInUse {
+                            msg: format!("Farmer ID {} is already in use", id),
+                        });
+                    }
 
                     Ok(Success::FarmCreatedSuccessfully {
                         msg: format!("Farm '{}' has been created successfully", farmer.farm_name),
@@ -366,12 +362,10 @@ pub fn delete_single_farm(farm_id: u64) -> Result<Success, Error> {
     //         msg: format!("Farm with ID '{}' doesn't exist", farm_id),
     //     })
     // }
-    let mut farmers = entitymanagement::return_farmers();
+    let removed = entitymanagement::FARMER_STORAGE.with(|storage| storage.borrow_mut().remove(&farm_id));
 
-    if let Some(_farmer) = farmers.iter_mut().find(|f| f.id == farm_id) {
-        entitymanagement::FARMER_STORAGE.with(|storage| {
-            storage.borrow_mut().remove(&farm_id);
-        });
+    if let Some(farmer) = removed {
+        entitymanagement::unindex_principal(&farmer.principal_id, EntityType::Farmer, farm_id);
 
         // entitymanagement::REGISTERED_FARMERS.with(|registered_farmers| {
         //     registered_farmers.borrow_mut().remove(&farm_id);
@@ -616,10 +610,13 @@ fn delete_farm(farm_id: u64) -> Result<entitymanagement::Success, entitymanageme
         });
 
         // Remove the farm from FARMER_STORAGE
-        entitymanagement::FARMER_STORAGE.with(|farmers| {
+        let removed = entitymanagement::FARMER_STORAGE.with(|farmers| {
             let mut farmers = farmers.borrow_mut();
-            farmers.remove(&farm_id);
+            farmers.remove(&farm_id)
         });
+        if let Some(farmer) = removed {
+            entitymanagement::unindex_principal(&farmer.principal_id, EntityType::Farmer, farm_id);
+        }
 
         Ok(entitymanagement::Success::FarmDeletedSuccesfully {
             msg: format!("Farm {} has been deleted succesfully", farm.farm_name),
@@ -0,0 +1,251 @@
+use candid::{CandidType, Decode, Encode};
+use ic_cdk::query;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::auditlog::{self, AuditAction};
+use crate::entitymanagement::{Error, Memory, MEMORY_MANAGER};
+use crate::loanmanagement::{self, Loan, LoanStatus};
+use crate::payments;
+use crate::refunds;
+use crate::tokenprices;
+use crate::tokens::{self, Token};
+
+// Tokens a funding round accepts; anything other than the loan token is valued in loan units when invested
+pub const INVESTMENT_TOKENS: [Token; 2] = [Token::CkUSDC, Token::CkETH];
+
+/**
+* FundingProgress Struct
+* Represents how much of a loan's funding round target has been committed by investors.
+* @param Defined In-Line
+* @return FundingProgress instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct FundingProgress {
+    pub loan_id: u64,
+    pub farm_id: u64,
+    pub status: LoanStatus,
+    pub target: u64,
+    pub committed: u128,
+    pub remaining: u128,
+    pub fully_subscribed: bool,
+    pub funding_round_expiry: Option<u64>,
+}
+
+/**
+* RoundInvestment Struct
+* Represents an investment accepted into a loan's funding round. Farm-level investment records do not say which
+* loan they funded, so this ties each of them to its round. Investments in another token than the loan's also keep
+* what they were worth in loan units when accepted, which is what counts towards the target and payouts.
+* @param Defined In-Line
+* @return RoundInvestment instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct RoundInvestment {
+    pub investor_id: u64,
+    pub amount: u128,
+    pub transaction_hash: String,
+    pub token: Token,
+    pub value: Option<u128>, // Value in the loan token's base units, None if invested in the loan token
+}
+
+impl RoundInvestment {
+    // What the investment counts for against the round's target, in the loan token's base units
+    pub fn loan_value(&self) -> u128 {
+        self.value.unwrap_or(self.amount)
+    }
+}
+
+impl Storable for RoundInvestment {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RoundInvestment {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Investments accepted into each round, keyed by (loan_id, sequence)
+    pub static ROUND_INVESTMENTS: RefCell<StableBTreeMap<(u64, u64), RoundInvestment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+        ));
+}
+
+// Returns the investments accepted into a loan's funding round, oldest first
+pub fn round_investments(loan_id: u64) -> Vec<RoundInvestment> {
+    ROUND_INVESTMENTS.with(|investments| {
+        investments
+            .borrow()
+            .range((loan_id, 0)..=(loan_id, u64::MAX))
+            .map(|(_, investment)| investment)
+            .collect()
+    })
+}
+
+// Committed amount against a loan's target
+pub fn progress_for(loan: &Loan) -> FundingProgress {
+    let target = loan.principal_amount;
+    let committed: u128 = round_investments(loan.id).iter().map(|i| i.loan_value()).sum();
+    let remaining = (target as u128).saturating_sub(committed);
+
+    FundingProgress {
+        loan_id: loan.id,
+        farm_id: loan.farm_id,
+        status: loan.status,
+        target,
+        committed,
+        remaining,
+        fully_subscribed: remaining == 0,
+        funding_round_expiry: loan.funding_round_expiry,
+    }
+}
+
+/**
+* Function: get_funding_progress
+* Description: Returns the committed amount against the funding round target of a farm's active loan.
+* @param farm_id: u64 - The ID of the farm
+* @return Result<FundingProgress, Error> - The funding progress, or an error if the farm has no active loan
+*/
+#[query]
+pub fn get_funding_progress(farm_id: u64) -> Result<FundingProgress, Error> {
+    loanmanagement::get_active_loan_for_farm(farm_id)
+        .map(|loan| progress_for(&loan))
+        .ok_or_else(|| Error::LoanNotFound {
+            msg: format!("No active loan for farm_id: {}", farm_id),
+        })
+}
+
+/**
+* Function: open_round
+* Description: Finds the farm's funding round that can accept an investment in the given token right now.
+* @param farm_id: u64 - The ID of the farm being invested in
+* @param token: Token - The token of the investment
+* @return Result<(Loan, FundingProgress), String> - The round's loan and progress, or an error if it cannot accept
+* the investment
+*/
+pub fn open_round(farm_id: u64, token: Token) -> Result<(Loan, FundingProgress), String> {
+    if !INVESTMENT_TOKENS.contains(&token) {
+        return Err(format!("{} cannot be invested in funding rounds", token.symbol()));
+    }
+
+    let loan = loanmanagement::get_active_loan_for_farm(farm_id)
+        .filter(|loan| loan.status == LoanStatus::Funding)
+        .ok_or_else(|| format!("Farm {} has no open funding round", farm_id))?;
+
+    if loan
+        .funding_round_expiry
+        .is_some_and(|expiry| ic_cdk::api::time() >= expiry)
+    {
+        return Err(format!("Funding round for farm {} has expired", farm_id));
+    }
+
+    let progress = progress_for(&loan);
+    if progress.fully_subscribed {
+        return Err(format!("Funding round for farm {} is fully subscribed", farm_id));
+    }
+
+    Ok((loan, progress))
+}
+
+/**
+* Function: commit_investment
+* Description: Records an investment against a farm's open funding round. Investments in another token than the
+* loan's are valued in loan units first. Only the part of the investment that fits under the round's target is
+* recorded; anything above it is refunded to the investor. The loan moves to Funded once the target is reached.
+* @param farm_id: u64 - The ID of the farm being invested in
+* @param investor_id: u64 - The ID of the investor
+* @param amount: u128 - The net amount invested after fees, in the token's base units
+* @param hash: String - The transaction hash of the deposit
+* @param token: Token - The token of the deposit
+* @return Result<u128, String> - The amount recorded against the round, in the token's base units, or an error if
+* the round is not open or the token cannot be valued
+*/
+pub async fn commit_investment(
+    farm_id: u64,
+    investor_id: u64,
+    amount: u128,
+    hash: String,
+    token: Token,
+) -> Result<u128, String> {
+    open_round(farm_id, token)?;
+    let value = tokenprices::loan_value(token, amount).await?;
+    if value == 0 {
+        return Err(format!(
+            "Investment of {} {} is worth nothing in loan units",
+            tokens::format_units(amount, token.decimals()),
+            token.symbol()
+        ));
+    }
+
+    // Checked again, since the round may have changed while the investment was being valued
+    let (mut loan, progress) = open_round(farm_id, token)?;
+    let accepted_value = value.min(progress.remaining);
+    let accepted = if accepted_value == value {
+        amount
+    } else {
+        amount * accepted_value / value
+    };
+    let excess = amount - accepted;
+
+    payments::store_investments(farm_id, accepted, investor_id, hash.clone(), token)?;
+    ROUND_INVESTMENTS.with(|investments| {
+        let mut investments = investments.borrow_mut();
+        let sequence = investments
+            .range((loan.id, 0)..=(loan.id, u64::MAX))
+            .count() as u64;
+        investments.insert(
+            (loan.id, sequence),
+            RoundInvestment {
+                investor_id,
+                amount: accepted,
+                transaction_hash: hash.clone(),
+                token,
+                value: (token != loanmanagement::LOAN_TOKEN).then_some(accepted_value),
+            },
+        );
+    });
+
+    let loan_token = loanmanagement::LOAN_TOKEN;
+    auditlog::record(
+        AuditAction::InvestmentRecorded,
+        format!("loan:{}", loan.id),
+        Some(format!(
+            "{} of {} committed",
+            tokens::format_units(progress.committed, loan_token.decimals()),
+            tokens::format_units(progress.target as u128, loan_token.decimals())
+        )),
+        Some(format!(
+            "investor {} committed {} {} worth {} {} ({}), {} refunded",
+            investor_id,
+            tokens::format_units(accepted, token.decimals()),
+            token.symbol(),
+            tokens::format_units(accepted_value, loan_token.decimals()),
+            loan_token.symbol(),
+            hash,
+            tokens::format_units(excess, token.decimals())
+        )),
+    );
+
+    // Close the round as soon as the target is met
+    if accepted_value >= progress.remaining {
+        loan.transition(LoanStatus::Funded)
+            .map_err(|e| e.to_string())?;
+        loanmanagement::save_loan(&loan);
+    }
+
+    if excess > 0 {
+        refunds::refund_excess_investment(loan.id, farm_id, investor_id, hash, token, excess).await;
+    }
+
+    Ok(accepted)
+}
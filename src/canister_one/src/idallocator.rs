@@ -0,0 +1,81 @@
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableCell;
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+use crate::entitymanagement::{Memory, MEMORY_MANAGER};
+
+/**
+* IdNamespace Enum
+* Kinds of record that are numbered independently.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdNamespace {
+    Farmer,
+    Investor,
+    SupplyAgriBusiness,
+    FarmsAgriBusiness,
+    AgribizFile, // Spreadsheets uploaded by farms agribusinesses
+    Investment,
+}
+
+type IdCell = StableCell<u64, Memory>;
+
+fn id_cell(memory_id: u8) -> RefCell<IdCell> {
+    RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id))), 0)
+            .expect("Failed to initialise id allocator"),
+    )
+}
+
+thread_local! {
+    // The last id handed out in each namespace; 0 when none has been
+    static LAST_FARMER_ID: RefCell<IdCell> = id_cell(48);
+    static LAST_INVESTOR_ID: RefCell<IdCell> = id_cell(49);
+    static LAST_SUPPLY_AGRIBUSINESS_ID: RefCell<IdCell> = id_cell(50);
+    static LAST_FARMS_AGRIBUSINESS_ID: RefCell<IdCell> = id_cell(51);
+    static LAST_AGRIBIZ_FILE_ID: RefCell<IdCell> = id_cell(52);
+    static LAST_INVESTMENT_ID: RefCell<IdCell> = id_cell(54);
+}
+
+fn last_id_cell(namespace: IdNamespace) -> &'static LocalKey<RefCell<IdCell>> {
+    match namespace {
+        IdNamespace::Farmer => &LAST_FARMER_ID,
+        IdNamespace::Investor => &LAST_INVESTOR_ID,
+        IdNamespace::SupplyAgriBusiness => &LAST_SUPPLY_AGRIBUSINESS_ID,
+        IdNamespace::FarmsAgriBusiness => &LAST_FARMS_AGRIBUSINESS_ID,
+        IdNamespace::AgribizFile => &LAST_AGRIBIZ_FILE_ID,
+        IdNamespace::Investment => &LAST_INVESTMENT_ID,
+    }
+}
+
+fn set_last_id(namespace: IdNamespace, id: u64) {
+    last_id_cell(namespace)
+        .with(|cell| cell.borrow_mut().set(id))
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to save the last {:?} id: {:?}", namespace, e)));
+}
+
+/**
+* Function: next_id
+* Description: Allocates the next id in a namespace. Ids start at 1, are never handed out twice and survive upgrades.
+* @param namespace: IdNamespace - The kind of record the id is for
+* @return u64 - The new id
+*/
+pub fn next_id(namespace: IdNamespace) -> u64 {
+    let id = last_id_cell(namespace).with(|cell| *cell.borrow().get()) + 1;
+    set_last_id(namespace, id);
+    id
+}
+
+/**
+* Function: reserve_ids_up_to
+* Description: Makes sure a namespace never allocates an id at or below one already in use.
+* @param namespace: IdNamespace - The namespace to advance
+* @param id: u64 - The highest id in use
+* @return None
+*/
+pub fn reserve_ids_up_to(namespace: IdNamespace, id: u64) {
+    if last_id_cell(namespace).with(|cell| *cell.borrow().get()) < id {
+        set_last_id(namespace, id);
+    }
+}
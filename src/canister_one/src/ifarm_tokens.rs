@@ -12,19 +12,23 @@ use b3_utils::ledger::{
     // ICRC2Allowance
 };
 use candid::{Principal, Nat};
-use num_traits::ToPrimitive;
 use crate::entitymanagement::{check_entity_type, EntityType};
+use crate::canistersettings::settings;
+use crate::tokens::{self, Token};
 // use crate::LEDGER;
 
-const IFARM_TOKEN: &str = "lradw-laaaa-aaaam-acrda-cai";
-const FEE_COLLECTOR_PRINCIPAL: &str = "3r4ur-bi57q-dnrjp-fdl3f-pd5ud-gux43-l6bk6-ff7p3-33zk4-nx7ym-mqe";
-const FEE_PERCENTAGE: f64 = 0.01; 
+// Defaults for the iFarm ledger and its transfer fee; deployments override them through the init arguments
+pub const IFARM_TOKEN: &str = "lradw-laaaa-aaaam-acrda-cai";
+pub const FEE_COLLECTOR_PRINCIPAL: &str = "3r4ur-bi57q-dnrjp-fdl3f-pd5ud-gux43-l6bk6-ff7p3-33zk4-nx7ym-mqe";
+pub const FEE_BPS: u64 = 100; // 1%
 
-async fn collect_fee(amount: &Nat) -> ICRC1TransferResult {
-    let fee_amount = (amount.0.to_f64().unwrap() * FEE_PERCENTAGE) as u64;
-    let fee_collector = Principal::from_text(FEE_COLLECTOR_PRINCIPAL).unwrap();
-    
-    let to = ICRCAccount::new(fee_collector, None);
+// Fee charged on an iFarm transfer, in base units
+fn fee_for(amount: &Nat) -> Result<u128, String> {
+    Ok(tokens::bps_of(tokens::nat_to_u128(amount)?, settings().ifarm_transfer_fee_bps as u128))
+}
+
+async fn collect_fee(fee_amount: u128) -> ICRC1TransferResult {
+    let to = ICRCAccount::new(settings().fee_collector, None);
     let transfer_args = ICRC1TransferArgs {
         to,
         amount: Nat::from(fee_amount),
@@ -34,14 +38,14 @@ async fn collect_fee(amount: &Nat) -> ICRC1TransferResult {
         created_at_time: None,
     };
     
-    ICRC1::from(IFARM_TOKEN).transfer(transfer_args).await.unwrap()
+    ICRC1::from(Token::IFarm.ledger()).transfer(transfer_args).await.unwrap()
 }
 
 // Check ifarm token balance
 #[ic_cdk::update]
 async fn ifarm_balance(principal_id: Principal) -> Nat {
     let account = ICRCAccount::new(principal_id, None);
-    ICRC1::from(IFARM_TOKEN).balance_of(account).await.unwrap()
+    ICRC1::from(Token::IFarm.ledger()).balance_of(account).await.unwrap()
 }   
 
 #[ic_cdk::update]
@@ -51,8 +55,8 @@ pub async fn ifarm_transfer(to: Principal, amount: Nat) -> Result<ICRC1TransferR
             Err("Caller is not registered".to_string())
         },
         _ => {
-            let fee_amount = (amount.0.to_f64().unwrap() * FEE_PERCENTAGE) as u64;
-            let _ = collect_fee(&amount).await;
+            let fee_amount = fee_for(&amount)?;
+            let _ = collect_fee(fee_amount).await;
 
             let transfer_amount = amount - Nat::from(fee_amount);
 
@@ -65,7 +69,7 @@ pub async fn ifarm_transfer(to: Principal, amount: Nat) -> Result<ICRC1TransferR
                 memo: None,
                 created_at_time: None,
             };
-            Ok(ICRC1::from(IFARM_TOKEN).transfer(transfer_args).await.unwrap())
+            Ok(ICRC1::from(Token::IFarm.ledger()).transfer(transfer_args).await.unwrap())
         }
     }
 }
@@ -90,11 +94,11 @@ async fn ifarm_approve(owner: Principal, spender: Principal, amount: Nat) -> Res
         created_at_time: None,
     };
 
-    ICRC2::from(IFARM_TOKEN).approve(approve_args).await.map_err(|e| format!("Approval failed: {:?}", e))
+    ICRC2::from(Token::IFarm.ledger()).approve(approve_args).await.map_err(|e| format!("Approval failed: {:?}", e))
 }
 
-// Transfer ifarm token
-#[ic_cdk::update]
+// Transfer ifarm token from an account that approved this canister. Not exposed as an endpoint, since anyone could
+// otherwise move the tokens farmers approve for their repayments.
 pub async fn ifarm_transfer_from(from: Principal, to: Principal, amount: Nat) -> Result<ICRC2TransferFromResult, String> {
     let from = ICRCAccount::new(from, None);
     let to = ICRCAccount::new(to, None);
@@ -108,7 +112,7 @@ pub async fn ifarm_transfer_from(from: Principal, to: Principal, amount: Nat) ->
         created_at_time: None,
     };
 
-    Ok(ICRC2::from(IFARM_TOKEN).transfer_from(transfer_from_args).await.unwrap())
+    Ok(ICRC2::from(Token::IFarm.ledger()).transfer_from(transfer_from_args).await.unwrap())
 }
 
 // Check token allowance
@@ -120,5 +124,5 @@ pub async fn ifarm_transfer_from(from: Principal, to: Principal, amount: Nat) ->
 //         account,
 //         spender,
 //     };
-//     ICRC2::from(IFARM_TOKEN).allowance(allowance_args).await.unwrap()
+//     ICRC2::from(Token::IFarm.ledger()).allowance(allowance_args).await.unwrap()
 // }
\ No newline at end of file
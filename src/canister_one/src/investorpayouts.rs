@@ -0,0 +1,384 @@
+use b3_utils::ledger::ICRCAccount;
+use candid::{CandidType, Decode, Encode, Nat, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::accesscontrol::caller_is_treasurer;
+use crate::entitymanagement::{self, Error, Memory, MEMORY_MANAGER};
+use crate::loanrepayments::{LoanRepayment, RepaymentCurrency};
+use crate::fundingrounds;
+use crate::ledgertransfers::{self, PreparedTransfer, TransferAttempt};
+use crate::transaction_fees;
+use crate::auditlog::{self, AuditAction};
+use crate::tokens;
+use crate::canistersettings::settings;
+
+// Platform fee taken from every repayment before it is distributed to investors
+pub const REPAYMENT_FEE_BPS: u64 = 100; // 1%, unless overridden by the init arguments
+// How long a payout can stay Pending before a retry assumes its send was interrupted
+const STALE_PENDING_PAYOUT: u64 = 30 * 60 * 1_000_000_000; // 30 minutes in nanoseconds
+
+/**
+* PayoutStatus Enum
+* Tracks whether an investor payout has reached the investor's account.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum PayoutStatus {
+    Pending,
+    Paid { block_index: Nat },
+    Failed { error: String },
+}
+
+/**
+* InvestorPayout Struct
+* Represents an investor's pro-rata share of a loan repayment.
+* @param Defined In-Line
+* @return InvestorPayout instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct InvestorPayout {
+    pub payout_id: u64,
+    pub loan_id: u64,
+    pub repayment_id: u64,
+    pub farm_id: u64,
+    pub investor_id: u64,
+    pub investor_principal: Option<Principal>,
+    pub currency: RepaymentCurrency,
+    pub amount: u64,
+    pub status: PayoutStatus,
+    pub attempts: u32,
+    pub created_at: u64,
+    pub last_attempt_at: Option<u64>,
+    pub transfer_attempt: Option<TransferAttempt>, // Latest ledger transfer attempt, reused while the ledger deduplicates it
+}
+
+impl Storable for InvestorPayout {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for InvestorPayout {
+    const MAX_SIZE: u32 = 1_024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Payouts keyed by payout id
+    pub static INVESTOR_PAYOUTS: RefCell<StableBTreeMap<u64, InvestorPayout, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+        ));
+
+    // Index of payouts per investor: (investor_id, payout_id) => ()
+    pub static INVESTOR_PAYOUT_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+        ));
+}
+
+/**
+* Function: calculate_investor_shares
+* Description: Splits an amount between the investors of a loan's funding round in proportion to how much each invested.
+* Shares are rounded down; any remainder stays with the canister.
+* @param loan_id: u64 - The ID of the loan
+* @param amount: u64 - The amount to split
+* @return Vec<(u64, u64)> - (investor_id, share) for each investor with a non-zero share
+*/
+pub fn calculate_investor_shares(loan_id: u64, amount: u64) -> Vec<(u64, u64)> {
+    let mut invested: BTreeMap<u64, u128> = BTreeMap::new();
+    for investment in fundingrounds::round_investments(loan_id) {
+        *invested.entry(investment.investor_id).or_insert(0) += investment.loan_value();
+    }
+    pro_rata_shares(invested, amount)
+}
+
+// Splits an amount in proportion to what each investor put in, rounding every share down
+fn pro_rata_shares(invested: BTreeMap<u64, u128>, amount: u64) -> Vec<(u64, u64)> {
+    let total: u128 = invested.values().sum();
+    if total == 0 {
+        return Vec::new();
+    }
+
+    invested
+        .into_iter()
+        .map(|(investor_id, invested_amount)| {
+            (investor_id, (amount as u128 * invested_amount / total) as u64)
+        })
+        .filter(|(_, share)| *share > 0)
+        .collect()
+}
+
+fn save_payout(payout: &InvestorPayout) {
+    INVESTOR_PAYOUTS.with(|payouts| payouts.borrow_mut().insert(payout.payout_id, payout.clone()));
+}
+
+/**
+* Function: distribute_repayment
+* Description: Deducts the platform fee from a repayment and pays each investor of the farm their pro-rata share.
+* A payout record is stored per investor before the transfer so failed transfers can be retried.
+* @param repayment: &LoanRepayment - The repayment to distribute
+* @return Vec<InvestorPayout> - The payouts created for the repayment
+*/
+pub async fn distribute_repayment(repayment: &LoanRepayment) -> Vec<InvestorPayout> {
+    let applied = repayment.amount - repayment.unapplied;
+    let fee = applied * settings().repayment_fee_bps / 10_000;
+    let _ = transaction_fees::store_transaction_fee(
+        format!("repayment_{}_{}", repayment.loan_id, repayment.repayment_id),
+        fee as u128,
+        repayment.currency.token(),
+    );
+
+    let current_time = ic_cdk::api::time();
+    let mut created = Vec::new();
+    for (investor_id, share) in calculate_investor_shares(repayment.loan_id, applied - fee) {
+        let payout_id = INVESTOR_PAYOUTS.with(|payouts| {
+            payouts
+                .borrow()
+                .last_key_value()
+                .map(|(id, _)| id + 1)
+                .unwrap_or(1)
+        });
+
+        let payout = InvestorPayout {
+            payout_id,
+            loan_id: repayment.loan_id,
+            repayment_id: repayment.repayment_id,
+            farm_id: repayment.farm_id,
+            investor_id,
+            investor_principal: entitymanagement::INVESTOR_STORAGE
+                .with(|investors| investors.borrow().get(&investor_id))
+                .map(|investor| investor.principal_id),
+            currency: repayment.currency,
+            amount: share,
+            status: PayoutStatus::Pending,
+            attempts: 0,
+            created_at: current_time,
+            last_attempt_at: None,
+            transfer_attempt: None,
+        };
+
+        save_payout(&payout);
+        INVESTOR_PAYOUT_INDEX.with(|index| index.borrow_mut().insert((investor_id, payout_id), ()));
+        created.push(payout);
+    }
+
+    let mut payouts = Vec::new();
+    for payout in created {
+        payouts.push(send_payout(payout).await);
+    }
+    payouts
+}
+
+/**
+* Function: send_payout
+* Description: Transfers a payout to the investor over ICRC-1 and records the outcome. Every attempt carries the
+* payout's id as its memo; resends within the ledger's deduplication window reuse the previous attempt, and later
+* ones first look the previous attempt up on the ledger, so a payout is never made twice.
+* @param payout: InvestorPayout - The payout to send
+* @return InvestorPayout - The payout with its updated status
+*/
+async fn send_payout(mut payout: InvestorPayout) -> InvestorPayout {
+    payout.attempts += 1;
+    payout.last_attempt_at = Some(ic_cdk::api::time());
+
+    let investor_principal = match payout.investor_principal {
+        Some(principal) => principal,
+        None => {
+            payout.status = PayoutStatus::Failed {
+                error: format!("Investor {} not found", payout.investor_id),
+            };
+            save_payout(&payout);
+            return payout;
+        }
+    };
+
+    // Mark the payout as in flight so it is not retried concurrently
+    payout.status = PayoutStatus::Pending;
+    save_payout(&payout);
+
+    let token = payout.currency.token();
+    let memo = payout.payout_id.to_be_bytes().to_vec();
+    payout.status = match ledgertransfers::prepare_transfer(token, payout.transfer_attempt.as_ref(), &memo).await {
+        Ok(PreparedTransfer::AlreadySent { block_index }) => PayoutStatus::Paid { block_index },
+        Ok(PreparedTransfer::Send(attempt)) => {
+            payout.transfer_attempt = Some(attempt.clone());
+            save_payout(&payout);
+
+            let to = ICRCAccount::new(investor_principal, None);
+            match ledgertransfers::send_transfer(token, &attempt, to, payout.amount as u128, memo).await {
+                Ok(block_index) => PayoutStatus::Paid { block_index },
+                Err(error) => PayoutStatus::Failed { error },
+            }
+        }
+        Err(error) => PayoutStatus::Failed { error },
+    };
+
+    save_payout(&payout);
+
+    auditlog::record(
+        AuditAction::TokensSent,
+        format!("payout:{}", payout.payout_id),
+        None,
+        Some(format!(
+            "{} {} to investor {}: {:?}",
+            tokens::format_units(payout.amount as u128, token.decimals()),
+            token.symbol(),
+            payout.investor_id,
+            payout.status
+        )),
+    );
+    payout
+}
+
+// Payouts a retry should pick up: failed ones, and pending ones whose send was interrupted by a trap
+fn needs_retry(payout: &InvestorPayout, now: u64) -> bool {
+    match payout.status {
+        PayoutStatus::Failed { .. } => true,
+        PayoutStatus::Pending => payout
+            .last_attempt_at
+            .is_none_or(|at| now.saturating_sub(at) >= STALE_PENDING_PAYOUT),
+        PayoutStatus::Paid { .. } => false,
+    }
+}
+
+/**
+* Function: retry_failed_payouts
+* Description: Re-sends every payout whose transfer failed, or that was left pending for longer than
+* STALE_PENDING_PAYOUT. Treasurer only.
+* @param None
+* @return Result<Vec<InvestorPayout>, Error> - The retried payouts with their new status
+*/
+#[update(guard = "caller_is_treasurer")]
+pub async fn retry_failed_payouts() -> Result<Vec<InvestorPayout>, Error> {
+    // Claim the payouts before the first await, so a concurrent retry cannot pick the same ones up
+    let now = ic_cdk::api::time();
+    let claimed: Vec<u64> = INVESTOR_PAYOUTS.with(|payouts| {
+        let mut payouts = payouts.borrow_mut();
+        let due: Vec<InvestorPayout> = payouts
+            .iter()
+            .map(|(_, payout)| payout)
+            .filter(|payout| needs_retry(payout, now))
+            .collect();
+        due
+            .into_iter()
+            .map(|mut payout| {
+                payout.status = PayoutStatus::Pending;
+                payout.last_attempt_at = Some(now);
+                payouts.insert(payout.payout_id, payout.clone());
+                payout.payout_id
+            })
+            .collect()
+    });
+
+    let mut retried = Vec::new();
+    for payout_id in claimed {
+        // Sends awaited before this one may have changed it
+        let Some(mut payout) = INVESTOR_PAYOUTS
+            .with(|payouts| payouts.borrow().get(&payout_id))
+            .filter(|payout| payout.status == PayoutStatus::Pending)
+        else {
+            continue;
+        };
+
+        // The investor may have registered since the payout was created
+        if payout.investor_principal.is_none() {
+            payout.investor_principal = entitymanagement::INVESTOR_STORAGE
+                .with(|investors| investors.borrow().get(&payout.investor_id))
+                .map(|investor| investor.principal_id);
+        }
+        retried.push(send_payout(payout).await);
+    }
+
+    Ok(retried)
+}
+
+/**
+* Function: get_investor_payouts
+* Description: Returns every payout made to an investor, oldest first.
+* @param investor_id: u64 - The ID of the investor
+* @return Vec<InvestorPayout> - The investor's payouts
+*/
+#[query]
+pub fn get_investor_payouts(investor_id: u64) -> Vec<InvestorPayout> {
+    let payout_ids: Vec<u64> = INVESTOR_PAYOUT_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((investor_id, 0)..=(investor_id, u64::MAX))
+            .map(|((_, payout_id), _)| payout_id)
+            .collect()
+    });
+
+    INVESTOR_PAYOUTS.with(|payouts| {
+        let payouts = payouts.borrow();
+        payout_ids.iter().filter_map(|id| payouts.get(id)).collect()
+    })
+}
+
+/**
+* Function: get_loan_payouts
+* Description: Returns every investor payout made from a loan's repayments.
+* @param loan_id: u64 - The ID of the loan
+* @return Vec<InvestorPayout> - The loan's payouts
+*/
+#[query]
+pub fn get_loan_payouts(loan_id: u64) -> Vec<InvestorPayout> {
+    INVESTOR_PAYOUTS.with(|payouts| {
+        payouts
+            .borrow()
+            .iter()
+            .filter(|(_, payout)| payout.loan_id == loan_id)
+            .map(|(_, payout)| payout)
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_follow_what_each_investor_put_in() {
+        let invested = BTreeMap::from([(1, 600), (2, 300), (3, 100)]);
+
+        assert_eq!(pro_rata_shares(invested, 1_000), vec![(1, 600), (2, 300), (3, 100)]);
+    }
+
+    #[test]
+    fn shares_round_down_and_leave_the_remainder_with_the_canister() {
+        let invested = BTreeMap::from([(1, 1), (2, 1), (3, 1)]);
+
+        let shares = pro_rata_shares(invested, 100);
+
+        assert_eq!(shares, vec![(1, 33), (2, 33), (3, 33)]);
+        assert_eq!(shares.iter().map(|(_, share)| share).sum::<u64>(), 99);
+    }
+
+    #[test]
+    fn shares_that_round_to_zero_are_left_out() {
+        let invested = BTreeMap::from([(1, 999), (2, 1)]);
+
+        assert_eq!(pro_rata_shares(invested, 100), vec![(1, 99)]);
+    }
+
+    #[test]
+    fn shares_do_not_overflow_on_large_amounts() {
+        let invested = BTreeMap::from([(1, u64::MAX as u128), (2, u64::MAX as u128)]);
+
+        assert_eq!(pro_rata_shares(invested, u64::MAX), vec![(1, u64::MAX / 2), (2, u64::MAX / 2)]);
+    }
+
+    #[test]
+    fn nothing_is_shared_without_investments() {
+        assert!(pro_rata_shares(BTreeMap::new(), 1_000).is_empty());
+        assert!(pro_rata_shares(BTreeMap::from([(1, 0)]), 1_000).is_empty());
+    }
+}
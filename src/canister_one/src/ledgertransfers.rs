@@ -0,0 +1,217 @@
+use b3_utils::ledger::{ICRCAccount, ICRC1, ICRC1TransferArgs, ICRC1TransferError};
+use candid::{CandidType, Nat, Principal};
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::tokens::Token;
+
+// How long after its creation time a transfer is resent with that same time. Ledgers deduplicate transfers for 24
+// hours; the last hour is left for the time between creating an attempt and it reaching the ledger.
+const DEDUP_WINDOW: u64 = 23 * 60 * 60 * 1_000_000_000; // 23 hours in nanoseconds
+// Ledgers accept a transfer up to 24 hours after its creation time, plus two minutes of clock drift
+const LEDGER_TX_WINDOW: u64 = (24 * 60 + 2) * 60 * 1_000_000_000;
+// Blocks fetched per call when searching the ledger
+const SEARCH_PAGE: u64 = 1_000;
+
+/**
+* TransferAttempt Struct
+* The latest attempt to send a transfer the canister must make exactly once.
+* @param Defined In-Line
+* @return TransferAttempt instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TransferAttempt {
+    pub created_at_time: u64, // Sent with every resend, so the ledger rejects a resend that went through as a duplicate
+    pub ledger_length: u64,   // Blocks on the ledger before the attempt, where a search for it starts
+}
+
+/**
+* PreparedTransfer Enum
+* How to go on with a transfer: send it with the given attempt, or record the block an earlier attempt reached.
+*/
+pub enum PreparedTransfer {
+    Send(TransferAttempt),
+    AlreadySent { block_index: Nat },
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetTransactionsRequest {
+    start: Nat,
+    length: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LedgerAccount {
+    owner: Principal,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LedgerTransfer {
+    from: LedgerAccount,
+    memo: Option<ByteBuf>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LedgerTransaction {
+    transfer: Option<LedgerTransfer>,
+    timestamp: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct TransactionRange {
+    transactions: Vec<LedgerTransaction>,
+}
+
+candid::define_function!(QueryArchiveFn : (GetTransactionsRequest) -> (TransactionRange) query);
+
+#[derive(CandidType, Deserialize)]
+struct ArchivedRange {
+    start: Nat,
+    length: Nat,
+    callback: QueryArchiveFn,
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetTransactionsResponse {
+    log_length: Nat,
+    first_index: Nat,
+    transactions: Vec<LedgerTransaction>,
+    archived_transactions: Vec<ArchivedRange>,
+}
+
+fn to_u64(value: &Nat) -> Result<u64, String> {
+    value.0.to_u64().ok_or_else(|| format!("{} does not fit in a u64", value))
+}
+
+async fn get_transactions(token: Token, start: u64, length: u64) -> Result<GetTransactionsResponse, String> {
+    let request = GetTransactionsRequest {
+        start: Nat::from(start),
+        length: Nat::from(length),
+    };
+    ic_cdk::call::<_, (GetTransactionsResponse,)>(token.ledger(), "get_transactions", (request,))
+        .await
+        .map(|(response,)| response)
+        .map_err(|(code, msg)| format!("Failed to read {} ledger blocks: {:?} {}", token.symbol(), code, msg))
+}
+
+// Blocks from `start` onwards, with the index of the first one, reading archived blocks from their archive
+async fn read_blocks(token: Token, start: u64) -> Result<(u64, Vec<LedgerTransaction>, u64), String> {
+    let response = get_transactions(token, start, SEARCH_PAGE).await?;
+    let log_length = to_u64(&response.log_length)?;
+
+    if let Some(archived) = response.archived_transactions.into_iter().next() {
+        let request = GetTransactionsRequest {
+            start: archived.start.clone(),
+            length: archived.length,
+        };
+        let (range,): (TransactionRange,) = ic_cdk::call(archived.callback.0.principal, &archived.callback.0.method, (request,))
+            .await
+            .map_err(|(code, msg)| format!("Failed to read archived {} blocks: {:?} {}", token.symbol(), code, msg))?;
+        return Ok((to_u64(&archived.start)?, range.transactions, log_length));
+    }
+
+    Ok((to_u64(&response.first_index)?, response.transactions, log_length))
+}
+
+/**
+* Function: find_transfer
+* Description: Searches the ledger for a transfer this canister made with the given attempt and memo. Only blocks
+* the ledger could have accepted the attempt in are read.
+* @param token: Token - The token the transfer was made in
+* @param attempt: &TransferAttempt - The attempt to look for
+* @param memo: &[u8] - The memo the transfer was sent with
+* @return Result<Option<Nat>, String> - The transfer's block, None if the ledger never accepted it, or an error
+*/
+async fn find_transfer(token: Token, attempt: &TransferAttempt, memo: &[u8]) -> Result<Option<Nat>, String> {
+    let canister = ic_cdk::id();
+    let accepted_until = attempt.created_at_time + LEDGER_TX_WINDOW;
+
+    let mut next = attempt.ledger_length;
+    loop {
+        let (first, transactions, log_length) = read_blocks(token, next).await?;
+        if next >= log_length {
+            return Ok(None);
+        }
+        if transactions.is_empty() || first > next {
+            return Err(format!("{} ledger returned no blocks from {}", token.symbol(), next));
+        }
+
+        for (offset, transaction) in transactions.iter().enumerate() {
+            let index = first + offset as u64;
+            if index < next {
+                continue;
+            }
+            if transaction.timestamp > accepted_until {
+                return Ok(None);
+            }
+            let matches = transaction.transfer.as_ref().is_some_and(|transfer| {
+                transfer.from.owner == canister
+                    && transfer.created_at_time == Some(attempt.created_at_time)
+                    && transfer.memo.as_ref().map(|memo| memo.as_slice()) == Some(memo)
+            });
+            if matches {
+                return Ok(Some(Nat::from(index)));
+            }
+        }
+        next = first + transactions.len() as u64;
+    }
+}
+
+/**
+* Function: prepare_transfer
+* Description: Decides how to send a transfer that must reach the ledger exactly once. Within the ledger's
+* deduplication window the previous attempt is reused, so the ledger turns a resend into a duplicate. Once the
+* window has passed, the ledger is searched for the previous attempt before a new one is made.
+* @param token: Token - The token to send
+* @param previous: Option<&TransferAttempt> - The latest attempt, if any
+* @param memo: &[u8] - The memo identifying the transfer
+* @return Result<PreparedTransfer, String> - The attempt to send, the block the transfer already reached, or an error
+*/
+pub async fn prepare_transfer(token: Token, previous: Option<&TransferAttempt>, memo: &[u8]) -> Result<PreparedTransfer, String> {
+    let now = ic_cdk::api::time();
+    if let Some(previous) = previous {
+        if now.saturating_sub(previous.created_at_time) < DEDUP_WINDOW {
+            return Ok(PreparedTransfer::Send(previous.clone()));
+        }
+        if let Some(block_index) = find_transfer(token, previous, memo).await? {
+            return Ok(PreparedTransfer::AlreadySent { block_index });
+        }
+    }
+
+    let ledger_length = to_u64(&get_transactions(token, 0, 0).await?.log_length)?;
+    Ok(PreparedTransfer::Send(TransferAttempt {
+        created_at_time: ic_cdk::api::time(),
+        ledger_length,
+    }))
+}
+
+/**
+* Function: send_transfer
+* Description: Sends a transfer from the canister's main account with an attempt from `prepare_transfer`. The
+* attempt must be saved first, so a retry after a trap still finds it.
+* @param token: Token - The token to send
+* @param attempt: &TransferAttempt - The attempt to send
+* @param to: ICRCAccount - The recipient
+* @param amount: u128 - The amount, in the token's base units
+* @param memo: Vec<u8> - The memo identifying the transfer
+* @return Result<Nat, String> - The block the transfer reached, or an error
+*/
+pub async fn send_transfer(token: Token, attempt: &TransferAttempt, to: ICRCAccount, amount: u128, memo: Vec<u8>) -> Result<Nat, String> {
+    let transfer_args = ICRC1TransferArgs {
+        to,
+        amount: Nat::from(amount),
+        from_subaccount: None,
+        fee: None,
+        memo: Some(memo),
+        created_at_time: Some(attempt.created_at_time),
+    };
+
+    match ICRC1::from(token.ledger()).transfer(transfer_args).await {
+        Ok(Ok(block_index)) => Ok(block_index),
+        Ok(Err(ICRC1TransferError::Duplicate { duplicate_of })) => Ok(duplicate_of),
+        Ok(Err(e)) => Err(format!("Ledger rejected the transfer: {:?}", e)),
+        Err(e) => Err(format!("Failed to call ledger: {}", e)),
+    }
+}
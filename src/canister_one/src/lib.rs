@@ -9,22 +9,55 @@ use crate::entitymanagement::Investor;
 use crate::entitymanagement::FarmsAgriBusiness; 
 use crate::farmsagribizmanagement::FileInfo; 
 use crate::ck_eth::receipt; 
-use crate::ck_eth::minter; 
 use crate::entitymanagement::FinancialReport;
 use crate::entitymanagement::FarmReport;
 use crate::entitymanagement::NewFarmer; 
+use crate::loanmanagement::Loan;
+use crate::loanrepayments::{LoanBalance, LoanRepayment, RepaymentCurrency};
+use crate::investorpayouts::InvestorPayout;
+use crate::fundingrounds::FundingProgress;
+use crate::refunds::{Refund, RefundMethod};
+use crate::loanpricing::{LoanQuote, PricingConfig};
+use crate::loandisbursements::Disbursement;
+use crate::tokens::{Token, TokenDescriptor};
+use crate::payments::InvestmentEntry;
+use crate::deposits::{PendingDeposit, ProcessedDeposit};
+use crate::depositaccounts::{BalanceWithdrawal, DepositAddress, DepositBalance, DepositSweep};
+use crate::evmrpc::EvmRpcConfig;
+use crate::withdrawals::{Withdrawal, WithdrawalStatus};
+use crate::accesscontrol::{PrincipalRoles, Role};
+use crate::auditlog::{AuditFilter, AuditHead, AuditLogPage};
+use crate::canistersettings::{CanisterArgs, CanisterSettings};
+use crate::treasury::{TransferProposal, TreasuryDestination, TreasuryPolicy, TreasuryTransfer};
 // use crate::askforloan;
 use ic_cdk::{query, update};
 use candid::Principal;
 use candid::Nat;
 
-use b3_utils::ledger::{ ICRC1TransferResult, ICRC2ApproveResult, ICRC2TransferFromResult};
+use b3_utils::ledger::{ ICRC1TransferResult, ICRC2ApproveResult};
 
 use crate::icrc_standards::SupportedStandard;
 use crate::icrc_standards::Icrc28TrustedOriginsResponse;
 
+mod accesscontrol;
 mod adminapproval;
+mod auditlog;
+mod canistersettings;
 mod askforloan;
+mod loanmanagement;
+mod loanrepayments;
+mod loandisbursements;
+mod loantimers;
+mod fundingrounds;
+mod refunds;
+mod loanpricing;
+mod tokens;
+mod tokenprices;
+mod deposits;
+mod depositaccounts;
+mod directinvestments;
+mod investorpayouts;
+mod ledgertransfers;
 mod creditscore;
 mod entitymanagement;
 mod farmerfiles;
@@ -33,6 +66,12 @@ mod payments;
 mod common;
 mod ck_eth;
 mod ck_eth_payments;
+mod evmrpc;
+mod withdrawals;
+mod treasury;
+mod migrations;
+mod unboundedmap;
+mod idallocator;
 mod transaction_fees;
 // mod supplymanagement;
 mod ckusdc_payments;
@@ -40,8 +79,7 @@ mod ifarm_tokens;
 // mod exchange_rate;
 mod approved_principals;
 mod icrc_standards;
-
-use ic_cdk::storage;
+mod upgradestate;
 
 // #[update] 
 // fn test_function(name: String) -> String {
@@ -104,211 +142,41 @@ fn display_farms_agribusinesses() -> Vec<entitymanagement::FarmsAgriBusiness> {
     entitymanagement::return_farms_agribusiness()
 }
 
-// Saving Stable State
-#[ic_cdk::pre_upgrade]
-fn pre_upgrade() {
-    let investor_investments = payments::INVESTOR_INVESTMENTS.with(|investor_investments| investor_investments.borrow().clone());
-    let farm_investments = payments::FARM_INVESTMENTS.with(|farm_investments| farm_investments.borrow().clone());
-    let transaction_fees = transaction_fees::TRANSACTION_FEES.with(|transaction_fees| transaction_fees.borrow().clone());
-
-    let farmers: Vec<_> = entitymanagement::FARMER_STORAGE.with(|storage| storage.borrow().iter().map(|(_, v)| v.clone()).collect());
-    let investors: Vec<_> = entitymanagement::INVESTOR_STORAGE.with(|storage| storage.borrow().iter().map(|(_, v)| v.clone()).collect());
-    let supply_agribusinesses: Vec<_> = entitymanagement::SUPPLY_AGRIBUSINESS_STORAGE.with(|storage| storage.borrow().iter().map(|(_, v)| v.clone()).collect());
-    let farms_agribusinesses: Vec<_> = entitymanagement::FARMS_AGRIBUSINESS_STORAGE.with(|storage| storage.borrow().iter().map(|(_, v)| v.clone()).collect());
-    let orders: Vec<_> = entitymanagement::ORDER_STORAGE.with(|storage| storage.borrow().iter().map(|(_, v)| v.clone()).collect());
-    let files: Vec<_> = entitymanagement::FILE_STORAGE.with(|storage| 
-        storage.borrow().iter()
-        .map(|(k, v)| (k.clone(), v.clone()))
-        .collect()
-    );
-    let agribiz_files: Vec<_> = farmsagribizmanagement::AGRIBIZ_FILE_STORAGE.with(|storage| 
-        storage.borrow().iter()
-        .map(|(k, v)| (k.clone(), v.clone()))
-        .collect()
-    );
-    
-    let file_infos: Vec<_> = farmsagribizmanagement::FILE_INFO_STORAGE.with(|storage|
-        storage.borrow().iter()
-        .map(|(k, v)| (k.clone(), v.clone()))
-        .collect()
-    );
-
-    let farm_reports: Vec<_> = farmerfiles::FARM_REPORTS.with(|reports| 
-        reports.borrow().iter()
-        .map(|(k, v)| (k.clone(), v.clone()))
-        .collect()
-    );
-
-    let farm_images: Vec<_> = farmsagribizmanagement::FARM_IMAGES.with(|images| 
-        images.borrow().iter()
-        .map(|(k, v)| (k.clone(), v.clone()))
-        .collect()
-    );    
-
-    storage::stable_save((
-        investor_investments,
-        farm_investments,
-        transaction_fees,
-        farmers,
-        investors,
-        supply_agribusinesses,
-        farms_agribusinesses,
-        orders,
-        files, 
-        agribiz_files,
-        file_infos,
-        farm_reports,
-        farm_images,
-    )).expect("Failed to save stable state");
+#[ic_cdk::init]
+fn init(args: Option<CanisterArgs>) {
+    let (admins, settings) = match args {
+        Some(CanisterArgs::Init(args)) => args.into_parts(),
+        Some(CanisterArgs::Upgrade(_)) => ic_cdk::trap("Install the canister with Init arguments"),
+        None => Default::default(),
+    };
+    migrations::mark_current_schema();
+    canistersettings::apply_settings(settings);
+    accesscontrol::bootstrap_roles(admins);
+    auditlog::certify_head();
+
+    loantimers::start_loan_timers();
+    depositaccounts::start_deposit_sweeper();
+    deposits::start_pending_deposit_checks();
+    withdrawals::start_withdrawal_polling();
 }
 
 // Restoring Stable State
 #[ic_cdk::post_upgrade]
-fn post_upgrade() {
-    match storage::stable_restore::<(
-        payments::InvestorInvestments,
-        payments::FarmInvestments,
-        HashMap<String, f64>,
-        Vec<entitymanagement::Farmer>,
-        Vec<entitymanagement::Investor>,
-        Vec<entitymanagement::SupplyAgriBusiness>,
-        Vec<entitymanagement::FarmsAgriBusiness>,
-        Vec<entitymanagement::Order>,
-        Vec<(entitymanagement::BoundedString, entitymanagement::BoundedBytes)>,
-        Vec<(entitymanagement::BoundedString, entitymanagement::BoundedBytes)>,
-        Vec<(u64, farmsagribizmanagement::FileInfo)>,
-        Option<Vec<(u64, farmerfiles::FarmerReportVec)>>,
-        Option<Vec<(u64, farmsagribizmanagement::ImagesBoundedBytes)>>,
-    )>() {
-        Ok((
-            investor_investments,
-            farm_investments,
-            transaction_fees,
-            farmers,
-            investors,
-            supply_agribusinesses,
-            farms_agribusinesses,
-            orders,
-            files,
-            agribiz_files,
-            file_infos,
-            farm_reports,
-            farm_images,
-        )) => {
-            payments::INVESTOR_INVESTMENTS.with(|investor_investments_cell| {
-                *investor_investments_cell.borrow_mut() = investor_investments;
-            });
-
-            payments::FARM_INVESTMENTS.with(|farm_investments_cell| {
-                *farm_investments_cell.borrow_mut() = farm_investments;
-            });
-
-            transaction_fees::TRANSACTION_FEES.with(|transaction_fees_cell| {
-                *transaction_fees_cell.borrow_mut() = transaction_fees;
-            });
-
-            entitymanagement::FARMER_STORAGE.with(|storage| {
-                let mut storage = storage.borrow_mut();
-                let keys: Vec<_> = storage.iter().map(|(k, _)| k).collect();
-                for key in keys {
-                    storage.remove(&key);
-                }
-                for farmer in farmers {
-                    storage.insert(farmer.id, farmer);
-                }
-            });
+fn post_upgrade(args: Option<CanisterArgs>) {
+    migrations::run_migrations();
 
-            entitymanagement::INVESTOR_STORAGE.with(|storage| {
-                let mut storage = storage.borrow_mut();
-                let keys: Vec<_> = storage.iter().map(|(k, _)| k).collect();
-                for key in keys {
-                    storage.remove(&key);
-                }
-                for investor in investors {
-                    storage.insert(investor.id, investor);
-                }
-            });
-
-            entitymanagement::SUPPLY_AGRIBUSINESS_STORAGE.with(|storage| {
-                let mut storage = storage.borrow_mut();
-                let keys: Vec<_> = storage.iter().map(|(k, _)| k).collect();
-                for key in keys {
-                    storage.remove(&key);
-                }
-                for supply_agribusiness in supply_agribusinesses {
-                    storage.insert(supply_agribusiness.id, supply_agribusiness);
-                }
-            });
-
-            entitymanagement::FARMS_AGRIBUSINESS_STORAGE.with(|storage| {
-                let mut storage = storage.borrow_mut();
-                let keys: Vec<_> = storage.iter().map(|(k, _)| k).collect();
-                for key in keys {
-                    storage.remove(&key);
-                }
-                for farms_agribusiness in farms_agribusinesses {
-                    storage.insert(farms_agribusiness.id, farms_agribusiness);
-                }
-            });
-
-            entitymanagement::ORDER_STORAGE.with(|storage| {
-                let mut storage = storage.borrow_mut();
-                let keys: Vec<_> = storage.iter().map(|(k, _)| k).collect();
-                for key in keys {
-                    storage.remove(&key);
-                }
-                for order in orders {
-                    storage.insert(order.order_id, order);
-                }
-            });
-
-            entitymanagement::FILE_STORAGE.with(|storage| {
-                let mut storage = storage.borrow_mut();
-                let keys: Vec<_> = storage.iter().map(|(k, _)| k).collect();
-                for key in keys {
-                    storage.remove(&key);
-                }
-                for (filename, data) in files {
-                    storage.insert(filename, data);
-                }
-            });
-
-            farmsagribizmanagement::AGRIBIZ_FILE_STORAGE.with(|storage| {
-                let mut storage = storage.borrow_mut();
-                for (filename, data) in agribiz_files {
-                    storage.insert(filename, data);
-                }
-            });
-    
-            farmsagribizmanagement::FILE_INFO_STORAGE.with(|storage| {
-                let mut storage = storage.borrow_mut();
-                for (id, info) in file_infos {
-                    storage.insert(id, info);
-                }
-            });
-
-            if let Some(reports) = farm_reports {
-                farmerfiles::FARM_REPORTS.with(|storage| {
-                    let mut storage = storage.borrow_mut();
-                    for (id, report_vec) in reports {
-                        storage.insert(id, report_vec);
-                    }
-                });
-            }
-
-            if let Some(images) = farm_images {
-                farmsagribizmanagement::FARM_IMAGES.with(|storage| {
-                    let mut storage = storage.borrow_mut();
-                    for (id, image_vec) in images {
-                        storage.insert(id, image_vec);
-                    }
-                });
-            }
-        }
-        Err(e) => {
-            ic_cdk::println!("Failed to restore stable state: {:?}", e);
-        }
+    match args {
+        Some(CanisterArgs::Upgrade(args)) => canistersettings::apply_settings(args),
+        Some(CanisterArgs::Init(_)) => ic_cdk::trap("Upgrade the canister with Upgrade arguments"),
+        None => {}
     }
+    auditlog::certify_head();
+
+    // Timers are cleared on upgrade
+    loantimers::start_loan_timers();
+    depositaccounts::start_deposit_sweeper();
+    deposits::start_pending_deposit_checks();
+    withdrawals::start_withdrawal_polling();
 }
 
 ic_cdk::export_candid!();
\ No newline at end of file
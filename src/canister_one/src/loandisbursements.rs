@@ -0,0 +1,254 @@
+use b3_utils::ledger::ICRCAccount;
+use candid::{CandidType, Decode, Encode, Nat, Principal};
+use ic_cdk::query;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::auditlog::{self, AuditAction};
+use crate::entitymanagement::{self, Error, Memory, MEMORY_MANAGER};
+use crate::fundingrounds;
+use crate::ledgertransfers::{self, PreparedTransfer, TransferAttempt};
+use crate::loanmanagement::{self, Loan, LoanStatus};
+use crate::tokens::{self, Token};
+use crate::transaction_fees;
+
+// How long a disbursement can stay Pending before another attempt assumes its send was interrupted
+const STALE_PENDING_DISBURSEMENT: u64 = 30 * 60 * 1_000_000_000; // 30 minutes in nanoseconds
+
+/**
+* DisbursementStatus Enum
+* Tracks whether the funds raised in one token have reached the farm.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum DisbursementStatus {
+    Pending,
+    Sent { block_index: Nat },
+    Failed { error: String },
+}
+
+/**
+* Disbursement Struct
+* The transfer to the farm of what a loan's funding round raised in one token, less that token's share of the
+* origination fee.
+* @param Defined In-Line
+* @return Disbursement instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Disbursement {
+    pub loan_id: u64,
+    pub farm_id: u64,
+    pub to: Principal,
+    pub token: Token,
+    pub raised: u128, // Committed to the round in this token, in its base units
+    pub fee: u128,    // Origination fee kept from the raised amount
+    pub amount: u128, // Sent to the farm
+    pub status: DisbursementStatus,
+    pub transfer_attempt: Option<TransferAttempt>,
+    pub last_attempt_at: Option<u64>,
+}
+
+impl Storable for Disbursement {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Disbursement {
+    const MAX_SIZE: u32 = 1_024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Disbursement {
+    // Whether a send can start now: never attempted, failed, or pending for so long its send was interrupted
+    fn is_due(&self, now: u64) -> bool {
+        match self.status {
+            DisbursementStatus::Sent { .. } => false,
+            DisbursementStatus::Failed { .. } => true,
+            DisbursementStatus::Pending => self
+                .last_attempt_at
+                .is_none_or(|at| now.saturating_sub(at) >= STALE_PENDING_DISBURSEMENT),
+        }
+    }
+}
+
+thread_local! {
+    // Disbursements keyed by (loan_id, sequence)
+    static DISBURSEMENTS: RefCell<StableBTreeMap<(u64, u64), Disbursement, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(57)))
+        ));
+}
+
+fn loan_disbursements(loan_id: u64) -> Vec<((u64, u64), Disbursement)> {
+    DISBURSEMENTS.with(|disbursements| {
+        disbursements
+            .borrow()
+            .range((loan_id, 0)..=(loan_id, u64::MAX))
+            .collect()
+    })
+}
+
+fn save_disbursement(key: (u64, u64), disbursement: &Disbursement) {
+    DISBURSEMENTS.with(|disbursements| disbursements.borrow_mut().insert(key, disbursement.clone()));
+}
+
+// Whether any of a loan's funds have been set aside for the farm, after which its round can no longer be refunded
+pub fn has_disbursements(loan_id: u64) -> bool {
+    !loan_disbursements(loan_id).is_empty()
+}
+
+// Records one disbursement per token the loan's round raised, the first time the loan is disbursed
+fn plan_disbursements(loan: &Loan) -> Result<(), Error> {
+    if has_disbursements(loan.id) {
+        return Ok(());
+    }
+
+    let farm = entitymanagement::FARMER_STORAGE
+        .with(|storage| storage.borrow().get(&loan.farm_id))
+        .ok_or_else(|| Error::FarmerNotFound {
+            msg: format!("Farm with ID {} not found", loan.farm_id),
+        })?;
+
+    let mut raised: BTreeMap<Token, u128> = BTreeMap::new();
+    for investment in fundingrounds::round_investments(loan.id) {
+        *raised.entry(investment.token).or_insert(0) += investment.amount;
+    }
+    if raised.is_empty() {
+        return Err(Error::Error {
+            msg: format!("Loan {} has no investments to disburse", loan.id),
+        });
+    }
+
+    for (sequence, (token, raised)) in raised.into_iter().enumerate() {
+        // Each token keeps the share of the fee the loan's principal does
+        let fee = raised * loan.origination_fee as u128 / loan.principal_amount.max(1) as u128;
+        save_disbursement(
+            (loan.id, sequence as u64),
+            &Disbursement {
+                loan_id: loan.id,
+                farm_id: loan.farm_id,
+                to: farm.principal_id,
+                token,
+                raised,
+                fee,
+                amount: raised - fee,
+                status: DisbursementStatus::Pending,
+                transfer_attempt: None,
+                last_attempt_at: None,
+            },
+        );
+    }
+    Ok(())
+}
+
+// Sends a disbursement to the farm over ICRC-1. The memo names the loan and the token; resends within the ledger's
+// deduplication window reuse the previous attempt, and later ones first look it up on the ledger.
+async fn send_disbursement(key: (u64, u64), mut disbursement: Disbursement) -> Disbursement {
+    disbursement.last_attempt_at = Some(ic_cdk::api::time());
+    disbursement.status = DisbursementStatus::Pending;
+    save_disbursement(key, &disbursement);
+
+    let token = disbursement.token;
+    let mut memo = disbursement.loan_id.to_be_bytes().to_vec();
+    memo.extend_from_slice(token.symbol().as_bytes());
+    disbursement.status = match ledgertransfers::prepare_transfer(token, disbursement.transfer_attempt.as_ref(), &memo).await {
+        Ok(PreparedTransfer::AlreadySent { block_index }) => DisbursementStatus::Sent { block_index },
+        Ok(PreparedTransfer::Send(attempt)) => {
+            disbursement.transfer_attempt = Some(attempt.clone());
+            save_disbursement(key, &disbursement);
+
+            let to = ICRCAccount::new(disbursement.to, None);
+            match ledgertransfers::send_transfer(token, &attempt, to, disbursement.amount, memo).await {
+                Ok(block_index) => DisbursementStatus::Sent { block_index },
+                Err(error) => DisbursementStatus::Failed { error },
+            }
+        }
+        Err(error) => DisbursementStatus::Failed { error },
+    };
+    save_disbursement(key, &disbursement);
+
+    if matches!(disbursement.status, DisbursementStatus::Sent { .. }) {
+        let reference = format!("loan:{}:origination:{}", disbursement.loan_id, token.symbol());
+        if let Err(e) = transaction_fees::store_transaction_fee(reference, disbursement.fee, token) {
+            ic_cdk::println!("Loan {}: failed to record the origination fee: {}", disbursement.loan_id, e);
+        }
+    }
+    auditlog::record(
+        AuditAction::TokensSent,
+        format!("disbursement:{}:{}", disbursement.loan_id, token.symbol()),
+        None,
+        Some(format!(
+            "{} {} to farm {}: {:?}",
+            tokens::format_units(disbursement.amount, token.decimals()),
+            token.symbol(),
+            disbursement.farm_id,
+            disbursement.status
+        )),
+    );
+    disbursement
+}
+
+/**
+* Function: disburse_funded_loan
+* Description: Sends the funds a loan raised to the farm and, once every transfer has gone through, moves the loan
+* to Disbursed and generates its repayment schedule. Transfers that fail leave the loan Funded; calling this again
+* retries them without resending those that went through.
+* @param loan_id: u64 - The ID of the funded loan
+* @return Result<Loan, Error> - The disbursed loan, or an error if the loan is not funded or a transfer failed
+*/
+pub async fn disburse_funded_loan(loan_id: u64) -> Result<Loan, Error> {
+    let loan = loanmanagement::find_loan(loan_id)?;
+    if loan.status != LoanStatus::Funded {
+        return Err(Error::InvalidLoanTransition {
+            msg: format!("Loan {} is not funded ({:?})", loan_id, loan.status),
+        });
+    }
+    plan_disbursements(&loan)?;
+
+    let now = ic_cdk::api::time();
+    for (key, disbursement) in loan_disbursements(loan_id) {
+        if disbursement.is_due(now) {
+            send_disbursement(key, disbursement).await;
+        }
+    }
+
+    let unsent: Vec<String> = loan_disbursements(loan_id)
+        .into_iter()
+        .filter(|(_, disbursement)| !matches!(disbursement.status, DisbursementStatus::Sent { .. }))
+        .map(|(_, disbursement)| format!("{}: {:?}", disbursement.token.symbol(), disbursement.status))
+        .collect();
+    if !unsent.is_empty() {
+        return Err(Error::TransferFailed {
+            msg: format!("Loan {} is not fully disbursed yet ({})", loan_id, unsent.join(", ")),
+        });
+    }
+
+    // Read again, since the loan may have been disbursed by another call while the transfers were awaited
+    let mut loan = loanmanagement::find_loan(loan_id)?;
+    if loan.status == LoanStatus::Funded {
+        loanmanagement::disburse_loan(&mut loan)?;
+        loanmanagement::save_loan(&loan);
+    }
+    Ok(loan)
+}
+
+/**
+* Function: get_loan_disbursements
+* Description: Returns the transfers of a loan's raised funds to its farm.
+* @param loan_id: u64 - The ID of the loan
+* @return Vec<Disbursement> - The loan's disbursements
+*/
+#[query]
+pub fn get_loan_disbursements(loan_id: u64) -> Vec<Disbursement> {
+    loan_disbursements(loan_id)
+        .into_iter()
+        .map(|(_, disbursement)| disbursement)
+        .collect()
+}
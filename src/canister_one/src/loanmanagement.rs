@@ -0,0 +1,734 @@
+use candid::{CandidType, Decode, Encode};
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::accesscontrol::{self, Role};
+use crate::auditlog::{self, AuditAction};
+use crate::entitymanagement::{self, Error, Memory, Success, TokenCollateral, MEMORY_MANAGER};
+use crate::fundingrounds;
+use crate::loanpricing::LoanQuote;
+use crate::refunds;
+use crate::loandisbursements;
+use crate::tokens::Token;
+
+pub const FUNDING_ROUND_DURATION: Duration = Duration::from_secs(30 * 24 * 60 * 60); // 1 month
+pub const INSTALLMENT_INTERVAL: Duration = Duration::from_secs(30 * 24 * 60 * 60); // Monthly installments
+// Token loans are raised, disbursed and repaid in; loan units are its base units
+pub const LOAN_TOKEN: Token = Token::CkUSDC;
+pub const DEFAULT_INSTALLMENT_COUNT: u32 = 6; // Tenor used when the farm does not pick one
+pub const MAX_COLLATERAL_CURRENCY_LEN: usize = 32;
+
+// Largest encoding of one installment: four nat64 fields and an opt nat64
+const MAX_INSTALLMENT_SIZE: u32 = 4 * 8 + 1 + 8;
+// Room kept in a stored loan for the candid type table and every field other than the installments
+const LOAN_HEADER_SIZE: u32 = 2_048;
+// Most installments a loan can hold and still fit its bounded size
+pub const MAX_INSTALLMENT_COUNT: u32 = (<Loan as BoundedStorable>::MAX_SIZE - LOAN_HEADER_SIZE) / MAX_INSTALLMENT_SIZE;
+
+const BPS_DENOMINATOR: u128 = 10_000;
+const SECONDS_PER_YEAR: u128 = 365 * 24 * 60 * 60;
+
+/**
+* LoanStatus Enum
+* Represents the lifecycle of a loan.
+* Requested -> Funding -> Funded -> Disbursed -> Repaying -> Repaid / Defaulted / Cancelled
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoanStatus {
+    Requested,
+    Funding,
+    Funded,
+    Disbursed,
+    Repaying,
+    Repaid,
+    Defaulted,
+    Cancelled,
+}
+
+impl LoanStatus {
+    /**
+    * Function: can_transition_to
+    * Description: Checks whether a loan in this state is allowed to move to the next state.
+    * @param next: &LoanStatus - The state the loan should move to
+    * @return bool - true if the transition is allowed
+    */
+    pub fn can_transition_to(&self, next: &LoanStatus) -> bool {
+        use LoanStatus::*;
+
+        matches!(
+            (self, next),
+            (Requested, Funding)
+                | (Requested, Cancelled)
+                | (Funding, Funded)
+                | (Funding, Cancelled)
+                | (Funded, Disbursed)
+                | (Funded, Cancelled)
+                | (Disbursed, Repaying)
+                | (Disbursed, Repaid)
+                | (Disbursed, Defaulted)
+                | (Repaying, Repaid)
+                | (Repaying, Defaulted)
+        )
+    }
+
+    // Repaid, Defaulted and Cancelled loans can no longer change state
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            LoanStatus::Repaid | LoanStatus::Defaulted | LoanStatus::Cancelled
+        )
+    }
+}
+
+/**
+* Installment Struct
+* Represents a single period of a loan's repayment schedule.
+* @param Defined In-Line
+* @return Installment instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct Installment {
+    pub due_date: u64,        // Timestamp (ns) the installment falls due
+    pub principal_due: u64,   // Principal portion of the installment
+    pub interest_due: u64,    // Interest portion of the installment
+    pub amount_paid: u64,     // Amount settled so far
+    pub paid_at: Option<u64>, // Timestamp (ns) the installment was fully settled
+}
+
+impl Installment {
+    pub fn total_due(&self) -> u64 {
+        self.principal_due + self.interest_due
+    }
+
+    pub fn outstanding(&self) -> u64 {
+        self.total_due().saturating_sub(self.amount_paid)
+    }
+}
+
+/**
+* Loan Struct
+* Represents a single loan taken by a farm, from the request through to repayment.
+* Amounts are denominated in loan units (the same unit as `Farmer.max_loan_amount`).
+* @param Defined In-Line
+* @return Loan instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct Loan {
+    pub id: u64,
+    pub farm_id: u64,
+    pub principal_amount: u64,
+    pub interest_rate_bps: u64, // Annual interest rate in basis points
+    pub origination_fee: u64,   // Kept from the principal at disbursement; the full principal is repaid
+    pub apr_bps: u64,
+    pub installment_count: u32,
+    pub installment_interval: Duration,
+    pub token_collateral: Option<TokenCollateral>,
+    pub status: LoanStatus,
+    pub requested_at: u64,
+    pub funding_round_start_time: Option<u64>,
+    pub funding_round_expiry: Option<u64>,
+    pub disbursed_at: Option<u64>,
+    pub maturity: Option<u64>,
+    pub closed_at: Option<u64>,
+    pub overdue_since: Option<u64>, // Set once the loan is past maturity with a balance still owed
+    pub installments: Vec<Installment>,
+}
+
+impl Loan {
+    /**
+    * Function: transition
+    * Description: Moves the loan to the next state if the transition is allowed.
+    * @param next: LoanStatus - The state the loan should move to
+    * @return Result<(), Error> - Ok if the transition was applied, otherwise an error
+    */
+    pub fn transition(&mut self, next: LoanStatus) -> Result<(), Error> {
+        self.transition_at(next, ic_cdk::api::time())
+    }
+
+    // Applies a transition as of the given time
+    fn transition_at(&mut self, next: LoanStatus, now: u64) -> Result<(), Error> {
+        if !self.status.can_transition_to(&next) {
+            return Err(Error::InvalidLoanTransition {
+                msg: format!(
+                    "Loan {} cannot move from {:?} to {:?}",
+                    self.id, self.status, next
+                ),
+            });
+        }
+
+        self.status = next;
+        if next.is_terminal() {
+            self.closed_at = Some(now);
+        }
+        Ok(())
+    }
+
+    // What the farm receives at disbursement: the principal less the origination fee
+    pub fn disbursed_amount(&self) -> u64 {
+        self.principal_amount.saturating_sub(self.origination_fee)
+    }
+
+    // Total principal and interest still owed on the loan
+    pub fn outstanding_balance(&self) -> u64 {
+        self.installments.iter().map(|i| i.outstanding()).sum()
+    }
+
+    /**
+    * Function: apply_payment
+    * Description: Applies a payment to the oldest outstanding installments first, recording partial payments,
+    * and moves the loan to Repaying or Repaid accordingly.
+    * @param amount: u64 - The amount paid
+    * @return Result<Vec<(u32, u64)>, Error> - (installment index, amount applied) for each installment touched
+    */
+    pub fn apply_payment(&mut self, amount: u64) -> Result<Vec<(u32, u64)>, Error> {
+        self.apply_payment_at(amount, ic_cdk::api::time())
+    }
+
+    // Applies a payment as of the given time
+    fn apply_payment_at(&mut self, amount: u64, current_time: u64) -> Result<Vec<(u32, u64)>, Error> {
+        if !matches!(self.status, LoanStatus::Disbursed | LoanStatus::Repaying) {
+            return Err(Error::InvalidLoanTransition {
+                msg: format!("Loan {} is not open for repayment ({:?})", self.id, self.status),
+            });
+        }
+
+        let mut remaining = amount;
+        let mut allocations = Vec::new();
+
+        for (index, installment) in self.installments.iter_mut().enumerate() {
+            if remaining == 0 {
+                break;
+            }
+
+            let payment = remaining.min(installment.outstanding());
+            if payment == 0 {
+                continue;
+            }
+
+            installment.amount_paid += payment;
+            if installment.outstanding() == 0 {
+                installment.paid_at = Some(current_time);
+            }
+            remaining -= payment;
+            allocations.push((index as u32, payment));
+        }
+
+        if self.outstanding_balance() == 0 {
+            self.transition_at(LoanStatus::Repaid, current_time)?;
+        } else if self.status == LoanStatus::Disbursed {
+            self.transition_at(LoanStatus::Repaying, current_time)?;
+        }
+
+        Ok(allocations)
+    }
+}
+
+impl Storable for Loan {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Loan {
+    const MAX_SIZE: u32 = 8_192; // Bounds the tenor, see MAX_INSTALLMENT_COUNT
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Stores every loan keyed by loan id
+    pub static LOAN_STORAGE: RefCell<StableBTreeMap<u64, Loan, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+        ));
+
+    // Index of loans per farm: (farm_id, loan_id) => ()
+    pub static FARM_LOANS: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+        ));
+}
+
+/**
+* Function: generate_installment_schedule
+* Description: Splits the principal into equal installments and charges interest on the declining balance.
+* Any rounding remainder of the principal is added to the final installment.
+* @param principal: u64 - Principal amount of the loan
+* @param interest_rate_bps: u64 - Annual interest rate in basis points
+* @param installment_count: u32 - Number of installments
+* @param interval: Duration - Time between installments
+* @param start_time: u64 - Timestamp (ns) the schedule starts from
+* @return Vec<Installment> - The generated repayment schedule
+*/
+pub fn generate_installment_schedule(
+    principal: u64,
+    interest_rate_bps: u64,
+    installment_count: u32,
+    interval: Duration,
+    start_time: u64,
+) -> Vec<Installment> {
+    if installment_count == 0 {
+        return Vec::new();
+    }
+
+    let count = installment_count as u64;
+    let base_principal = principal / count;
+    let remainder = principal % count;
+    let interval_nanos = interval.as_nanos() as u64;
+    let mut outstanding = principal as u128;
+
+    (1..=count)
+        .map(|period| {
+            let principal_due = if period == count {
+                base_principal + remainder
+            } else {
+                base_principal
+            };
+            let interest_due = outstanding * interest_rate_bps as u128 * interval.as_secs() as u128
+                / (BPS_DENOMINATOR * SECONDS_PER_YEAR);
+            outstanding -= principal_due as u128;
+
+            Installment {
+                due_date: start_time + interval_nanos * period,
+                principal_due,
+                interest_due: interest_due as u64,
+                amount_paid: 0,
+                paid_at: None,
+            }
+        })
+        .collect()
+}
+
+/**
+* Function: create_loan
+* Description: Creates a loan for a farm and opens its funding round.
+* The quoted terms are stamped onto the loan so later pricing changes do not affect it.
+* @param quote: &LoanQuote - The terms the loan is taken on
+* @param token_collateral: TokenCollateral - Collateral pledged against the loan
+* @return Result<Loan, Error> - The created loan, or an error if the farm already has an active loan
+*/
+pub fn create_loan(quote: &LoanQuote, token_collateral: TokenCollateral) -> Result<Loan, Error> {
+    let farm_id = quote.farm_id;
+    if token_collateral.currency.len() > MAX_COLLATERAL_CURRENCY_LEN {
+        return Err(Error::Error {
+            msg: format!("Collateral currency must be at most {} bytes", MAX_COLLATERAL_CURRENCY_LEN),
+        });
+    }
+    if get_active_loan_for_farm(farm_id).is_some() {
+        return Err(Error::Error {
+            msg: "You cannot ask for a loan while processing another loan!".to_string(),
+        });
+    }
+
+    let current_time = ic_cdk::api::time();
+    let id = LOAN_STORAGE.with(|loans| {
+        loans
+            .borrow()
+            .last_key_value()
+            .map(|(id, _)| id + 1)
+            .unwrap_or(1)
+    });
+
+    let mut loan = Loan {
+        id,
+        farm_id,
+        principal_amount: quote.amount,
+        interest_rate_bps: quote.interest_rate_bps,
+        origination_fee: quote.origination_fee,
+        apr_bps: quote.apr_bps,
+        installment_count: quote.tenor,
+        installment_interval: INSTALLMENT_INTERVAL,
+        token_collateral: Some(token_collateral),
+        status: LoanStatus::Requested,
+        requested_at: current_time,
+        funding_round_start_time: None,
+        funding_round_expiry: None,
+        disbursed_at: None,
+        maturity: None,
+        closed_at: None,
+        overdue_since: None,
+        installments: Vec::new(),
+    };
+
+    // Open the funding round straight away
+    loan.transition(LoanStatus::Funding)?;
+    loan.funding_round_start_time = Some(current_time);
+    loan.funding_round_expiry = Some(current_time + FUNDING_ROUND_DURATION.as_nanos() as u64);
+
+    save_loan(&loan);
+    FARM_LOANS.with(|index| index.borrow_mut().insert((farm_id, id), ()));
+
+    Ok(loan)
+}
+
+/**
+* Function: disburse_loan
+* Description: Marks a funded loan as disbursed and generates its repayment schedule from the current time. Only
+* called once the raised funds have reached the farm, see `loandisbursements::disburse_funded_loan`.
+* @param loan: &mut Loan - The loan to disburse
+* @return Result<(), Error> - Ok if the loan was disbursed, otherwise an error
+*/
+pub fn disburse_loan(loan: &mut Loan) -> Result<(), Error> {
+    loan.transition(LoanStatus::Disbursed)?;
+
+    let current_time = ic_cdk::api::time();
+    loan.disbursed_at = Some(current_time);
+    loan.installments = generate_installment_schedule(
+        loan.principal_amount,
+        loan.interest_rate_bps,
+        loan.installment_count,
+        loan.installment_interval,
+        current_time,
+    );
+    loan.maturity = loan.installments.last().map(|i| i.due_date);
+    Ok(())
+}
+
+// Persists a loan and mirrors its state onto the farmer record
+pub fn save_loan(loan: &Loan) {
+    LOAN_STORAGE.with(|loans| loans.borrow_mut().insert(loan.id, loan.clone()));
+    sync_farmer_loan_fields(loan);
+}
+
+/**
+* Function: sync_farmer_loan_fields
+* Description: Keeps the loan summary fields on the Farmer record in line with the farm's loan.
+* @param loan: &Loan - The loan whose state should be reflected on the farmer
+* @return None
+*/
+fn sync_farmer_loan_fields(loan: &Loan) {
+    entitymanagement::FARMER_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        if let Some(mut farm) = storage.get(&loan.farm_id) {
+            if loan.status.is_terminal() {
+                farm.current_loan_ask = None;
+                farm.loaned = false;
+                farm.funding_round_start_time = None;
+                farm.time_for_funding_round_to_expire = None;
+                farm.loan_start_time = None;
+                farm.loan_maturity = None;
+            } else {
+                farm.current_loan_ask = Some(loan.principal_amount);
+                farm.loaned = matches!(loan.status, LoanStatus::Disbursed | LoanStatus::Repaying);
+                farm.token_collateral = loan.token_collateral.clone();
+
+                if loan.status == LoanStatus::Funding {
+                    farm.funding_round_start_time = loan.funding_round_start_time;
+                    farm.time_for_funding_round_to_expire = Some(FUNDING_ROUND_DURATION);
+                } else {
+                    farm.funding_round_start_time = None;
+                    farm.time_for_funding_round_to_expire = None;
+                }
+
+                farm.loan_start_time = loan.disbursed_at;
+                farm.loan_maturity = match (loan.disbursed_at, loan.maturity) {
+                    (Some(start), Some(maturity)) => {
+                        Some(Duration::from_nanos(maturity.saturating_sub(start)))
+                    }
+                    _ => None,
+                };
+            }
+            storage.insert(loan.farm_id, farm);
+        }
+    });
+}
+
+// Returns all loans taken by a farm, oldest first
+pub fn loans_for_farm(farm_id: u64) -> Vec<Loan> {
+    let loan_ids: Vec<u64> = FARM_LOANS.with(|index| {
+        index
+            .borrow()
+            .range((farm_id, 0)..=(farm_id, u64::MAX))
+            .map(|((_, loan_id), _)| loan_id)
+            .collect()
+    });
+
+    LOAN_STORAGE.with(|loans| {
+        let loans = loans.borrow();
+        loan_ids.iter().filter_map(|id| loans.get(id)).collect()
+    })
+}
+
+// Returns the farm's loan that has not yet been repaid, defaulted or cancelled
+pub fn get_active_loan_for_farm(farm_id: u64) -> Option<Loan> {
+    loans_for_farm(farm_id)
+        .into_iter()
+        .rev()
+        .find(|loan| !loan.status.is_terminal())
+}
+
+pub fn find_loan(loan_id: u64) -> Result<Loan, Error> {
+    LOAN_STORAGE
+        .with(|loans| loans.borrow().get(&loan_id))
+        .ok_or_else(|| Error::LoanNotFound {
+            msg: format!("No loan found with ID: {}", loan_id),
+        })
+}
+
+/**
+* Function: get_loan
+* Description: Retrieves the details of a specific loan by its ID.
+* @param loan_id: u64 - The ID of the loan to be retrieved
+* @return Result<Loan, Error> - The Loan instance if found, or an error message otherwise
+*/
+#[query]
+pub fn get_loan(loan_id: u64) -> Result<Loan, Error> {
+    find_loan(loan_id)
+}
+
+/**
+* Function: get_farm_loans
+* Description: Retrieves the loan history of a farm, oldest first.
+* @param farm_id: u64 - The ID of the farm
+* @return Vec<Loan> - All loans taken by the farm
+*/
+#[query]
+pub fn get_farm_loans(farm_id: u64) -> Vec<Loan> {
+    loans_for_farm(farm_id)
+}
+
+/**
+* Function: get_active_loan
+* Description: Retrieves the farm's current loan, if any.
+* @param farm_id: u64 - The ID of the farm
+* @return Result<Loan, Error> - The active loan, or an error if the farm has none
+*/
+#[query]
+pub fn get_active_loan(farm_id: u64) -> Result<Loan, Error> {
+    get_active_loan_for_farm(farm_id).ok_or_else(|| Error::LoanNotFound {
+        msg: format!("No active loan for farm_id: {}", farm_id),
+    })
+}
+
+/**
+* Function: cancel_loan
+* Description: Cancels a loan that has not yet been disbursed and refunds every investment committed to its funding
+* round. Only the farm owner, its agribusiness or a loan officer can cancel.
+* @param loan_id: u64 - The ID of the loan to cancel
+* @return Result<Success, Error> - Success message if the loan was cancelled, or an error message otherwise
+*/
+#[update]
+pub async fn cancel_loan(loan_id: u64) -> Result<Success, Error> {
+    let mut loan = find_loan(loan_id)?;
+    let caller = ic_cdk::caller();
+
+    let farm = entitymanagement::FARMER_STORAGE
+        .with(|storage| storage.borrow().get(&loan.farm_id))
+        .ok_or_else(|| Error::FarmerNotFound {
+            msg: format!("Farm with ID {} not found", loan.farm_id),
+        })?;
+
+    if farm.principal_id != caller
+        && farm.agri_business != caller.to_string()
+        && !accesscontrol::caller_has_role(Role::LoanOfficer)
+    {
+        return Err(Error::NotAuthorized {
+            msg: "Only the farm owner, its agribusiness or a loan officer can cancel this loan".to_string(),
+        });
+    }
+
+    // Once funds have gone to the farm the round cannot be refunded
+    if loandisbursements::has_disbursements(loan_id) {
+        return Err(Error::InvalidLoanTransition {
+            msg: format!("Loan {} is being disbursed and can no longer be cancelled", loan_id),
+        });
+    }
+
+    let previous_status = loan.status;
+    loan.transition(LoanStatus::Cancelled)?;
+    save_loan(&loan);
+    auditlog::record(
+        AuditAction::LoanCancelled,
+        format!("loan:{}", loan_id),
+        Some(format!("{:?}", previous_status)),
+        Some(format!("{:?}", loan.status)),
+    );
+
+    if fundingrounds::round_investments(loan_id).is_empty() {
+        return Ok(Success::LoanCancelled {
+            msg: format!("Loan {} has been cancelled", loan_id),
+        });
+    }
+
+    // The refunds are recorded before the first transfer is awaited; failed ones can be retried with retry_refund
+    let refunds = refunds::refund_round(loan_id).await?;
+    Ok(Success::LoanCancelled {
+        msg: format!("Loan {} has been cancelled and {} investments queued for refund", loan_id, refunds.len()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_STATUSES: [LoanStatus; 8] = [
+        LoanStatus::Requested,
+        LoanStatus::Funding,
+        LoanStatus::Funded,
+        LoanStatus::Disbursed,
+        LoanStatus::Repaying,
+        LoanStatus::Repaid,
+        LoanStatus::Defaulted,
+        LoanStatus::Cancelled,
+    ];
+
+    #[test]
+    fn only_lifecycle_transitions_are_allowed() {
+        use LoanStatus::*;
+        let allowed = [
+            (Requested, Funding),
+            (Requested, Cancelled),
+            (Funding, Funded),
+            (Funding, Cancelled),
+            (Funded, Disbursed),
+            (Funded, Cancelled),
+            (Disbursed, Repaying),
+            (Disbursed, Repaid),
+            (Disbursed, Defaulted),
+            (Repaying, Repaid),
+            (Repaying, Defaulted),
+        ];
+
+        for from in ALL_STATUSES {
+            for to in ALL_STATUSES {
+                assert_eq!(
+                    from.can_transition_to(&to),
+                    allowed.contains(&(from, to)),
+                    "{:?} -> {:?}",
+                    from,
+                    to
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn terminal_statuses_cannot_move() {
+        for from in ALL_STATUSES.into_iter().filter(|status| status.is_terminal()) {
+            assert!(ALL_STATUSES.iter().all(|to| !from.can_transition_to(to)), "{:?}", from);
+        }
+    }
+
+    #[test]
+    fn schedule_repays_the_whole_principal_with_the_remainder_last() {
+        let schedule = generate_installment_schedule(1_000_003, 1_200, 6, INSTALLMENT_INTERVAL, 0);
+
+        assert_eq!(schedule.len(), 6);
+        assert!(schedule[..5].iter().all(|installment| installment.principal_due == 166_667));
+        assert_eq!(schedule[5].principal_due, 166_668);
+        assert_eq!(schedule.iter().map(|installment| installment.principal_due).sum::<u64>(), 1_000_003);
+        assert!(schedule.iter().all(|installment| installment.amount_paid == 0 && installment.paid_at.is_none()));
+    }
+
+    #[test]
+    fn schedule_charges_interest_on_the_declining_balance() {
+        // 12% a year on 1,200,000 over 30-day periods
+        let schedule = generate_installment_schedule(1_200_000, 1_200, 3, INSTALLMENT_INTERVAL, 0);
+        let interest: Vec<u64> = schedule.iter().map(|installment| installment.interest_due).collect();
+
+        assert_eq!(interest, vec![11_835, 7_890, 3_945]);
+    }
+
+    #[test]
+    fn schedule_falls_due_every_interval_after_the_start() {
+        let start = 1_000;
+        let schedule = generate_installment_schedule(600, 0, 3, INSTALLMENT_INTERVAL, start);
+        let interval = INSTALLMENT_INTERVAL.as_nanos() as u64;
+
+        let due_dates: Vec<u64> = schedule.iter().map(|installment| installment.due_date).collect();
+        assert_eq!(due_dates, vec![start + interval, start + 2 * interval, start + 3 * interval]);
+        assert!(schedule.iter().all(|installment| installment.interest_due == 0));
+    }
+
+    #[test]
+    fn schedule_without_installments_is_empty() {
+        assert!(generate_installment_schedule(1_000, 1_200, 0, INSTALLMENT_INTERVAL, 0).is_empty());
+    }
+
+    // A disbursed loan of 300 repaid in three interest-free installments of 100
+    fn disbursed_loan() -> Loan {
+        Loan {
+            id: 1,
+            farm_id: 1,
+            principal_amount: 300,
+            interest_rate_bps: 0,
+            origination_fee: 0,
+            apr_bps: 0,
+            installment_count: 3,
+            installment_interval: INSTALLMENT_INTERVAL,
+            token_collateral: None,
+            status: LoanStatus::Disbursed,
+            requested_at: 0,
+            funding_round_start_time: None,
+            funding_round_expiry: None,
+            disbursed_at: Some(0),
+            maturity: None,
+            closed_at: None,
+            overdue_since: None,
+            installments: generate_installment_schedule(300, 0, 3, INSTALLMENT_INTERVAL, 0),
+        }
+    }
+
+    #[test]
+    fn payment_settles_the_oldest_installments_first() {
+        let mut loan = disbursed_loan();
+
+        let allocations = loan.apply_payment_at(150, 7).unwrap();
+
+        assert_eq!(allocations, vec![(0, 100), (1, 50)]);
+        assert_eq!(loan.installments[0].paid_at, Some(7));
+        assert_eq!(loan.installments[1].amount_paid, 50);
+        assert_eq!(loan.installments[1].paid_at, None);
+        assert_eq!(loan.outstanding_balance(), 150);
+        assert_eq!(loan.status, LoanStatus::Repaying);
+    }
+
+    #[test]
+    fn partial_payments_add_up_on_an_installment() {
+        let mut loan = disbursed_loan();
+
+        assert_eq!(loan.apply_payment_at(40, 1).unwrap(), vec![(0, 40)]);
+        assert_eq!(loan.apply_payment_at(80, 2).unwrap(), vec![(0, 60), (1, 20)]);
+
+        assert_eq!(loan.installments[0].paid_at, Some(2));
+        assert_eq!(loan.installments[1].amount_paid, 20);
+        assert_eq!(loan.outstanding_balance(), 180);
+    }
+
+    #[test]
+    fn paying_the_balance_repays_and_closes_the_loan() {
+        let mut loan = disbursed_loan();
+
+        let allocations = loan.apply_payment_at(300, 9).unwrap();
+
+        assert_eq!(allocations, vec![(0, 100), (1, 100), (2, 100)]);
+        assert_eq!(loan.status, LoanStatus::Repaid);
+        assert_eq!(loan.closed_at, Some(9));
+    }
+
+    #[test]
+    fn overpayment_is_not_applied() {
+        let mut loan = disbursed_loan();
+
+        let applied: u64 = loan.apply_payment_at(500, 1).unwrap().iter().map(|(_, amount)| amount).sum();
+
+        assert_eq!(applied, 300);
+        assert_eq!(loan.status, LoanStatus::Repaid);
+    }
+
+    #[test]
+    fn payment_is_rejected_unless_the_loan_is_being_repaid() {
+        let mut loan = disbursed_loan();
+        loan.status = LoanStatus::Funded;
+
+        assert!(loan.apply_payment_at(100, 1).is_err());
+        assert_eq!(loan.outstanding_balance(), 300);
+    }
+}
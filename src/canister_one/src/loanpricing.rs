@@ -0,0 +1,357 @@
+use candid::{CandidType, Decode, Encode};
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableCell, Storable};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::accesscontrol::caller_is_super_admin;
+use crate::auditlog::{self, AuditAction};
+use crate::entitymanagement::{self, Error, Memory, Success, MEMORY_MANAGER};
+use crate::loanmanagement::{self, INSTALLMENT_INTERVAL};
+
+const BPS_DENOMINATOR: u64 = 10_000;
+const MONTHS_PER_YEAR: u64 = 12;
+
+/**
+* CreditBand Struct
+* Interest rate charged to farms whose credit score is at least `min_score`.
+* @param Defined In-Line
+* @return CreditBand instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CreditBand {
+    pub min_score: u64,
+    pub interest_rate_bps: u64, // Annual interest rate in basis points
+    pub origination_fee_bps: u64, // One-off fee on the principal
+}
+
+/**
+* TenorPremium Struct
+* Extra annual interest charged on loans with a tenor of at least `min_tenor` months.
+* @param Defined In-Line
+* @return TenorPremium instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TenorPremium {
+    pub min_tenor: u32,
+    pub premium_bps: u64,
+}
+
+/**
+* PricingConfig Struct
+* Maps credit-score bands and tenor to loan terms.
+* @param Defined In-Line
+* @return PricingConfig instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PricingConfig {
+    pub credit_bands: Vec<CreditBand>,
+    pub tenor_premiums: Vec<TenorPremium>,
+    pub min_tenor: u32, // Months
+    pub max_tenor: u32, // Months
+}
+
+impl Default for PricingConfig {
+    fn default() -> Self {
+        Self {
+            credit_bands: vec![
+                CreditBand { min_score: 0, interest_rate_bps: 2_400, origination_fee_bps: 300 },
+                CreditBand { min_score: 500, interest_rate_bps: 1_800, origination_fee_bps: 200 },
+                CreditBand { min_score: 650, interest_rate_bps: 1_400, origination_fee_bps: 150 },
+                CreditBand { min_score: 750, interest_rate_bps: 1_200, origination_fee_bps: 100 },
+            ],
+            tenor_premiums: vec![
+                TenorPremium { min_tenor: 7, premium_bps: 100 },
+                TenorPremium { min_tenor: 13, premium_bps: 250 },
+            ],
+            min_tenor: 1,
+            max_tenor: 24,
+        }
+    }
+}
+
+impl Storable for PricingConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl PricingConfig {
+    // Checks the config can price every credit score and tenor it accepts
+    fn validate(&self) -> Result<(), String> {
+        if self.credit_bands.is_empty() {
+            return Err("At least one credit band is required".to_string());
+        }
+        if !self.credit_bands.iter().any(|band| band.min_score == 0) {
+            return Err("A credit band starting at score 0 is required".to_string());
+        }
+        if self.min_tenor == 0 || self.min_tenor > self.max_tenor {
+            return Err("Tenor bounds are invalid".to_string());
+        }
+        // Every installment is stored on the loan, which has a bounded size
+        if self.max_tenor > loanmanagement::MAX_INSTALLMENT_COUNT {
+            return Err(format!(
+                "The maximum tenor cannot exceed {} months",
+                loanmanagement::MAX_INSTALLMENT_COUNT
+            ));
+        }
+        if self
+            .credit_bands
+            .iter()
+            .any(|band| band.origination_fee_bps >= BPS_DENOMINATOR)
+        {
+            return Err("Origination fees must be below 100%".to_string());
+        }
+        Ok(())
+    }
+
+    // Band with the highest minimum score the credit score reaches
+    fn band_for(&self, credit_score: u64) -> &CreditBand {
+        self.credit_bands
+            .iter()
+            .filter(|band| credit_score >= band.min_score)
+            .max_by_key(|band| band.min_score)
+            .expect("validated config has a band starting at 0")
+    }
+
+    // Premium for the longest tenor threshold the tenor reaches
+    fn premium_for(&self, tenor: u32) -> u64 {
+        self.tenor_premiums
+            .iter()
+            .filter(|premium| tenor >= premium.min_tenor)
+            .max_by_key(|premium| premium.min_tenor)
+            .map(|premium| premium.premium_bps)
+            .unwrap_or(0)
+    }
+}
+
+/**
+* LoanQuote Struct
+* Terms a farm would be offered for a loan of a given amount and tenor.
+* @param Defined In-Line
+* @return LoanQuote instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct LoanQuote {
+    pub farm_id: u64,
+    pub amount: u64,
+    pub tenor: u32, // Number of monthly installments
+    pub credit_score: u64,
+    pub interest_rate_bps: u64,
+    pub origination_fee: u64,  // Deducted from the amount when the loan is disbursed
+    pub disbursed_amount: u64, // What the farm receives: the amount less the origination fee
+    pub total_interest: u64,
+    pub total_repayable: u64,
+    pub apr_bps: u64, // Interest plus the origination fee, annualised
+}
+
+thread_local! {
+    pub static PRICING_CONFIG: RefCell<StableCell<PricingConfig, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+            PricingConfig::default(),
+        )
+        .expect("Failed to initialise pricing config")
+    );
+}
+
+/**
+* Function: price_loan
+* Description: Prices a loan for a farm from its credit score and the requested tenor.
+* @param farm_id: u64 - The ID of the farm
+* @param amount: u64 - The amount requested
+* @param tenor: u32 - The number of monthly installments
+* @return Result<LoanQuote, Error> - The quoted terms, or an error if the farm cannot borrow on these terms
+*/
+pub fn price_loan(farm_id: u64, amount: u64, tenor: u32) -> Result<LoanQuote, Error> {
+    let farm = entitymanagement::FARMER_STORAGE
+        .with(|storage| storage.borrow().get(&farm_id))
+        .ok_or_else(|| Error::FarmerNotFound {
+            msg: format!("Farm with ID {} not found", farm_id),
+        })?;
+
+    let credit_score = farm.credit_score.ok_or_else(|| Error::Error {
+        msg: "Credit score is not available!".to_string(),
+    })?;
+    let max_loan_amount = farm.max_loan_amount.ok_or_else(|| Error::Error {
+        msg: "Maximum loan amount is not available!".to_string(),
+    })?;
+
+    if amount == 0 {
+        return Err(Error::FieldEmpty {
+            msg: "Loan amount must be greater than zero".to_string(),
+        });
+    }
+    if amount > max_loan_amount {
+        return Err(Error::Error {
+            msg: "Loan ask should not be greater than maximum loan amount!".to_string(),
+        });
+    }
+
+    let config = PRICING_CONFIG.with(|config| config.borrow().get().clone());
+    if tenor < config.min_tenor || tenor > config.max_tenor {
+        return Err(Error::Error {
+            msg: format!(
+                "Tenor must be between {} and {} months",
+                config.min_tenor, config.max_tenor
+            ),
+        });
+    }
+
+    let band = config.band_for(credit_score);
+    let interest_rate_bps = band.interest_rate_bps + config.premium_for(tenor);
+    let origination_fee = amount * band.origination_fee_bps / BPS_DENOMINATOR;
+
+    let total_interest: u64 = loanmanagement::generate_installment_schedule(
+        amount,
+        interest_rate_bps,
+        tenor,
+        INSTALLMENT_INTERVAL,
+        0,
+    )
+    .iter()
+    .map(|installment| installment.interest_due)
+    .sum();
+
+    Ok(LoanQuote {
+        farm_id,
+        amount,
+        tenor,
+        credit_score,
+        interest_rate_bps,
+        origination_fee,
+        disbursed_amount: amount - origination_fee,
+        total_interest,
+        total_repayable: amount + total_interest,
+        apr_bps: interest_rate_bps
+            + band.origination_fee_bps * MONTHS_PER_YEAR / tenor as u64,
+    })
+}
+
+/**
+* Function: quote_loan
+* Description: Quotes the interest rate, fees and total cost of a loan without creating it.
+* @param farm_id: u64 - The ID of the farm
+* @param amount: u64 - The amount requested
+* @param tenor: u32 - The number of monthly installments
+* @return Result<LoanQuote, Error> - The quoted terms, or an error otherwise
+*/
+#[query]
+pub fn quote_loan(farm_id: u64, amount: u64, tenor: u32) -> Result<LoanQuote, Error> {
+    price_loan(farm_id, amount, tenor)
+}
+
+/**
+* Function: get_pricing_config
+* Description: Returns the credit bands, tenor premiums and tenor bounds used to price loans.
+* @param None
+* @return PricingConfig - The current pricing config
+*/
+#[query]
+pub fn get_pricing_config() -> PricingConfig {
+    PRICING_CONFIG.with(|config| config.borrow().get().clone())
+}
+
+/**
+* Function: set_pricing_config
+* Description: Replaces the pricing config. Loans already requested keep the terms they were quoted. SuperAdmin only.
+* @param config: PricingConfig - The new pricing config
+* @return Result<Success, Error> - Success message if the config was updated, or an error otherwise
+*/
+#[update(guard = "caller_is_super_admin")]
+pub fn set_pricing_config(config: PricingConfig) -> Result<Success, Error> {
+    config
+        .validate()
+        .map_err(|msg| Error::Error { msg })?;
+
+    let after = format!("{:?}", config);
+    let previous = PRICING_CONFIG
+        .with(|cell| cell.borrow_mut().set(config))
+        .map_err(|e| Error::Error {
+            msg: format!("Failed to save pricing config: {:?}", e),
+        })?;
+    auditlog::record(
+        AuditAction::ConfigChanged,
+        "pricing_config".to_string(),
+        Some(format!("{:?}", previous)),
+        Some(after),
+    );
+
+    Ok(Success::PricingConfigUpdated {
+        msg: "Loan pricing updated".to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entitymanagement::TokenCollateral;
+    use crate::loanmanagement::{Installment, Loan, LoanStatus, MAX_COLLATERAL_CURRENCY_LEN, MAX_INSTALLMENT_COUNT};
+    use ic_stable_structures::BoundedStorable;
+    use std::time::Duration;
+
+    // A loan with every field at its largest encoding
+    fn largest_loan(tenor: u32) -> Loan {
+        let installment = Installment {
+            due_date: u64::MAX,
+            principal_due: u64::MAX,
+            interest_due: u64::MAX,
+            amount_paid: u64::MAX,
+            paid_at: Some(u64::MAX),
+        };
+        Loan {
+            id: u64::MAX,
+            farm_id: u64::MAX,
+            principal_amount: u64::MAX,
+            interest_rate_bps: u64::MAX,
+            origination_fee: u64::MAX,
+            apr_bps: u64::MAX,
+            installment_count: u32::MAX,
+            installment_interval: Duration::new(u64::MAX, 999_999_999),
+            token_collateral: Some(TokenCollateral {
+                currency: "x".repeat(MAX_COLLATERAL_CURRENCY_LEN),
+                amount: u64::MAX,
+            }),
+            status: LoanStatus::Cancelled,
+            requested_at: u64::MAX,
+            funding_round_start_time: Some(u64::MAX),
+            funding_round_expiry: Some(u64::MAX),
+            disbursed_at: Some(u64::MAX),
+            maturity: Some(u64::MAX),
+            closed_at: Some(u64::MAX),
+            overdue_since: Some(u64::MAX),
+            installments: vec![installment; tenor as usize],
+        }
+    }
+
+    #[test]
+    fn largest_allowed_tenor_fits_in_a_stored_loan() {
+        let config = PricingConfig {
+            max_tenor: MAX_INSTALLMENT_COUNT,
+            ..PricingConfig::default()
+        };
+        assert!(config.validate().is_ok());
+
+        let encoded = largest_loan(config.max_tenor).to_bytes().len();
+        assert!(
+            encoded <= Loan::MAX_SIZE as usize,
+            "loan with {} installments encodes to {} bytes",
+            config.max_tenor,
+            encoded
+        );
+    }
+
+    #[test]
+    fn tenor_beyond_the_stored_loan_bound_is_rejected() {
+        let config = PricingConfig {
+            max_tenor: MAX_INSTALLMENT_COUNT + 1,
+            ..PricingConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,386 @@
+use b3_utils::ledger::ICRCAccount;
+use candid::{CandidType, Decode, Encode, Nat, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::accesscontrol::caller_is_treasurer;
+use crate::auditlog::{self, AuditAction};
+use crate::ckusdc_payments;
+use crate::entitymanagement::{Error, Memory, MEMORY_MANAGER};
+use crate::ifarm_tokens;
+use crate::investorpayouts;
+use crate::ledgertransfers::{self, PreparedTransfer, TransferAttempt};
+use crate::loanmanagement::{self, LoanStatus};
+use crate::tokens::{self, Token};
+
+// How long an unapplied refund can stay Pending before a retry assumes its send was interrupted
+const STALE_PENDING_REFUND: u64 = 30 * 60 * 1_000_000_000; // 30 minutes in nanoseconds
+
+/**
+* RepaymentCurrency Enum
+* Tokens a loan can be repaid in. Both settle 1:1 against loan units.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RepaymentCurrency {
+    CkUSDC,
+    IFarm,
+}
+
+impl RepaymentCurrency {
+    pub fn token(&self) -> Token {
+        match self {
+            RepaymentCurrency::CkUSDC => Token::CkUSDC,
+            RepaymentCurrency::IFarm => Token::IFarm,
+        }
+    }
+}
+
+/**
+* UnappliedRefund Enum
+* Outcome of returning the unapplied part of a repayment to the payer.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum UnappliedRefund {
+    Pending,
+    Refunded { block_index: Nat },
+    Failed { error: String },
+}
+
+/**
+* LoanRepayment Struct
+* Represents a single repayment received against a loan and how it was applied to the schedule.
+* @param Defined In-Line
+* @return LoanRepayment instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct LoanRepayment {
+    pub repayment_id: u64,
+    pub loan_id: u64,
+    pub farm_id: u64,
+    pub payer: Principal,
+    pub amount: u64,
+    pub currency: RepaymentCurrency,
+    pub block_index: Nat,              // Ledger block of the transfer_from
+    pub timestamp: u64,
+    pub allocations: Vec<(u32, u64)>, // (installment index, amount applied)
+    pub unapplied: u64,               // Received after the loan had already been settled
+    pub outstanding_after: u64,
+    pub unapplied_refund: Option<UnappliedRefund>, // Set when there is an unapplied amount to send back to the payer
+    pub unapplied_attempt: Option<TransferAttempt>, // Latest transfer of the unapplied amount, reused while the ledger deduplicates it
+}
+
+/**
+* LoanBalance Struct
+* Authoritative view of what is still owed on a loan.
+* @param Defined In-Line
+* @return LoanBalance instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct LoanBalance {
+    pub loan_id: u64,
+    pub status: LoanStatus,
+    pub principal_outstanding: u64,
+    pub interest_outstanding: u64,
+    pub total_outstanding: u64,
+    pub next_due_date: Option<u64>,
+    pub next_due_amount: u64,
+}
+
+impl Storable for LoanRepayment {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LoanRepayment {
+    const MAX_SIZE: u32 = 2_048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Repayments keyed by (loan_id, repayment_id)
+    pub static LOAN_REPAYMENTS: RefCell<StableBTreeMap<(u64, u64), LoanRepayment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+        ));
+}
+
+/**
+* Function: collect_repayment
+* Description: Pulls the repayment from the payer's account into the canister using ICRC-2 transfer_from.
+* @param payer: Principal - The account the funds are pulled from
+* @param amount: u64 - The amount to pull
+* @param currency: RepaymentCurrency - The ledger to pull from
+* @return Result<Nat, Error> - The ledger block index of the transfer, or an error
+*/
+async fn collect_repayment(
+    payer: Principal,
+    amount: u64,
+    currency: RepaymentCurrency,
+) -> Result<Nat, Error> {
+    let canister = ic_cdk::id();
+    let result = match currency {
+        RepaymentCurrency::CkUSDC => {
+            ckusdc_payments::ckusdc_transfer_from(payer, canister, Nat::from(amount)).await
+        }
+        RepaymentCurrency::IFarm => {
+            ifarm_tokens::ifarm_transfer_from(payer, canister, Nat::from(amount)).await
+        }
+    };
+
+    match result {
+        Ok(Ok(block_index)) => Ok(block_index),
+        Ok(Err(e)) => Err(Error::TransferFailed {
+            msg: format!("Ledger rejected the repayment: {:?}", e),
+        }),
+        Err(e) => Err(Error::TransferFailed { msg: e }),
+    }
+}
+
+/**
+* Function: repay_loan
+* Description: Repays a disbursed loan. Funds are pulled from the caller's account (the caller must first approve
+* this canister on the chosen ledger) and applied to the oldest outstanding installment first.
+* @param loan_id: u64 - The ID of the loan being repaid
+* @param amount: u64 - The amount to repay, in loan units
+* @param currency: RepaymentCurrency - The token the repayment is made in
+* @return Result<LoanRepayment, Error> - The recorded repayment, or an error message otherwise
+*/
+#[update]
+pub async fn repay_loan(
+    loan_id: u64,
+    amount: u64,
+    currency: RepaymentCurrency,
+) -> Result<LoanRepayment, Error> {
+    if amount == 0 {
+        return Err(Error::FieldEmpty {
+            msg: "Repayment amount must be greater than zero".to_string(),
+        });
+    }
+
+    let payer = ic_cdk::caller();
+    if payer == Principal::anonymous() {
+        return Err(Error::NotAuthorized {
+            msg: "Anonymous callers cannot repay loans".to_string(),
+        });
+    }
+
+    let loan = loanmanagement::find_loan(loan_id)?;
+    if !matches!(loan.status, LoanStatus::Disbursed | LoanStatus::Repaying) {
+        return Err(Error::InvalidLoanTransition {
+            msg: format!("Loan {} is not open for repayment ({:?})", loan_id, loan.status),
+        });
+    }
+
+    let outstanding = loan.outstanding_balance();
+    if amount > outstanding {
+        return Err(Error::Error {
+            msg: format!(
+                "Repayment of {} exceeds the outstanding balance of {}",
+                amount, outstanding
+            ),
+        });
+    }
+
+    let block_index = collect_repayment(payer, amount, currency).await?;
+
+    // Re-read the loan as another repayment may have landed while waiting on the ledger
+    let mut loan = loanmanagement::find_loan(loan_id)?;
+    let allocations = if matches!(loan.status, LoanStatus::Disbursed | LoanStatus::Repaying) {
+        loan.apply_payment(amount)?
+    } else {
+        Vec::new()
+    };
+    let applied: u64 = allocations.iter().map(|(_, paid)| paid).sum();
+    loanmanagement::save_loan(&loan);
+
+    let repayment_id = LOAN_REPAYMENTS.with(|repayments| {
+        repayments
+            .borrow()
+            .range((loan_id, 0)..=(loan_id, u64::MAX))
+            .count() as u64
+            + 1
+    });
+
+    let mut repayment = LoanRepayment {
+        repayment_id,
+        loan_id,
+        farm_id: loan.farm_id,
+        payer,
+        amount,
+        currency,
+        block_index,
+        timestamp: ic_cdk::api::time(),
+        allocations,
+        unapplied: amount - applied,
+        outstanding_after: loan.outstanding_balance(),
+        unapplied_refund: None,
+        unapplied_attempt: None,
+    };
+    let unapplied = repayment.unapplied;
+    if unapplied > 0 {
+        // Recorded before any await, so the refund can be retried if it never completes
+        repayment.unapplied_refund = Some(UnappliedRefund::Pending);
+    }
+    save_repayment(&repayment);
+
+    // Failed payouts are recorded on the payout ledger and retried separately, so they never fail the repayment
+    investorpayouts::distribute_repayment(&repayment).await;
+
+    if unapplied > 0 {
+        repayment = refund_unapplied(repayment).await;
+    }
+
+    Ok(repayment)
+}
+
+fn save_repayment(repayment: &LoanRepayment) {
+    LOAN_REPAYMENTS.with(|repayments| {
+        repayments
+            .borrow_mut()
+            .insert((repayment.loan_id, repayment.repayment_id), repayment.clone())
+    });
+}
+
+/**
+* Function: refund_unapplied
+* Description: Sends the part of a repayment that found nothing left to settle back to the payer over ICRC-1 and
+* records the outcome. The transfer's memo names the loan and repayment; resends within the ledger's deduplication
+* window reuse the previous attempt, and later ones first look it up on the ledger.
+* @param repayment: LoanRepayment - The repayment with an unapplied amount
+* @return LoanRepayment - The repayment with its unapplied refund updated
+*/
+async fn refund_unapplied(mut repayment: LoanRepayment) -> LoanRepayment {
+    let token = repayment.currency.token();
+    let mut memo = repayment.loan_id.to_be_bytes().to_vec();
+    memo.extend_from_slice(&repayment.repayment_id.to_be_bytes());
+
+    repayment.unapplied_refund = Some(UnappliedRefund::Pending);
+    save_repayment(&repayment);
+
+    let refund = match ledgertransfers::prepare_transfer(token, repayment.unapplied_attempt.as_ref(), &memo).await {
+        Ok(PreparedTransfer::AlreadySent { block_index }) => UnappliedRefund::Refunded { block_index },
+        Ok(PreparedTransfer::Send(attempt)) => {
+            repayment.unapplied_attempt = Some(attempt.clone());
+            save_repayment(&repayment);
+
+            let to = ICRCAccount::new(repayment.payer, None);
+            match ledgertransfers::send_transfer(token, &attempt, to, repayment.unapplied as u128, memo).await {
+                Ok(block_index) => UnappliedRefund::Refunded { block_index },
+                Err(error) => UnappliedRefund::Failed { error },
+            }
+        }
+        Err(error) => UnappliedRefund::Failed { error },
+    };
+    repayment.unapplied_refund = Some(refund.clone());
+    save_repayment(&repayment);
+
+    auditlog::record(
+        AuditAction::TokensSent,
+        format!("repayment:{}:{}", repayment.loan_id, repayment.repayment_id),
+        None,
+        Some(format!(
+            "{} {} unapplied returned to {}: {:?}",
+            tokens::format_units(repayment.unapplied as u128, token.decimals()),
+            token.symbol(),
+            repayment.payer,
+            refund
+        )),
+    );
+    repayment
+}
+
+/**
+* Function: retry_unapplied_refund
+* Description: Re-sends the unapplied part of a repayment whose refund failed, or was left pending for longer than
+* STALE_PENDING_REFUND. Treasurer only.
+* @param loan_id: u64 - The ID of the loan
+* @param repayment_id: u64 - The ID of the repayment
+* @return Result<LoanRepayment, Error> - The repayment with its new refund status, or an error otherwise
+*/
+#[update(guard = "caller_is_treasurer")]
+pub async fn retry_unapplied_refund(loan_id: u64, repayment_id: u64) -> Result<LoanRepayment, Error> {
+    // Claimed as Pending before the first await, so a concurrent retry cannot send it too
+    let now = ic_cdk::api::time();
+    let repayment = LOAN_REPAYMENTS.with(|repayments| {
+        let mut repayments = repayments.borrow_mut();
+        let mut repayment = repayments.get(&(loan_id, repayment_id)).ok_or_else(|| Error::Error {
+            msg: format!("Repayment {} of loan {} not found", repayment_id, loan_id),
+        })?;
+
+        let retryable = match repayment.unapplied_refund {
+            Some(UnappliedRefund::Failed { .. }) => true,
+            // A resend of an attempt still in flight is deduplicated by the ledger
+            Some(UnappliedRefund::Pending) => now.saturating_sub(repayment.timestamp) >= STALE_PENDING_REFUND,
+            Some(UnappliedRefund::Refunded { .. }) | None => false,
+        };
+        if !retryable {
+            return Err(Error::Error {
+                msg: format!("Repayment {} of loan {} has no failed refund", repayment_id, loan_id),
+            });
+        }
+
+        repayment.unapplied_refund = Some(UnappliedRefund::Pending);
+        repayments.insert((loan_id, repayment_id), repayment.clone());
+        Ok(repayment)
+    })?;
+
+    Ok(refund_unapplied(repayment).await)
+}
+
+/**
+* Function: get_loan_balance
+* Description: Returns the outstanding balance of a loan, split into principal and interest.
+* Payments within an installment are treated as settling interest before principal.
+* @param loan_id: u64 - The ID of the loan
+* @return Result<LoanBalance, Error> - The loan balance, or an error if the loan does not exist
+*/
+#[query]
+pub fn get_loan_balance(loan_id: u64) -> Result<LoanBalance, Error> {
+    let loan = loanmanagement::find_loan(loan_id)?;
+
+    let mut principal_outstanding = 0;
+    let mut interest_outstanding = 0;
+    for installment in loan.installments.iter() {
+        let interest_paid = installment.amount_paid.min(installment.interest_due);
+        let principal_paid = installment.amount_paid - interest_paid;
+        interest_outstanding += installment.interest_due - interest_paid;
+        principal_outstanding += installment.principal_due.saturating_sub(principal_paid);
+    }
+
+    let next_due = loan.installments.iter().find(|i| i.outstanding() > 0);
+
+    Ok(LoanBalance {
+        loan_id,
+        status: loan.status,
+        principal_outstanding,
+        interest_outstanding,
+        total_outstanding: loan.outstanding_balance(),
+        next_due_date: next_due.map(|i| i.due_date),
+        next_due_amount: next_due.map(|i| i.outstanding()).unwrap_or(0),
+    })
+}
+
+/**
+* Function: get_loan_repayments
+* Description: Returns every repayment received against a loan, oldest first.
+* @param loan_id: u64 - The ID of the loan
+* @return Vec<LoanRepayment> - The repayments recorded for the loan
+*/
+#[query]
+pub fn get_loan_repayments(loan_id: u64) -> Vec<LoanRepayment> {
+    LOAN_REPAYMENTS.with(|repayments| {
+        repayments
+            .borrow()
+            .range((loan_id, 0)..=(loan_id, u64::MAX))
+            .map(|(_, repayment)| repayment)
+            .collect()
+    })
+}
@@ -0,0 +1,130 @@
+use ic_cdk::update;
+use std::time::Duration;
+
+use crate::accesscontrol::caller_is_loan_officer;
+use crate::entitymanagement::{Error, Success};
+use crate::loandisbursements;
+use crate::loanmanagement::{self, Loan, LoanStatus};
+use crate::fundingrounds;
+use crate::refunds;
+
+// How often the loan sweeper runs
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60); // Hourly
+// How long a loan may stay overdue past maturity before it is defaulted
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(30 * 24 * 60 * 60); // 1 month
+
+/**
+* Function: start_loan_timers
+* Description: Arms the periodic loan sweeper. Timers do not survive upgrades, so this must be called from both
+* `init` and `post_upgrade`.
+* @param None
+* @return None
+*/
+pub fn start_loan_timers() {
+    ic_cdk_timers::set_timer_interval(SWEEP_INTERVAL, || ic_cdk::spawn(sweep_loans()));
+}
+
+/**
+* Function: sweep_loans
+* Description: Closes expired funding rounds and moves loans past maturity to overdue and then to default.
+* @param None
+* @return None
+*/
+pub async fn sweep_loans() {
+    let current_time = ic_cdk::api::time();
+    let open_loans: Vec<u64> = loanmanagement::LOAN_STORAGE.with(|loans| {
+        loans
+            .borrow()
+            .iter()
+            .filter(|(_, loan)| !loan.status.is_terminal())
+            .map(|(loan_id, _)| loan_id)
+            .collect()
+    });
+
+    for loan_id in open_loans {
+        // Earlier loans' refunds are awaited, so this one may have been cancelled, disbursed or repaid since
+        let Ok(loan) = loanmanagement::find_loan(loan_id) else { continue };
+        let result = match loan.status {
+            LoanStatus::Funding
+                if loan.funding_round_expiry.is_some_and(|expiry| current_time >= expiry) =>
+            {
+                close_funding_round(loan).await
+            }
+            // Retries the transfers of a disbursement that failed part way
+            LoanStatus::Funded if loandisbursements::has_disbursements(loan.id) => {
+                loandisbursements::disburse_funded_loan(loan.id).await.map(|_| ())
+            }
+            LoanStatus::Disbursed | LoanStatus::Repaying => check_maturity(loan, current_time),
+            _ => Ok(()),
+        };
+
+        if let Err(e) = result {
+            ic_cdk::println!("Loan sweeper: {}", e);
+        }
+    }
+}
+
+/**
+* Function: close_funding_round
+* Description: Closes an expired funding round. Fully subscribed rounds are disbursed to the farm straight away,
+* otherwise the loan is cancelled and the farm's investors are refunded.
+* @param loan: Loan - The loan whose funding round has expired
+* @return Result<(), Error> - Ok if the round was closed, otherwise an error
+*/
+async fn close_funding_round(mut loan: Loan) -> Result<(), Error> {
+    if fundingrounds::progress_for(&loan).fully_subscribed {
+        loan.transition(LoanStatus::Funded)?;
+        loanmanagement::save_loan(&loan);
+        loandisbursements::disburse_funded_loan(loan.id).await?;
+        return Ok(());
+    }
+
+    loan.transition(LoanStatus::Cancelled)?;
+    loanmanagement::save_loan(&loan);
+    refunds::refund_round(loan.id).await?;
+    Ok(())
+}
+
+/**
+* Function: check_maturity
+* Description: Flags a loan as overdue once it passes maturity with a balance still owed, and defaults it
+* once the grace period has also passed.
+* @param loan: Loan - The disbursed loan to check
+* @param current_time: u64 - The time of the sweep
+* @return Result<(), Error> - Ok if the loan was checked, otherwise an error
+*/
+fn check_maturity(mut loan: Loan, current_time: u64) -> Result<(), Error> {
+    let maturity = match loan.maturity {
+        Some(maturity) if current_time >= maturity && loan.outstanding_balance() > 0 => maturity,
+        _ => return Ok(()),
+    };
+
+    let past_grace_period = current_time >= maturity + DEFAULT_GRACE_PERIOD.as_nanos() as u64;
+    if loan.overdue_since.is_some() && !past_grace_period {
+        return Ok(());
+    }
+
+    if loan.overdue_since.is_none() {
+        loan.overdue_since = Some(current_time);
+    }
+    if past_grace_period {
+        loan.transition(LoanStatus::Defaulted)?;
+    }
+
+    loanmanagement::save_loan(&loan);
+    Ok(())
+}
+
+/**
+* Function: run_loan_sweeper
+* Description: Runs the loan sweeper immediately instead of waiting for the next timer tick. Loan officer only.
+* @param None
+* @return Result<Success, Error> - Success once the sweep has completed
+*/
+#[update(guard = "caller_is_loan_officer")]
+pub async fn run_loan_sweeper() -> Result<Success, Error> {
+    sweep_loans().await;
+    Ok(Success::LoanSweepCompleted {
+        msg: "Loan sweep completed".to_string(),
+    })
+}
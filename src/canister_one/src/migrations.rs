@@ -0,0 +1,488 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::thread::LocalKey;
+
+use candid::Principal;
+use ic_cdk::query;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, StableCell, Storable};
+
+use crate::accesscontrol;
+use crate::depositaccounts;
+use crate::deposits;
+use crate::entitymanagement::{self, BoundedBytes, BoundedString, EntityType, Memory, MEMORY_MANAGER};
+use crate::farmerfiles;
+use crate::farmsagribizmanagement;
+use crate::idallocator::{self, IdNamespace};
+use crate::payments;
+use crate::tokens::Token;
+use crate::transaction_fees;
+use crate::treasury;
+use crate::unboundedmap::UnboundedMap;
+use crate::upgradestate;
+
+// Schema version of a canister installed before versions were stored
+const UNVERSIONED: u32 = 0;
+// Investments, fees and approved spenders moved into stable memory; the first layout with nothing saved on upgrade
+const STABLE_MEMORY_VERSION: u32 = 3;
+
+/**
+* Migration Struct
+* One step from the previous schema version to `to`. Steps run once each, in order, on upgrade.
+*
+* Stored records are candid, so a field added to a record such as Farmer as an `Option` reads back as None from
+* records written before it. Any other change to a stored record needs a step that rewrites the records.
+*/
+struct Migration {
+    to: u32,
+    description: &'static str,
+    run: fn() -> Result<(), String>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        to: 4,
+        description: "register the deposits behind recorded investments and move admins into the role registry",
+        run: migrate_to_role_registry,
+    },
+    Migration {
+        to: 5,
+        description: "move farmers, investors, agribusinesses, orders and file infos out of 1 KiB bounded maps",
+        run: migrate_to_unbounded_entities,
+    },
+    Migration {
+        to: 6,
+        description: "start the stable id allocators after the highest ids already in use",
+        run: seed_id_allocators,
+    },
+    Migration {
+        to: 7,
+        description: "index registered entities by principal",
+        run: index_entity_principals,
+    },
+    Migration {
+        to: 8,
+        description: "number investments from a stable id allocator",
+        run: seed_investment_ids,
+    },
+    Migration {
+        to: 9,
+        description: "index deposit accounts by investor",
+        run: index_deposit_accounts,
+    },
+];
+
+pub const CURRENT_SCHEMA_VERSION: u32 = MIGRATIONS[MIGRATIONS.len() - 1].to;
+
+thread_local! {
+    static SCHEMA_VERSION: RefCell<StableCell<u32, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40))),
+            UNVERSIONED,
+        )
+        .expect("Failed to initialise schema version")
+    );
+}
+
+fn schema_version() -> u32 {
+    SCHEMA_VERSION.with(|version| *version.borrow().get())
+}
+
+fn set_schema_version(version: u32) {
+    SCHEMA_VERSION
+        .with(|cell| cell.borrow_mut().set(version))
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to save schema version: {:?}", e)));
+}
+
+// Layout of the state saved on upgrade at schema version 2
+type StableStateV2 = (
+    payments::InvestorInvestments,
+    payments::FarmInvestments,
+    HashMap<String, (u128, Token)>,
+    Vec<entitymanagement::Farmer>,
+    Vec<entitymanagement::Investor>,
+    Vec<entitymanagement::SupplyAgriBusiness>,
+    Vec<entitymanagement::FarmsAgriBusiness>,
+    Vec<entitymanagement::Order>,
+    Vec<(BoundedString, BoundedBytes)>,
+    Vec<(BoundedString, BoundedBytes)>,
+    Vec<(u64, farmsagribizmanagement::FileInfo)>,
+    Option<Vec<(u64, farmerfiles::FarmerReportVec)>>,
+    Option<Vec<(u64, farmsagribizmanagement::ImagesBoundedBytes)>>,
+);
+
+// Layout saved at schema version 1, before money amounts were kept in token base units
+type StableStateV1 = (
+    payments::LegacyInvestments,
+    payments::LegacyInvestments,
+    HashMap<String, f64>,
+    Vec<entitymanagement::Farmer>,
+    Vec<entitymanagement::Investor>,
+    Vec<entitymanagement::SupplyAgriBusiness>,
+    Vec<entitymanagement::FarmsAgriBusiness>,
+    Vec<entitymanagement::Order>,
+    Vec<(BoundedString, BoundedBytes)>,
+    Vec<(BoundedString, BoundedBytes)>,
+    Vec<(u64, farmsagribizmanagement::FileInfo)>,
+    Option<Vec<(u64, farmerfiles::FarmerReportVec)>>,
+    Option<Vec<(u64, farmsagribizmanagement::ImagesBoundedBytes)>>,
+);
+
+/**
+* SavedState Enum
+* State saved as one candid value on upgrade by versions before schema version 3, tagged with the layout it was
+* decoded as.
+*/
+enum SavedState {
+    V1(Box<StableStateV1>),
+    V2(Box<StableStateV2>),
+}
+
+// The saved state carries no version, so each layout is tried from newest to oldest
+fn decode_saved_state() -> Result<SavedState, String> {
+    let v2_error = match upgradestate::restore::<StableStateV2>() {
+        Ok(state) => return Ok(SavedState::V2(Box::new(state))),
+        Err(e) => e,
+    };
+    match upgradestate::restore::<StableStateV1>() {
+        Ok(state) => Ok(SavedState::V1(Box::new(state))),
+        Err(v1_error) => Err(format!(
+            "Saved state matches no known layout (version 2: {}; version 1: {})",
+            v2_error, v1_error
+        )),
+    }
+}
+
+fn migrate_v1_to_v2(state: StableStateV1) -> StableStateV2 {
+    let (
+        investor_investments,
+        farm_investments,
+        transaction_fees,
+        farmers,
+        investors,
+        supply_agribusinesses,
+        farms_agribusinesses,
+        orders,
+        files,
+        agribiz_files,
+        file_infos,
+        farm_reports,
+        farm_images,
+    ) = state;
+
+    // Fees were keyed by the hash of the investment they were taken from
+    let transaction_fees = transaction_fees
+        .into_iter()
+        .map(|(hash, fee)| {
+            let token = farm_investments.token_of(&hash).unwrap_or(Token::CkUSDC);
+            (hash, (fee as u128, token))
+        })
+        .collect();
+
+    (
+        investor_investments.into(),
+        farm_investments.into(),
+        transaction_fees,
+        farmers,
+        investors,
+        supply_agribusinesses,
+        farms_agribusinesses,
+        orders,
+        files,
+        agribiz_files,
+        file_infos,
+        farm_reports,
+        farm_images,
+    )
+}
+
+// Moves the saved state into the stable maps. Records already in a map are overwritten with their saved copy.
+fn migrate_v2_to_v3(state: StableStateV2) -> Result<(), String> {
+    let (
+        _investor_investments,
+        farm_investments,
+        transaction_fees,
+        farmers,
+        investors,
+        supply_agribusinesses,
+        farms_agribusinesses,
+        orders,
+        files,
+        agribiz_files,
+        file_infos,
+        farm_reports,
+        farm_images,
+    ) = state;
+
+    // Each investment was saved under both its investor and its farm
+    payments::import_saved_investments(farm_investments)?;
+    for (hash, (fee, token)) in transaction_fees {
+        transaction_fees::insert_transaction_fee(hash, fee, token)?;
+    }
+
+    entitymanagement::FARMER_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for farmer in farmers {
+            storage.insert(farmer.id, farmer);
+        }
+    });
+
+    entitymanagement::INVESTOR_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for investor in investors {
+            storage.insert(investor.id, investor);
+        }
+    });
+
+    entitymanagement::SUPPLY_AGRIBUSINESS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for supply_agribusiness in supply_agribusinesses {
+            storage.insert(supply_agribusiness.id, supply_agribusiness);
+        }
+    });
+
+    entitymanagement::FARMS_AGRIBUSINESS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for farms_agribusiness in farms_agribusinesses {
+            storage.insert(farms_agribusiness.id, farms_agribusiness);
+        }
+    });
+
+    entitymanagement::ORDER_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for order in orders {
+            storage.insert(order.order_id, order);
+        }
+    });
+
+    entitymanagement::FILE_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for (filename, data) in files {
+            storage.insert(filename, data);
+        }
+    });
+
+    farmsagribizmanagement::AGRIBIZ_FILE_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for (filename, data) in agribiz_files {
+            storage.insert(filename, data);
+        }
+    });
+
+    farmsagribizmanagement::FILE_INFO_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for (id, info) in file_infos {
+            storage.insert(id, info);
+        }
+    });
+
+    if let Some(reports) = farm_reports {
+        farmerfiles::FARM_REPORTS.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            for (id, report_vec) in reports {
+                storage.insert(id, report_vec);
+            }
+        });
+    }
+
+    if let Some(images) = farm_images {
+        farmsagribizmanagement::FARM_IMAGES.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            for (id, image_vec) in images {
+                storage.insert(id, image_vec);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+fn migrate_to_role_registry() -> Result<(), String> {
+    // Deposits credited before the registry existed must not be credited again
+    deposits::register_recorded_investments();
+
+    // Admins were hard-coded and treasury roles kept apart before the role registry existed
+    accesscontrol::grant_legacy_admins();
+    treasury::migrate_treasury_roles();
+    Ok(())
+}
+
+// A record of a map whose values were bounded to 1 KiB, read back as its encoded bytes
+struct BoundedRecord(Vec<u8>);
+
+impl Storable for BoundedRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        BoundedRecord(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for BoundedRecord {
+    const MAX_SIZE: u32 = 1_024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Copies every record of a bounded map into its unbounded replacement. The bounded map is left as it was; its
+// memory id is not reused.
+fn copy_bounded_records<V: Storable>(
+    bounded_memory_id: u8,
+    map: &'static LocalKey<RefCell<UnboundedMap<V>>>,
+) -> usize {
+    let bounded: StableBTreeMap<u64, BoundedRecord, Memory> =
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(bounded_memory_id))));
+
+    map.with(|map| {
+        let mut map = map.borrow_mut();
+        let mut copied = 0;
+        for (id, record) in bounded.iter() {
+            map.insert(id, V::from_bytes(Cow::Owned(record.0)));
+            copied += 1;
+        }
+        copied
+    })
+}
+
+fn migrate_to_unbounded_entities() -> Result<(), String> {
+    let copied = copy_bounded_records(1, &entitymanagement::FARMER_STORAGE)
+        + copy_bounded_records(2, &entitymanagement::INVESTOR_STORAGE)
+        + copy_bounded_records(3, &entitymanagement::SUPPLY_AGRIBUSINESS_STORAGE)
+        + copy_bounded_records(4, &entitymanagement::FARMS_AGRIBUSINESS_STORAGE)
+        + copy_bounded_records(5, &entitymanagement::FARMS_FOR_AGRIBUSINESS_STORAGE)
+        + copy_bounded_records(6, &entitymanagement::ORDER_STORAGE)
+        + copy_bounded_records(10, &farmsagribizmanagement::FILE_INFO_STORAGE);
+    ic_cdk::println!("Copied {} records into unbounded maps", copied);
+    Ok(())
+}
+
+fn highest_id<V: Storable>(map: &'static LocalKey<RefCell<UnboundedMap<V>>>) -> u64 {
+    map.with(|map| map.borrow().keys().last().unwrap_or(0))
+}
+
+// Ids used to come from heap counters that restarted on every upgrade, or from the number of farmers
+fn seed_id_allocators() -> Result<(), String> {
+    let farmers = highest_id(&entitymanagement::FARMER_STORAGE).max(highest_id(&entitymanagement::FARMS_FOR_AGRIBUSINESS_STORAGE));
+    idallocator::reserve_ids_up_to(IdNamespace::Farmer, farmers);
+    idallocator::reserve_ids_up_to(IdNamespace::Investor, highest_id(&entitymanagement::INVESTOR_STORAGE));
+    idallocator::reserve_ids_up_to(IdNamespace::SupplyAgriBusiness, highest_id(&entitymanagement::SUPPLY_AGRIBUSINESS_STORAGE));
+    idallocator::reserve_ids_up_to(IdNamespace::FarmsAgriBusiness, highest_id(&entitymanagement::FARMS_AGRIBUSINESS_STORAGE));
+    idallocator::reserve_ids_up_to(IdNamespace::AgribizFile, highest_id(&farmsagribizmanagement::FILE_INFO_STORAGE));
+    Ok(())
+}
+
+// Principals registered more than once keep the first entity found, the one the old full scans resolved them to.
+// Farms added by agribusinesses carry the anonymous principal, which is never indexed.
+fn index_entity_principals() -> Result<(), String> {
+    let mut entities: Vec<(Principal, EntityType, u64)> = Vec::new();
+    entitymanagement::FARMER_STORAGE.with(|farmers| {
+        entities.extend(farmers.borrow().iter().map(|(id, farmer)| (farmer.principal_id, EntityType::Farmer, id)))
+    });
+    entitymanagement::INVESTOR_STORAGE.with(|investors| {
+        entities.extend(investors.borrow().iter().map(|(id, investor)| (investor.principal_id, EntityType::Investor, id)))
+    });
+    entitymanagement::SUPPLY_AGRIBUSINESS_STORAGE.with(|agribusiness| {
+        entities.extend(agribusiness.borrow().iter().map(|(id, agribiz)| (agribiz.principal_id, EntityType::SupplyAgriBusiness, id)))
+    });
+    entitymanagement::FARMS_AGRIBUSINESS_STORAGE.with(|agribusiness| {
+        entities.extend(agribusiness.borrow().iter().map(|(id, agribiz)| (agribiz.principal_id, EntityType::FarmsAgriBusiness, id)))
+    });
+
+    for (principal_id, entity_type, id) in entities {
+        if principal_id != Principal::anonymous() && entitymanagement::registered_entity(&principal_id).is_none() {
+            entitymanagement::index_principal(principal_id, entity_type, id);
+        }
+    }
+    Ok(())
+}
+
+// Investment ids used to follow the highest key in the map, so removing the last one let its id be reused
+fn seed_investment_ids() -> Result<(), String> {
+    let highest = payments::INVESTMENTS.with(|investments| {
+        investments.borrow().last_key_value().map(|(id, _)| id).unwrap_or(0)
+    });
+    idallocator::reserve_ids_up_to(IdNamespace::Investment, highest);
+    Ok(())
+}
+
+fn index_deposit_accounts() -> Result<(), String> {
+    depositaccounts::index_deposit_accounts();
+    Ok(())
+}
+
+fn fail(msg: String) -> ! {
+    ic_cdk::trap(&format!("Upgrade aborted, the previous version keeps running: {}", msg))
+}
+
+/**
+* Function: mark_current_schema
+* Description: Records a freshly installed canister as being at the current schema version.
+* @param None
+* @return None
+*/
+pub fn mark_current_schema() {
+    set_schema_version(CURRENT_SCHEMA_VERSION);
+}
+
+/**
+* Function: run_migrations
+* Description: Brings stable state up to the current schema version on upgrade, one version at a time. Traps if any
+* step fails, so the upgrade is rolled back rather than the canister starting with missing state. Must run before
+* anything else in post_upgrade touches stable memory.
+* @param None
+* @return None
+*/
+pub fn run_migrations() {
+    // Saved state must be read before the memory manager is first used, which replaces it with a fresh header
+    let saved = if upgradestate::has_saved_state() {
+        Some(decode_saved_state().unwrap_or_else(|e| fail(e)))
+    } else {
+        None
+    };
+
+    let mut version = match &saved {
+        Some(SavedState::V1(_)) => 1,
+        Some(SavedState::V2(_)) => 2,
+        // Installed with the stable maps, before versions were stored
+        None if schema_version() == UNVERSIONED => STABLE_MEMORY_VERSION,
+        None => schema_version(),
+    };
+    if version > CURRENT_SCHEMA_VERSION {
+        fail(format!(
+            "stable state is at schema version {}, newer than this build's version {}",
+            version, CURRENT_SCHEMA_VERSION
+        ));
+    }
+
+    if let Some(saved) = saved {
+        let state = match saved {
+            SavedState::V1(state) => migrate_v1_to_v2(*state),
+            SavedState::V2(state) => *state,
+        };
+        migrate_v2_to_v3(state).unwrap_or_else(|e| fail(format!("migration to schema version 3 failed: {}", e)));
+        upgradestate::clear();
+        version = STABLE_MEMORY_VERSION;
+    }
+
+    let from = version;
+    for migration in MIGRATIONS.iter().filter(|migration| migration.to > from) {
+        ic_cdk::println!("Migrating to schema version {}: {}", migration.to, migration.description);
+        (migration.run)()
+            .unwrap_or_else(|e| fail(format!("migration to schema version {} failed: {}", migration.to, e)));
+        version = migration.to;
+    }
+    set_schema_version(version);
+}
+
+/**
+* Function: get_schema_version
+* Description: Returns the schema version of the canister's stable state.
+* @param None
+* @return u32 - The schema version
+*/
+#[query]
+pub fn get_schema_version() -> u32 {
+    schema_version()
+}
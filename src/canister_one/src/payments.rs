@@ -1,97 +1,281 @@
 use std::collections::HashMap;
-use std::cell::RefCell;
+use std::thread::LocalKey;
+use std::{borrow::Cow, cell::RefCell};
 
-use candid::CandidType;
+use candid::{CandidType, Decode, Encode};
 use ic_cdk::{query, update};
-use serde::Deserialize;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
 
-// Defining structs 
+use crate::accesscontrol::caller_is_treasurer;
+use crate::entitymanagement::{Memory, MEMORY_MANAGER};
+use crate::idallocator::{self, IdNamespace};
+use crate::auditlog::{self, AuditAction};
+use crate::tokens::{self, Token};
+
+// Longest transaction hash an investment can be recorded under, so records stay within their bounded size
+pub const MAX_TRANSACTION_HASH_LEN: usize = 512;
+
+// (farm_id or investor_id, amount in the token's base units, transaction_hash, token)
+pub type InvestmentEntry = (u64, u128, String, Token);
+
+/**
+* Investment Struct
+* An investment made by an investor in a farm.
+* @param Defined In-Line
+* @return Investment instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Investment {
+    pub investment_id: u64,
+    pub farm_id: u64,
+    pub investor_id: u64,
+    pub amount: u128, // In the token's base units
+    pub transaction_hash: String,
+    pub token: Token,
+}
+
+impl Storable for Investment {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Investment {
+    const MAX_SIZE: u32 = 1_024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Investments as they were kept on the heap and saved on upgrade, before they moved to stable memory
 #[derive(Clone, CandidType, Deserialize)]
 pub struct InvestorInvestments {
-    investments: HashMap<u64, Vec<(u64, f64, String, String)>>, // investor_id => [(farm_id, amount, transaction_hash, currency), ...]
+    investments: HashMap<u64, Vec<InvestmentEntry>>, // investor_id => [(farm_id, amount, transaction_hash, token), ...]
 }
 
 #[derive(Clone, CandidType, Deserialize)]
 pub struct FarmInvestments {
-    investments: HashMap<u64, Vec<(u64, f64, String, String)>>, // farm_id => [(investor_id, amount), ...]
+    investments: HashMap<u64, Vec<InvestmentEntry>>, // farm_id => [(investor_id, amount, transaction_hash, token), ...]
 }
 
+// Investments as saved before amounts were kept in token base units
+#[derive(Clone, CandidType, Deserialize)]
+pub struct LegacyInvestments {
+    investments: HashMap<u64, Vec<(u64, f64, String, String)>>,
+}
+
+impl LegacyInvestments {
+    fn into_entries(self) -> HashMap<u64, Vec<InvestmentEntry>> {
+        self.investments
+            .into_iter()
+            .map(|(id, entries)| {
+                let entries = entries
+                    .into_iter()
+                    .map(|(other_id, amount, hash, currency)| {
+                        // Only ckETH and ckUSDC investments were ever recorded
+                        let token = Token::from_symbol(&currency).unwrap_or(Token::CkUSDC);
+                        (other_id, amount as u128, hash, token)
+                    })
+                    .collect();
+                (id, entries)
+            })
+            .collect()
+    }
+
+    // Token of a legacy investment, looked up by its transaction hash
+    pub fn token_of(&self, transaction_hash: &str) -> Option<Token> {
+        self.investments
+            .values()
+            .flatten()
+            .find(|(_, _, hash, _)| hash == transaction_hash)
+            .and_then(|(_, _, _, currency)| Token::from_symbol(currency))
+    }
+}
+
+impl From<LegacyInvestments> for InvestorInvestments {
+    fn from(legacy: LegacyInvestments) -> Self {
+        Self { investments: legacy.into_entries() }
+    }
+}
+
+impl From<LegacyInvestments> for FarmInvestments {
+    fn from(legacy: LegacyInvestments) -> Self {
+        Self { investments: legacy.into_entries() }
+    }
+}
+
+// (farm_id or investor_id, investment_id) => ()
+type InvestmentIndex = StableBTreeMap<(u64, u64), (), Memory>;
+
 thread_local! {
-    pub static INVESTOR_INVESTMENTS: RefCell<InvestorInvestments> = RefCell::new(InvestorInvestments { investments: HashMap::new() });
-    pub static FARM_INVESTMENTS: RefCell<FarmInvestments> = RefCell::new(FarmInvestments { investments: HashMap::new() });
+    // Investments keyed by investment id
+    pub static INVESTMENTS: RefCell<StableBTreeMap<u64, Investment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+        ));
+
+    // Index of investments per investor: (investor_id, investment_id) => ()
+    static INVESTOR_INVESTMENT_INDEX: RefCell<InvestmentIndex> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36)))
+        ));
+
+    // Index of investments per farm: (farm_id, investment_id) => ()
+    static FARM_INVESTMENT_INDEX: RefCell<InvestmentIndex> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))
+        ));
+}
+
+fn insert_investment(farm_id: u64, amount: u128, investor_id: u64, transaction_hash: String, token: Token) {
+    let investment_id = idallocator::next_id(IdNamespace::Investment);
+
+    INVESTMENTS.with(|investments| {
+        investments.borrow_mut().insert(
+            investment_id,
+            Investment {
+                investment_id,
+                farm_id,
+                investor_id,
+                amount,
+                transaction_hash,
+                token,
+            },
+        )
+    });
+    INVESTOR_INVESTMENT_INDEX.with(|index| index.borrow_mut().insert((investor_id, investment_id), ()));
+    FARM_INVESTMENT_INDEX.with(|index| index.borrow_mut().insert((farm_id, investment_id), ()));
+}
+
+// Investments listed under an id in one of the indexes, oldest first
+fn indexed_investments(
+    index: &'static LocalKey<RefCell<InvestmentIndex>>,
+    id: u64,
+) -> Vec<Investment> {
+    let investment_ids: Vec<u64> = index.with(|index| {
+        index
+            .borrow()
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|((_, investment_id), _)| investment_id)
+            .collect()
+    });
+
+    INVESTMENTS.with(|investments| {
+        let investments = investments.borrow();
+        investment_ids.iter().filter_map(|id| investments.get(id)).collect()
+    })
 }
 
-#[update] 
+/**
+* Function: import_saved_investments
+* Description: Moves investments saved on upgrade by earlier versions into stable memory. Every investment was saved
+* under both its farm and its investor, so only the per-farm copy is imported.
+* @param farm_investments: FarmInvestments - The saved investments, keyed by farm
+* @return Result<(), String> - An error naming the first investment that cannot be stored
+*/
+pub fn import_saved_investments(farm_investments: FarmInvestments) -> Result<(), String> {
+    let mut farm_ids: Vec<u64> = farm_investments.investments.keys().copied().collect();
+    farm_ids.sort_unstable();
+
+    let mut investments = farm_investments.investments;
+    for farm_id in farm_ids {
+        for (investor_id, amount, hash, token) in investments.remove(&farm_id).unwrap_or_default() {
+            if hash.len() > MAX_TRANSACTION_HASH_LEN {
+                return Err(format!("Investment by investor {} in farm {} has an oversized transaction hash", investor_id, farm_id));
+            }
+            insert_investment(farm_id, amount, investor_id, hash, token);
+        }
+    }
+    Ok(())
+}
+
+#[update(guard = "caller_is_treasurer")]
 pub fn store_investments(
     farm_id: u64, 
-    amount: f64, 
+    amount: u128, 
     investor_id: u64, 
     transaction_hash: String, 
-    currency: String
+    token: Token
 ) -> Result<(), String> {
-    INVESTOR_INVESTMENTS.with(|investor_investments| {
-        let mut investor_investments = investor_investments.borrow_mut();
-        investor_investments.investments.entry(investor_id).or_insert_with(|| Vec::new()).push((farm_id, amount, transaction_hash.clone(), currency.clone())); 
-    });
+    if transaction_hash.len() > MAX_TRANSACTION_HASH_LEN {
+        return Err(format!("Transaction hash must be at most {} bytes", MAX_TRANSACTION_HASH_LEN));
+    }
 
-    FARM_INVESTMENTS.with(|farm_investments| {
-        let mut farm_investments = farm_investments.borrow_mut();
-        farm_investments.investments.entry(farm_id).or_insert_with(|| Vec::new()).push((investor_id, amount, transaction_hash, currency.clone()));
-    });
+    insert_investment(farm_id, amount, investor_id, transaction_hash.clone(), token);
 
+    auditlog::record(
+        AuditAction::InvestmentRecorded,
+        format!("farm:{}", farm_id),
+        None,
+        Some(format!(
+            "investor {} invested {} {} ({})",
+            investor_id,
+            tokens::format_units(amount, token.decimals()),
+            token.symbol(),
+            transaction_hash
+        )),
+    );
     Ok(())
 }
 
 #[query]
-fn get_investments_by_investor(investor_id: u64) -> Option<Vec<(u64, f64, String, String)>> {
-    INVESTOR_INVESTMENTS.with(|investor_investments| {
-        let investor_investments = investor_investments.borrow();
-        investor_investments.investments.get(&investor_id).cloned()
-    })
+fn get_investments_by_investor(investor_id: u64) -> Option<Vec<InvestmentEntry>> {
+    let investments = indexed_investments(&INVESTOR_INVESTMENT_INDEX, investor_id);
+    if investments.is_empty() {
+        return None;
+    }
+    Some(
+        investments
+            .into_iter()
+            .map(|i| (i.farm_id, i.amount, i.transaction_hash, i.token))
+            .collect(),
+    )
 }
 
 #[query]
-fn get_investments_by_farm(farm_id: u64) -> Option<Vec<(u64, f64, String, String)>> {
-    FARM_INVESTMENTS.with(|farm_investments| {
-        let farm_investments = farm_investments.borrow();
-        farm_investments.investments.get(&farm_id).cloned()
-    })
+pub fn get_investments_by_farm(farm_id: u64) -> Option<Vec<InvestmentEntry>> {
+    let investments = indexed_investments(&FARM_INVESTMENT_INDEX, farm_id);
+    if investments.is_empty() {
+        return None;
+    }
+    Some(
+        investments
+            .into_iter()
+            .map(|i| (i.investor_id, i.amount, i.transaction_hash, i.token))
+            .collect(),
+    )
 }
 
-// Calculating total investments recieved by a farm
+// Calculating total investments recieved by a farm in a token
 #[query]
-fn calculate_total_investments_received_by_farm(farm_id: u64) -> f64 {
-    FARM_INVESTMENTS.with(|farm_investments| {
-        let farm_investments = farm_investments.borrow();
-        farm_investments.investments.get(&farm_id)
-            .map(|investments| investments.iter().map(|(_, amount, _, _)| amount).sum())
-            .unwrap_or(0.0)
-    })
+pub fn calculate_total_investments_received_by_farm(farm_id: u64, token: Token) -> u128 {
+    indexed_investments(&FARM_INVESTMENT_INDEX, farm_id)
+        .iter()
+        .filter(|investment| investment.token == token)
+        .map(|investment| investment.amount)
+        .sum()
 }
 
-// Calculating total investments made by an investor on a specific farm 
+// Calculating total investments made by an investor on a specific farm in a token
 #[query]    
-fn calculate_total_investments_by_investor_on_farm(investor_id: u64, farm_id: u64) -> f64 {
-    INVESTOR_INVESTMENTS.with(|investor_investments| {
-        let investor_investments = investor_investments.borrow();
-        investor_investments.investments.get(&investor_id)
-            .map(|investments| {
-                investments.iter()
-                    .filter(|(f_id, _, _, _)| *f_id == farm_id)
-                    .map(|(_, amount, _, _)| amount)
-                    .sum()
-            })
-            .unwrap_or(0.0)
-    })
+fn calculate_total_investments_by_investor_on_farm(investor_id: u64, farm_id: u64, token: Token) -> u128 {
+    indexed_investments(&INVESTOR_INVESTMENT_INDEX, investor_id)
+        .iter()
+        .filter(|investment| investment.farm_id == farm_id && investment.token == token)
+        .map(|investment| investment.amount)
+        .sum()
 }
 
-// Calculating total investments made by an investor across all farms
+// Calculating total investments made by an investor across all farms in a token
 #[query]
-fn calculate_total_investments_by_investor(investor_id: u64) -> f64 {
-    INVESTOR_INVESTMENTS.with(|investor_investments| {
-        let investor_investments = investor_investments.borrow();
-        investor_investments.investments.get(&investor_id)
-            .map(|investments| investments.iter().map(|(_, amount, _, _)| amount).sum())
-            .unwrap_or(0.0)
-    })
-}
\ No newline at end of file
+fn calculate_total_investments_by_investor(investor_id: u64, token: Token) -> u128 {
+    indexed_investments(&INVESTOR_INVESTMENT_INDEX, investor_id)
+        .iter()
+        .filter(|investment| investment.token == token)
+        .map(|investment| investment.amount)
+        .sum()
+}
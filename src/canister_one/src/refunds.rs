@@ -0,0 +1,437 @@
+use b3_utils::ledger::ICRCAccount;
+use candid::{CandidType, Decode, Encode, Nat};
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::accesscontrol::{caller_is_loan_officer, caller_is_treasurer};
+use crate::ck_eth_payments;
+use crate::entitymanagement::{self, Error, Memory, MEMORY_MANAGER};
+use crate::fundingrounds;
+use crate::ledgertransfers::{self, PreparedTransfer, TransferAttempt};
+use crate::loanmanagement::{self, LoanStatus};
+use crate::payments;
+use crate::auditlog::{self, AuditAction};
+use crate::tokens::{self, Token};
+use crate::withdrawals::{self, Withdrawal, WithdrawalStatus};
+
+// How long a refund can stay Pending before a retry assumes its send was interrupted
+const STALE_PENDING_REFUND: u64 = 30 * 60 * 1_000_000_000; // 30 minutes in nanoseconds
+
+/**
+* RefundReason Enum
+* Why an investment (or part of one) is being returned to the investor.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RefundReason {
+    FailedRound,      // The loan's funding round closed below target
+    ExcessInvestment, // The part of an investment above the round's target
+}
+
+/**
+* RefundMethod Enum
+* How a refund is returned: to the investor's principal over ICRC-1, or withdrawn to the Ethereum
+* address the deposit was sent from.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RefundMethod {
+    Icrc1,
+    Withdrawal,
+}
+
+/**
+* RefundStatus Enum
+* Tracks whether a refund has reached the investor. Withdrawal refunds stay Withdrawing until the minter has
+* finalized the Ethereum transaction.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum RefundStatus {
+    Pending,
+    Withdrawing { withdrawal_id: u64 },
+    Refunded { block_index: Nat },
+    Failed { error: String },
+}
+
+/**
+* Refund Struct
+* Represents the return of a single investment to its investor.
+* @param Defined In-Line
+* @return Refund instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct Refund {
+    pub refund_id: u64,
+    pub loan_id: u64,
+    pub farm_id: u64,
+    pub investor_id: u64,
+    pub transaction_hash: String, // Hash of the deposit being refunded
+    pub token: Token,
+    pub amount: u128, // In the token's base units
+    pub reason: RefundReason,
+    pub method: RefundMethod,
+    pub withdrawal_address: Option<String>, // Set once a withdrawal refund has resolved the deposit's sender
+    pub withdrawal_id: Option<u64>,         // Withdrawal tracking the refund on Ethereum
+    pub status: RefundStatus,
+    pub attempts: u32,
+    pub created_at: u64,
+    pub last_attempt_at: Option<u64>,
+    pub transfer_attempt: Option<TransferAttempt>, // Latest ICRC-1 transfer attempt, reused while the ledger deduplicates it
+}
+
+impl Storable for Refund {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Refund {
+    const MAX_SIZE: u32 = 1_024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Refunds keyed by refund id
+    pub static REFUNDS: RefCell<StableBTreeMap<u64, Refund, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+        ));
+}
+
+fn save_refund(refund: &Refund) {
+    REFUNDS.with(|refunds| refunds.borrow_mut().insert(refund.refund_id, refund.clone()));
+}
+
+fn refunds_matching(filter: impl Fn(&Refund) -> bool) -> Vec<Refund> {
+    REFUNDS.with(|refunds| {
+        refunds
+            .borrow()
+            .iter()
+            .map(|(_, refund)| refund)
+            .filter(|refund| filter(refund))
+            .collect()
+    })
+}
+
+// Creates and stores a pending refund
+fn queue_refund(
+    loan_id: u64,
+    farm_id: u64,
+    investor_id: u64,
+    transaction_hash: String,
+    token: Token,
+    amount: u128,
+    reason: RefundReason,
+) -> Refund {
+    let refund_id = REFUNDS.with(|refunds| {
+        refunds
+            .borrow()
+            .last_key_value()
+            .map(|(id, _)| id + 1)
+            .unwrap_or(1)
+    });
+
+    let refund = Refund {
+        refund_id,
+        loan_id,
+        farm_id,
+        investor_id,
+        transaction_hash,
+        token,
+        amount,
+        reason,
+        method: RefundMethod::Icrc1,
+        withdrawal_address: None,
+        withdrawal_id: None,
+        status: RefundStatus::Pending,
+        attempts: 0,
+        created_at: ic_cdk::api::time(),
+        last_attempt_at: None,
+        transfer_attempt: None,
+    };
+    save_refund(&refund);
+    refund
+}
+
+/**
+* Function: send_refund
+* Description: Returns a refund to the investor using the refund's method and records the outcome.
+* @param refund: Refund - The refund to send
+* @return Refund - The refund with its updated status
+*/
+async fn send_refund(mut refund: Refund) -> Refund {
+    refund.attempts += 1;
+    refund.last_attempt_at = Some(ic_cdk::api::time());
+
+    // Mark the refund as in flight so it is not retried concurrently
+    refund.status = RefundStatus::Pending;
+    save_refund(&refund);
+
+    refund.status = match refund.method {
+        RefundMethod::Icrc1 => match send_icrc1_refund(&mut refund).await {
+            Ok(block_index) => RefundStatus::Refunded { block_index },
+            Err(error) => RefundStatus::Failed { error },
+        },
+        RefundMethod::Withdrawal => match send_withdrawal(&mut refund).await {
+            Ok(withdrawal_id) => RefundStatus::Withdrawing { withdrawal_id },
+            Err(error) => RefundStatus::Failed { error },
+        },
+    };
+    save_refund(&refund);
+    audit_refund(&refund);
+    refund
+}
+
+fn audit_refund(refund: &Refund) {
+    auditlog::record(
+        AuditAction::TokensSent,
+        format!("refund:{}", refund.refund_id),
+        None,
+        Some(format!(
+            "{} {} to investor {} by {:?}: {:?}",
+            tokens::format_units(refund.amount, refund.token.decimals()),
+            refund.token.symbol(),
+            refund.investor_id,
+            refund.method,
+            refund.status
+        )),
+    );
+}
+
+// Returns a refund to the investor's principal over ICRC-1. The refund's id is the memo; resends within the ledger's
+// deduplication window reuse the previous attempt, and later ones first look it up on the ledger.
+async fn send_icrc1_refund(refund: &mut Refund) -> Result<Nat, String> {
+    let investor = entitymanagement::INVESTOR_STORAGE
+        .with(|investors| investors.borrow().get(&refund.investor_id))
+        .ok_or_else(|| format!("Investor {} not found", refund.investor_id))?;
+
+    let memo = refund.refund_id.to_be_bytes().to_vec();
+    let attempt = match ledgertransfers::prepare_transfer(refund.token, refund.transfer_attempt.as_ref(), &memo).await? {
+        PreparedTransfer::AlreadySent { block_index } => return Ok(block_index),
+        PreparedTransfer::Send(attempt) => attempt,
+    };
+    refund.transfer_attempt = Some(attempt.clone());
+    save_refund(refund);
+
+    let to = ICRCAccount::new(investor.principal_id, None);
+    ledgertransfers::send_transfer(refund.token, &attempt, to, refund.amount, memo).await
+}
+
+// Withdraws a refund to the Ethereum address its deposit came from, returning the withdrawal tracking it
+async fn send_withdrawal(refund: &mut Refund) -> Result<u64, String> {
+    let recipient = match refund.withdrawal_address.clone() {
+        Some(address) => address,
+        None => {
+            let address =
+                ck_eth_payments::deposit_source_address(refund.transaction_hash.clone()).await?;
+            refund.withdrawal_address = Some(address.clone());
+            address
+        }
+    };
+
+    let withdrawal = withdrawals::request_withdrawal(refund.token, refund.amount, recipient).await?;
+    refund.withdrawal_id = Some(withdrawal.withdrawal_id);
+    Ok(withdrawal.withdrawal_id)
+}
+
+/**
+* Function: settle_withdrawal
+* Description: Settles the refund a withdrawal was made for once the minter reports on it. A finalized
+* withdrawal refunds the investor; a reimbursed one leaves the funds back in the canister, so the refund is marked
+* failed to be retried. A withdrawal the minter has no record of also fails the refund, for a treasurer to look into.
+* @param withdrawal: &Withdrawal - The withdrawal whose status changed
+* @return None
+*/
+pub fn settle_withdrawal(withdrawal: &Withdrawal) {
+    let waiting = RefundStatus::Withdrawing {
+        withdrawal_id: withdrawal.withdrawal_id,
+    };
+    let Some(mut refund) = refunds_matching(|refund| refund.status == waiting).pop() else {
+        return;
+    };
+
+    refund.status = match withdrawal.status {
+        WithdrawalStatus::Finalized => RefundStatus::Refunded {
+            block_index: withdrawal.cketh_block_index.clone().unwrap_or_default(),
+        },
+        WithdrawalStatus::Reimbursed => RefundStatus::Failed {
+            error: format!(
+                "Withdrawal {} failed on Ethereum and was reimbursed to the canister",
+                withdrawal.withdrawal_id
+            ),
+        },
+        // The burn may still have gone through, so check the withdrawal before retrying the refund
+        WithdrawalStatus::NotFound => RefundStatus::Failed {
+            error: format!("The minter has no record of withdrawal {}", withdrawal.withdrawal_id),
+        },
+        WithdrawalStatus::Pending | WithdrawalStatus::Sent | WithdrawalStatus::Failed => return,
+    };
+    save_refund(&refund);
+    audit_refund(&refund);
+}
+
+/**
+* Function: refund_excess_investment
+* Description: Records and sends the refund of the part of an investment that exceeded a round's target.
+* @param loan_id: u64 - The loan whose round the investment was made into
+* @param farm_id: u64 - The ID of the farm
+* @param investor_id: u64 - The ID of the investor
+* @param transaction_hash: String - The hash of the deposit
+* @param token: Token - The token of the deposit
+* @param amount: u128 - The excess amount to refund
+* @return Refund - The recorded refund
+*/
+pub async fn refund_excess_investment(
+    loan_id: u64,
+    farm_id: u64,
+    investor_id: u64,
+    transaction_hash: String,
+    token: Token,
+    amount: u128,
+) -> Refund {
+    let refund = queue_refund(
+        loan_id,
+        farm_id,
+        investor_id,
+        transaction_hash,
+        token,
+        amount,
+        RefundReason::ExcessInvestment,
+    );
+    send_refund(refund).await
+}
+
+/**
+* Function: refund_round
+* Description: Refunds every investment made into a cancelled loan's funding round. Investments that already
+* have a refund recorded are skipped, so this is safe to call more than once.
+* @param loan_id: u64 - The ID of the cancelled loan
+* @return Result<Vec<Refund>, Error> - The refunds sent, or an error if the loan was not cancelled
+*/
+pub async fn refund_round(loan_id: u64) -> Result<Vec<Refund>, Error> {
+    let loan = loanmanagement::find_loan(loan_id)?;
+    if loan.status != LoanStatus::Cancelled {
+        return Err(Error::InvalidLoanTransition {
+            msg: format!("Loan {} has not been cancelled ({:?})", loan_id, loan.status),
+        });
+    }
+
+    let round_hashes: Vec<String> = fundingrounds::round_investments(loan_id)
+        .into_iter()
+        .map(|investment| investment.transaction_hash)
+        .collect();
+    let already_refunded: Vec<String> = refunds_matching(|refund| {
+        refund.loan_id == loan_id && refund.reason == RefundReason::FailedRound
+    })
+    .into_iter()
+    .map(|refund| refund.transaction_hash)
+    .collect();
+
+    let queued: Vec<Refund> = payments::get_investments_by_farm(loan.farm_id)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(_, _, hash, _)| round_hashes.contains(hash) && !already_refunded.contains(hash))
+        .map(|(investor_id, amount, hash, token)| {
+            queue_refund(
+                loan_id,
+                loan.farm_id,
+                investor_id,
+                hash,
+                token,
+                amount,
+                RefundReason::FailedRound,
+            )
+        })
+        .collect();
+
+    let mut sent = Vec::new();
+    for refund in queued {
+        sent.push(send_refund(refund).await);
+    }
+    Ok(sent)
+}
+
+/**
+* Function: refund_failed_round
+* Description: Refunds the investors of a cancelled loan's funding round. Loan officer only.
+* @param loan_id: u64 - The ID of the cancelled loan
+* @return Result<Vec<Refund>, Error> - The refunds sent, or an error otherwise
+*/
+#[update(guard = "caller_is_loan_officer")]
+pub async fn refund_failed_round(loan_id: u64) -> Result<Vec<Refund>, Error> {
+    refund_round(loan_id).await
+}
+
+/**
+* Function: retry_refund
+* Description: Re-sends a failed refund, or one left pending for longer than STALE_PENDING_REFUND, optionally
+* switching how it is returned. Treasurer only.
+* @param refund_id: u64 - The ID of the refund
+* @param method: Option<RefundMethod> - The method to use instead of the refund's current one
+* @return Result<Refund, Error> - The refund with its new status, or an error otherwise
+*/
+#[update(guard = "caller_is_treasurer")]
+pub async fn retry_refund(refund_id: u64, method: Option<RefundMethod>) -> Result<Refund, Error> {
+    let mut refund = REFUNDS
+        .with(|refunds| refunds.borrow().get(&refund_id))
+        .ok_or_else(|| Error::RefundNotFound {
+            msg: format!("Refund {} not found", refund_id),
+        })?;
+
+    let now = ic_cdk::api::time();
+    let retryable = match refund.status {
+        RefundStatus::Failed { .. } => true,
+        RefundStatus::Pending => refund
+            .last_attempt_at
+            .is_none_or(|at| now.saturating_sub(at) >= STALE_PENDING_REFUND),
+        RefundStatus::Withdrawing { .. } | RefundStatus::Refunded { .. } => false,
+    };
+    if !retryable {
+        return Err(Error::Error {
+            msg: format!("Refund {} has not failed", refund_id),
+        });
+    }
+
+    if let Some(method) = method {
+        refund.method = method;
+    }
+    Ok(send_refund(refund).await)
+}
+
+/**
+* Function: get_loan_refunds
+* Description: Returns every refund recorded against a loan's funding round.
+* @param loan_id: u64 - The ID of the loan
+* @return Vec<Refund> - The loan's refunds
+*/
+#[query]
+pub fn get_loan_refunds(loan_id: u64) -> Vec<Refund> {
+    refunds_matching(|refund| refund.loan_id == loan_id)
+}
+
+/**
+* Function: get_investor_refunds
+* Description: Returns every refund made to an investor.
+* @param investor_id: u64 - The ID of the investor
+* @return Vec<Refund> - The investor's refunds
+*/
+#[query]
+pub fn get_investor_refunds(investor_id: u64) -> Vec<Refund> {
+    refunds_matching(|refund| refund.investor_id == investor_id)
+}
+
+/**
+* Function: get_failed_refunds
+* Description: Returns every refund whose last attempt failed, for follow-up.
+* @param None
+* @return Vec<Refund> - The failed refunds
+*/
+#[query]
+pub fn get_failed_refunds() -> Vec<Refund> {
+    refunds_matching(|refund| matches!(refund.status, RefundStatus::Failed { .. }))
+}
@@ -0,0 +1,125 @@
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+
+use crate::loanmanagement;
+use crate::tokens::Token;
+
+// Exchange rate canister on the IC
+pub const EXCHANGE_RATE_CANISTER: &str = "uf6dk-hyaaa-aaaaq-qaaaq-cai";
+// Cycles the exchange rate canister charges per request; any it does not use are refunded
+const EXCHANGE_RATE_CYCLES: u128 = 1_000_000_000;
+
+#[derive(CandidType, Deserialize)]
+enum AssetClass {
+    Cryptocurrency,
+    FiatCurrency,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Asset {
+    symbol: String,
+    class: AssetClass,
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetExchangeRateRequest {
+    base_asset: Asset,
+    quote_asset: Asset,
+    timestamp: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ExchangeRateMetadata {
+    decimals: u32,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ExchangeRate {
+    rate: u64,
+    metadata: ExchangeRateMetadata,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct OtherError {
+    code: u32,
+    description: String,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum ExchangeRateError {
+    AnonymousPrincipalNotAllowed,
+    Pending,
+    CryptoBaseAssetNotFound,
+    CryptoQuoteAssetNotFound,
+    StablecoinRateNotFound,
+    StablecoinRateTooFewRates,
+    StablecoinRateZeroRate,
+    ForexInvalidTimestamp,
+    ForexBaseAssetNotFound,
+    ForexQuoteAssetNotFound,
+    ForexAssetsNotFound,
+    RateLimited,
+    NotEnoughCycles,
+    FailedToAcceptCycles,
+    InconsistentRatesReceived,
+    Other(OtherError),
+}
+
+// Latest USD price of ETH, as (rate, decimals of the rate)
+async fn eth_usd_rate() -> Result<(u128, u32), String> {
+    let request = GetExchangeRateRequest {
+        base_asset: Asset {
+            symbol: "ETH".to_string(),
+            class: AssetClass::Cryptocurrency,
+        },
+        quote_asset: Asset {
+            symbol: "USD".to_string(),
+            class: AssetClass::FiatCurrency,
+        },
+        timestamp: None,
+    };
+
+    let exchange_rate_canister = Principal::from_text(EXCHANGE_RATE_CANISTER).expect("exchange rate canister id is valid");
+    let (result,): (Result<ExchangeRate, ExchangeRateError>,) = ic_cdk::api::call::call_with_payment128(
+        exchange_rate_canister,
+        "get_exchange_rate",
+        (request,),
+        EXCHANGE_RATE_CYCLES,
+    )
+    .await
+    .map_err(|(code, msg)| format!("Failed to call the exchange rate canister: {:?} {}", code, msg))?;
+
+    let rate = result.map_err(|e| format!("No ETH/USD rate available: {:?}", e))?;
+    if rate.rate == 0 {
+        return Err("The exchange rate canister returned a zero ETH/USD rate".to_string());
+    }
+    Ok((rate.rate as u128, rate.metadata.decimals))
+}
+
+/**
+* Function: loan_value
+* Description: Values an amount of a token in the loan token's base units, which funding round targets are set in.
+* ckUSDC is taken at par with USD, and ckETH is valued at the exchange rate canister's latest ETH/USD rate.
+* @param token: Token - The token of the amount
+* @param amount: u128 - The amount, in the token's base units
+* @return Result<u128, String> - The value in the loan token's base units, rounded down, or an error if the token
+* cannot be valued
+*/
+pub async fn loan_value(token: Token, amount: u128) -> Result<u128, String> {
+    let loan_token = loanmanagement::LOAN_TOKEN;
+    if token == loan_token {
+        return Ok(amount);
+    }
+    if token != Token::CkETH {
+        return Err(format!("{} cannot be invested in funding rounds", token.symbol()));
+    }
+
+    let (rate, rate_decimals) = eth_usd_rate().await?;
+    let scale = 10u128
+        .checked_pow(rate_decimals + token.decimals() as u32 - loan_token.decimals() as u32)
+        .ok_or_else(|| "ETH/USD rate has too many decimals".to_string())?;
+    amount
+        .checked_mul(rate)
+        .map(|value| value / scale)
+        .ok_or_else(|| format!("{} {} is too large to value", amount, token.symbol()))
+}
@@ -0,0 +1,230 @@
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::query;
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+
+use crate::canistersettings;
+
+const BPS_DENOMINATOR: u128 = 10_000;
+// Default platform fee taken from every investment deposit
+pub const INVESTMENT_FEE_BPS: u64 = 50; // 0.5%
+
+/**
+* Token Enum
+* Tokens the canister holds and records amounts in. Records store the token alongside an amount in the
+* token's base units, and the token resolves to its full descriptor.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Token {
+    CkETH,
+    CkUSDC,
+    IFarm,
+}
+
+/**
+* TokenDescriptor Struct
+* Describes how to read and move amounts of a token.
+* @param Defined In-Line
+* @return TokenDescriptor instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TokenDescriptor {
+    pub token: Token,
+    pub symbol: String,
+    pub decimals: u8,
+    pub ledger: Principal,
+}
+
+impl Token {
+    pub const ALL: [Token; 3] = [Token::CkETH, Token::CkUSDC, Token::IFarm];
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Token::CkETH => "ckETH",
+            Token::CkUSDC => "ckUSDC",
+            Token::IFarm => "iFarm",
+        }
+    }
+
+    pub fn decimals(&self) -> u8 {
+        match self {
+            Token::CkETH => 18,
+            Token::CkUSDC => 6,
+            Token::IFarm => 6, // Minted 1:1 against ckUSDC loan units
+        }
+    }
+
+    // Ledger canister id the token is held on
+    pub fn ledger(&self) -> Principal {
+        let settings = canistersettings::settings();
+        match self {
+            Token::CkETH => settings.cketh_ledger,
+            Token::CkUSDC => settings.ckusdc_ledger,
+            Token::IFarm => settings.ifarm_ledger,
+        }
+    }
+
+    pub fn descriptor(&self) -> TokenDescriptor {
+        TokenDescriptor {
+            token: *self,
+            symbol: self.symbol().to_string(),
+            decimals: self.decimals(),
+            ledger: self.ledger(),
+        }
+    }
+
+    pub fn from_symbol(symbol: &str) -> Option<Token> {
+        Token::ALL.into_iter().find(|token| token.symbol() == symbol)
+    }
+}
+
+// Converts a ledger amount to base units, failing if it does not fit in a u128
+pub fn nat_to_u128(amount: &Nat) -> Result<u128, String> {
+    amount
+        .0
+        .to_u128()
+        .ok_or_else(|| format!("Amount {} is too large", amount))
+}
+
+// Portion of an amount given in basis points, rounded down
+pub fn bps_of(amount: u128, bps: u128) -> u128 {
+    amount * bps / BPS_DENOMINATOR
+}
+
+// Platform fee on an investment deposit, in the deposit's base units
+pub fn investment_fee(amount: u128) -> u128 {
+    bps_of(amount, canistersettings::settings().investment_fee_bps as u128)
+}
+
+/**
+* Function: format_units
+* Description: Formats an amount in base units as a decimal string, e.g. 1_500_000 with 6 decimals is "1.5".
+* @param amount: u128 - The amount in base units
+* @param decimals: u8 - The number of decimals of the token
+* @return String - The formatted amount
+*/
+pub fn format_units(amount: u128, decimals: u8) -> String {
+    let scale = 10u128.pow(decimals as u32);
+    let whole = amount / scale;
+    let fraction = amount % scale;
+
+    if fraction == 0 {
+        return whole.to_string();
+    }
+
+    let fraction = format!("{:0width$}", fraction, width = decimals as usize);
+    format!("{}.{}", whole, fraction.trim_end_matches('0'))
+}
+
+/**
+* Function: parse_units
+* Description: Parses a decimal string into base units, e.g. "1.5" with 6 decimals is 1_500_000.
+* @param value: &str - The decimal amount
+* @param decimals: u8 - The number of decimals of the token
+* @return Result<u128, String> - The amount in base units, or an error if it is malformed or too precise
+*/
+pub fn parse_units(value: &str, decimals: u8) -> Result<u128, String> {
+    let (whole, fraction) = value.trim().split_once('.').unwrap_or((value.trim(), ""));
+
+    if whole.is_empty() && fraction.is_empty() {
+        return Err("Amount is empty".to_string());
+    }
+    if fraction.len() > decimals as usize {
+        return Err(format!("Amount has more than {} decimals", decimals));
+    }
+    if !whole.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+        return Err(format!("Invalid amount: {}", value));
+    }
+
+    let digits = format!("{}{:0<width$}", whole, fraction, width = decimals as usize);
+    digits
+        .parse::<u128>()
+        .map_err(|_| format!("Amount {} is too large", value))
+}
+
+/**
+* Function: get_supported_tokens
+* Description: Returns the descriptor of every token the canister records amounts in.
+* @param None
+* @return Vec<TokenDescriptor> - The supported tokens
+*/
+#[query]
+pub fn get_supported_tokens() -> Vec<TokenDescriptor> {
+    Token::ALL.iter().map(|token| token.descriptor()).collect()
+}
+
+/**
+* Function: format_token_amount
+* Description: Formats an amount in a token's base units for display.
+* @param token: Token - The token the amount is in
+* @param amount: Nat - The amount in base units
+* @return Result<String, String> - The formatted amount with its symbol, or an error if the amount is too large
+*/
+#[query]
+pub fn format_token_amount(token: Token, amount: Nat) -> Result<String, String> {
+    let amount = nat_to_u128(&amount)?;
+    Ok(format!("{} {}", format_units(amount, token.decimals()), token.symbol()))
+}
+
+/**
+* Function: parse_token_amount
+* Description: Converts a decimal amount entered by a user into the token's base units.
+* @param token: Token - The token the amount is in
+* @param value: String - The decimal amount
+* @return Result<Nat, String> - The amount in base units, or an error if it is malformed
+*/
+#[query]
+pub fn parse_token_amount(token: Token, value: String) -> Result<Nat, String> {
+    parse_units(&value, token.decimals()).map(Nat::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_units_trims_trailing_zeros() {
+        assert_eq!(format_units(1_500_000, 6), "1.5");
+        assert_eq!(format_units(2_000_000, 6), "2");
+        assert_eq!(format_units(1, 6), "0.000001");
+        assert_eq!(format_units(0, 6), "0");
+        assert_eq!(format_units(42, 0), "42");
+    }
+
+    #[test]
+    fn parse_units_scales_to_base_units() {
+        assert_eq!(parse_units("1.5", 6), Ok(1_500_000));
+        assert_eq!(parse_units(" 2 ", 6), Ok(2_000_000));
+        assert_eq!(parse_units(".25", 6), Ok(250_000));
+        assert_eq!(parse_units("3.", 6), Ok(3_000_000));
+        assert_eq!(parse_units("0.000000000000000001", 18), Ok(1));
+    }
+
+    #[test]
+    fn parse_units_round_trips_format_units() {
+        for amount in [0, 1, 10, 1_500_000, 123_456_789, u64::MAX as u128] {
+            assert_eq!(parse_units(&format_units(amount, 6), 6), Ok(amount));
+            assert_eq!(parse_units(&format_units(amount, 18), 18), Ok(amount));
+        }
+    }
+
+    #[test]
+    fn parse_units_rejects_malformed_amounts() {
+        assert_eq!(parse_units("", 6), Err("Amount is empty".to_string()));
+        assert_eq!(parse_units(" . ", 6), Err("Amount is empty".to_string()));
+        assert_eq!(parse_units("1.0000001", 6), Err("Amount has more than 6 decimals".to_string()));
+        assert_eq!(parse_units("1.5", 0), Err("Amount has more than 0 decimals".to_string()));
+        assert!(parse_units("-1", 6).is_err());
+        assert!(parse_units("1,5", 6).is_err());
+        assert!(parse_units("1.2.3", 6).is_err());
+        assert!(parse_units("1e6", 6).is_err());
+    }
+
+    #[test]
+    fn parse_units_rejects_amounts_that_overflow() {
+        let too_large = format!("{}0", u128::MAX);
+        assert_eq!(parse_units(&too_large, 0), Err(format!("Amount {} is too large", too_large)));
+        assert!(parse_units(&u128::MAX.to_string(), 6).is_err());
+        assert_eq!(parse_units(&u128::MAX.to_string(), 0), Ok(u128::MAX));
+    }
+}
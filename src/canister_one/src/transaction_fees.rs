@@ -1,23 +1,85 @@
-use std::collections::HashMap;
-use std::cell::RefCell;
+use std::{borrow::Cow, cell::RefCell};
+
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+
+use crate::accesscontrol::caller_is_treasurer;
+use crate::auditlog::{self, AuditAction};
+use crate::entitymanagement::{BoundedString, Memory, MEMORY_MANAGER};
+use crate::tokens::{format_units, Token};
+
+// Fee keys share BoundedString's stored size limit
+const MAX_FEE_KEY_LEN: usize = 1_024;
+
+/**
+* TransactionFee Struct
+* A fee taken by the platform, in the token's base units.
+* @param Defined In-Line
+* @return TransactionFee instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct TransactionFee {
+    pub fee: u128,
+    pub token: Token,
+}
+
+impl Storable for TransactionFee {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TransactionFee {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
 
 thread_local! {
-    pub static TRANSACTION_FEES: RefCell<HashMap<String, f64>> = RefCell::new(HashMap::new());
+    // transaction_hash => fee
+    static TRANSACTION_FEES: RefCell<StableBTreeMap<BoundedString, TransactionFee, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38)))
+        ));
 }
 
-#[ic_cdk::update]
-pub fn store_transaction_fee(transaction_hash: String, fee: f64) -> Result<(), String> {
-    TRANSACTION_FEES.with(|transaction_fees| {
-        let mut transaction_fees = transaction_fees.borrow_mut();
-        transaction_fees.insert(transaction_hash, fee);
+// Saves a fee without recording it in the audit log, for fees carried over from an earlier version
+pub fn insert_transaction_fee(transaction_hash: String, fee: u128, token: Token) -> Result<Option<(u128, Token)>, String> {
+    if transaction_hash.len() > MAX_FEE_KEY_LEN {
+        return Err(format!("Transaction hash must be at most {} bytes", MAX_FEE_KEY_LEN));
+    }
+    let previous = TRANSACTION_FEES.with(|transaction_fees| {
+        transaction_fees
+            .borrow_mut()
+            .insert(BoundedString(transaction_hash), TransactionFee { fee, token })
     });
+    Ok(previous.map(|previous| (previous.fee, previous.token)))
+}
+
+// Fees are recorded by the deposit and payout flows; recording one by hand is a treasury action
+#[ic_cdk::update(guard = "caller_is_treasurer")]
+pub fn store_transaction_fee(transaction_hash: String, fee: u128, token: Token) -> Result<(), String> {
+    let previous = insert_transaction_fee(transaction_hash.clone(), fee, token)?;
+
+    let describe = |(fee, token): (u128, Token)| format!("{} {}", format_units(fee, token.decimals()), token.symbol());
+    auditlog::record(
+        AuditAction::FeeRecorded,
+        format!("fee:{}", transaction_hash),
+        previous.map(describe),
+        Some(describe((fee, token))),
+    );
     Ok(())
 }
 
 #[ic_cdk::query]
-pub fn get_all_transaction_fees() -> Vec<(String, f64)> {
+pub fn get_all_transaction_fees() -> Vec<(String, u128, Token)> {
     TRANSACTION_FEES.with(|transaction_fees| {
         let transaction_fees = transaction_fees.borrow();
-        transaction_fees.iter().map(|(k, v)| (k.clone(), *v)).collect()
+        transaction_fees.iter().map(|(k, fee)| (k.0, fee.fee, fee.token)).collect()
     })
-}
\ No newline at end of file
+}
@@ -0,0 +1,743 @@
+use b3_utils::ledger::{ICRCAccount, ICRC1, ICRC1TransferArgs};
+use candid::{CandidType, Decode, Encode, Nat, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, StableCell, Storable};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::accesscontrol::{self, caller_is_super_admin, principal_key, Role};
+use crate::auditlog::{self, AuditAction};
+use crate::entitymanagement::{Error, Memory, Success, MEMORY_MANAGER};
+use crate::tokens::{self, nat_to_u128, Token};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+// How long a transfer proposal can collect approvals
+const PROPOSAL_TTL: u64 = 7 * NANOS_PER_DAY;
+
+type PrincipalKey = Blob<29>;
+
+/**
+* TreasuryRole Enum
+* Limit tiers for treasury transfers. SuperAdmins get the Admin tier and Treasurers the Treasurer tier.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TreasuryRole {
+    Admin,
+    Treasurer,
+}
+
+impl Storable for TreasuryRole {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TreasuryRole {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/**
+* RoleLimit Struct
+* The most each member of a role may send of a token per day without approval.
+* @param Defined In-Line
+* @return RoleLimit instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RoleLimit {
+    pub role: TreasuryRole,
+    pub token: Token,
+    pub daily_limit: u128, // In the token's base units
+}
+
+/**
+* ApprovalThreshold Struct
+* Transfers of a token at or above `amount` need approval from several admins.
+* @param Defined In-Line
+* @return ApprovalThreshold instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ApprovalThreshold {
+    pub token: Token,
+    pub amount: u128,
+}
+
+/**
+* TreasuryPolicy Struct
+* Limits on moving the canister's funds.
+* @param Defined In-Line
+* @return TreasuryPolicy instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TreasuryPolicy {
+    pub role_limits: Vec<RoleLimit>,
+    pub approval_thresholds: Vec<ApprovalThreshold>,
+    pub required_approvals: u8, // Distinct admins, including the proposer, needed for large transfers
+}
+
+impl Default for TreasuryPolicy {
+    fn default() -> Self {
+        const ETH: u128 = 1_000_000_000_000_000_000;
+        const USDC: u128 = 1_000_000;
+        Self {
+            role_limits: vec![
+                RoleLimit { role: TreasuryRole::Admin, token: Token::CkETH, daily_limit: ETH },
+                RoleLimit { role: TreasuryRole::Admin, token: Token::CkUSDC, daily_limit: 10_000 * USDC },
+                RoleLimit { role: TreasuryRole::Treasurer, token: Token::CkETH, daily_limit: ETH / 10 },
+                RoleLimit { role: TreasuryRole::Treasurer, token: Token::CkUSDC, daily_limit: 1_000 * USDC },
+            ],
+            approval_thresholds: vec![
+                ApprovalThreshold { token: Token::CkETH, amount: ETH / 2 },
+                ApprovalThreshold { token: Token::CkUSDC, amount: 5_000 * USDC },
+            ],
+            required_approvals: 2,
+        }
+    }
+}
+
+impl Storable for TreasuryPolicy {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl TreasuryPolicy {
+    // Roles without a limit for a token cannot send it
+    fn daily_limit(&self, role: TreasuryRole, token: Token) -> u128 {
+        self.role_limits
+            .iter()
+            .find(|limit| limit.role == role && limit.token == token)
+            .map(|limit| limit.daily_limit)
+            .unwrap_or(0)
+    }
+
+    // Tokens without a threshold always need approval
+    fn needs_approval(&self, token: Token, amount: u128) -> bool {
+        self.approval_thresholds
+            .iter()
+            .find(|threshold| threshold.token == token)
+            .is_none_or(|threshold| amount >= threshold.amount)
+    }
+}
+
+/**
+* TreasuryDestination Struct
+* An account treasury funds may be sent to.
+* @param Defined In-Line
+* @return TreasuryDestination instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TreasuryDestination {
+    pub account: String, // ICRC-1 text encoding
+    pub label: String,
+    pub added_by: Principal,
+    pub added_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+struct DestinationList(Vec<TreasuryDestination>);
+
+impl Storable for DestinationList {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// What one principal has sent on a given day
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+struct DailySpend {
+    day: u64,
+    spent: Vec<(Token, u128)>,
+}
+
+impl Storable for DailySpend {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DailySpend {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/**
+* ProposalStatus Enum
+* Where a large treasury transfer stands.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ProposalStatus {
+    Open,
+    Executing,
+    Executed { block_index: Nat },
+    Failed { error: String },
+    Rejected { by: Principal },
+}
+
+/**
+* TransferProposal Struct
+* A treasury transfer above the approval threshold, waiting for admins to approve it.
+* @param Defined In-Line
+* @return TransferProposal instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TransferProposal {
+    pub proposal_id: u64,
+    pub token: Token,
+    pub to: String,
+    pub amount: u128,
+    pub proposed_by: Principal,
+    pub approvals: Vec<Principal>,
+    pub status: ProposalStatus,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+impl Storable for TransferProposal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TransferProposal {
+    const MAX_SIZE: u32 = 2_048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/**
+* TreasuryTransfer Enum
+* Outcome of a treasury transfer request: sent straight away, or waiting for approvals.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum TreasuryTransfer {
+    Sent { block_index: Nat },
+    AwaitingApproval { proposal_id: u64, approvals: u8, required: u8 },
+}
+
+thread_local! {
+    static TREASURY_POLICY: RefCell<StableCell<TreasuryPolicy, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))),
+            TreasuryPolicy::default(),
+        )
+        .expect("Failed to initialise treasury policy")
+    );
+
+    // Accounts treasury funds may be sent to
+    static TREASURY_DESTINATIONS: RefCell<StableCell<DestinationList, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))),
+            DestinationList::default(),
+        )
+        .expect("Failed to initialise treasury destinations")
+    );
+
+    // Treasury roles granted before the role registry existed; emptied by `migrate_treasury_roles`
+    static TREASURY_ROLES: RefCell<StableBTreeMap<PrincipalKey, TreasuryRole, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+        ));
+
+    // Amounts sent today, keyed by sender
+    static DAILY_SPEND: RefCell<StableBTreeMap<PrincipalKey, DailySpend, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+        ));
+
+    // Transfer proposals keyed by proposal id
+    pub static TRANSFER_PROPOSALS: RefCell<StableBTreeMap<u64, TransferProposal, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))
+        ));
+}
+
+fn policy() -> TreasuryPolicy {
+    TREASURY_POLICY.with(|policy| policy.borrow().get().clone())
+}
+
+fn caller_role() -> Option<TreasuryRole> {
+    if accesscontrol::caller_has_role(Role::SuperAdmin) {
+        Some(TreasuryRole::Admin)
+    } else if accesscontrol::caller_has_role(Role::Treasurer) {
+        Some(TreasuryRole::Treasurer)
+    } else {
+        None
+    }
+}
+
+/**
+* Function: migrate_treasury_roles
+* Description: Moves treasury roles into the role registry. Both treasury roles become the Treasurer role, so nobody
+* gains more than treasury access; SuperAdmins can grant more afterwards.
+* @param None
+* @return None
+*/
+pub fn migrate_treasury_roles() {
+    let migrated: Vec<(PrincipalKey, TreasuryRole)> =
+        TREASURY_ROLES.with(|roles| roles.borrow().iter().collect());
+
+    for (key, _) in migrated {
+        let principal = Principal::from_slice(key.as_slice());
+        accesscontrol::insert_grant(&principal, Role::Treasurer, ic_cdk::id());
+        TREASURY_ROLES.with(|roles| roles.borrow_mut().remove(&key));
+    }
+}
+
+// Normalises an account to its ICRC-1 text encoding and checks it is allow-listed
+fn allowed_destination(to: &str) -> Result<ICRCAccount, Error> {
+    let account = ICRCAccount::from_str(to).map_err(|_| Error::Error {
+        msg: format!("Invalid account: {}", to),
+    })?;
+    let encoded = account.to_string();
+
+    let allowed = TREASURY_DESTINATIONS.with(|destinations| {
+        destinations
+            .borrow()
+            .get()
+            .0
+            .iter()
+            .any(|destination| destination.account == encoded)
+    });
+    if !allowed {
+        return Err(Error::NotAuthorized {
+            msg: format!("{} is not an allowed treasury destination", encoded),
+        });
+    }
+    Ok(account)
+}
+
+fn current_day() -> u64 {
+    ic_cdk::api::time() / NANOS_PER_DAY
+}
+
+// Adds to what a principal has sent today if it stays within the limit
+fn reserve_daily_spend(principal: &Principal, token: Token, amount: u128, limit: u128) -> Result<(), Error> {
+    let key = principal_key(principal);
+    let today = current_day();
+
+    DAILY_SPEND.with(|spend| {
+        let mut spend = spend.borrow_mut();
+        let mut daily = spend.get(&key).filter(|daily| daily.day == today).unwrap_or(DailySpend {
+            day: today,
+            spent: Vec::new(),
+        });
+
+        let spent = daily
+            .spent
+            .iter()
+            .find(|(spent_token, _)| *spent_token == token)
+            .map(|(_, spent)| *spent)
+            .unwrap_or(0);
+        if spent + amount > limit {
+            return Err(Error::NotAuthorized {
+                msg: format!(
+                    "Daily {} limit exceeded: {} of {} already sent today",
+                    token.symbol(),
+                    tokens::format_units(spent, token.decimals()),
+                    tokens::format_units(limit, token.decimals())
+                ),
+            });
+        }
+
+        match daily.spent.iter_mut().find(|(spent_token, _)| *spent_token == token) {
+            Some((_, spent)) => *spent += amount,
+            None => daily.spent.push((token, amount)),
+        }
+        spend.insert(key, daily);
+        Ok(())
+    })
+}
+
+// Gives back a reservation whose transfer failed
+fn release_daily_spend(principal: &Principal, token: Token, amount: u128) {
+    let key = principal_key(principal);
+    DAILY_SPEND.with(|spend| {
+        let mut spend = spend.borrow_mut();
+        if let Some(mut daily) = spend.get(&key).filter(|daily| daily.day == current_day()) {
+            if let Some((_, spent)) = daily.spent.iter_mut().find(|(spent_token, _)| *spent_token == token) {
+                *spent = spent.saturating_sub(amount);
+            }
+            spend.insert(key, daily);
+        }
+    });
+}
+
+async fn send(token: Token, to: ICRCAccount, amount: u128) -> Result<Nat, String> {
+    let target = format!("treasury:{}", to);
+    let transfer_args = ICRC1TransferArgs {
+        to,
+        amount: Nat::from(amount),
+        from_subaccount: None,
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+
+    let result = match ICRC1::from(token.ledger()).transfer(transfer_args).await {
+        Ok(Ok(block_index)) => Ok(block_index),
+        Ok(Err(e)) => Err(format!("Ledger rejected the transfer: {:?}", e)),
+        Err(e) => Err(format!("Failed to call ledger: {}", e)),
+    };
+
+    let outcome = match &result {
+        Ok(block_index) => format!("block {}", block_index),
+        Err(error) => error.clone(),
+    };
+    auditlog::record(
+        AuditAction::TokensSent,
+        target,
+        None,
+        Some(format!(
+            "{} {}: {}",
+            tokens::format_units(amount, token.decimals()),
+            token.symbol(),
+            outcome
+        )),
+    );
+    result
+}
+
+fn save_proposal(proposal: &TransferProposal) {
+    TRANSFER_PROPOSALS.with(|proposals| {
+        proposals
+            .borrow_mut()
+            .insert(proposal.proposal_id, proposal.clone())
+    });
+}
+
+fn awaiting_approval(proposal: &TransferProposal, required: u8) -> TreasuryTransfer {
+    TreasuryTransfer::AwaitingApproval {
+        proposal_id: proposal.proposal_id,
+        approvals: proposal.approvals.len() as u8,
+        required,
+    }
+}
+
+// Sends an approved proposal and records the outcome
+async fn execute_proposal(mut proposal: TransferProposal) -> Result<TreasuryTransfer, Error> {
+    let to = allowed_destination(&proposal.to)?;
+
+    proposal.status = ProposalStatus::Executing;
+    save_proposal(&proposal);
+
+    let result = send(proposal.token, to, proposal.amount).await;
+    proposal.status = match &result {
+        Ok(block_index) => ProposalStatus::Executed { block_index: block_index.clone() },
+        Err(error) => ProposalStatus::Failed { error: error.clone() },
+    };
+    save_proposal(&proposal);
+
+    result
+        .map(|block_index| TreasuryTransfer::Sent { block_index })
+        .map_err(|msg| Error::TransferFailed { msg })
+}
+
+/**
+* Function: treasury_transfer
+* Description: Sends the canister's funds to an allow-listed account. Transfers below the approval threshold are
+* sent straight away if they fit within the caller's daily limit; larger ones become a proposal that other admins
+* must approve.
+* @param token: Token - The token to send
+* @param to: String - The ICRC-1 account to send to
+* @param amount: Nat - The amount to send, in the token's base units
+* @return Result<TreasuryTransfer, Error> - The transfer or the proposal awaiting approval, or an error otherwise
+*/
+pub async fn treasury_transfer(token: Token, to: String, amount: Nat) -> Result<TreasuryTransfer, Error> {
+    let role = caller_role().ok_or_else(|| Error::NotAuthorized {
+        msg: "Only SuperAdmins and treasurers can move treasury funds".to_string(),
+    })?;
+    let amount = nat_to_u128(&amount).map_err(|msg| Error::Error { msg })?;
+    let account = allowed_destination(&to)?;
+    let policy = policy();
+    let caller = ic_cdk::caller();
+
+    if policy.needs_approval(token, amount) {
+        if role != TreasuryRole::Admin {
+            return Err(Error::NotAuthorized {
+                msg: "Only SuperAdmins can propose transfers above the approval threshold".to_string(),
+            });
+        }
+
+        let proposal = TRANSFER_PROPOSALS.with(|proposals| {
+            let mut proposals = proposals.borrow_mut();
+            let proposal_id = proposals
+                .last_key_value()
+                .map(|(id, _)| id + 1)
+                .unwrap_or(1);
+            let now = ic_cdk::api::time();
+            let proposal = TransferProposal {
+                proposal_id,
+                token,
+                to: account.to_string(),
+                amount,
+                proposed_by: caller,
+                approvals: vec![caller],
+                status: ProposalStatus::Open,
+                created_at: now,
+                expires_at: now + PROPOSAL_TTL,
+            };
+            proposals.insert(proposal_id, proposal.clone());
+            proposal
+        });
+        auditlog::record(
+            AuditAction::TransferProposed,
+            format!("proposal:{}", proposal.proposal_id),
+            None,
+            Some(format!(
+                "{} {} to {}",
+                tokens::format_units(amount, token.decimals()),
+                token.symbol(),
+                proposal.to
+            )),
+        );
+
+        if proposal.approvals.len() >= policy.required_approvals as usize {
+            return execute_proposal(proposal).await;
+        }
+        return Ok(awaiting_approval(&proposal, policy.required_approvals));
+    }
+
+    reserve_daily_spend(&caller, token, amount, policy.daily_limit(role, token))?;
+    match send(token, account, amount).await {
+        Ok(block_index) => Ok(TreasuryTransfer::Sent { block_index }),
+        Err(msg) => {
+            release_daily_spend(&caller, token, amount);
+            Err(Error::TransferFailed { msg })
+        }
+    }
+}
+
+/**
+* Function: approve_treasury_transfer
+* Description: Approves a transfer proposal, sending it once enough admins have approved. SuperAdmin only.
+* @param proposal_id: u64 - The ID of the proposal
+* @return Result<TreasuryTransfer, Error> - The transfer or the remaining approvals needed, or an error otherwise
+*/
+#[update(guard = "caller_is_super_admin")]
+pub async fn approve_treasury_transfer(proposal_id: u64) -> Result<TreasuryTransfer, Error> {
+    let mut proposal = TRANSFER_PROPOSALS
+        .with(|proposals| proposals.borrow().get(&proposal_id))
+        .ok_or_else(|| Error::Error {
+            msg: format!("Transfer proposal {} not found", proposal_id),
+        })?;
+
+    if proposal.status != ProposalStatus::Open {
+        return Err(Error::Error {
+            msg: format!("Transfer proposal {} is not open ({:?})", proposal_id, proposal.status),
+        });
+    }
+    if ic_cdk::api::time() >= proposal.expires_at {
+        return Err(Error::Error {
+            msg: format!("Transfer proposal {} has expired", proposal_id),
+        });
+    }
+
+    let caller = ic_cdk::caller();
+    let approvals_before = proposal.approvals.len();
+    if !proposal.approvals.contains(&caller) {
+        proposal.approvals.push(caller);
+    }
+    save_proposal(&proposal);
+    auditlog::record(
+        AuditAction::TransferApproved,
+        format!("proposal:{}", proposal_id),
+        Some(format!("{} approvals", approvals_before)),
+        Some(format!("{} approvals", proposal.approvals.len())),
+    );
+
+    let required = policy().required_approvals;
+    if proposal.approvals.len() >= required as usize {
+        return execute_proposal(proposal).await;
+    }
+    Ok(awaiting_approval(&proposal, required))
+}
+
+/**
+* Function: reject_treasury_transfer
+* Description: Rejects an open transfer proposal so it can no longer be approved. SuperAdmin only.
+* @param proposal_id: u64 - The ID of the proposal
+* @return Result<TransferProposal, Error> - The rejected proposal, or an error otherwise
+*/
+#[update(guard = "caller_is_super_admin")]
+pub fn reject_treasury_transfer(proposal_id: u64) -> Result<TransferProposal, Error> {
+    let mut proposal = TRANSFER_PROPOSALS
+        .with(|proposals| proposals.borrow().get(&proposal_id))
+        .filter(|proposal| proposal.status == ProposalStatus::Open)
+        .ok_or_else(|| Error::Error {
+            msg: format!("No open transfer proposal with ID {}", proposal_id),
+        })?;
+
+    proposal.status = ProposalStatus::Rejected { by: ic_cdk::caller() };
+    save_proposal(&proposal);
+    auditlog::record(
+        AuditAction::TransferRejected,
+        format!("proposal:{}", proposal_id),
+        Some(format!("{:?}", ProposalStatus::Open)),
+        Some(format!("{:?}", proposal.status)),
+    );
+    Ok(proposal)
+}
+
+/**
+* Function: add_treasury_destination
+* Description: Allows treasury funds to be sent to an account. SuperAdmin only.
+* @param account: String - The ICRC-1 account to allow
+* @param label: String - What the account is for
+* @return Result<Success, Error> - Success message if the account was added, or an error otherwise
+*/
+#[update(guard = "caller_is_super_admin")]
+pub fn add_treasury_destination(account: String, label: String) -> Result<Success, Error> {
+    let account = ICRCAccount::from_str(&account)
+        .map_err(|_| Error::Error {
+            msg: format!("Invalid account: {}", account),
+        })?
+        .to_string();
+
+    TREASURY_DESTINATIONS.with(|cell| {
+        let mut destinations = cell.borrow().get().clone();
+        destinations.0.retain(|destination| destination.account != account);
+        destinations.0.push(TreasuryDestination {
+            account: account.clone(),
+            label,
+            added_by: ic_cdk::caller(),
+            added_at: ic_cdk::api::time(),
+        });
+        cell.borrow_mut().set(destinations)
+    })
+    .map_err(|e| Error::Error {
+        msg: format!("Failed to save treasury destinations: {:?}", e),
+    })?;
+
+    auditlog::record(
+        AuditAction::ConfigChanged,
+        format!("treasury_destination:{}", account),
+        None,
+        Some("allowed".to_string()),
+    );
+    Ok(Success::TreasuryUpdated {
+        msg: format!("{} added to treasury destinations", account),
+    })
+}
+
+/**
+* Function: remove_treasury_destination
+* Description: Stops treasury funds from being sent to an account. SuperAdmin only.
+* @param account: String - The ICRC-1 account to remove
+* @return Result<Success, Error> - Success message if the account was removed, or an error otherwise
+*/
+#[update(guard = "caller_is_super_admin")]
+pub fn remove_treasury_destination(account: String) -> Result<Success, Error> {
+    TREASURY_DESTINATIONS.with(|cell| {
+        let mut destinations = cell.borrow().get().clone();
+        destinations.0.retain(|destination| destination.account != account);
+        cell.borrow_mut().set(destinations)
+    })
+    .map_err(|e| Error::Error {
+        msg: format!("Failed to save treasury destinations: {:?}", e),
+    })?;
+
+    auditlog::record(
+        AuditAction::ConfigChanged,
+        format!("treasury_destination:{}", account),
+        Some("allowed".to_string()),
+        None,
+    );
+    Ok(Success::TreasuryUpdated {
+        msg: format!("{} removed from treasury destinations", account),
+    })
+}
+
+/**
+* Function: set_treasury_policy
+* Description: Replaces the daily limits, approval thresholds and number of approvals. SuperAdmin only.
+* @param policy: TreasuryPolicy - The new treasury policy
+* @return Result<Success, Error> - Success message if the policy was updated, or an error otherwise
+*/
+#[update(guard = "caller_is_super_admin")]
+pub fn set_treasury_policy(policy: TreasuryPolicy) -> Result<Success, Error> {
+    if policy.required_approvals < 2 {
+        return Err(Error::Error {
+            msg: "Large transfers must need at least two approvals".to_string(),
+        });
+    }
+
+    let after = format!("{:?}", policy);
+    let previous = TREASURY_POLICY
+        .with(|cell| cell.borrow_mut().set(policy))
+        .map_err(|e| Error::Error {
+            msg: format!("Failed to save treasury policy: {:?}", e),
+        })?;
+    auditlog::record(
+        AuditAction::ConfigChanged,
+        "treasury_policy".to_string(),
+        Some(format!("{:?}", previous)),
+        Some(after),
+    );
+
+    Ok(Success::TreasuryUpdated {
+        msg: "Treasury policy updated".to_string(),
+    })
+}
+
+/**
+* Function: get_treasury_policy
+* Description: Returns the daily limits, approval thresholds and number of approvals for treasury transfers.
+* @param None
+* @return TreasuryPolicy - The current treasury policy
+*/
+#[query]
+pub fn get_treasury_policy() -> TreasuryPolicy {
+    policy()
+}
+
+/**
+* Function: get_treasury_destinations
+* Description: Returns the accounts treasury funds may be sent to.
+* @param None
+* @return Vec<TreasuryDestination> - The allowed destinations
+*/
+#[query]
+pub fn get_treasury_destinations() -> Vec<TreasuryDestination> {
+    TREASURY_DESTINATIONS.with(|destinations| destinations.borrow().get().0.clone())
+}
+
+/**
+* Function: get_transfer_proposals
+* Description: Returns treasury transfer proposals, optionally only the open ones.
+* @param open_only: bool - Whether to return only proposals still collecting approvals
+* @return Vec<TransferProposal> - The matching proposals
+*/
+#[query]
+pub fn get_transfer_proposals(open_only: bool) -> Vec<TransferProposal> {
+    TRANSFER_PROPOSALS.with(|proposals| {
+        proposals
+            .borrow()
+            .iter()
+            .map(|(_, proposal)| proposal)
+            .filter(|proposal| !open_only || proposal.status == ProposalStatus::Open)
+            .collect()
+    })
+}
@@ -0,0 +1,186 @@
+use std::borrow::Cow;
+use std::marker::PhantomData;
+
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+
+use crate::entitymanagement::Memory;
+
+// Size of the pieces a value is split into
+const CHUNK_SIZE: usize = 1_024;
+
+#[derive(Clone)]
+struct Chunk(Vec<u8>);
+
+impl Storable for Chunk {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Chunk(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for Chunk {
+    const MAX_SIZE: u32 = CHUNK_SIZE as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/**
+* UnboundedMap Struct
+* A stable map from ids to values of any size. Each value is encoded once and stored as consecutive chunks, so a
+* record that keeps growing, like a farmer collecting tags, assets and reports, never outgrows its map.
+* @param Defined In-Line
+* @return UnboundedMap instance over the given memory.
+*/
+pub struct UnboundedMap<V: Storable> {
+    chunks: StableBTreeMap<(u64, u32), Chunk, Memory>, // (id, chunk index) => chunk
+    _value: PhantomData<V>,
+}
+
+impl<V: Storable> UnboundedMap<V> {
+    pub fn init(memory: Memory) -> Self {
+        Self {
+            chunks: StableBTreeMap::init(memory),
+            _value: PhantomData,
+        }
+    }
+
+    pub fn get(&self, key: &u64) -> Option<V> {
+        let mut bytes = Vec::new();
+        let mut found = false;
+        for (_, chunk) in self.chunks.range((*key, 0)..=(*key, u32::MAX)) {
+            bytes.extend_from_slice(&chunk.0);
+            found = true;
+        }
+        found.then(|| V::from_bytes(Cow::Owned(bytes)))
+    }
+
+    pub fn insert(&mut self, key: u64, value: V) -> Option<V> {
+        let previous = self.remove(&key);
+        let bytes = value.to_bytes();
+        if bytes.is_empty() {
+            self.chunks.insert((key, 0), Chunk(Vec::new()));
+        }
+        for (index, piece) in (0..).zip(bytes.chunks(CHUNK_SIZE)) {
+            self.chunks.insert((key, index), Chunk(piece.to_vec()));
+        }
+        previous
+    }
+
+    // Inserts a value under an id nothing is stored under yet; returns false, leaving the map unchanged, otherwise
+    pub fn insert_new(&mut self, key: u64, value: V) -> bool {
+        if self.chunks.contains_key(&(key, 0)) {
+            return false;
+        }
+        self.insert(key, value);
+        true
+    }
+
+    pub fn remove(&mut self, key: &u64) -> Option<V> {
+        let previous = self.get(key)?;
+        let indexes: Vec<u32> = self
+            .chunks
+            .range((*key, 0)..=(*key, u32::MAX))
+            .map(|((_, index), _)| index)
+            .collect();
+        for index in indexes {
+            self.chunks.remove(&(*key, index));
+        }
+        Some(previous)
+    }
+
+    // Ids in ascending order, found by jumping from one id's first chunk to the next id's
+    pub fn keys(&self) -> impl Iterator<Item = u64> + '_ {
+        let mut next = Some(0);
+        std::iter::from_fn(move || {
+            let from = next?;
+            let ((key, _), _) = self.chunks.range((from, 0)..).next()?;
+            next = key.checked_add(1);
+            Some(key)
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u64, V)> + '_ {
+        self.keys()
+            .map(|key| (key, self.get(&key).expect("listed ids have chunks")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+    use ic_stable_structures::DefaultMemoryImpl;
+
+    fn new_map() -> UnboundedMap<Vec<u8>> {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        UnboundedMap::init(memory_manager.get(MemoryId::new(0)))
+    }
+
+    fn chunk_count(map: &UnboundedMap<Vec<u8>>, key: u64) -> usize {
+        map.chunks.range((key, 0)..=(key, u32::MAX)).count()
+    }
+
+    // A value spanning `chunks` whole chunks plus part of another, filled with `fill`
+    fn value(chunks: usize, fill: u8) -> Vec<u8> {
+        vec![fill; chunks * CHUNK_SIZE + CHUNK_SIZE / 2]
+    }
+
+    #[test]
+    fn values_spanning_several_chunks_round_trip() {
+        let mut map = new_map();
+        map.insert(1, value(3, 1));
+        map.insert(2, Vec::new());
+
+        assert_eq!(map.get(&1), Some(value(3, 1)));
+        assert_eq!(map.get(&2), Some(Vec::new()));
+        assert_eq!(map.get(&3), None);
+        assert_eq!(map.keys().collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn insert_rewrites_a_partial_last_chunk() {
+        let mut map = new_map();
+        map.insert(1, value(2, 1));
+
+        assert_eq!(map.insert(1, value(2, 2)), Some(value(2, 1)));
+        assert_eq!(map.get(&1), Some(value(2, 2)));
+        assert_eq!(chunk_count(&map, 1), 3);
+    }
+
+    #[test]
+    fn insert_of_a_smaller_value_drops_the_extra_chunks() {
+        let mut map = new_map();
+        map.insert(1, value(4, 1));
+        map.insert(2, value(1, 2));
+
+        map.insert(1, vec![3; 10]);
+        assert_eq!(map.get(&1), Some(vec![3; 10]));
+        assert_eq!(chunk_count(&map, 1), 1);
+        assert_eq!(map.get(&2), Some(value(1, 2)));
+    }
+
+    #[test]
+    fn remove_leaves_no_chunks_behind() {
+        let mut map = new_map();
+        map.insert(1, value(1, 1));
+        map.insert(2, value(3, 2));
+        map.insert(3, value(1, 3));
+
+        assert_eq!(map.remove(&2), Some(value(3, 2)));
+        assert_eq!(chunk_count(&map, 2), 0);
+        assert_eq!(map.get(&2), None);
+        assert_eq!(map.remove(&2), None);
+        assert_eq!(map.keys().collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(map.chunks.len(), 4);
+    }
+
+    #[test]
+    fn insert_new_refuses_an_id_in_use() {
+        let mut map = new_map();
+        assert!(map.insert_new(1, vec![1]));
+        assert!(!map.insert_new(1, vec![2]));
+        assert_eq!(map.get(&1), Some(vec![1]));
+    }
+}
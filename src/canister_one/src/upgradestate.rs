@@ -0,0 +1,74 @@
+use candid::utils::{decode_args, ArgumentDecoder};
+use ic_cdk::storage;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::Memory as _;
+
+use crate::entitymanagement::{Memory, MEMORY_MANAGER};
+
+// Length of the saved state, written ahead of it
+const LENGTH_PREFIX: u64 = 8;
+
+// Heap state saved on upgrade gets its own virtual memory, so the memory manager's header and the stable maps survive
+fn saved_state_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)))
+}
+
+// Earlier versions saved their state with `stable_save`, at the start of stable memory over the memory manager's header
+fn has_legacy_state() -> bool {
+    if ic_cdk::api::stable::stable64_size() == 0 {
+        return false;
+    }
+    let mut magic = [0u8; 4];
+    ic_cdk::api::stable::stable64_read(0, &mut magic);
+    &magic == b"DIDL"
+}
+
+/**
+* Function: has_saved_state
+* Description: Checks whether an earlier version saved heap state on upgrade that has not been imported yet.
+* @param None
+* @return bool - Whether saved state is waiting to be restored
+*/
+pub fn has_saved_state() -> bool {
+    if has_legacy_state() {
+        return true;
+    }
+    let memory = saved_state_memory();
+    memory.size() > 0 && saved_length(&memory) > 0
+}
+
+fn saved_length(memory: &Memory) -> u64 {
+    let mut length = [0u8; LENGTH_PREFIX as usize];
+    memory.read(0, &mut length);
+    u64::from_le_bytes(length)
+}
+
+/**
+* Function: restore
+* Description: Reads the heap state saved before the upgrade. State saved by versions that wrote it over the start of
+* stable memory is read from there, so this must run before anything else touches the memory manager.
+* @param None
+* @return Result<T, String> - The saved state, or an error if none was saved or it does not decode as T
+*/
+pub fn restore<T: for<'de> ArgumentDecoder<'de>>() -> Result<T, String> {
+    if has_legacy_state() {
+        return storage::stable_restore::<T>();
+    }
+
+    let memory = saved_state_memory();
+    if memory.size() == 0 {
+        return Err("No state was saved".to_string());
+    }
+    let mut bytes = vec![0u8; saved_length(&memory) as usize];
+    memory.read(LENGTH_PREFIX, &mut bytes);
+    decode_args(&bytes).map_err(|e| e.to_string())
+}
+
+// Forgets state saved in its own virtual memory once it has been imported. State saved over the start of stable
+// memory is already gone by then, replaced by the memory manager's header.
+pub fn clear() {
+    let memory = saved_state_memory();
+    if memory.size() > 0 {
+        memory.write(0, &0u64.to_le_bytes());
+    }
+}
@@ -0,0 +1,352 @@
+use b3_utils::api::{CallCycles, InterCall};
+use b3_utils::ledger::{ICRCAccount, ICRC2, ICRC2Allowance, ICRC2AllowanceArgs, ICRC2ApproveArgs, ICRC2ApproveError};
+use candid::{CandidType, Decode, Encode, Nat};
+use ic_cdk::query;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::auditlog::{self, AuditAction};
+use crate::ck_eth::minter;
+use crate::canistersettings::settings;
+use crate::entitymanagement::{Memory, MEMORY_MANAGER};
+use crate::refunds;
+use crate::tokens::{self, Token};
+
+// How often the minter is asked about withdrawals that have not settled
+pub const WITHDRAWAL_POLL_INTERVAL: Duration = Duration::from_secs(10 * 60); // Every 10 minutes
+// Times the minter's allowance is raised before giving up, when concurrent withdrawals keep changing it
+const APPROVE_ATTEMPTS: usize = 3;
+
+/**
+* WithdrawalStatus Enum
+* Where a withdrawal to Ethereum stands, as reported by the minter.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WithdrawalStatus {
+    Pending,    // Accepted by the minter, no transaction sent yet
+    Sent,       // Transaction sent, not yet finalized
+    Finalized,  // Transaction finalized on Ethereum
+    Reimbursed, // Transaction failed and the minter returned the funds
+    Failed,     // Rejected by the minter, or failed and awaiting reimbursement
+    NotFound,   // The minter has no record of the burn; left for a treasurer to investigate
+}
+
+impl WithdrawalStatus {
+    pub fn is_settled(&self) -> bool {
+        matches!(
+            self,
+            WithdrawalStatus::Finalized | WithdrawalStatus::Reimbursed | WithdrawalStatus::NotFound
+        )
+    }
+}
+
+/**
+* Withdrawal Struct
+* A request to the minter to send ETH or USDC from the canister to an Ethereum address.
+* @param Defined In-Line
+* @return Withdrawal instance with the specified fields.
+*/
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Withdrawal {
+    pub withdrawal_id: u64,
+    pub token: Token,
+    pub amount: u128, // In the token's base units
+    pub recipient: String,
+    pub cketh_block_index: Option<Nat>,   // ckETH burn the minter tracks the withdrawal by
+    pub ckerc20_block_index: Option<Nat>, // ckUSDC burn, for USDC withdrawals
+    pub status: WithdrawalStatus,
+    pub transaction_hash: Option<String>,
+    pub reimbursed_amount: Option<Nat>,
+    pub error: Option<String>,
+    pub requested_at: u64,
+    pub last_polled_at: Option<u64>,
+}
+
+impl Storable for Withdrawal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Withdrawal {
+    const MAX_SIZE: u32 = 1_024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Withdrawals keyed by withdrawal id
+    pub static WITHDRAWALS: RefCell<StableBTreeMap<u64, Withdrawal, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))
+        ));
+}
+
+fn save_withdrawal(withdrawal: &Withdrawal) {
+    WITHDRAWALS.with(|withdrawals| {
+        withdrawals
+            .borrow_mut()
+            .insert(withdrawal.withdrawal_id, withdrawal.clone())
+    });
+}
+
+// Lets the minter burn another `amount` of a token from the canister's main account. An approval replaces the
+// allowance, so it is raised from the current one, and only if no other withdrawal changed it in between.
+async fn approve_minter(token: Token, amount: Nat) -> Result<(), String> {
+    let spender = ICRCAccount::new(settings().cketh_minter, None);
+    let allowance_args = ICRC2AllowanceArgs {
+        account: ICRCAccount::new(ic_cdk::id(), None),
+        spender: spender.clone(),
+    };
+    let (current,): (ICRC2Allowance,) = ic_cdk::call(token.ledger(), "icrc2_allowance", (allowance_args,))
+        .await
+        .map_err(|(code, msg)| format!("Failed to read the minter's {} allowance: {:?} {}", token.symbol(), code, msg))?;
+
+    let mut expected_allowance = current.allowance;
+    for _ in 0..APPROVE_ATTEMPTS {
+        let approve_args = ICRC2ApproveArgs {
+            from_subaccount: None,
+            spender: spender.clone(),
+            amount: expected_allowance.clone() + amount.clone(),
+            expected_allowance: Some(expected_allowance.clone()),
+            expires_at: None,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        };
+
+        match ICRC2::from(token.ledger()).approve(approve_args).await {
+            Ok(Ok(_)) => return Ok(()),
+            // Another withdrawal approved or spent meanwhile; add to the allowance it left
+            Ok(Err(ICRC2ApproveError::AllowanceChanged { current_allowance })) => expected_allowance = current_allowance,
+            Ok(Err(e)) => return Err(format!("{} ledger rejected the minter approval: {:?}", token.symbol(), e)),
+            Err(e) => return Err(format!("Failed to call {} ledger: {}", token.symbol(), e)),
+        }
+    }
+    Err(format!("The minter's {} allowance kept changing, try again", token.symbol()))
+}
+
+// Burns ckETH for a withdrawal, returning the burn block index
+async fn withdraw_eth(amount: u128, recipient: String) -> Result<minter::RetrieveEthRequest, String> {
+    approve_minter(Token::CkETH, Nat::from(amount)).await?;
+
+    let withdraw = minter::WithdrawalArg {
+        amount: Nat::from(amount),
+        recipient,
+    };
+    let result: minter::WithdrawalResult = InterCall::from(settings().cketh_minter)
+        .call("withdraw_eth", withdraw, CallCycles::NoPay)
+        .await
+        .map_err(|e| format!("Failed to call minter: {}", e))?;
+
+    result.map_err(|e| format!("Minter rejected the withdrawal: {:?}", e))
+}
+
+// Burns ckUSDC for a withdrawal, and ckETH for its gas, returning both burn block indexes
+async fn withdraw_usdc(amount: u128, recipient: String) -> Result<minter::RetrieveErc20Request, String> {
+    let ckusdc_ledger = Token::CkUSDC.descriptor().ledger;
+
+    // The minter pays the Ethereum gas from the canister's ckETH
+    let price: minter::Eip1559TransactionPrice = InterCall::from(settings().cketh_minter)
+        .call(
+            "eip_1559_transaction_price",
+            Some(minter::Eip1559TransactionPriceArg { ckerc20_ledger_id: ckusdc_ledger }),
+            CallCycles::NoPay,
+        )
+        .await
+        .map_err(|e| format!("Failed to get the withdrawal gas price: {}", e))?;
+    approve_minter(Token::CkETH, price.max_transaction_fee).await?;
+    approve_minter(Token::CkUSDC, Nat::from(amount)).await?;
+
+    let withdraw = minter::WithdrawErc20Arg {
+        amount: Nat::from(amount),
+        ckerc20_ledger_id: ckusdc_ledger,
+        recipient,
+    };
+    let result: minter::WithdrawErc20Result = InterCall::from(settings().cketh_minter)
+        .call("withdraw_erc20", withdraw, CallCycles::NoPay)
+        .await
+        .map_err(|e| format!("Failed to call minter: {}", e))?;
+
+    result.map_err(|e| format!("Minter rejected the withdrawal: {:?}", e))
+}
+
+/**
+* Function: request_withdrawal
+* Description: Asks the minter to send ckETH or ckUSDC held by the canister to an Ethereum address and records the
+* request. Rejected requests are recorded as failed.
+* @param token: Token - The token to withdraw
+* @param amount: u128 - The amount to withdraw, in the token's base units
+* @param recipient: String - The Ethereum address to send to
+* @return Result<Withdrawal, String> - The recorded withdrawal, or an error if the minter rejected it
+*/
+pub async fn request_withdrawal(token: Token, amount: u128, recipient: String) -> Result<Withdrawal, String> {
+    let withdrawal_id = WITHDRAWALS.with(|withdrawals| {
+        withdrawals
+            .borrow()
+            .last_key_value()
+            .map(|(id, _)| id + 1)
+            .unwrap_or(1)
+    });
+
+    let mut withdrawal = Withdrawal {
+        withdrawal_id,
+        token,
+        amount,
+        recipient: recipient.clone(),
+        cketh_block_index: None,
+        ckerc20_block_index: None,
+        status: WithdrawalStatus::Pending,
+        transaction_hash: None,
+        reimbursed_amount: None,
+        error: None,
+        requested_at: ic_cdk::api::time(),
+        last_polled_at: None,
+    };
+    // Reserve the id before awaiting the minter
+    save_withdrawal(&withdrawal);
+
+    let result = match token {
+        Token::CkETH => withdraw_eth(amount, recipient)
+            .await
+            .map(|request| (request.block_index, None)),
+        Token::CkUSDC => withdraw_usdc(amount, recipient)
+            .await
+            .map(|request| (request.cketh_block_index, Some(request.ckerc20_block_index))),
+        Token::IFarm => Err("iFarm cannot be withdrawn to Ethereum".to_string()),
+    };
+
+    let outcome = match result {
+        Ok((cketh_block_index, ckerc20_block_index)) => {
+            withdrawal.cketh_block_index = Some(cketh_block_index);
+            withdrawal.ckerc20_block_index = ckerc20_block_index;
+            Ok(())
+        }
+        Err(error) => {
+            withdrawal.status = WithdrawalStatus::Failed;
+            withdrawal.error = Some(error.clone());
+            Err(error)
+        }
+    };
+    save_withdrawal(&withdrawal);
+
+    auditlog::record(
+        AuditAction::TokensWithdrawn,
+        format!("withdrawal:{}", withdrawal_id),
+        None,
+        Some(format!(
+            "{} {} to {}: {}",
+            tokens::format_units(amount, token.decimals()),
+            token.symbol(),
+            withdrawal.recipient,
+            withdrawal.error.as_deref().unwrap_or("requested")
+        )),
+    );
+    outcome.map(|_| withdrawal)
+}
+
+// Updates a withdrawal from the minter's view of it
+async fn poll_withdrawal(mut withdrawal: Withdrawal) -> Result<(), String> {
+    let block_index = match withdrawal.cketh_block_index.as_ref().and_then(|index| index.0.to_u64()) {
+        Some(block_index) => block_index,
+        None => return Ok(()),
+    };
+
+    let status: minter::RetrieveEthStatus = InterCall::from(settings().cketh_minter)
+        .call("retrieve_eth_status", block_index, CallCycles::NoPay)
+        .await
+        .map_err(|e| format!("Failed to call minter: {}", e))?;
+
+    withdrawal.last_polled_at = Some(ic_cdk::api::time());
+    match status {
+        minter::RetrieveEthStatus::NotFound => {
+            withdrawal.status = WithdrawalStatus::NotFound;
+            withdrawal.error = Some("Minter has no record of the withdrawal".to_string());
+        }
+        minter::RetrieveEthStatus::Pending | minter::RetrieveEthStatus::TxCreated => {
+            withdrawal.status = WithdrawalStatus::Pending;
+        }
+        minter::RetrieveEthStatus::TxSent(tx) => {
+            withdrawal.status = WithdrawalStatus::Sent;
+            withdrawal.transaction_hash = Some(tx.transaction_hash);
+        }
+        minter::RetrieveEthStatus::TxFinalized(minter::TxFinalizedStatus::Success(tx)) => {
+            withdrawal.status = WithdrawalStatus::Finalized;
+            withdrawal.transaction_hash = Some(tx.transaction_hash);
+        }
+        minter::RetrieveEthStatus::TxFinalized(minter::TxFinalizedStatus::Reimbursed(tx)) => {
+            withdrawal.status = WithdrawalStatus::Reimbursed;
+            withdrawal.transaction_hash = Some(tx.transaction_hash);
+            withdrawal.reimbursed_amount = Some(tx.reimbursed_amount);
+        }
+        minter::RetrieveEthStatus::TxFinalized(minter::TxFinalizedStatus::PendingReimbursement(tx)) => {
+            withdrawal.status = WithdrawalStatus::Failed;
+            withdrawal.transaction_hash = Some(tx.transaction_hash);
+            withdrawal.error = Some("Transaction failed, awaiting reimbursement".to_string());
+        }
+    }
+
+    save_withdrawal(&withdrawal);
+    refunds::settle_withdrawal(&withdrawal);
+    Ok(())
+}
+
+/**
+* Function: poll_withdrawals
+* Description: Refreshes the status of every withdrawal the minter accepted that has not settled yet.
+* @param None
+* @return None
+*/
+pub async fn poll_withdrawals() {
+    let unsettled: Vec<Withdrawal> = WITHDRAWALS.with(|withdrawals| {
+        withdrawals
+            .borrow()
+            .iter()
+            .map(|(_, withdrawal)| withdrawal)
+            .filter(|withdrawal| withdrawal.cketh_block_index.is_some() && !withdrawal.status.is_settled())
+            .collect()
+    });
+
+    for withdrawal in unsettled {
+        let withdrawal_id = withdrawal.withdrawal_id;
+        if let Err(e) = poll_withdrawal(withdrawal).await {
+            ic_cdk::println!("Withdrawal {}: {}", withdrawal_id, e);
+        }
+    }
+}
+
+/**
+* Function: start_withdrawal_polling
+* Description: Arms the timer that tracks withdrawals through the minter. Called from both `init` and
+* `post_upgrade`.
+* @param None
+* @return None
+*/
+pub fn start_withdrawal_polling() {
+    ic_cdk_timers::set_timer_interval(WITHDRAWAL_POLL_INTERVAL, || ic_cdk::spawn(poll_withdrawals()));
+}
+
+/**
+* Function: get_withdrawals
+* Description: Returns the withdrawals to Ethereum, optionally only those with a given status.
+* @param status: Option<WithdrawalStatus> - The status to filter by
+* @return Vec<Withdrawal> - The matching withdrawals
+*/
+#[query]
+pub fn get_withdrawals(status: Option<WithdrawalStatus>) -> Vec<Withdrawal> {
+    WITHDRAWALS.with(|withdrawals| {
+        withdrawals
+            .borrow()
+            .iter()
+            .map(|(_, withdrawal)| withdrawal)
+            .filter(|withdrawal| status.is_none_or(|status| withdrawal.status == status))
+            .collect()
+    })
+}